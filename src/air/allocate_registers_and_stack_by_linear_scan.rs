@@ -14,6 +14,7 @@ use crate::{
     utils::{
         bitvector::BitVector, deque::VecDequeExt, index_set::IndexMap, phase_scope::phase_scope,
     },
+    width::{bytes_for_width, Width},
     OptLevel,
 };
 
@@ -38,6 +39,7 @@ use super::{
     stack_slot::{StackSlotId, StackSlotKind},
     tmp::Tmp,
     tmp_set::TmpMap,
+    tmp_width::TmpWidth,
 };
 
 /// This implements the Poletto and Sarkar register allocator called "linear scan":
@@ -79,6 +81,7 @@ struct LinearScan<'a, 'b> {
     used_spillslots: BitVector,
     did_spill: bool,
     biases: HashMap<Tmp, indexmap::IndexSet<Tmp>>,
+    tmp_width: TmpWidth,
 }
 
 impl<'a, 'b: 'a> LinearScan<'a, 'b> {
@@ -87,6 +90,7 @@ impl<'a, 'b: 'a> LinearScan<'a, 'b> {
             start_index: IndexMap::with_capacity(code.blocks.len()),
             map: TmpMap::with_capacity(code.num_gp_tmps + code.num_fp_tmps),
             insertion_sets: IndexMap::with_capacity(code.blocks.len()),
+            tmp_width: TmpWidth::new(code),
             code,
             allowed_registers_in_priority_order: [Vec::new(), Vec::new()],
             allowed_registers: [ScalarRegisterSet::empty(), ScalarRegisterSet::empty()],
@@ -588,9 +592,13 @@ impl<'a, 'b: 'a> LinearScan<'a, 'b> {
     }
 
     fn spill(&mut self, tmp: Tmp) {
-        let slot = self
-            .code
-            .add_stack_slot(size_of::<usize>(), StackSlotKind::Spill);
+        // Vectors need the whole 16 bytes, everything else fits in a word.
+        let byte_size = if self.tmp_width.required_width(tmp) == Width::W128 {
+            bytes_for_width(Width::W128)
+        } else {
+            size_of::<usize>()
+        };
+        let slot = self.code.add_stack_slot(byte_size, StackSlotKind::Spill);
 
         let entry = &mut self.map[tmp];
         debug_assert!(entry.spilled.is_none());
@@ -647,6 +655,8 @@ impl<'a, 'b: 'a> LinearScan<'a, 'b> {
                         if let Some(spilled) = spilled {
                             let mov = if bank == Bank::GP {
                                 Opcode::Move
+                            } else if self.tmp_width.required_width(*tmp) == Width::W128 {
+                                Opcode::MoveVector
                             } else {
                                 Opcode::MoveDouble
                             };
@@ -713,21 +723,34 @@ impl<'a, 'b: 'a> LinearScan<'a, 'b> {
                     }
 
                     let spill_index = self.map[*tmp].spill_index;
+                    let expired_slot = self.map[*tmp].spilled.unwrap();
+                    let expired_words = self.code.stack_slot(expired_slot).byte_size() as usize / 8;
                     self.active.pop_front();
-                    self.used_spillslots.set(spill_index, false);
+                    for i in 0..expired_words {
+                        self.used_spillslots.set(spill_index + i, false);
+                    }
                 }
 
-                let ix = self.used_spillslots.find_bit(0, false);
+                let slot_size = self.code.stack_slot(spilled).byte_size() as usize;
+                let words = slot_size / 8;
+
+                // Vector slots take two adjacent words and start at an even word so that they
+                // are 16-byte aligned.
+                let mut ix = self.used_spillslots.find_bit(0, false);
+                while words == 2 && (ix % 2 != 0 || self.used_spillslots.get(ix + 1)) {
+                    ix = self.used_spillslots.find_bit(ix + 1, false);
+                }
                 self.map[tmp].spill_index = ix;
-                let slot_size = 8;
 
                 let offset = -(self.code.frame_size as isize)
-                    - (self.map[tmp].spill_index as isize) * (slot_size as isize)
+                    - (self.map[tmp].spill_index as isize) * 8
                     - (slot_size as isize);
 
                 self.code.proc.stack_slots[spilled.0].offset_from_fp = offset;
 
-                self.used_spillslots.set(self.map[tmp].spill_index, true);
+                for i in 0..words {
+                    self.used_spillslots.set(self.map[tmp].spill_index + i, true);
+                }
                 self.active.push_front(tmp);
             }
         }
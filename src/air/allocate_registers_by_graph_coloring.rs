@@ -36,6 +36,7 @@ use super::pad_interference::pad_interference;
 use super::stack_slot::StackSlotKind;
 use super::tmp::AbsoluteIndexed;
 use super::tmp::Tmp;
+use super::tmp_width::TmpWidth;
 use super::use_counts::UseCounts;
 
 trait MoveSetTrait {
@@ -2350,6 +2351,11 @@ impl<'a> GraphColoringRegisterAllocation<'a> {
         }
     }
 
+    /// Spills are at least 64 bits wide, but vectors have to keep all 128 bits.
+    fn spill_width(tmp_width: &TmpWidth, tmp: Tmp) -> Width {
+        tmp_width.required_width(tmp).max(Width::W64)
+    }
+
     fn stack_slot_minimum_width(width: Width) -> usize {
         if width <= Width::W32 {
             4
@@ -2372,6 +2378,7 @@ impl<'a> GraphColoringRegisterAllocation<'a> {
     ) {
         let mut stackslots = HashMap::new();
         let code_ptr = (&mut *allocator.allocator).code as *mut Code;
+        let tmp_width = TmpWidth::new(unsafe { &*code_ptr });
         for &tmp in allocator.allocator.spilled_tmps.iter() {
             let tmp = if BANK == Bank::GP as i8 {
                 AbsoluteIndexed::<{ Bank::GP as i8 }>::tmp_for_absolute_index(tmp as usize)
@@ -2388,7 +2395,7 @@ impl<'a> GraphColoringRegisterAllocation<'a> {
             unspillable_tmps.set(index, true);
 
             let stackslot = unsafe { &mut *code_ptr }.add_stack_slot(
-                Self::stack_slot_minimum_width(Width::W64),
+                Self::stack_slot_minimum_width(Self::spill_width(&tmp_width, tmp)),
                 StackSlotKind::Spill,
             );
             let is_new_tmp = stackslots.insert(tmp, stackslot).is_none();
@@ -2412,7 +2419,7 @@ impl<'a> GraphColoringRegisterAllocation<'a> {
                 let args = inst.args.as_ptr() as usize;
                 inst.for_each_arg_mut(
                     unsafe { &mut *code_ptr },
-                    |arg_index, arg, role, arg_bank, _width| {
+                    |arg_index, arg, role, arg_bank, width| {
                         if !arg.is_tmp() {
                             return;
                         }
@@ -2492,7 +2499,15 @@ impl<'a> GraphColoringRegisterAllocation<'a> {
                                 return;
                             }
 
-                            let spill_width = Width::W64;
+                            let spill_width = Self::spill_width(&tmp_width, arg.tmp());
+
+                            // Spilling in place would leave the rest of a vector's slot unwritten.
+                            if role.is_any_def()
+                                && width < spill_width
+                                && spill_width > Width::W64
+                            {
+                                return;
+                            }
 
                             allocator
                                 .allocator
@@ -2549,10 +2564,12 @@ impl<'a> GraphColoringRegisterAllocation<'a> {
                     }
 
                     if let Some(stack_slot) = stackslots.get(tmp).copied() {
-                        let _spill_width = Width::W64;
+                        let spill_width = Self::spill_width(&tmp_width, *tmp);
 
                         let mov = if BANK == Bank::GP as i8 {
                             Opcode::Move
+                        } else if spill_width == Width::W128 {
+                            Opcode::MoveVector
                         } else {
                             Opcode::MoveDouble
                         };
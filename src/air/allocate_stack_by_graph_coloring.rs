@@ -75,6 +75,7 @@ impl<'a, 'b, G: InterferenceGraph> GraphColoringStackAllocator<'a, 'b, G> {
         let width = match inst.kind.opcode {
             Opcode::Move | Opcode::MoveDouble => Width::W64,
             Opcode::Move32 | Opcode::MoveFloat => Width::W32,
+            Opcode::MoveVector => Width::W128,
             _ => return false,
         };
 
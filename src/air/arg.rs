@@ -1,6 +1,7 @@
 use crate::{
     bank::{bank_for_type, Bank},
     jit::reg::Reg,
+    simd::{SIMDInfo, SIMDLane, SIMDSignMode},
     typ::Type,
     width::{bytes_for_width, width_for_bytes, Width},
 };
@@ -200,6 +201,15 @@ impl ArgRole {
         self.is_any_use() && !self.is_cold_use()
     }
 
+    /// Returns the cold version of a use role. Roles that aren't uses are returned unchanged.
+    pub const fn cooled(self) -> Self {
+        match self {
+            Self::Use => Self::ColdUse,
+            Self::LateUse => Self::LateColdUse,
+            _ => self,
+        }
+    }
+
     pub const fn temperature(self) -> ArgTemperature {
         if self.is_cold_use() {
             ArgTemperature::Cold
@@ -782,6 +792,14 @@ impl Arg {
         }
     }
 
+    pub fn new_simd_info(info: SIMDInfo) -> Self {
+        Self {
+            kind: ArgKind::SIMDInfo,
+            offset: (info.lane as i64) | ((info.sign_mode as i64) << 8),
+            ..Default::default()
+        }
+    }
+
     pub fn zero_reg() -> Self {
         Self {
             kind: ArgKind::ZeroReg,
@@ -1163,6 +1181,7 @@ impl Arg {
                 | ArgKind::StatusCond
                 | ArgKind::Special
                 | ArgKind::WidthArg
+                | ArgKind::SIMDInfo
         ) || (self.is_tmp() && self.is_gp_tmp())
         {
             true
@@ -1327,6 +1346,14 @@ impl Arg {
         unsafe { std::mem::transmute::<u8, StatusCondition>(self.offset as _) }
     }
 
+    pub fn as_simd_info(&self) -> SIMDInfo {
+        debug_assert!(self.is_simd_info());
+        SIMDInfo {
+            lane: unsafe { std::mem::transmute::<u8, SIMDLane>(self.offset as _) },
+            sign_mode: unsafe { std::mem::transmute::<u8, SIMDSignMode>((self.offset >> 8) as _) },
+        }
+    }
+
     pub fn is_invertible(&self) -> bool {
         match self.kind {
             ArgKind::RelCond | ArgKind::DoubleCond | ArgKind::StatusCond => true,
@@ -1476,8 +1503,8 @@ impl std::fmt::Display for Arg {
             }
 
             ArgKind::Stack => {
-                if self.offset != 0 {
-                    write!(f, "{}", self.offset)?;
+                if self.offset() != 0 {
+                    write!(f, "{}", self.offset())?;
                 }
 
                 write!(f, "($stack{})", self.stack_slot().0)
@@ -1498,6 +1525,7 @@ impl std::fmt::Display for Arg {
             ArgKind::StatusCond => write!(f, "{:?}", self.as_status_condition()),
             ArgKind::Special => write!(f, "$special{}", self.special().0),
             ArgKind::WidthArg => write!(f, "{}", self.width()),
+            ArgKind::SIMDInfo => write!(f, "{:?}", self.as_simd_info()),
             _ => todo!(),
         }
     }
@@ -14,6 +14,7 @@ use crate::{
 
 use super::{
     arg::{Arg, ArgKind, ArgRole},
+    ccalling_convention::return_value_register,
     code::Code,
     form_table::is_arm64,
     generation_context::GenerationContext,
@@ -23,7 +24,7 @@ use super::{
 
 /// Use this special for constructing a C call. Arg 0 is of course a Special arg that refers to the
 /// CCallSpecial object. Arg 1 is the callee, and it can be an ImmPtr, a register, or an address. The
-/// next three args - arg 2, arg 3, and arg 4 - hold the return value GPRs and FPR. The remaining args
+/// next four args - arg 2 through arg 5 - hold the return value GPRs and FPRs. The remaining args
 /// are just the set of argument registers used by this call. For arguments that go to the stack, you
 /// have to do the grunt work of doing those stack stores. In fact, the only reason why we specify the
 /// argument registers as arguments to a call is so that the liveness analysis can see that they get
@@ -48,7 +49,7 @@ impl CCallSpecial {
     pub const NUM_RETURN_GP_ARGS: usize = 2;
     pub const RETURN_FP_ARG_OFFSET: usize =
         Self::NUM_SPECIAL_ARGS + Self::NUM_CALLEE_ARGS + Self::NUM_RETURN_GP_ARGS;
-    pub const NUM_RETURN_FP_ARGS: usize = 1;
+    pub const NUM_RETURN_FP_ARGS: usize = 2;
     pub const ARG_ARG_OFFSET: usize = Self::NUM_SPECIAL_ARGS
         + Self::NUM_CALLEE_ARGS
         + Self::NUM_RETURN_GP_ARGS
@@ -62,6 +63,7 @@ impl CCallSpecial {
         clobbered.remove(Reg::new_gpr(RETURN_VALUE_GPR));
         clobbered.remove(Reg::new_gpr(RETURN_VALUE_GPR2));
         clobbered.remove(Reg::new_fpr(RETURN_VALUE_FPR));
+        clobbered.remove(return_value_register(Bank::FP, 1));
 
         Self {
             clobbered_regs: clobbered,
@@ -210,6 +212,12 @@ impl CCallSpecial {
             return false;
         }
 
+        if inst.args[Self::RETURN_FP_ARG_OFFSET + 1]
+            != Arg::new_tmp(Tmp::from_reg(return_value_register(Bank::FP, 1)))
+        {
+            return false;
+        }
+
         for i in Self::ARG_ARG_OFFSET..inst.args.len() {
             let arg = inst.args[i];
 
@@ -1,43 +1,182 @@
 use macroassembler::jit::{
-    fpr_info::RETURN_VALUE_FPR,
+    fpr_info::{self, RETURN_VALUE_FPR},
     gpr_info::{RETURN_VALUE_GPR, RETURN_VALUE_GPR2},
 };
 
 use crate::{
     bank::{bank_for_type, Bank},
     jit::{reg::Reg, register_at_offset::round_up_to_multiple_of},
-    typ::{size_of_type, TypeKind},
+    procedure::Procedure,
+    typ::{size_of_type, Type, TypeKind},
     value::ValueId,
-    width::{bytes_for_width, width_for_type},
+    width::{bytes_for_width, width_for_type, Width},
 };
 
 use super::{
-    arg::Arg, ccall_special::CCallSpecial, code::Code, inst::Inst, opcode::Opcode, tmp::Tmp,
+    arg::Arg, ccall_special::CCallSpecial, code::Code, form_table::is_x86, inst::Inst,
+    opcode::Opcode, tmp::Tmp,
 };
 
+/// How the C calling convention passes or returns a value of aggregate type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AggregateClass {
+    /// Each eightbyte of the aggregate goes into the next register of its bank.
+    ///
+    /// Return values use `RAX` and then `RDX` for GP eightbytes, `XMM0` and then `XMM1` for FP
+    /// eightbytes. Arguments use the argument registers, but if there are not enough of them left
+    /// for all eightbytes the whole aggregate is passed on stack.
+    Registers(Vec<Bank>),
+    /// Arguments are copied to the stack argument area. For return values the caller passes a
+    /// pointer to memory for the result as a hidden first argument. The callee stores the result
+    /// there and returns the pointer.
+    Memory,
+}
+
+/// Classifies how an aggregate of type `typ` is passed or returned according to the System V ABI.
+///
+/// Aggregates larger than 16 bytes are passed in memory. Smaller ones are split into eightbytes:
+/// an eightbyte is FP if all fields overlapping it are `Float` or `Double`, and GP otherwise.
+pub fn classify_aggregate(proc: &Procedure, typ: Type) -> AggregateClass {
+    let size = proc.size_of(typ);
+
+    if size > 16 {
+        return AggregateClass::Memory;
+    }
+
+    let mut banks = vec![Bank::FP; (size + 7) / 8];
+
+    for_each_scalar_field(proc, typ, 0, &mut |typ, offset| {
+        assert!(!typ.is_vector(), "vectors in aggregates are not supported");
+
+        if bank_for_type(typ) == Bank::GP {
+            banks[offset / 8] = Bank::GP;
+        }
+    });
+
+    AggregateClass::Registers(banks)
+}
+
+/// Returns true if a value of type `typ` is returned through a hidden pointer argument.
+pub fn returns_in_memory(proc: &Procedure, typ: Type) -> bool {
+    typ.is_aggregate() && classify_aggregate(proc, typ) == AggregateClass::Memory
+}
+
+fn for_each_scalar_field(
+    proc: &Procedure,
+    typ: Type,
+    offset: usize,
+    f: &mut impl FnMut(Type, usize),
+) {
+    if !typ.is_aggregate() {
+        f(typ, offset);
+        return;
+    }
+
+    let aggregate = proc.aggregate(typ);
+
+    for index in 0..aggregate.num_elements() {
+        let element = aggregate.element_type(index);
+        for_each_scalar_field(proc, element, offset + aggregate.offset_of(index), f);
+    }
+}
+
+/// Register that returns the `index`th eightbyte of `bank`. The second FP return register is the
+/// same as the second FP argument register.
+pub fn return_value_register(bank: Bank, index: usize) -> Reg {
+    match (bank, index) {
+        (Bank::GP, 0) => Reg::new_gpr(RETURN_VALUE_GPR),
+        (Bank::GP, 1) => Reg::new_gpr(RETURN_VALUE_GPR2),
+        (Bank::FP, 0) => Reg::new_fpr(RETURN_VALUE_FPR),
+        (Bank::FP, 1) => Reg::new_fpr(fpr_info::to_argument_register(1)),
+        _ => panic!("no return register #{} in {:?} bank", index, bank),
+    }
+}
+
+/// Registers holding the eightbytes of an aggregate of type `typ` that is returned in registers.
+pub fn aggregate_return_registers(proc: &Procedure, typ: Type) -> Vec<Reg> {
+    let banks = match classify_aggregate(proc, typ) {
+        AggregateClass::Registers(banks) => banks,
+        AggregateClass::Memory => panic!("{} is returned in memory", typ),
+    };
+
+    let mut counts = [0; 2];
+
+    banks
+        .iter()
+        .map(|&bank| {
+            let count = &mut counts[(bank == Bank::FP) as usize];
+            *count += 1;
+            return_value_register(bank, *count - 1)
+        })
+        .collect()
+}
+
 pub fn ccall_result_count(code: &Code<'_>, value: ValueId) -> usize {
-    match code.proc.value(value).typ().kind() {
+    let typ = code.proc.value(value).typ();
+
+    match typ.kind() {
         TypeKind::Void => 0,
+        TypeKind::Aggregate => aggregate_return_registers(code.proc, typ).len(),
         _ => 1,
     }
 }
 
+/// Bank and width of result `index` of a C call. Aggregates are returned as whole eightbytes.
+pub fn ccall_result_bank_and_width(code: &Code<'_>, value: ValueId, index: usize) -> (Bank, Width) {
+    let typ = code.proc.value(value).typ();
+
+    if typ.is_aggregate() {
+        let reg = aggregate_return_registers(code.proc, typ)[index];
+        let bank = if reg.is_gpr() { Bank::GP } else { Bank::FP };
+        (bank, Width::W64)
+    } else {
+        (bank_for_type(typ), width_for_type(typ))
+    }
+}
+
 pub fn ccall_argument_register_count(code: &Code<'_>, value: ValueId) -> usize {
-    match code.proc.value(value).typ().kind() {
+    ccall_argument_location_count(code.proc, code.proc.value(value).typ())
+}
+
+/// Number of locations that [`ccall_argument_locations`] returns for an argument of type `typ`.
+pub fn ccall_argument_location_count(proc: &Procedure, typ: Type) -> usize {
+    if typ.is_aggregate() {
+        // Aggregates are passed one eightbyte at a time, either in registers or on stack.
+        (proc.size_of(typ) + 7) / 8
+    } else {
+        argument_register_count_for_type(typ)
+    }
+}
+
+/// Bank and width of the `index`th register or stack slot that passes an argument of type `typ`.
+/// Aggregates are passed as whole eightbytes. Eightbytes of aggregates passed in memory are moved
+/// through GP registers.
+pub fn ccall_argument_bank_and_width(proc: &Procedure, typ: Type, index: usize) -> (Bank, Width) {
+    if !typ.is_aggregate() {
+        return (bank_for_type(typ), width_for_type(typ));
+    }
+
+    match classify_aggregate(proc, typ) {
+        AggregateClass::Registers(banks) => (banks[index], Width::W64),
+        AggregateClass::Memory => (Bank::GP, Width::W64),
+    }
+}
+
+fn argument_register_count_for_type(typ: Type) -> usize {
+    match typ.kind() {
         TypeKind::Void => 0,
         _ => 1,
     }
 }
 
 fn marshall_ccall_argument_impl<const BANK: i8>(
-    code: &Code,
     result: &mut Vec<Arg>,
     argument_count: &mut usize,
     stack_offset: &mut usize,
-    child: ValueId,
+    typ: Type,
 ) {
     let bank = Bank::from(BANK);
-    let register_count = ccall_argument_register_count(code, child);
+    let register_count = argument_register_count_for_type(typ);
 
     if *argument_count < bank.num_of_argument_registers() {
         for _ in 0..register_count {
@@ -56,8 +195,8 @@ fn marshall_ccall_argument_impl<const BANK: i8>(
     let slot_alignment;
 
     if cfg!(target_arch = "aarch64") && cfg!(any(target_os = "macos", target_os = "ios")) {
-        slot_size = bytes_for_width(width_for_type(code.proc.value(child).typ()));
-        slot_alignment = size_of_type(code.proc.value(child).typ());
+        slot_size = bytes_for_width(width_for_type(typ));
+        slot_alignment = size_of_type(typ);
     } else {
         slot_size = 8;
         slot_alignment = slot_size as _;
@@ -70,66 +209,220 @@ fn marshall_ccall_argument_impl<const BANK: i8>(
     }
 }
 
+fn marshall_ccall_aggregate(
+    proc: &Procedure,
+    result: &mut Vec<Arg>,
+    gp_argument_count: &mut usize,
+    fp_argument_count: &mut usize,
+    stack_offset: &mut usize,
+    typ: Type,
+) {
+    if let AggregateClass::Registers(banks) = classify_aggregate(proc, typ) {
+        let gp_needed = banks.iter().filter(|&&bank| bank == Bank::GP).count();
+        let fp_needed = banks.len() - gp_needed;
+
+        // An aggregate is never split between registers and stack.
+        if *gp_argument_count + gp_needed <= Bank::GP.num_of_argument_registers()
+            && *fp_argument_count + fp_needed <= Bank::FP.num_of_argument_registers()
+        {
+            for bank in banks {
+                let reg = match bank {
+                    Bank::GP => Reg::new_gpr(bank.to_argument_register(*gp_argument_count)),
+                    Bank::FP => Reg::new_fpr(bank.to_argument_register(*fp_argument_count)),
+                };
+                result.push(Arg::new_tmp(Tmp::from_reg(reg)));
+
+                match bank {
+                    Bank::GP => *gp_argument_count += 1,
+                    Bank::FP => *fp_argument_count += 1,
+                }
+            }
+
+            return;
+        }
+    }
+
+    *stack_offset = round_up_to_multiple_of(8, *stack_offset as _) as usize;
+    for _ in 0..(proc.size_of(typ) + 7) / 8 {
+        result.push(Arg::new_call_arg(*stack_offset as _));
+        *stack_offset += 8;
+    }
+}
+
 fn marshall_ccall_argument(
-    code: &mut Code,
+    proc: &Procedure,
     result: &mut Vec<Arg>,
     gp_argument_count: &mut usize,
     fp_argument_count: &mut usize,
     stack_offset: &mut usize,
-    child: ValueId,
+    typ: Type,
 ) {
-    match bank_for_type(code.proc.value(child).typ()) {
+    if typ.is_aggregate() {
+        marshall_ccall_aggregate(
+            proc,
+            result,
+            gp_argument_count,
+            fp_argument_count,
+            stack_offset,
+            typ,
+        );
+        return;
+    }
+
+    match bank_for_type(typ) {
         Bank::FP => marshall_ccall_argument_impl::<{ Bank::FP as i8 }>(
-            code,
             result,
             fp_argument_count,
             stack_offset,
-            child,
+            typ,
         ),
         Bank::GP => marshall_ccall_argument_impl::<{ Bank::GP as i8 }>(
-            code,
             result,
             gp_argument_count,
             stack_offset,
-            child,
+            typ,
         ),
     }
 }
 
-pub fn compute_ccalling_convention(code: &mut Code, value: ValueId) -> Vec<Arg> {
+/// Computes where arguments of `types` are passed in the C calling convention. Each location is
+/// either an argument register or a `CallArg` at an offset into the stack argument area. Also
+/// returns the size of the stack argument area in bytes. Aggregates take one location for each of
+/// their eightbytes.
+///
+/// This is used both for outgoing arguments of calls and for incoming parameters of a procedure.
+pub fn ccall_argument_locations(proc: &Procedure, types: &[Type]) -> (Vec<Arg>, usize) {
     let mut result = vec![];
 
-    result.push(Arg::new_tmp(Tmp::from_reg(Reg::new_gpr(
-        CCallSpecial::SCRATCH_REGISTER,
-    )))); // for callee
-
     let mut gp_argument_count = 0;
     let mut fp_argument_count = 0;
     let mut stack_offset = 0;
 
-    for i in 1..code.proc.value(value).children.len() {
-        let child = code.proc.value(value).children[i];
+    for &typ in types {
         marshall_ccall_argument(
-            code,
+            proc,
             &mut result,
             &mut gp_argument_count,
             &mut fp_argument_count,
             &mut stack_offset,
-            child,
+            typ,
         );
     }
 
+    (result, stack_offset)
+}
+
+/// Computes where the procedure being compiled receives its arguments according to its signature,
+/// like [`ccall_argument_locations`]. If the result is returned in memory, the hidden pointer to it
+/// comes first. Procedures without a signature receive no arguments.
+pub fn incoming_argument_locations(proc: &Procedure) -> (Vec<Arg>, usize) {
+    let signature = match proc.signature() {
+        Some(signature) => signature,
+        None => return (vec![], 0),
+    };
+
+    let hidden = returns_in_memory(proc, signature.ret) as usize;
+    let types = std::iter::repeat(Type::Int64)
+        .take(hidden)
+        .chain(signature.params.iter().copied())
+        .collect::<Vec<_>>();
+
+    ccall_argument_locations(proc, &types)
+}
+
+/// Checks that tail call `value` fits its stack arguments into the incoming stack arguments of the
+/// procedure being compiled. They are written over the incoming ones, and that area belongs to our
+/// caller, so a tail call cannot grow it.
+pub fn check_tail_call_stack_arguments(proc: &Procedure, value: ValueId) -> Result<(), String> {
+    let types = proc.value(value).children[1..]
+        .iter()
+        .map(|&child| proc.value(child).typ())
+        .collect::<Vec<_>>();
+    let (_, needed) = ccall_argument_locations(proc, &types);
+    let (_, available) = incoming_argument_locations(proc);
+
+    if needed > available {
+        return Err(format!(
+            "tail call needs {} bytes of stack arguments, but the procedure only receives {}",
+            needed, available
+        ));
+    }
+
+    Ok(())
+}
+
+/// Locations of parameter `index` of the procedure being compiled, see
+/// [`incoming_argument_locations`]. Aggregates take one location for each of their eightbytes.
+pub fn parameter_locations(proc: &Procedure, index: usize) -> Vec<Arg> {
+    let signature = proc.signature().expect("procedure has no signature");
+    let (locations, _) = incoming_argument_locations(proc);
+
+    let start = returns_in_memory(proc, signature.ret) as usize
+        + signature.params[..index]
+            .iter()
+            .map(|&typ| ccall_argument_location_count(proc, typ))
+            .sum::<usize>();
+    let count = ccall_argument_location_count(proc, signature.params[index]);
+
+    locations[start..start + count].to_vec()
+}
+
+pub fn compute_ccalling_convention(code: &mut Code, value: ValueId) -> Vec<Arg> {
+    let mut result = vec![];
+
+    result.push(Arg::new_tmp(Tmp::from_reg(Reg::new_gpr(
+        CCallSpecial::SCRATCH_REGISTER,
+    )))); // for callee
+
+    let types = code.proc.value(value).children[1..]
+        .iter()
+        .map(|&child| code.proc.value(child).typ())
+        .collect::<Vec<_>>();
+
+    let (arguments, stack_size) = ccall_argument_locations(code.proc, &types);
+    result.extend(arguments);
+
     code.call_arg_area_size = code
         .call_arg_area_size
-        .max(round_up_to_multiple_of(16, stack_offset as _) as _);
+        .max(round_up_to_multiple_of(16, stack_size as _) as _);
     result
 }
 
-pub fn ccall_result(code: &mut Code, value: ValueId, _index: usize) -> Tmp {
-    match code.proc.value(value).typ().kind() {
+/// Variadic callees on x86 expect an upper bound of the number of vector registers used for
+/// arguments in AL. If the call `value` needs it, returns the `Move` that sets AL for the argument
+/// locations `arguments`, together with AL itself so that the call can use it.
+pub fn variadic_vector_count(
+    code: &Code,
+    value: ValueId,
+    arguments: &[Arg],
+) -> Option<(Inst, Arg)> {
+    if !is_x86() || !code.proc.value(value).is_variadic_ccall() {
+        return None;
+    }
+
+    let fp_argument_count = arguments
+        .iter()
+        .filter(|argument| argument.is_fpr())
+        .count();
+    let al = Arg::new_tmp(Tmp::from_reg(Reg::new_gpr(RETURN_VALUE_GPR)));
+
+    let inst = Inst::new(
+        Opcode::Move.into(),
+        value,
+        &[Arg::new_imm(fp_argument_count as i64), al],
+    );
+
+    Some((inst, al))
+}
+
+pub fn ccall_result(code: &mut Code, value: ValueId, index: usize) -> Tmp {
+    let typ = code.proc.value(value).typ();
+
+    match typ.kind() {
         TypeKind::Void => Tmp::empty(),
         TypeKind::Int32 | TypeKind::Int64 => Tmp::from_reg(Reg::new_gpr(RETURN_VALUE_GPR)),
         TypeKind::Float | TypeKind::Double => Tmp::from_reg(Reg::new_fpr(RETURN_VALUE_FPR)),
+        TypeKind::Aggregate => Tmp::from_reg(aggregate_return_registers(code.proc, typ)[index]),
 
         _ => todo!(),
     }
@@ -146,6 +439,8 @@ pub fn build_ccall(code: &mut Code, origin: ValueId, arguments: &[Arg]) -> Inst
         .push(Arg::new_tmp(Tmp::from_reg(Reg::new_gpr(RETURN_VALUE_GPR2))));
     inst.args
         .push(Arg::new_tmp(Tmp::from_reg(Reg::new_fpr(RETURN_VALUE_FPR))));
+    inst.args
+        .push(Arg::new_tmp(Tmp::from_reg(return_value_register(Bank::FP, 1))));
     for i in 1..arguments.len() {
         if arguments[i].is_tmp() {
             inst.args.push(arguments[i]);
@@ -21,7 +21,7 @@ use super::{
         emit_function_epilogue, emit_function_epilogue_with_empty_frame, emit_function_prologue,
         emit_restore, emit_save,
     },
-    special::{Special, SpecialId},
+    special::{Special, SpecialId, SpecialKind},
     stack_slot::{StackSlot, StackSlotId, StackSlotKind},
     tmp::Tmp,
 };
@@ -450,6 +450,9 @@ impl<'a> std::fmt::Display for Code<'a> {
                     if i != 0 {
                         write!(f, ", ")?;
                     }
+                    if succ.1 == Frequency::Rare {
+                        write!(f, "Rare:")?;
+                    }
                     write!(f, "BB{}", succ.0 .0)?;
                 }
 
@@ -457,6 +460,35 @@ impl<'a> std::fmt::Display for Code<'a> {
             }
         }
 
+        if !self.proc.stack_slots.is_empty() {
+            writeln!(f, "Stack slots:")?;
+            for slot in self.proc.stack_slots.iter() {
+                write!(
+                    f,
+                    "  stack{}: {} {:?}",
+                    slot.index(),
+                    slot.byte_size(),
+                    slot.kind()
+                )?;
+                if slot.min_alignment > 1 {
+                    write!(f, " align {}", slot.min_alignment)?;
+                }
+                writeln!(f)?;
+            }
+        }
+
+        if !self.proc.specials.is_empty() {
+            writeln!(f, "Specials:")?;
+            for special in self.proc.specials.iter() {
+                let kind = match special.kind {
+                    SpecialKind::CCall(_) => "CCall",
+                    SpecialKind::Patchpoint(_) => "Patchpoint",
+                    SpecialKind::Check(_) => "Check",
+                };
+                writeln!(f, "  special{}: {}", special.index, kind)?;
+            }
+        }
+
         Ok(())
     }
 }
@@ -3,15 +3,14 @@ use std::collections::HashSet;
 
 use macroassembler::assembler::{abstract_macro_assembler::Jump, TargetMacroAssembler};
 
-use crate::{
-    bank::{bank_for_type, Bank},
-    jit::register_set::RegisterSetBuilder,
-    width::{width_for_type, Width},
-};
+use crate::{bank::Bank, jit::register_set::RegisterSetBuilder, width::Width};
 
 use super::{
     arg::{Arg, ArgKind, ArgRole},
-    ccalling_convention::{ccall_argument_register_count, ccall_result_count},
+    ccalling_convention::{
+        ccall_argument_bank_and_width, ccall_argument_register_count, ccall_result_bank_and_width,
+        ccall_result_count,
+    },
     code::Code,
     generation_context::GenerationContext,
     inst::Inst,
@@ -158,20 +157,18 @@ impl CCallCustom {
         let result_count = ccall_result_count(code, inst.origin);
 
         for n in 0..result_count {
-            let typ = code.proc.value(inst.origin).typ();
+            let (bank, width) = ccall_result_bank_and_width(code, inst.origin, n);
 
-            next(ArgRole::Def, bank_for_type(typ), width_for_type(typ));
+            next(ArgRole::Def, bank, width);
         }
 
         for i in 1..code.proc.value(inst.origin).children.len() {
             let child = code.proc.value(inst.origin).children[i];
+            let typ = code.proc.value(child).typ();
 
             for j in 0..ccall_argument_register_count(code, child) {
-                next(
-                    ArgRole::Use,
-                    bank_for_type(code.proc.value(child).typ()),
-                    width_for_type(code.proc.value(child).typ()),
-                );
+                let (bank, width) = ccall_argument_bank_and_width(code.proc, typ, j);
+                next(ArgRole::Use, bank, width);
             }
         }
 
@@ -196,20 +193,18 @@ impl CCallCustom {
         let result_count = ccall_result_count(code, inst.origin);
 
         for n in 0..result_count {
-            let typ = code.proc.value(inst.origin).typ();
+            let (bank, width) = ccall_result_bank_and_width(code, inst.origin, n);
 
-            next(ArgRole::Def, bank_for_type(typ), width_for_type(typ));
+            next(ArgRole::Def, bank, width);
         }
 
         for i in 1..code.proc.value(inst.origin).children.len() {
             let child = code.proc.value(inst.origin).children[i];
+            let typ = code.proc.value(child).typ();
 
             for j in 0..ccall_argument_register_count(code, child) {
-                next(
-                    ArgRole::Use,
-                    bank_for_type(code.proc.value(child).typ()),
-                    width_for_type(code.proc.value(child).typ()),
-                );
+                let (bank, width) = ccall_argument_bank_and_width(code.proc, typ, j);
+                next(ArgRole::Use, bank, width);
             }
         }
 
@@ -240,10 +235,10 @@ impl CCallCustom {
         }
 
         // The arguments can only refer to the stack, tmps, or immediates.
-        for i in (0..inst.args.len() - 1).rev() {
+        for i in 1..inst.args.len() {
             let arg = inst.args[i];
 
-            if !arg.is_tmp() || !arg.is_stack_memory() && !arg.is_some_imm() {
+            if !arg.is_tmp() && !arg.is_stack_memory() && !arg.is_some_imm() {
                 return false;
             }
         }
@@ -261,26 +256,25 @@ impl CCallCustom {
                 return false;
             }
 
-            if !inst.args[offset].can_represent(code.proc.value(inst.origin).typ()) {
+            let (bank, _) = ccall_result_bank_and_width(code, inst.origin, i);
+            if !inst.args[offset].is_bank(bank) {
                 return false;
             }
 
             offset += 1;
         }
 
-        let mut check_next_arg = |child| {
-            let res = inst.args[offset].can_represent(code.proc.value(inst.origin).typ());
-            offset += 1;
-            res
-        };
-
         for i in 1..code.proc.value(inst.origin).children.len() {
             let child = code.proc.value(inst.origin).children[i];
+            let typ = code.proc.value(child).typ();
 
             for j in 0..ccall_argument_register_count(code, child) {
-                if !check_next_arg(child) {
+                let (bank, _) = ccall_argument_bank_and_width(code.proc, typ, j);
+                if !inst.args[offset].is_bank(bank) {
                     return false;
                 }
+
+                offset += 1;
             }
         }
 
@@ -324,17 +318,20 @@ impl ColdCCallCustom {
         inst: &Inst,
         mut lambda: impl FnMut(usize, &Arg, ArgRole, Bank, Width),
     ) {
+        // Arguments of a cold call may just as well be passed from the stack.
         CCallCustom::for_each_arg(code, inst, |ix, arg, role, bank, width| {
-            lambda(ix, arg, role, bank, width)
+            lambda(ix, arg, role.cooled(), bank, width)
         });
     }
 
     pub fn for_each_arg_mut(
         code: &mut Code<'_>,
         inst: &mut Inst,
-        lambda: impl FnMut(usize, &mut Arg, ArgRole, Bank, Width),
+        mut lambda: impl FnMut(usize, &mut Arg, ArgRole, Bank, Width),
     ) {
-        CCallCustom::for_each_arg_mut(code, inst, lambda);
+        CCallCustom::for_each_arg_mut(code, inst, |ix, arg, role, bank, width| {
+            lambda(ix, arg, role.cooled(), bank, width)
+        });
     }
 
     pub fn is_valid_form_static(args: &[ArgKind]) -> bool {
@@ -371,7 +368,7 @@ impl ColdCCallCustom {
         jit: &mut TargetMacroAssembler,
         context: &mut GenerationContext,
     ) -> Jump {
-        todo!()
+        unreachable!("ColdCCall is lowered by lower_after_regalloc, which knows the live registers")
     }
 }
 
@@ -546,7 +543,7 @@ impl ShuffleCustom {
         jit: &mut TargetMacroAssembler,
         context: &mut GenerationContext,
     ) -> Jump {
-        todo!()
+        unreachable!("Shuffle is lowered by lower_after_regalloc, which can pick scratch registers")
     }
 }
 
@@ -603,3 +600,142 @@ impl EntrySwitchCustom {
         todo!()
     }
 }
+
+pub struct TailCallCustom {}
+
+impl TailCallCustom {
+    pub fn for_each_arg(
+        code: &Code<'_>,
+        inst: &Inst,
+        mut lambda: impl FnMut(usize, &Arg, ArgRole, Bank, Width),
+    ) {
+        // The callee and all arguments must stay alive until the frame is torn down.
+        for (index, arg) in inst.args.iter().enumerate() {
+            lambda(index, arg, ArgRole::Use, arg.bank(), Width::W64);
+        }
+    }
+
+    pub fn for_each_arg_mut(
+        code: &mut Code<'_>,
+        inst: &mut Inst,
+        mut lambda: impl FnMut(usize, &mut Arg, ArgRole, Bank, Width),
+    ) {
+        for (index, arg) in inst.args.iter_mut().enumerate() {
+            let bank = arg.bank();
+            lambda(index, arg, ArgRole::Use, bank, Width::W64);
+        }
+    }
+
+    pub fn is_valid_form_static(args: &[ArgKind]) -> bool {
+        let _ = args;
+        false
+    }
+
+    pub fn is_valid_form(inst: &Inst, code: &Code<'_>) -> bool {
+        if inst.args.is_empty() || !inst.args[0].is_gp() {
+            return false;
+        }
+
+        inst.args
+            .iter()
+            .all(|arg| arg.is_tmp() || arg.is_some_imm())
+    }
+
+    pub fn admits_stack(inst: &Inst, arg_index: usize, code: &Code<'_>) -> bool {
+        false
+    }
+
+    pub fn admits_extended_offset_addr(inst: &Inst, arg_index: usize, code: &Code<'_>) -> bool {
+        false
+    }
+
+    pub fn is_terminal(inst: &Inst, code: &Code<'_>) -> bool {
+        true
+    }
+
+    pub fn has_non_arg_effects(inst: &Inst, code: &Code<'_>) -> bool {
+        true
+    }
+
+    pub fn has_non_arg_non_control_effects(inst: &Inst, code: &Code<'_>) -> bool {
+        true
+    }
+
+    pub fn generate(
+        inst: &Inst,
+        jit: &mut TargetMacroAssembler,
+        context: &mut GenerationContext,
+    ) -> Jump {
+        // By now `lower_macros` has placed the arguments and the callee into registers that are
+        // not restored by the epilogue, so we can tear down our frame and jump to the callee. It
+        // returns straight to our caller.
+        context.code.emit_epilogue_without_return(jit);
+        jit.far_jump(inst.args[0].gpr());
+
+        Jump::default()
+    }
+}
+
+pub struct RetTupleCustom {}
+
+impl RetTupleCustom {
+    pub fn for_each_arg(
+        code: &Code<'_>,
+        inst: &Inst,
+        mut lambda: impl FnMut(usize, &Arg, ArgRole, Bank, Width),
+    ) {
+        for (index, arg) in inst.args.iter().enumerate() {
+            lambda(index, arg, ArgRole::Use, arg.bank(), Width::W64);
+        }
+    }
+
+    pub fn for_each_arg_mut(
+        code: &mut Code<'_>,
+        inst: &mut Inst,
+        mut lambda: impl FnMut(usize, &mut Arg, ArgRole, Bank, Width),
+    ) {
+        for (index, arg) in inst.args.iter_mut().enumerate() {
+            let bank = arg.bank();
+            lambda(index, arg, ArgRole::Use, bank, Width::W64);
+        }
+    }
+
+    pub fn is_valid_form_static(args: &[ArgKind]) -> bool {
+        let _ = args;
+        false
+    }
+
+    pub fn is_valid_form(inst: &Inst, code: &Code<'_>) -> bool {
+        inst.args.iter().all(|arg| arg.is_reg())
+    }
+
+    pub fn admits_stack(inst: &Inst, arg_index: usize, code: &Code<'_>) -> bool {
+        false
+    }
+
+    pub fn admits_extended_offset_addr(inst: &Inst, arg_index: usize, code: &Code<'_>) -> bool {
+        false
+    }
+
+    pub fn is_terminal(inst: &Inst, code: &Code<'_>) -> bool {
+        true
+    }
+
+    pub fn has_non_arg_effects(inst: &Inst, code: &Code<'_>) -> bool {
+        true
+    }
+
+    pub fn has_non_arg_non_control_effects(inst: &Inst, code: &Code<'_>) -> bool {
+        false
+    }
+
+    pub fn generate(
+        inst: &Inst,
+        jit: &mut TargetMacroAssembler,
+        context: &mut GenerationContext,
+    ) -> Jump {
+        context.code.emit_epilogue(jit);
+
+        Jump::default()
+    }
+}
@@ -21,6 +21,11 @@ pub fn move_for(bank: Bank, width: Width) -> Opcode {
             Bank::FP => Opcode::MoveDouble,
             Bank::GP => Opcode::Move,
         },
+        Width::W128 => {
+            assert_eq!(bank, Bank::FP);
+
+            Opcode::MoveVector
+        }
 
         _ => unreachable!(),
     }
@@ -26,15 +26,20 @@ use super::{
     generation_context::GenerationContext, lower_after_regalloc::lower_after_regalloc,
     lower_entry_switch::lower_entry_switch, lower_macros::lower_macros,
     lower_stack_args::lower_stack_args, opcode::Opcode, simplify_cfg::simplify_cfg,
+    validate::validate,
 };
 
 pub fn prepare_for_generation(code: &mut Code<'_>) {
     phase_scope("air::prepare_for_generation", || {
         code.reset_reachability();
+        validate_after_phase(code, "lowering to Air");
         simplify_cfg(code);
+        validate_after_phase(code, "simplify_cfg");
         // Lower macros before register allocation. Some examples are `CCall`s.
         lower_macros(code);
+        validate_after_phase(code, "lower_macros");
         eliminate_dead_code(code);
+        validate_after_phase(code, "eliminate_dead_code");
 
         let num_tmps = code.num_tmps(Bank::GP) + code.num_tmps(Bank::FP);
 
@@ -50,44 +55,65 @@ pub fn prepare_for_generation(code: &mut Code<'_>) {
             // When we're compiling quickly, we do register and stack allocation in one linear scan
             // phase. It's fast because it computes liveness only once.
             allocate_registers_and_stack_by_linear_scan(code);
+            validate_after_phase(code, "allocate_registers_and_stack_by_linear_scan");
             // We may still need to do post-allocation lowering. Doing it after both register and
             // stack allocation is less optimal, but it works fine.
             lower_after_regalloc(code);
+            validate_after_phase(code, "lower_after_regalloc");
         } else {
             // Register allocation for all the Tmps that do not have a corresponding machine
             // register. After this phase, every Tmp has a reg.
             allocate_registers_by_graph_coloring(code);
+            validate_after_phase(code, "allocate_registers_by_graph_coloring");
 
             // This replaces uses of spill slots with registers or constants if possible. It
             // does this by minimizing the amount that we perturb the already-chosen register
             // allocation. It may extend the live ranges of registers though.
             fix_obvious_spills(code);
+            validate_after_phase(code, "fix_obvious_spills");
 
             lower_after_regalloc(code);
+            validate_after_phase(code, "lower_after_regalloc");
             // This does first-fit allocation of stack slots using an interference graph plus a
             // bunch of other optimizations.
             allocate_stack_by_graph_coloring(code);
+            validate_after_phase(code, "allocate_stack_by_graph_coloring");
         }
 
         // This turns all Stack and CallArg Args into Addr args that use the frame pointer.
         lower_stack_args(code);
+        validate_after_phase(code, "lower_stack_args");
         lower_entry_switch(code);
+        validate_after_phase(code, "lower_entry_switch");
         //eliminate_dead_code(code);
         // If we coalesced moves then we can unbreak critical edges. This is the main reason for this
         // phase.
         simplify_cfg(code);
         code.reset_reachability();
+        validate_after_phase(code, "simplify_cfg");
         //eliminate_dead_code(code);
         code.reset_reachability();
         // Optimize the order of basic blocks based on their frequency. Before this we used RPO sort that does not produce
         // best order for blocks but aids in optimizations.
         optimize_block_order(code);
+        validate_after_phase(code, "optimize_block_order");
         if code.proc.options.dump_air_at_each_phase {
             println!("AIR after prepare_for_generation:\n{}", code);
         }
     });
 }
 
+/// Panics if `validate_air_at_each_phase` is enabled and `phase` left the code malformed.
+fn validate_after_phase(code: &Code<'_>, phase: &str) {
+    if !code.proc.options.validate_air_at_each_phase {
+        return;
+    }
+
+    if let Err(error) = validate(code) {
+        panic!("Air validation failed after {}: {}\n{}", phase, error, code);
+    }
+}
+
 pub fn generate<'a, 'b>(code: &'a mut Code<'b>, jit: &mut TargetMacroAssembler) {
     generate_with_already_allocated_registers(code, jit);
 }
@@ -11,7 +11,10 @@ use crate::{
 use super::{
     arg::{Arg, ArgRole},
     code::Code,
-    custom::{CCallCustom, ColdCCallCustom, EntrySwitchCustom, PatchCustom, ShuffleCustom},
+    custom::{
+        CCallCustom, ColdCCallCustom, EntrySwitchCustom, PatchCustom, RetTupleCustom,
+        ShuffleCustom, TailCallCustom,
+    },
     form_table::{decode_form_bank, decode_form_role, decode_form_width, is_x86},
     kind::Kind,
     opcode::Opcode,
@@ -153,6 +156,10 @@ impl Inst {
 
             Opcode::ColdCCall => ColdCCallCustom::for_each_arg(code, self, f),
 
+            Opcode::TailCall => TailCallCustom::for_each_arg(code, self, f),
+
+            Opcode::RetTuple => RetTupleCustom::for_each_arg(code, self, f),
+
             _ => self.for_each_arg_simple(f),
         }
     }
@@ -173,6 +180,10 @@ impl Inst {
 
             Opcode::ColdCCall => ColdCCallCustom::for_each_arg_mut(code, self, f),
 
+            Opcode::TailCall => TailCallCustom::for_each_arg_mut(code, self, f),
+
+            Opcode::RetTuple => RetTupleCustom::for_each_arg_mut(code, self, f),
+
             _ => self.for_each_arg_simple_mut(f),
         }
     }
@@ -4,14 +4,16 @@ use crate::{
     bank::Bank,
     jit::register_set::{RegisterSetBuilder, ScalarRegisterSet},
     utils::phase_scope::phase_scope,
-    width::{bytes_for_width, width_for_type},
+    width::{bytes_for_width, Width},
 };
 
 use super::{
     arg::Arg,
     basic_block::BasicBlockId,
     ccalling_convention::{
-        build_ccall, ccall_result, ccall_result_count, compute_ccalling_convention,
+        build_ccall, ccall_argument_bank_and_width, ccall_argument_register_count, ccall_result,
+        ccall_result_bank_and_width, ccall_result_count, compute_ccalling_convention,
+        variadic_vector_count,
     },
     code::Code,
     emit_shuffle::{emit_shuffle, ShufflePair},
@@ -137,12 +139,10 @@ pub fn lower_after_regalloc(code: &mut Code) {
 
                         let mut pairs = vec![];
 
-                        let mut i = 0;
-
-                        while i < inst.args.len() {
-                            let src = inst.args[i];
-                            let dst = inst.args[i + 1];
-                            let width = inst.args[i + 2].width();
+                        for triple in inst.args.chunks_exact(3) {
+                            let src = triple[0];
+                            let dst = triple[1];
+                            let width = triple[2].width();
 
                             src.for_each_tmp_fast(|tmp| {
                                 if tmp.is_reg() {
@@ -156,8 +156,6 @@ pub fn lower_after_regalloc(code: &mut Code) {
                             });
 
                             pairs.push(ShufflePair::new(src, dst, width));
-
-                            i += 3;
                         }
                         let origin = inst.origin;
                         let gp_scratch = get_scratches(code, set, Bank::GP);
@@ -186,36 +184,51 @@ pub fn lower_after_regalloc(code: &mut Code) {
                         let mut pre_used =
                             live_regs.build_and_validate().build_scalar_register_set();
                         let mut post_used = pre_used;
-                        let destinations = compute_ccalling_convention(code, value);
+                        let mut destinations = compute_ccalling_convention(code, value);
+                        let result_count = ccall_result_count(code, value);
 
                         let mut results = Vec::new();
                         let mut original_results = Vec::new();
 
-                        for i in 0..ccall_result_count(code, value) {
-                            results.push(ccall_result(code, value, i));
-                            original_results.push(inst.args[i + 2]);
+                        for index in 0..result_count {
+                            results.push(ccall_result(code, value, index));
+                            original_results.push(inst.args[index + 2]);
                         }
 
-                        let mut pairs = Vec::new();
-
-                        for i in 0..destinations.len() {
-                            let child = code.proc.value(value).children[i];
-                            let src = inst.args[if i >= 1 { i + results.len() + 1 } else { i + 1 }];
-
-                            let dst = destinations[i];
-
-                            let width = width_for_type(code.proc.value(child).typ());
-
-                            pairs.push(ShufflePair::new(src, dst, width));
+                        // The callee goes first, then every argument takes one location per
+                        // register it needs, exactly like in `lower_macros`.
+                        let mut pairs = vec![ShufflePair::new(
+                            inst.args[1],
+                            destinations[0],
+                            Width::W64,
+                        )];
+
+                        let mut offset = 1;
+                        for child_index in 1..code.proc.value(value).children.len() {
+                            let child = code.proc.value(value).children[child_index];
+                            let typ = code.proc.value(child).typ();
+
+                            for j in 0..ccall_argument_register_count(code, child) {
+                                let (_, width) = ccall_argument_bank_and_width(code.proc, typ, j);
+                                pairs.push(ShufflePair::new(
+                                    inst.args[offset + result_count + 1],
+                                    destinations[offset],
+                                    width,
+                                ));
+                                offset += 1;
+                            }
+                        }
 
-                            src.for_each_tmp_fast(|tmp| {
+                        // Scratches must not clobber any source or destination of the shuffle.
+                        for pair in pairs.iter() {
+                            pair.src.for_each_tmp_fast(|tmp| {
                                 if tmp.is_reg() {
                                     pre_used.add(tmp.reg());
                                 }
                             });
-                            dst.for_each_tmp_fast(|tmp| {
+                            pair.dst.for_each_tmp_fast(|tmp| {
                                 if tmp.is_reg() {
-                                    post_used.add(tmp.reg());
+                                    pre_used.add(tmp.reg());
                                 }
                             });
                         }
@@ -229,6 +242,8 @@ pub fn lower_after_regalloc(code: &mut Code) {
                             }
                         }
 
+                        // Only the caller-saved registers that are live across the call are saved,
+                        // and only on this path.
                         let mut stack_slots = vec![];
 
                         regs_to_save.for_each_with_width(|reg, width| {
@@ -247,6 +262,13 @@ pub fn lower_after_regalloc(code: &mut Code) {
 
                         insertion_set.insert_insts(inst_index, insts);
 
+                        if let Some((set_al, al)) =
+                            variadic_vector_count(code, value, &destinations[1..])
+                        {
+                            insertion_set.insert_inst(inst_index, set_al);
+                            destinations.push(al);
+                        }
+
                         let mut new_inst = build_ccall(code, inst.origin, &destinations);
                         if old_kind.effects {
                             new_inst.kind.effects = true;
@@ -254,7 +276,7 @@ pub fn lower_after_regalloc(code: &mut Code) {
 
                         code.block_mut(BasicBlockId(i)).insts[inst_index] = new_inst;
 
-                        pairs.truncate(0);
+                        pairs.clear();
 
                         let mut stack_slot_index = 0;
 
@@ -266,17 +288,18 @@ pub fn lower_after_regalloc(code: &mut Code) {
                             pairs.push(ShufflePair::new(Arg::new_stack(stack_slot, 0), arg, width));
                         });
 
-                        for i in 0..results.len() {
-                            let typ = code.proc.value(value).typ();
+                        for index in 0..result_count {
+                            let (_, width) = ccall_result_bank_and_width(code, value, index);
                             let pair = ShufflePair::new(
-                                Arg::new_tmp(results[i]),
-                                original_results[i],
-                                width_for_type(typ),
+                                Arg::new_tmp(results[index]),
+                                original_results[index],
+                                width,
                             );
                             pairs.push(pair);
 
-                            if original_results[i].is_reg() {
-                                post_used.add(original_results[i].reg());
+                            post_used.add(results[index].reg());
+                            if original_results[index].is_reg() {
+                                post_used.add(original_results[index].reg());
                             }
                         }
 
@@ -1,27 +1,28 @@
+use macroassembler::assembler::TargetMacroAssembler;
 use tinyvec::TinyVec;
 
 use crate::{
-    typ::TypeKind,
-    utils::phase_scope::phase_scope,
-    width::{width_for_type, Width},
+    bank::Bank, jit::reg::Reg, typ::TypeKind, utils::phase_scope::phase_scope, width::Width,
 };
 
 use super::{
     arg::Arg,
     basic_block::BasicBlockId,
     ccalling_convention::{
-        build_ccall, ccall_argument_register_count, ccall_result, ccall_result_count,
-        compute_ccalling_convention,
+        build_ccall, ccall_argument_bank_and_width, ccall_argument_register_count, ccall_result,
+        ccall_result_bank_and_width, ccall_result_count, compute_ccalling_convention,
+        variadic_vector_count,
     },
     code::Code,
     emit_shuffle::{create_shuffle, ShufflePair},
     insertion_set::InsertionSet,
     inst::Inst,
     opcode::Opcode,
+    tmp::Tmp,
 };
 
 /// Air has some opcodes that are very high-level and are meant to reduce the amount of low-level
-/// knowledge in the B3->Air lowering. The current example is CCall.
+/// knowledge in the B3->Air lowering. The current examples are CCall and TailCall.
 pub fn lower_macros(code: &mut Code) {
     phase_scope("air::lower_macros", || {
         let mut insertion_set = InsertionSet::new();
@@ -31,7 +32,7 @@ pub fn lower_macros(code: &mut Code) {
                 let inst = &code.block(block_id).insts[inst_index];
 
                 match inst.kind.opcode {
-                    Opcode::CCall | Opcode::ColdCCall => {
+                    Opcode::CCall => {
                         let value = inst.origin;
                         let old_kind = inst.kind;
                         let inst = inst.clone();
@@ -61,10 +62,11 @@ pub fn lower_macros(code: &mut Code) {
 
                         for i in 1..code.proc.value(value).children.len() {
                             let child = code.proc.value(value).children[i];
+                            let typ = code.proc.value(child).typ();
 
-                            for _ in 0..ccall_argument_register_count(code, child) {
-                                let typ = code.proc.value(child).typ();
-                                add_next_pair(width_for_type(typ));
+                            for j in 0..ccall_argument_register_count(code, child) {
+                                let (_, width) = ccall_argument_bank_and_width(code.proc, typ, j);
+                                add_next_pair(width);
                             }
                         }
 
@@ -102,16 +104,14 @@ pub fn lower_macros(code: &mut Code) {
                         // Indicate that we're using our original callee argument.
                         destinations[0] = inst.args[1];
 
-                        let result_dst0 = if result_count >= 1 {
-                            inst.args[2]
-                        } else {
-                            Arg::default()
-                        };
-                        let _result_dst1 = if result_count >= 2 {
-                            inst.args[3]
-                        } else {
-                            Arg::default()
-                        };
+                        if let Some((set_al, al)) =
+                            variadic_vector_count(code, value, &destinations[1..])
+                        {
+                            insertion_set.insert_inst(inst_index, set_al);
+                            destinations.push(al);
+                        }
+
+                        let result_dsts = inst.args[2..2 + result_count].to_vec();
 
                         let mut inst = build_ccall(code, inst.origin, &destinations);
 
@@ -121,44 +121,105 @@ pub fn lower_macros(code: &mut Code) {
 
                         code.block_mut(block_id).insts[inst_index] = inst;
 
-                        match code.proc.value(value).typ().kind() {
-                            TypeKind::Void => {}
-                            TypeKind::Float => insertion_set.insert_inst(
-                                inst_index + 1,
-                                Inst::new(
-                                    Opcode::MoveFloat.into(),
-                                    value,
-                                    &[Arg::new_tmp(ccall_result(code, value, 0)), result_dst0],
-                                ),
-                            ),
-                            TypeKind::Double => insertion_set.insert_inst(
-                                inst_index + 1,
-                                Inst::new(
-                                    Opcode::MoveDouble.into(),
-                                    value,
-                                    &[Arg::new_tmp(ccall_result(code, value, 0)), result_dst0],
-                                ),
-                            ),
-                            TypeKind::Int32 => insertion_set.insert_inst(
-                                inst_index + 1,
-                                Inst::new(
-                                    Opcode::Move32.into(),
-                                    value,
-                                    &[Arg::new_tmp(ccall_result(code, value, 0)), result_dst0],
-                                ),
-                            ),
+                        let typ = code.proc.value(value).typ();
+
+                        for (index, &result_dst) in result_dsts.iter().enumerate() {
+                            let opcode = match typ.kind() {
+                                TypeKind::Float => Opcode::MoveFloat,
+                                TypeKind::Double => Opcode::MoveDouble,
+                                TypeKind::Int32 => Opcode::Move32,
+                                TypeKind::Int64 => Opcode::Move,
+                                // Aggregates are returned as whole eightbytes.
+                                TypeKind::Aggregate => {
+                                    match ccall_result_bank_and_width(code, value, index).0 {
+                                        Bank::GP => Opcode::Move,
+                                        Bank::FP => Opcode::MoveDouble,
+                                    }
+                                }
 
-                            TypeKind::Int64 => insertion_set.insert_inst(
+                                _ => todo!(),
+                            };
+
+                            insertion_set.insert_inst(
                                 inst_index + 1,
                                 Inst::new(
-                                    Opcode::Move.into(),
+                                    opcode.into(),
                                     value,
-                                    &[Arg::new_tmp(ccall_result(code, value, 0)), result_dst0],
+                                    &[Arg::new_tmp(ccall_result(code, value, index)), result_dst],
                                 ),
-                            ),
+                            );
+                        }
+                    }
+
+                    Opcode::TailCall => {
+                        let value = inst.origin;
+                        let inst = inst.clone();
+
+                        // Tail calls don't need an outgoing argument area of their own.
+                        let call_arg_area_size = code.call_arg_area_size;
+                        let destinations = compute_ccalling_convention(code, value);
+                        code.call_arg_area_size = call_arg_area_size;
+
+                        // The callee goes into the scratch register: it is neither callee-saved nor
+                        // an argument register, so it survives both the argument shuffle and the
+                        // epilogue.
+                        let mut shuffle_pairs = TinyVec::<[ShufflePair; 16]>::new();
+                        shuffle_pairs.push(ShufflePair::new(
+                            inst.args[0],
+                            destinations[0],
+                            Width::W64,
+                        ));
+
+                        let mut tail_call_args = vec![destinations[0]];
 
-                            _ => todo!(),
+                        // Like for CCall, every argument takes one location per register it needs.
+                        let mut offset = 1;
+
+                        for i in 1..code.proc.value(value).children.len() {
+                            let child = code.proc.value(value).children[i];
+                            let typ = code.proc.value(child).typ();
+
+                            for j in 0..ccall_argument_register_count(code, child) {
+                                let (_, width) = ccall_argument_bank_and_width(code.proc, typ, j);
+                                let source = inst.args[offset];
+                                let destination = destinations[offset];
+                                offset += 1;
+
+                                if destination.is_call_arg() {
+                                    // Stack arguments are written over our own incoming
+                                    // arguments, which start right above the saved frame pointer
+                                    // and the return address. `lower_macros` in B3 made sure that
+                                    // our caller passed enough of them.
+                                    let fp = Tmp::from_reg(Reg::new_gpr(
+                                        TargetMacroAssembler::FRAME_POINTER_REGISTER,
+                                    ));
+                                    let slot = Arg::new_addr(fp, 16 + destination.offset());
+
+                                    shuffle_pairs.push(ShufflePair::new(source, slot, width));
+                                } else {
+                                    shuffle_pairs.push(ShufflePair::new(
+                                        source,
+                                        destination,
+                                        width,
+                                    ));
+                                    tail_call_args.push(destination);
+                                }
+                            }
                         }
+
+                        insertion_set
+                            .insert_inst(inst_index, create_shuffle(value, &shuffle_pairs));
+
+                        // AL is set after the shuffle, which doesn't know that it is live.
+                        if let Some((set_al, al)) =
+                            variadic_vector_count(code, value, &destinations[1..])
+                        {
+                            insertion_set.insert_inst(inst_index, set_al);
+                            tail_call_args.push(al);
+                        }
+
+                        code.block_mut(block_id).insts[inst_index] =
+                            Inst::new(Opcode::TailCall.into(), value, &tail_call_args);
                     }
 
                     _ => (),
@@ -56,6 +56,7 @@ pub mod lower_entry_switch;
 pub mod lower_macros;
 pub mod lower_stack_args;
 pub mod pad_interference;
+pub mod parser;
 pub mod reg_liveness;
 pub mod report_used_registers;
 pub mod simplify_cfg;
@@ -66,6 +67,7 @@ pub mod tmp;
 pub mod tmp_set;
 pub mod tmp_width;
 pub mod use_counts;
+pub mod validate;
 
 pub mod opcode {
     include!(concat!(env!("OUT_DIR"), "/opcode.rs"));
@@ -89,6 +91,8 @@ pub mod opcode_utils {
 }
 
 pub use opcode_utils::is_valid_form;
+pub use parser::parse_code;
+pub use validate::validate;
 #[allow(clippy::all)]
 pub mod opcode_generated {
     #![allow(
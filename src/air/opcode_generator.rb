@@ -855,7 +855,18 @@ writeH("opcode_generated") {
     }
     outp.puts "    }"
     outp.puts "}"
-    
+
+    outp.puts "pub(crate) fn parse_internal(name: &str) -> Option<Opcode>"
+    outp.puts "{"
+    outp.puts "    match name {"
+    $opcodes.keys.each {
+        | opcode |
+        outp.puts "    \"#{opcode}\" => Some(Opcode::#{opcode}),"
+    }
+    outp.puts "    _ => None,"
+    outp.puts "    }"
+    outp.puts "}"
+
     outp.puts "pub static G_FORM_TABLE: [u8; #{$opcodes.size * formTableWidth}] = ["
     ix = 0
     $opcodes.values.each {
@@ -1278,8 +1289,7 @@ writeH("opcode_generated") {
                 when "StatusCond"
                     outp.print "self.args[#{index}].as_status_condition()"
                 when "SIMDInfo"
-                    outp.print "todo!(\"SIMDInfo\");)"
-                    #outp.print "args[#{index}].simdInfo()"
+                    outp.print "self.args[#{index}].as_simd_info()"
                 end
             }
 
@@ -0,0 +1,479 @@
+#[cfg(target_arch = "x86_64")]
+use macroassembler::assembler::macro_assembler_x86_common::StatusCondition;
+use macroassembler::assembler::{
+    abstract_macro_assembler::Extend, DoubleCondition, RelationalCondition, ResultCondition,
+};
+
+use crate::{
+    bank::Bank,
+    block::Frequency,
+    jit::reg::Reg,
+    parser::ParseError,
+    procedure::Procedure,
+    simd::{simd_info, SIMD_LANES, SIMD_SIGN_MODES},
+    value::ValueId,
+    width::Width,
+};
+
+use super::{
+    arg::Arg,
+    basic_block::BasicBlockId,
+    code::Code,
+    form_table::parse_internal,
+    inst::Inst,
+    kind::Kind,
+    opcode::Opcode,
+    special::SpecialId,
+    stack_slot::{StackSlotId, StackSlotKind},
+    tmp::Tmp,
+};
+
+/// Builds Air code from the text printed by the `Display` implementation of [`Code`].
+///
+/// Block, Tmp, stack slot and special numbers are kept, so displaying the result prints `text`
+/// again. Stack slots and specials are added to `proc`, which must not have any yet. The `CCall`
+/// special is the only one that can be parsed, since the others refer to B3 values. Origins are not
+/// printed, so every instruction gets the default origin, and `CCall` and `ColdCCall` instructions
+/// are rejected because their arguments depend on the B3 origin. Some argument kinds print the same
+/// way and are read back as one of them: `$1` is an `Imm`, `$0x1` is a `BigImm` and `(%rax)` is an
+/// `Addr`.
+/// Predecessor lists are taken as written; call [`Code::reset_reachability`] if they may be stale.
+pub fn parse_code<'a>(proc: &'a mut Procedure, text: &str) -> Result<Code<'a>, ParseError> {
+    let lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim_end()))
+        .filter(|(_, line)| !line.is_empty())
+        .collect::<Vec<_>>();
+
+    let mut parser = Parser {
+        code: Code::new(proc),
+        line: lines.first().map_or(1, |&(line, _)| line),
+        registers: vec![],
+        conditions: conditions(),
+        block_refs: vec![],
+    };
+
+    let mut reg = Reg::first();
+    while reg.is_set() {
+        parser.registers.push((reg.to_string(), reg));
+        reg = reg.next();
+    }
+
+    // Stack slots and specials are printed after the blocks that use them.
+    let sections = lines
+        .iter()
+        .position(|&(_, line)| !line.starts_with(' ') && !line.starts_with("BB"))
+        .unwrap_or(lines.len());
+
+    parser.parse_sections(&lines[sections..])?;
+    parser.parse_blocks(&lines[..sections])?;
+    parser.finish()
+}
+
+/// Every condition the compiler can produce, used to look conditions up by their printed name.
+fn conditions() -> Vec<Arg> {
+    let mut conditions = vec![];
+
+    for cond in [
+        RelationalCondition::Equal,
+        RelationalCondition::NotEqual,
+        RelationalCondition::Above,
+        RelationalCondition::AboveOrEqual,
+        RelationalCondition::Below,
+        RelationalCondition::BelowOrEqual,
+        RelationalCondition::GreaterThan,
+        RelationalCondition::GreaterThanOrEqual,
+        RelationalCondition::LessThan,
+        RelationalCondition::LessThanOrEqual,
+    ] {
+        conditions.push(Arg::new_rel_cond(cond));
+    }
+
+    for cond in [ResultCondition::NonZero, ResultCondition::Overflow] {
+        conditions.push(Arg::new_res_cond(cond));
+    }
+    conditions.push(Arg::new_res_cond(ResultCondition::NonZero).inverted(true));
+
+    for cond in [
+        DoubleCondition::EqualAndOrdered,
+        DoubleCondition::EqualOrUnordered,
+        DoubleCondition::NotEqualOrUnordered,
+        DoubleCondition::GreaterThanAndOrdered,
+        DoubleCondition::GreaterThanOrEqualAndOrdered,
+        DoubleCondition::LessThanAndOrdered,
+        DoubleCondition::LessThanOrEqualAndOrdered,
+    ] {
+        let arg = Arg::new_double_cond(cond);
+        conditions.push(arg);
+        conditions.push(arg.inverted(true));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    for cond in [StatusCondition::Success, StatusCondition::Failure] {
+        conditions.push(Arg::new_status_cond(cond));
+    }
+
+    conditions
+}
+
+struct Parser<'a> {
+    code: Code<'a>,
+    /// Line that is being parsed, used for errors.
+    line: usize,
+    /// Every register with its printed name.
+    registers: Vec<(String, Reg)>,
+    conditions: Vec<Arg>,
+    /// Blocks referred to by each line. Successors may be defined after their use, so they are
+    /// checked once everything is parsed.
+    block_refs: Vec<(usize, BasicBlockId)>,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            line: self.line,
+            message: message.into(),
+        })
+    }
+
+    fn parse_number<T: std::str::FromStr>(&self, text: &str) -> Result<T, ParseError> {
+        match text.parse() {
+            Ok(number) => Ok(number),
+            Err(_) => self.error(format!("expected a number, got `{}`", text)),
+        }
+    }
+
+    /// Parse a number that follows `prefix`, like the 3 in `BB3`.
+    fn parse_id(&self, text: &str, prefix: &str) -> Result<usize, ParseError> {
+        match text.strip_prefix(prefix) {
+            Some(digits) => self.parse_number(digits),
+            None => self.error(format!("expected `{}<number>`, got `{}`", prefix, text)),
+        }
+    }
+
+    fn parse_block(&mut self, text: &str) -> Result<BasicBlockId, ParseError> {
+        let block = BasicBlockId(self.parse_id(text, "BB")?);
+        self.block_refs.push((self.line, block));
+        Ok(block)
+    }
+
+    fn parse_sections(&mut self, lines: &[(usize, &str)]) -> Result<(), ParseError> {
+        let mut section = "";
+
+        for &(line, text) in lines {
+            self.line = line;
+
+            if !text.starts_with(' ') {
+                section = text;
+                if section != "Stack slots:" && section != "Specials:" {
+                    return self.error(format!("unknown section `{}`", section));
+                }
+                continue;
+            }
+
+            let (name, definition) = match text.trim_start().split_once(": ") {
+                Some(pair) => pair,
+                None => return self.error(format!("expected `name: definition`, got `{}`", text)),
+            };
+
+            if section == "Stack slots:" {
+                self.parse_stack_slot(name, definition)?;
+            } else {
+                self.parse_special(name, definition)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parse_stack_slot(&mut self, name: &str, definition: &str) -> Result<(), ParseError> {
+        let index = self.parse_id(name, "stack")?;
+
+        let (definition, alignment) = match definition.split_once(" align ") {
+            Some((definition, alignment)) => (definition, self.parse_number(alignment)?),
+            None => (definition, 1u32),
+        };
+
+        if !alignment.is_power_of_two() || alignment > 16 {
+            return self.error(format!("invalid stack slot alignment {}", alignment));
+        }
+
+        let (size, kind) = match definition.split_once(' ') {
+            Some((size, "Locked")) => (size, StackSlotKind::Locked),
+            Some((size, "Spill")) => (size, StackSlotKind::Spill),
+            _ => return self.error(format!("expected a size and a kind, got `{}`", definition)),
+        };
+
+        let size = self.parse_number(size)?;
+        let slot = self.code.add_stack_slot(size, kind);
+        if slot.0 != index {
+            return self.error(format!("{} is defined out of order", name));
+        }
+
+        self.code.stack_slot_mut(slot).min_alignment = alignment;
+
+        Ok(())
+    }
+
+    fn parse_special(&mut self, name: &str, definition: &str) -> Result<(), ParseError> {
+        let index = self.parse_id(name, "special")?;
+
+        if definition != "CCall" {
+            return self.error(format!("`{}` specials cannot be parsed", definition));
+        }
+
+        if self.code.ccall_special.is_some() || self.code.ccall_special().0 != index {
+            return self.error(format!("{} is defined out of order", name));
+        }
+
+        Ok(())
+    }
+
+    fn parse_blocks(&mut self, lines: &[(usize, &str)]) -> Result<(), ParseError> {
+        let mut current = None;
+
+        for &(line, text) in lines {
+            self.line = line;
+
+            if let Some(header) = text.strip_prefix("BB") {
+                let (index, frequency) = match header.split_once(": ; frequency = ") {
+                    Some(pair) => pair,
+                    None => return self.error(format!("expected a block header, got `{}`", text)),
+                };
+
+                let index = self.parse_number::<usize>(index)?;
+                let frequency = self.parse_number(frequency)?;
+                if index != self.code.blocks.len() {
+                    return self.error(format!("BB{} is defined out of order", index));
+                }
+
+                current = Some(self.code.add_block(frequency));
+                continue;
+            }
+
+            let block = match current {
+                Some(block) => block,
+                None => return self.error("expected a block header"),
+            };
+
+            let text = text.trim_start();
+
+            if let Some(predecessors) = text.strip_prefix("Predecessors: ") {
+                for predecessor in predecessors.split(", ") {
+                    let predecessor = self.parse_block(predecessor)?;
+                    self.code.block_mut(block).predecessors.push(predecessor);
+                }
+            } else if let Some(successors) = text.strip_prefix("Successors: ") {
+                for successor in successors.split(", ") {
+                    let (successor, frequency) = match successor.strip_prefix("Rare:") {
+                        Some(successor) => (successor, Frequency::Rare),
+                        None => (successor, Frequency::Normal),
+                    };
+
+                    let successor = self.parse_block(successor)?;
+                    self.code
+                        .block_mut(block)
+                        .successors
+                        .push((successor, frequency));
+                }
+            } else {
+                let inst = self.parse_inst(text)?;
+                self.code.block_mut(block).insts.push(inst);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parse_inst(&mut self, text: &str) -> Result<Inst, ParseError> {
+        let (kind, args) = text.split_once(' ').unwrap_or((text, ""));
+
+        let (opcode, effects) = match kind.strip_suffix("<Effects>") {
+            Some(opcode) => (opcode, true),
+            None => (kind, false),
+        };
+
+        let opcode = match parse_internal(opcode) {
+            Some(opcode) => opcode,
+            None => return self.error(format!("unknown opcode `{}`", opcode)),
+        };
+
+        if matches!(opcode, Opcode::CCall | Opcode::ColdCCall) {
+            return self.error(format!("`{:?}` instructions cannot be parsed", opcode));
+        }
+
+        let mut parsed = vec![];
+        for arg in split_args(args.trim()) {
+            parsed.push(self.parse_arg(arg)?);
+        }
+
+        Ok(Inst::new(
+            Kind { opcode, effects },
+            ValueId::default(),
+            &parsed,
+        ))
+    }
+
+    fn parse_tmp(&mut self, text: &str) -> Result<Tmp, ParseError> {
+        if let Some(index) = text.strip_prefix("%tmp") {
+            let index = self.parse_number(index)?;
+            self.code.num_gp_tmps = self.code.num_gp_tmps.max(index + 1);
+            return Ok(Tmp::tmp_for_index(Bank::GP, index));
+        }
+
+        if let Some(index) = text.strip_prefix("%ftmp") {
+            let index = self.parse_number(index)?;
+            self.code.num_fp_tmps = self.code.num_fp_tmps.max(index + 1);
+            return Ok(Tmp::tmp_for_index(Bank::FP, index));
+        }
+
+        match self.registers.iter().find(|(name, _)| name == text) {
+            Some(&(_, reg)) => Ok(Tmp::from_reg(reg)),
+            None => self.error(format!("unknown register `{}`", text)),
+        }
+    }
+
+    fn parse_arg(&mut self, text: &str) -> Result<Arg, ParseError> {
+        if text == "%xzr" {
+            return Ok(Arg::zero_reg());
+        }
+
+        if text.starts_with('%') || self.registers.iter().any(|(name, _)| name == text) {
+            return Ok(Arg::new_tmp(self.parse_tmp(text)?));
+        }
+
+        if let Some(index) = text.strip_prefix("$special") {
+            let special = SpecialId(self.parse_number(index)?);
+            if self.code.proc.specials.at(special).is_none() {
+                return self.error(format!("special{} is not defined", special.0));
+            }
+            return Ok(Arg::new_special(special));
+        }
+
+        if let Some(value) = text.strip_prefix('$') {
+            if let Some(digits) = value.strip_prefix("0x") {
+                return match u64::from_str_radix(digits, 16) {
+                    Ok(value) => Ok(Arg::new_bigimm(value as i64)),
+                    Err(_) => self.error(format!("expected a hexadecimal number, got `{}`", text)),
+                };
+            }
+
+            return Ok(Arg::new_imm(self.parse_number(value)?));
+        }
+
+        if let Some((offset, address)) =
+            text.strip_suffix(')').and_then(|text| text.split_once('('))
+        {
+            return self.parse_address(offset, address);
+        }
+
+        if text.starts_with("SIMDInfo") {
+            for &lane in SIMD_LANES.iter() {
+                for &sign_mode in SIMD_SIGN_MODES.iter() {
+                    let info = simd_info(lane, sign_mode);
+                    if format!("{:?}", info) == text {
+                        return Ok(Arg::new_simd_info(info));
+                    }
+                }
+            }
+        }
+
+        for width in [Width::W8, Width::W16, Width::W32, Width::W64, Width::W128] {
+            if width.to_string() == text {
+                return Ok(Arg::new_width_arg(width));
+            }
+        }
+
+        match self.conditions.iter().find(|cond| cond.to_string() == text) {
+            Some(&cond) => Ok(cond),
+            None => self.error(format!("unknown argument `{}`", text)),
+        }
+    }
+
+    /// Parse `offset(address)`, where `offset` may be empty.
+    fn parse_address(&mut self, offset: &str, address: &str) -> Result<Arg, ParseError> {
+        let offset = if offset.is_empty() {
+            0
+        } else {
+            self.parse_number(offset)?
+        };
+
+        if let Some(index) = address.strip_prefix("$stack") {
+            let slot = StackSlotId(self.parse_number(index)?);
+            if slot.0 >= self.code.proc.stack_slots.len() {
+                return self.error(format!("stack{} is not defined", slot.0));
+            }
+            return Ok(Arg::new_stack(slot, offset));
+        }
+
+        if address == "call_arg" {
+            return Ok(Arg::new_call_arg(offset));
+        }
+
+        let parts = address.split(',').collect::<Vec<_>>();
+        match parts[..] {
+            [base] => Ok(Arg::new_addr(self.parse_tmp(base)?, offset as i64)),
+            [base, index] | [base, index, _] => {
+                let scale = match parts.get(2) {
+                    Some(scale) => self.parse_number(scale)?,
+                    None => 1,
+                };
+                let base = self.parse_tmp(base)?;
+                let index = self.parse_tmp(index)?;
+                Ok(Arg::new_index(
+                    base,
+                    index,
+                    scale,
+                    offset as isize,
+                    Extend::None,
+                ))
+            }
+            _ => self.error(format!("expected an address, got `{}`", address)),
+        }
+    }
+
+    fn finish(self) -> Result<Code<'a>, ParseError> {
+        let num_blocks = self.code.blocks.len();
+
+        if let Some(&(line, block)) = self
+            .block_refs
+            .iter()
+            .find(|(_, block)| block.0 >= num_blocks)
+        {
+            return Err(ParseError {
+                line,
+                message: format!("BB{} is not defined", block.0),
+            });
+        }
+
+        Ok(self.code)
+    }
+}
+
+/// Split the arguments of an instruction at commas that are not inside an address or a
+/// `SIMDInfo`.
+fn split_args(text: &str) -> Vec<&str> {
+    let mut args = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+
+    if text.is_empty() {
+        return args;
+    }
+
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' | '{' => depth += 1,
+            ')' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+
+    args.push(text[start..].trim());
+    args
+}
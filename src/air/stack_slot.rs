@@ -29,6 +29,9 @@ impl Default for StackSlotKind {
 #[derive(Debug)]
 pub struct StackSlot {
     pub byte_size: u32,
+    /// Alignment the client asked for. The slot is aligned to at least this, or more if its size
+    /// calls for it.
+    pub min_alignment: u32,
     pub kind: StackSlotKind,
     pub index: usize,
     pub offset_from_fp: isize,
@@ -89,14 +92,18 @@ impl StackSlot {
     }
 
     pub fn alignment(&self) -> usize {
-        if self.byte_size() <= 1 {
+        let alignment = if self.byte_size() <= 1 {
             1
         } else if self.byte_size() <= 2 {
             2
         } else if self.byte_size() <= 4 {
             4
-        } else {
+        } else if self.byte_size() <= 8 {
             8
-        }
+        } else {
+            16
+        };
+
+        alignment.max(self.min_alignment as usize)
     }
 }
@@ -1,9 +1,63 @@
+use std::collections::HashMap;
+
 use crate::{
-    bank::{minimum_width, Bank},
+    bank::{conservative_width_without_vectors, minimum_width, Bank},
     width::Width,
 };
 
-pub struct TmpWidth {}
+use super::{basic_block::BasicBlockId, code::Code, tmp::Tmp};
+
+/// Records the widest use and the widest def of every tmp in the code. Register allocators use this
+/// to size spill slots and to pick spill moves, so that a tmp holding a vector is spilled as a
+/// vector.
+pub struct TmpWidth {
+    widths: HashMap<Tmp, Widths>,
+}
+
+impl TmpWidth {
+    pub fn new(code: &Code) -> Self {
+        let mut widths = HashMap::<Tmp, Widths>::new();
+
+        for block in (0..code.blocks.len()).map(BasicBlockId) {
+            for inst in code.block(block).insts.iter() {
+                inst.for_each_tmp(code, |tmp, role, bank, width| {
+                    let widths = widths
+                        .entry(tmp)
+                        .or_insert_with(|| Widths::from_bank(bank));
+
+                    if role.is_any_use() {
+                        widths.use_ = widths.use_.max(width);
+                    }
+
+                    if role.is_any_def() {
+                        widths.def = widths.def.max(width);
+                    }
+                });
+            }
+        }
+
+        Self { widths }
+    }
+
+    pub fn use_width(&self, tmp: Tmp) -> Width {
+        self.widths
+            .get(&tmp)
+            .map(|widths| widths.use_)
+            .unwrap_or_else(|| conservative_width_without_vectors(tmp.bank()))
+    }
+
+    pub fn def_width(&self, tmp: Tmp) -> Width {
+        self.widths
+            .get(&tmp)
+            .map(|widths| widths.def)
+            .unwrap_or_else(|| conservative_width_without_vectors(tmp.bank()))
+    }
+
+    /// Number of bits that have to be preserved when `tmp` is spilled.
+    pub fn required_width(&self, tmp: Tmp) -> Width {
+        self.use_width(tmp).max(self.def_width(tmp))
+    }
+}
 
 pub struct Widths {
     pub use_: Width,
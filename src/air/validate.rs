@@ -0,0 +1,303 @@
+use crate::bank::Bank;
+
+use super::{
+    basic_block::BasicBlockId,
+    code::Code,
+    form_table::{is_deinitely_terminal, is_return},
+    opcode::Opcode,
+};
+
+/// Error reported by [`validate`]. `block` and `inst` point at the offending instruction, if
+/// there is one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub block: Option<BasicBlockId>,
+    pub inst: Option<usize>,
+    pub message: String,
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+
+        if let Some(inst) = self.inst {
+            write!(f, " at inst {}", inst)?;
+        }
+
+        if let Some(block) = self.block {
+            write!(f, " in BB{}", block.0)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Checks that `code` is well formed. This is a port of WebKit's AirValidate.
+///
+/// Only blocks reachable from the entrypoints are checked. Unreachable blocks are skipped entirely,
+/// including their successor lists: phases like `simplify_cfg` leave dead blocks pointing at live
+/// ones and only recompute predecessors from the entrypoints, so an unreachable block listing a
+/// reachable successor is not reported. In reachable blocks:
+/// - every instruction has a valid form for the current CPU,
+/// - every block ends with a terminal and has no other terminals,
+/// - Tmps are used in their own bank, GP Tmps are no wider than a pointer and every non-register
+///   Tmp was created by `code`,
+/// - stack args refer to existing stack slots, and spill slot accesses stay inside the slot,
+/// - successors exist and agree with predecessors, every predecessor is reachable, and jumps,
+///   branches and returns have the right number of successors.
+pub fn validate(code: &Code<'_>) -> Result<(), ValidationError> {
+    if code.blocks.is_empty() {
+        return Ok(());
+    }
+
+    let reachable = compute_reachable(code)?;
+
+    for &block in reachable.iter() {
+        validate_block(code, block)?;
+        validate_cfg(code, block, &reachable)?;
+    }
+
+    Ok(())
+}
+
+fn error<T>(
+    block: Option<BasicBlockId>,
+    inst: Option<usize>,
+    message: String,
+) -> Result<T, ValidationError> {
+    Err(ValidationError {
+        block,
+        inst,
+        message,
+    })
+}
+
+fn compute_reachable(code: &Code<'_>) -> Result<Vec<BasicBlockId>, ValidationError> {
+    let mut seen = vec![false; code.blocks.len()];
+    let mut worklist = vec![];
+
+    if code.entrypoints().is_empty() {
+        seen[0] = true;
+        worklist.push(BasicBlockId(0));
+    } else {
+        for &(entrypoint, _) in code.entrypoints() {
+            if entrypoint.0 >= code.blocks.len() {
+                return error(
+                    None,
+                    None,
+                    format!("entrypoint BB{} does not exist", entrypoint.0),
+                );
+            }
+
+            if seen[entrypoint.0] {
+                return error(
+                    Some(entrypoint),
+                    None,
+                    "block is listed as an entrypoint twice".to_string(),
+                );
+            }
+
+            seen[entrypoint.0] = true;
+            worklist.push(entrypoint);
+        }
+    }
+
+    let mut reachable = vec![];
+
+    while let Some(block) = worklist.pop() {
+        reachable.push(block);
+
+        if code.block(block).index != block.0 {
+            return error(
+                Some(block),
+                None,
+                format!("block thinks its index is {}", code.block(block).index),
+            );
+        }
+
+        for &(successor, _) in code.block(block).successors.iter() {
+            if successor.0 >= code.blocks.len() {
+                return error(
+                    Some(block),
+                    None,
+                    format!("successor BB{} does not exist", successor.0),
+                );
+            }
+
+            if !seen[successor.0] {
+                seen[successor.0] = true;
+                worklist.push(successor);
+            }
+        }
+    }
+
+    reachable.sort();
+    Ok(reachable)
+}
+
+fn validate_block(code: &Code<'_>, block: BasicBlockId) -> Result<(), ValidationError> {
+    let insts = &code.block(block).insts;
+
+    if insts.is_empty() {
+        return error(Some(block), None, "block is empty".to_string());
+    }
+
+    for (index, inst) in insts.iter().enumerate() {
+        if !inst.is_valid_form(code) {
+            return error(Some(block), Some(index), format!("invalid form: {}", inst));
+        }
+
+        let is_last = index + 1 == insts.len();
+        let is_terminal = inst.is_terminal(code);
+
+        if is_terminal && !is_last {
+            return error(
+                Some(block),
+                Some(index),
+                format!("terminal in the middle of a block: {}", inst),
+            );
+        }
+
+        if !is_terminal && is_last {
+            return error(
+                Some(block),
+                Some(index),
+                format!("block does not end with a terminal: {}", inst),
+            );
+        }
+
+        let mut problem = None;
+
+        inst.for_each_arg(code, |_, arg, role, bank, width| {
+            if problem.is_some() {
+                return;
+            }
+
+            arg.for_each_tmp(role, bank, width, |tmp, _, bank, width| {
+                if problem.is_some() {
+                    return;
+                }
+
+                if tmp.bank() != bank {
+                    problem = Some(format!("{} used as a {} argument", tmp, bank));
+                } else if bank == Bank::GP && width.bytes() > 8 {
+                    problem = Some(format!("{} used at {}", tmp, width));
+                } else if tmp.has_tmp_index() && tmp.tmp_index() >= code.num_tmps(bank) {
+                    problem = Some(format!("{} was not created by this code", tmp));
+                }
+            });
+
+            if problem.is_some() || !arg.is_stack() {
+                return;
+            }
+
+            let slot = arg.stack_slot();
+
+            if slot.0 >= code.proc.stack_slots.len() || code.stack_slot(slot).index != slot.0 {
+                problem = Some(format!("stack slot {} does not exist", slot.0));
+                return;
+            }
+
+            let stack_slot = code.stack_slot(slot);
+
+            if stack_slot.is_spill()
+                && (arg.offset() < 0
+                    || arg.offset() as usize + width.bytes() > stack_slot.byte_size() as usize)
+            {
+                problem = Some(format!(
+                    "{} access at offset {} is outside of spill slot {}",
+                    width,
+                    arg.offset(),
+                    slot.0
+                ));
+            }
+        });
+
+        if let Some(problem) = problem {
+            return error(
+                Some(block),
+                Some(index),
+                format!("{} in: {}", problem, inst),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_cfg(
+    code: &Code<'_>,
+    block: BasicBlockId,
+    reachable: &[BasicBlockId],
+) -> Result<(), ValidationError> {
+    let basic_block = code.block(block);
+    let terminal = basic_block.last().unwrap();
+    let num_successors = basic_block.successors.len();
+
+    let expected = if terminal.kind.opcode == Opcode::Jump {
+        Some(1)
+    } else if is_return(terminal.kind.opcode) || terminal.kind.opcode == Opcode::Oops {
+        Some(0)
+    } else if is_deinitely_terminal(terminal.kind.opcode) {
+        // Everything else that is a terminal by its opcode is a conditional branch.
+        Some(2)
+    } else {
+        None
+    };
+
+    if let Some(expected) = expected {
+        if num_successors != expected {
+            return error(
+                Some(block),
+                Some(basic_block.len() - 1),
+                format!(
+                    "{} must have {} successors, got {}",
+                    terminal, expected, num_successors
+                ),
+            );
+        }
+    }
+
+    for &(successor, _) in basic_block.successors.iter() {
+        if !code.block(successor).predecessors.contains(&block) {
+            return error(
+                Some(block),
+                None,
+                format!(
+                    "successor BB{} does not list it as a predecessor",
+                    successor.0
+                ),
+            );
+        }
+    }
+
+    for &predecessor in basic_block.predecessors.iter() {
+        if reachable.binary_search(&predecessor).is_err() {
+            return error(
+                Some(block),
+                None,
+                format!("predecessor BB{} is not reachable", predecessor.0),
+            );
+        }
+
+        if !code
+            .block(predecessor)
+            .successors
+            .iter()
+            .any(|&(successor, _)| successor == block)
+        {
+            return error(
+                Some(block),
+                None,
+                format!(
+                    "predecessor BB{} does not list it as a successor",
+                    predecessor.0
+                ),
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -1,14 +1,21 @@
 use std::collections::{HashMap, HashSet};
 
-use crate::{BlockId, NumChildren, Opcode, Procedure, Type, Value, ValueId};
+use crate::{
+    utils::phase_scope::phase_scope, BlockId, NumChildren, Opcode, Procedure, Value, ValueData,
+    ValueId,
+};
 
 /// Finds all alloca instructions that do not have the identity
 /// property, that is, all alloca instructions that are used
 /// at least once by something that is neither a load or a store.
 ///
+/// An alloca is only considered promotable when every load and store that
+/// uses it accesses the whole alloca at offset zero with its exact type.
+///
 /// # Return
 ///
-/// A set of all alloca instructions without the identity property.
+/// A set of all alloca instructions with the identity property (these can be promoted
+/// to variables) and a set of all alloca instructions without it.
 pub fn get_allocas_without_identity(proc: &mut Procedure) -> (HashSet<ValueId>, HashSet<ValueId>) {
     let mut allocas = HashSet::new();
     let mut blacklisted_allocas = HashSet::new();
@@ -38,21 +45,41 @@ fn visit_for_identity_property(
     match value.opcode(proc) {
         Opcode::Load => {
             let memory_value = proc.value(value).memory_value().unwrap();
+            let pointer = value.child(proc, 0);
 
-            if memory_value.0 != 0 && value.child(proc, 0).opcode(proc) == Opcode::Alloca {
-                println!("ayo");
-                blacklisted_allocas.insert(value.child(proc, 0));
+            if pointer.opcode(proc) == Opcode::Alloca
+                && (memory_value.0 != 0
+                    || proc.value(pointer).alloca() != Some(proc.value(value).typ()))
+            {
+                blacklisted_allocas.insert(pointer);
             }
         }
         Opcode::Store => {
-            if value.child(proc, 0).opcode(proc) == Opcode::Alloca {
-                blacklisted_allocas.insert(value.child(proc, 0));
+            let memory_value = proc.value(value).memory_value().unwrap();
+            let stored = value.child(proc, 0);
+            let pointer = value.child(proc, 1);
+
+            // Storing the address of an alloca makes it escape.
+            if stored.opcode(proc) == Opcode::Alloca {
+                blacklisted_allocas.insert(stored);
+            }
+
+            if pointer.opcode(proc) == Opcode::Alloca
+                && (memory_value.0 != 0
+                    || proc.value(pointer).alloca() != Some(proc.value(stored).typ()))
+            {
+                blacklisted_allocas.insert(pointer);
             }
         }
 
         op => {
             if op == Opcode::Alloca {
-                allocas.insert(value);
+                let typ = proc.value(value).alloca().unwrap();
+                if typ.is_numeric() {
+                    allocas.insert(value);
+                } else {
+                    blacklisted_allocas.insert(value);
+                }
             }
 
             let args = &proc.value(value).children;
@@ -67,75 +94,66 @@ fn visit_for_identity_property(
     }
 }
 
-pub fn alloca_to_reg(proc: &mut Procedure) {
-    let (allocas, blacklisted) = get_allocas_without_identity(proc);
-
-    let mut alloca_to_var = HashMap::new();
-
-    for &alloca in allocas.iter() {
-        let typ = proc.value(alloca).alloca().expect("alloca");
-
-        let var = proc.add_variable(typ);
-
-        alloca_to_var.insert(alloca, var);
-    }
-
-    let mut alloca_to_slot = HashMap::new();
-    for &blacklisted in blacklisted.iter() {
-        println!("{:?}", blacklisted);
-        let size = proc.value(blacklisted).alloca().expect("alloca").size() as usize;
-        let slot = proc.add_stack_slot(size, crate::air::stack_slot::StackSlotKind::Locked);
-
-        alloca_to_slot.insert(blacklisted, slot);
-    }
+/// Promotes allocas that are only loaded from and stored to into variables. Loads become `Get`
+/// and stores become `Set`, which are then turned into SSA form by `fix_ssa`.
+///
+/// Allocas that escape are left alone and are lowered to stack slots later.
+pub fn alloca_to_reg(proc: &mut Procedure) -> bool {
+    phase_scope("b3::alloca_to_reg", || {
+        let (allocas, _) = get_allocas_without_identity(proc);
 
-    for block in (0..proc.blocks.len()).map(BlockId) {
-        for value_index in 0..proc.block(block).len() {
-            let value = proc.block(block)[value_index];
+        if allocas.is_empty() {
+            return false;
+        }
 
-            if value.opcode(proc) == Opcode::Alloca {
-                if allocas.contains(&value) {
-                    proc.value_mut(value).replace_with_nop_ignoring_type();
-                } else {
-                    assert!(blacklisted.contains(&value));
-
-                    let slot = *alloca_to_slot.get(&value).unwrap();
-                    let index = proc.value(value).index;
-                    *proc.value_mut(value) = Value::new(
-                        Opcode::SlotBase,
-                        Type::Int64,
-                        NumChildren::Zero,
-                        &[],
-                        crate::ValueData::SlotBase(slot),
-                    );
-                    proc.value_mut(value).index = index;
-                }
-            } else if value.opcode(proc) == Opcode::Load {
-                let ptr = value.child(proc, 0);
+        let mut alloca_to_var = HashMap::new();
 
-                if let Some(var) = alloca_to_var.get(&ptr) {
-                    let var_get = proc.add_variable_get(*var);
+        for &alloca in allocas.iter() {
+            let typ = proc.value(alloca).alloca().expect("alloca");
 
-                    proc.block_mut(block)[value_index] = var_get;
-                    proc.value_mut(var_get).owner = Some(block);
-                }
-            } else if value.opcode(proc) == Opcode::Store {
-                let ptr = value.child(proc, 1);
-                let value = value.child(proc, 0);
+            let var = proc.add_variable(typ);
 
-                if let Some(var) = alloca_to_var.get(&ptr) {
-                    let var_set = proc.add_variable_set(*var, value);
+            alloca_to_var.insert(alloca, var);
+        }
 
-                    proc.block_mut(block)[value_index] = var_set;
-                    proc.value_mut(var_set).owner = Some(block);
+        for block in (0..proc.blocks.len()).map(BlockId) {
+            for value_index in 0..proc.block(block).len() {
+                let value = proc.block(block)[value_index];
+
+                match value.opcode(proc) {
+                    Opcode::Alloca if allocas.contains(&value) => {
+                        proc.value_mut(value).replace_with_nop_ignoring_type();
+                    }
+
+                    Opcode::Load => {
+                        let pointer = value.child(proc, 0);
+
+                        if let Some(&var) = alloca_to_var.get(&pointer) {
+                            let value = proc.value_mut(value);
+                            value.replace_with(Opcode::Get, value.typ, value.owner);
+                            value.num_children = NumChildren::Zero;
+                            value.data = ValueData::Variable(var);
+                        }
+                    }
+
+                    Opcode::Store => {
+                        let pointer = value.child(proc, 1);
+                        let stored = value.child(proc, 0);
+
+                        if let Some(&var) = alloca_to_var.get(&pointer) {
+                            let value = proc.value_mut(value);
+                            value.replace_with(Opcode::Set, value.typ, value.owner);
+                            value.num_children = NumChildren::One;
+                            value.children.push(stored);
+                            value.data = ValueData::Variable(var);
+                        }
+                    }
+
+                    _ => (),
                 }
             }
         }
-    }
-
-    if allocas.len() != 0 || blacklisted.len() != 0 {
-        proc.reset_value_owners();
-    }
 
-    println!("{}", proc.display());
+        true
+    })
 }
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+
+use crate::{analysis::natural_loops::NaturalLoop, BlockId, Opcode, Procedure, TypeKind, ValueId};
+
+/// A Phi in the loop header that starts at `init` and goes up by the constant `step` on every
+/// iteration:
+///
+/// ```text
+/// pre_header:
+///     Upsilon(init, ^phi)
+/// header:
+///     phi = Phi()
+/// latch:
+///     increment = Add(phi, step)
+///     Upsilon(increment, ^phi)
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BasicInductionVariable {
+    pub phi: ValueId,
+    pub init: ValueId,
+    /// The step, wrapped to the type of the Phi.
+    pub step: i64,
+    pub increment: ValueId,
+    pub init_upsilon: ValueId,
+    pub latch_upsilon: ValueId,
+}
+
+impl BasicInductionVariable {
+    /// Returns true if `value` computes `phi + step`, like the increment does.
+    pub fn is_incremented_by(&self, proc: &Procedure, value: ValueId) -> bool {
+        step_of(proc, value, self.phi) == Some(self.step)
+    }
+}
+
+/// A value inside the loop that computes `base + phi * scale` for a basic induction variable, where
+/// `scale` is a constant and `base` is loop invariant. Only values that multiply or shift the
+/// induction variable are recorded, since those are the ones worth strength reducing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DerivedInductionVariable {
+    pub value: ValueId,
+    /// Index of the basic induction variable in [`InductionVariables::basic`].
+    pub basic: usize,
+    /// The scale, wrapped to the type of the value.
+    pub scale: i64,
+    pub base: Option<ValueId>,
+}
+
+/// The induction variables of a loop in SSA form.
+#[derive(Debug)]
+pub struct InductionVariables {
+    pub pre_header: BlockId,
+    pub latch: BlockId,
+    pub basic: Vec<BasicInductionVariable>,
+    pub derived: Vec<DerivedInductionVariable>,
+}
+
+impl InductionVariables {
+    /// Finds the induction variables of `loop_`, or returns `None` if the loop doesn't have a
+    /// pre-header and a single latch. Value owners have to be up to date, and `upsilons` has to map
+    /// every Phi to the Upsilons that feed it.
+    pub fn new(
+        proc: &Procedure,
+        loop_: &NaturalLoop<Procedure>,
+        upsilons: &HashMap<ValueId, Vec<ValueId>>,
+    ) -> Option<Self> {
+        let header = loop_.header();
+        let mut pre_header = None;
+        let mut latch = None;
+
+        for &predecessor in proc.block(header).predecessor_list().iter() {
+            let slot = if loop_.contains(predecessor) {
+                &mut latch
+            } else {
+                &mut pre_header
+            };
+
+            if slot.replace(predecessor).is_some() {
+                return None;
+            }
+        }
+
+        let pre_header = pre_header?;
+        let latch = latch?;
+
+        let mut basic = vec![];
+
+        for &phi in proc.block(header).iter() {
+            if proc.value(phi).kind.opcode() != Opcode::Phi || bits_of(proc, phi).is_none() {
+                continue;
+            }
+
+            let phi_upsilons = match upsilons.get(&phi) {
+                Some(phi_upsilons) if phi_upsilons.len() == 2 => phi_upsilons,
+                _ => continue,
+            };
+
+            let mut init_upsilon = None;
+            let mut latch_upsilon = None;
+
+            for &upsilon in phi_upsilons.iter() {
+                match proc.value(upsilon).owner {
+                    Some(owner) if owner == pre_header => init_upsilon = Some(upsilon),
+                    Some(owner) if owner == latch => latch_upsilon = Some(upsilon),
+                    _ => (),
+                }
+            }
+
+            let (init_upsilon, latch_upsilon) = match (init_upsilon, latch_upsilon) {
+                (Some(init_upsilon), Some(latch_upsilon)) => (init_upsilon, latch_upsilon),
+                _ => continue,
+            };
+
+            let increment = proc.value(latch_upsilon).children[0];
+
+            let step = match step_of(proc, increment, phi) {
+                Some(step) if step != 0 => step,
+                _ => continue,
+            };
+
+            basic.push(BasicInductionVariable {
+                phi,
+                init: proc.value(init_upsilon).children[0],
+                step,
+                increment,
+                init_upsilon,
+                latch_upsilon,
+            });
+        }
+
+        let mut derived = vec![];
+
+        if !basic.is_empty() {
+            for &block in loop_.body() {
+                for &value in proc.block(block).iter() {
+                    if let Some(derived_value) = derive(proc, loop_, &basic, value) {
+                        derived.push(derived_value);
+                    }
+                }
+            }
+        }
+
+        Some(Self {
+            pre_header,
+            latch,
+            basic,
+            derived,
+        })
+    }
+}
+
+/// Returns the number of bits of `value` if it is an integer.
+pub fn bits_of(proc: &Procedure, value: ValueId) -> Option<u32> {
+    match proc.value(value).typ().kind() {
+        TypeKind::Int32 => Some(32),
+        TypeKind::Int64 => Some(64),
+        _ => None,
+    }
+}
+
+/// Wraps `value` to an integer of `bits` bits.
+pub fn wrap_to(bits: u32, value: i64) -> i64 {
+    if bits == 32 {
+        value as i32 as i64
+    } else {
+        value
+    }
+}
+
+fn int_constant(proc: &Procedure, value: ValueId) -> Option<i64> {
+    proc.value(value).as_int()
+}
+
+fn step_of(proc: &Procedure, value: ValueId, phi: ValueId) -> Option<i64> {
+    let bits = bits_of(proc, phi)?;
+    let children = &proc.value(value).children;
+
+    let step = match proc.value(value).kind.opcode() {
+        Opcode::Add if children[0] == phi => int_constant(proc, children[1])?,
+        Opcode::Add if children[1] == phi => int_constant(proc, children[0])?,
+        Opcode::Sub if children[0] == phi => int_constant(proc, children[1])?.wrapping_neg(),
+        _ => return None,
+    };
+
+    Some(wrap_to(bits, step))
+}
+
+/// Matches `phi * scale`, `scale * phi` and `phi << amount`.
+fn scaled(
+    proc: &Procedure,
+    basic: &[BasicInductionVariable],
+    value: ValueId,
+) -> Option<(usize, i64)> {
+    let bits = bits_of(proc, value)?;
+    let children = &proc.value(value).children;
+
+    let (phi, scale) = match proc.value(value).kind.opcode() {
+        Opcode::Mul => match (
+            int_constant(proc, children[0]),
+            int_constant(proc, children[1]),
+        ) {
+            (None, Some(scale)) => (children[0], scale),
+            (Some(scale), None) => (children[1], scale),
+            _ => return None,
+        },
+
+        Opcode::Shl => {
+            let amount = int_constant(proc, children[1])? as u32 & (bits - 1);
+            (children[0], 1i64.wrapping_shl(amount))
+        }
+
+        _ => return None,
+    };
+
+    let scale = wrap_to(bits, scale);
+
+    if scale == 0 {
+        return None;
+    }
+
+    let index = basic.iter().position(|iv| iv.phi == phi)?;
+
+    Some((index, scale))
+}
+
+fn is_loop_invariant(proc: &Procedure, loop_: &NaturalLoop<Procedure>, value: ValueId) -> bool {
+    match proc.value(value).owner {
+        _ if proc.value(value).is_constant() => true,
+        Some(owner) => !loop_.contains(owner),
+        None => false,
+    }
+}
+
+fn derive(
+    proc: &Procedure,
+    loop_: &NaturalLoop<Procedure>,
+    basic: &[BasicInductionVariable],
+    value: ValueId,
+) -> Option<DerivedInductionVariable> {
+    if let Some((index, scale)) = scaled(proc, basic, value) {
+        return Some(DerivedInductionVariable {
+            value,
+            basic: index,
+            scale,
+            base: None,
+        });
+    }
+
+    if proc.value(value).kind.opcode() != Opcode::Add {
+        return None;
+    }
+
+    let children = &proc.value(value).children;
+
+    for (scaled_child, base) in [(children[0], children[1]), (children[1], children[0])] {
+        if let Some((index, scale)) = scaled(proc, basic, scaled_child) {
+            if is_loop_invariant(proc, loop_, base) {
+                return Some(DerivedInductionVariable {
+                    value,
+                    basic: index,
+                    scale,
+                    base: Some(base),
+                });
+            }
+        }
+    }
+
+    None
+}
@@ -1,4 +1,5 @@
 pub mod dominators;
+pub mod induction_variables;
 pub mod liveness;
 pub mod loop_analysis;
 pub mod natural_loops;
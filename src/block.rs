@@ -7,13 +7,15 @@ use crate::{
     jit::reg::Reg,
     opcode::Opcode,
     patchpoint_value::PatchpointValue,
-    procedure::Procedure,
+    procedure::{GlobalId, Procedure, ProcedureId},
     sparse_collection::SparseElement,
     stackmap_value::StackMapValue,
     typ::Type,
     utils::index_set::KeyIndex,
+    simd::{element_count, scalar_type, SIMDInfo},
     value::{NumChildren, Value, ValueData, ValueId},
     variable::VariableId,
+    width::Width,
     ConstrainedValue, ValueRep, ValueRepKind,
 };
 use std::ops::{Deref, DerefMut, Range};
@@ -206,7 +208,7 @@ impl BasicBlock {
                     .fmt_successors(f, proc, self)?;
             } else {
                 for (i, succ) in self.successor_list.iter().enumerate() {
-                    write!(f, "BB{}", succ.0 .0)?;
+                    fmt_frequent_block(f, *succ)?;
 
                     if i < self.successor_list.len() - 1 {
                         write!(f, ", ")?;
@@ -374,6 +376,18 @@ impl Default for Frequency {
     }
 }
 
+/// Print a successor as `BB3`, or `Rare:BB3` if it is rarely taken.
+pub(crate) fn fmt_frequent_block<W: std::fmt::Write>(
+    f: &mut W,
+    block: FrequentBlock,
+) -> std::fmt::Result {
+    if block.1 == Frequency::Rare {
+        write!(f, "Rare:")?;
+    }
+
+    write!(f, "BB{}", block.0 .0)
+}
+
 pub fn max_frequency(a: Frequency, b: Frequency) -> Frequency {
     if a == Frequency::Normal {
         a
@@ -543,6 +557,13 @@ impl<'a> BasicBlockBuilder<'a> {
         x
     }
 
+    /// Add a new 128-bit vector constant.
+    pub fn const128(&mut self, val: i128) -> ValueId {
+        let x = self.procedure.add(Value::make_const128(val));
+        self.add_value(x);
+        x
+    }
+
     /// Access to a variable. It emits `Get` opcode but later it is lowered
     /// to SSA form.
     pub fn var_get(&mut self, var: VariableId) -> ValueId {
@@ -589,7 +610,7 @@ impl<'a> BasicBlockBuilder<'a> {
             ValueData::MemoryValue {
                 offset,
                 range: range.unwrap_or(0..usize::MAX),
-                fence_range: fence_range.unwrap_or(0..usize::MAX),
+                fence_range: fence_range.unwrap_or(0..0),
             },
         );
 
@@ -614,7 +635,7 @@ impl<'a> BasicBlockBuilder<'a> {
             ValueData::MemoryValue {
                 offset,
                 range: range.unwrap_or(0..usize::MAX),
-                fence_range: fence_range.unwrap_or(0..usize::MAX),
+                fence_range: fence_range.unwrap_or(0..0),
             },
         );
 
@@ -639,7 +660,7 @@ impl<'a> BasicBlockBuilder<'a> {
             ValueData::MemoryValue {
                 offset,
                 range: range.unwrap_or(0..usize::MAX),
-                fence_range: fence_range.unwrap_or(0..usize::MAX),
+                fence_range: fence_range.unwrap_or(0..0),
             },
         );
 
@@ -663,7 +684,7 @@ impl<'a> BasicBlockBuilder<'a> {
             ValueData::MemoryValue {
                 offset,
                 range: range.unwrap_or(0..usize::MAX),
-                fence_range: fence_range.unwrap_or(0..usize::MAX),
+                fence_range: fence_range.unwrap_or(0..0),
             },
         );
 
@@ -674,12 +695,16 @@ impl<'a> BasicBlockBuilder<'a> {
 
     /// Load value from memory.
     ///
+    /// `ty` may be an aggregate type, such loads can only be passed by value to
+    /// [`ccall`](Self::ccall).
+    ///
     /// # Parameters
     /// - `ty` - type of the value to load
     /// - `ptr` - pointer to the memory location
     /// - `offset` - offset from the pointer
     /// - `range` - range of the memory location that is accessed by this load
-    /// - `fence_range` - range for atomic fence (TODO: document)
+    /// - `fence_range` - if non-empty, this load is a load-acquire: accesses to this range that
+    /// come after it cannot be reordered above it. Defaults to an empty range.
     pub fn load(
         &mut self,
         ty: Type,
@@ -696,7 +721,7 @@ impl<'a> BasicBlockBuilder<'a> {
             ValueData::MemoryValue {
                 offset,
                 range: range.unwrap_or(0..usize::MAX),
-                fence_range: fence_range.unwrap_or(0..usize::MAX),
+                fence_range: fence_range.unwrap_or(0..0),
             },
         );
 
@@ -713,7 +738,8 @@ impl<'a> BasicBlockBuilder<'a> {
     /// - `ptr` - pointer to the memory location
     /// - `offset` - offset from the pointer
     /// - `range` - range of the memory location that is accessed by this store
-    /// - `fence_range` - range for atomic fence (TODO: document)
+    /// - `fence_range` - if non-empty, this store is a store-release: accesses to this range that
+    /// come before it cannot be reordered below it. Defaults to an empty range.
     pub fn store(
         &mut self,
         value: ValueId,
@@ -730,7 +756,7 @@ impl<'a> BasicBlockBuilder<'a> {
             ValueData::MemoryValue {
                 offset,
                 range: range.unwrap_or(0..usize::MAX),
-                fence_range: fence_range.unwrap_or(0..usize::MAX),
+                fence_range: fence_range.unwrap_or(0..0),
             },
         );
 
@@ -756,7 +782,7 @@ impl<'a> BasicBlockBuilder<'a> {
             ValueData::MemoryValue {
                 offset,
                 range: range.unwrap_or(0..usize::MAX),
-                fence_range: fence_range.unwrap_or(0..usize::MAX),
+                fence_range: fence_range.unwrap_or(0..0),
             },
         );
 
@@ -782,7 +808,7 @@ impl<'a> BasicBlockBuilder<'a> {
             ValueData::MemoryValue {
                 offset,
                 range: range.unwrap_or(0..usize::MAX),
-                fence_range: fence_range.unwrap_or(0..usize::MAX),
+                fence_range: fence_range.unwrap_or(0..0),
             },
         );
 
@@ -790,6 +816,138 @@ impl<'a> BasicBlockBuilder<'a> {
         self.add_value(x);
     }
 
+    /// Standalone memory fence. See [`Opcode::Fence`].
+    ///
+    /// # Parameters
+    ///
+    /// - `read` - range the fence is treated as reading, stores to it cannot move across the fence
+    /// - `write` - range the fence is treated as writing, no access to it can move across the fence
+    ///
+    /// Both ranges default to the whole heap, which is a full fence.
+    pub fn fence(&mut self, read: Option<Range<usize>>, write: Option<Range<usize>>) {
+        let value = Value::new(
+            Opcode::Fence,
+            Type::Void,
+            NumChildren::Zero,
+            &[],
+            ValueData::Fence {
+                read: read.unwrap_or(0..usize::MAX),
+                write: write.unwrap_or(0..usize::MAX),
+            },
+        );
+
+        let x = self.procedure.add(value);
+        self.add_value(x);
+    }
+
+    /// Atomic compare and swap that returns `1` if `new_value` was stored and `0` otherwise. See
+    /// [`Opcode::AtomicWeakCAS`].
+    ///
+    /// # Parameters
+    ///
+    /// - `width` - width of the memory access, `expected` and `new_value` must be `Type::Int64`
+    /// for `Width::W64` and `Type::Int32` otherwise
+    /// - `expected` - value that memory location is compared with
+    /// - `new_value` - value to store if comparison succeeds
+    /// - `ptr` - pointer to the memory location
+    /// - `offset` - offset from the pointer
+    /// - `range` - range of the memory location that is accessed by this operation
+    /// - `fence_range` - range of memory that this operation is ordered with
+    pub fn atomic_weak_cas(
+        &mut self,
+        width: Width,
+        expected: ValueId,
+        new_value: ValueId,
+        ptr: ValueId,
+        offset: i32,
+        range: Option<Range<usize>>,
+        fence_range: Option<Range<usize>>,
+    ) -> ValueId {
+        let x = self.procedure.add_atomic(
+            Opcode::AtomicWeakCAS.into(),
+            width,
+            &[expected, new_value, ptr],
+            offset,
+            range.unwrap_or(0..usize::MAX),
+            fence_range.unwrap_or(0..usize::MAX),
+        );
+        self.add_value(x);
+        x
+    }
+
+    /// Atomic compare and swap that returns the old value of the memory location, sign-extended
+    /// for `Width::W8` and `Width::W16`. See [`Opcode::AtomicStrongCAS`].
+    ///
+    /// Parameters are the same as for [`BasicBlockBuilder::atomic_weak_cas`].
+    pub fn atomic_strong_cas(
+        &mut self,
+        width: Width,
+        expected: ValueId,
+        new_value: ValueId,
+        ptr: ValueId,
+        offset: i32,
+        range: Option<Range<usize>>,
+        fence_range: Option<Range<usize>>,
+    ) -> ValueId {
+        let x = self.procedure.add_atomic(
+            Opcode::AtomicStrongCAS.into(),
+            width,
+            &[expected, new_value, ptr],
+            offset,
+            range.unwrap_or(0..usize::MAX),
+            fence_range.unwrap_or(0..usize::MAX),
+        );
+        self.add_value(x);
+        x
+    }
+
+    /// Atomic read-modify-write operation that returns the old value of the memory location,
+    /// sign-extended for `Width::W8` and `Width::W16`. `op` is one of `AtomicXchgAdd`,
+    /// `AtomicXchgAnd`, `AtomicXchgOr`, `AtomicXchgSub`, `AtomicXchgXor` or `AtomicXchg`.
+    pub fn atomic_xchg_op(
+        &mut self,
+        op: Opcode,
+        width: Width,
+        operand: ValueId,
+        ptr: ValueId,
+        offset: i32,
+        range: Option<Range<usize>>,
+        fence_range: Option<Range<usize>>,
+    ) -> ValueId {
+        assert!(op.is_atomic_xchg());
+        let x = self.procedure.add_atomic(
+            op.into(),
+            width,
+            &[operand, ptr],
+            offset,
+            range.unwrap_or(0..usize::MAX),
+            fence_range.unwrap_or(0..usize::MAX),
+        );
+        self.add_value(x);
+        x
+    }
+
+    /// Atomically exchange `value` with the memory location and return the old value.
+    pub fn atomic_xchg(
+        &mut self,
+        width: Width,
+        value: ValueId,
+        ptr: ValueId,
+        offset: i32,
+        range: Option<Range<usize>>,
+        fence_range: Option<Range<usize>>,
+    ) -> ValueId {
+        self.atomic_xchg_op(
+            Opcode::AtomicXchg,
+            width,
+            value,
+            ptr,
+            offset,
+            range,
+            fence_range,
+        )
+    }
+
     /// Create binary operation. This asserts that the types of the operands are the same and
     /// `op` is a binary operation.
     pub fn binary(&mut self, op: Opcode, lhs: ValueId, rhs: ValueId) -> ValueId {
@@ -1122,14 +1280,147 @@ impl<'a> BasicBlockBuilder<'a> {
         self.add_value(x);
         x
     }
+
+    fn vector_value(
+        &mut self,
+        op: Opcode,
+        typ: Type,
+        info: SIMDInfo,
+        immediate: u8,
+        args: &[ValueId],
+    ) -> ValueId {
+        let num_children = match args.len() {
+            1 => NumChildren::One,
+            2 => NumChildren::Two,
+            _ => unreachable!(),
+        };
+        let value = Value::new(
+            op,
+            typ,
+            num_children,
+            args,
+            ValueData::SIMD { info, immediate },
+        );
+
+        let x = self.procedure.add(value);
+        self.add_value(x);
+        x
+    }
+
+    /// Lane-wise binary vector operation, see [`Opcode::is_vector_binary`]. Both operands must be
+    /// `Type::V128`. Comparisons set every bit of the lanes where the condition holds and clear
+    /// the others. The sign mode of `info` picks signed or unsigned `VectorMin`/`VectorMax`.
+    ///
+    /// Floating point `VectorMin`/`VectorMax` return NaN if either lane is NaN, and order -0.0
+    /// below +0.0.
+    pub fn vector_binary(
+        &mut self,
+        op: Opcode,
+        info: SIMDInfo,
+        lhs: ValueId,
+        rhs: ValueId,
+    ) -> ValueId {
+        assert!(op.is_vector_binary());
+        assert!(self.procedure.value(lhs).typ() == Type::V128);
+        assert!(self.procedure.value(rhs).typ() == Type::V128);
+        self.vector_value(op, Type::V128, info, 0, &[lhs, rhs])
+    }
+
+    /// Lane-wise unary vector operation, see [`Opcode::is_vector_unary`]. `value` must be
+    /// `Type::V128`.
+    pub fn vector_unary(&mut self, op: Opcode, info: SIMDInfo, value: ValueId) -> ValueId {
+        assert!(op.is_vector_unary());
+        assert!(self.procedure.value(value).typ() == Type::V128);
+        self.vector_value(op, Type::V128, info, 0, &[value])
+    }
+
+    /// Shift every lane of `vector` by `amount` bits. `op` is `Opcode::VectorShl` or
+    /// `Opcode::VectorShr`, the latter is arithmetic or logical depending on the sign mode of
+    /// `info`. `amount` is a `Type::Int32` taken modulo the lane width.
+    pub fn vector_shift(
+        &mut self,
+        op: Opcode,
+        info: SIMDInfo,
+        vector: ValueId,
+        amount: ValueId,
+    ) -> ValueId {
+        assert!(matches!(op, Opcode::VectorShl | Opcode::VectorShr));
+        assert!(self.procedure.value(vector).typ() == Type::V128);
+        assert!(self.procedure.value(amount).typ() == Type::Int32);
+        self.vector_value(op, Type::V128, info, 0, &[vector, amount])
+    }
+
+    /// Vector with every lane set to `scalar`, whose type must be the scalar type of the lane.
+    pub fn vector_splat(&mut self, info: SIMDInfo, scalar: ValueId) -> ValueId {
+        assert!(self.procedure.value(scalar).typ() == scalar_type(info.lane));
+        self.vector_value(Opcode::VectorSplat, Type::V128, info, 0, &[scalar])
+    }
+
+    /// Extract lane `lane` of `vector`. Lanes narrower than 32 bits are sign or zero extended
+    /// to `Type::Int32` depending on the sign mode of `info`.
+    pub fn vector_extract_lane(&mut self, info: SIMDInfo, lane: u8, vector: ValueId) -> ValueId {
+        assert!((lane as usize) < element_count(info.lane));
+        assert!(self.procedure.value(vector).typ() == Type::V128);
+        let typ = scalar_type(info.lane);
+        self.vector_value(Opcode::VectorExtractLane, typ, info, lane, &[vector])
+    }
+
+    /// Copy of `vector` with lane `lane` replaced by `scalar`.
+    pub fn vector_replace_lane(
+        &mut self,
+        info: SIMDInfo,
+        lane: u8,
+        vector: ValueId,
+        scalar: ValueId,
+    ) -> ValueId {
+        assert!((lane as usize) < element_count(info.lane));
+        assert!(self.procedure.value(vector).typ() == Type::V128);
+        assert!(self.procedure.value(scalar).typ() == scalar_type(info.lane));
+        self.vector_value(
+            Opcode::VectorReplaceLane,
+            Type::V128,
+            info,
+            lane,
+            &[vector, scalar],
+        )
+    }
+
     /// This is a regular ordinary C function call, using the system C calling convention. Make sure
     /// that the arguments are passed using the right types. The first argument is the callee.
+    ///
+    /// `ret` may be an aggregate type, in which case the result is a tuple whose elements are read
+    /// with [`extract`](Self::extract). Arguments of aggregate type are passed by value, they are
+    /// either such tuples or [`load`](Self::load)s of an aggregate type.
     pub fn ccall(
         &mut self,
         ret: Type,
         callee: ValueId,
         args: &[ValueId],
         effects: Effects,
+    ) -> ValueId {
+        self.ccall_impl(ret, callee, args, effects, false)
+    }
+
+    /// Like [`ccall`](Self::ccall), but `callee` takes variable arguments like `printf`. Variable
+    /// arguments are passed like fixed ones after the default argument promotions of C, so pass
+    /// `Double` instead of `Float` and `Int32` or `Int64` instead of smaller integers.
+    pub fn ccall_variadic(
+        &mut self,
+        ret: Type,
+        callee: ValueId,
+        args: &[ValueId],
+        effects: Effects,
+    ) -> ValueId {
+        self.ccall_impl(ret, callee, args, effects, true)
+    }
+
+    fn ccall_impl(
+        &mut self,
+        ret: Type,
+        callee: ValueId,
+        args: &[ValueId],
+        effects: Effects,
+        variadic: bool,
     ) -> ValueId {
         let value = Value::new(
             Opcode::CCall,
@@ -1139,7 +1430,7 @@ impl<'a> BasicBlockBuilder<'a> {
                 .chain(args.iter().cloned())
                 .collect::<Vec<_>>()
                 .as_slice(),
-            ValueData::CCallValue(effects),
+            ValueData::CCallValue { effects, variadic },
         );
 
         let x = self.procedure.add(value);
@@ -1147,6 +1438,52 @@ impl<'a> BasicBlockBuilder<'a> {
         x
     }
 
+    /// Call `callee` in tail position. This terminates the block: our frame is torn down and the
+    /// callee returns directly to our caller, so its result is our result.
+    ///
+    /// Arguments are passed like in [`ccall`](Self::ccall). Arguments that do not fit into
+    /// registers are written over our own incoming stack arguments, so the callee may not need more
+    /// stack arguments than the signature of the procedure gives us.
+    /// [`validate`](crate::validate::validate) reports such calls and compiling them panics.
+    ///
+    /// When `callee` is [`procedure_addr`](Self::procedure_addr) of the procedure being compiled,
+    /// the call is turned into a jump back to the entry.
+    pub fn tail_ccall(&mut self, callee: ValueId, args: &[ValueId]) {
+        self.tail_ccall_impl(callee, args, false)
+    }
+
+    /// Like [`tail_ccall`](Self::tail_ccall), but `callee` takes variable arguments, see
+    /// [`ccall_variadic`](Self::ccall_variadic).
+    pub fn tail_ccall_variadic(&mut self, callee: ValueId, args: &[ValueId]) {
+        self.tail_ccall_impl(callee, args, true)
+    }
+
+    fn tail_ccall_impl(&mut self, callee: ValueId, args: &[ValueId], variadic: bool) {
+        let data = if variadic {
+            ValueData::CCallValue {
+                effects: Effects::for_call(),
+                variadic,
+            }
+        } else {
+            ValueData::None
+        };
+
+        let value = Value::new(
+            Opcode::TailCCall,
+            Type::Void,
+            NumChildren::VarArgs,
+            std::iter::once(callee)
+                .chain(args.iter().cloned())
+                .collect::<Vec<_>>()
+                .as_slice(),
+            data,
+        );
+
+        let value = self.procedure.add(value);
+
+        self.add_value(value);
+    }
+
     /// Jump to a block.
     ///
     /// `target` is the block to jump to. It is allowed to be `None`,
@@ -1238,6 +1575,19 @@ impl<'a> BasicBlockBuilder<'a> {
     ///
     /// If `None` is passed this is a void return, otherwise the value is returned.
     pub fn return_(&mut self, value: Option<ValueId>) {
+        if let Some(signature) = self.procedure.signature() {
+            assert!(
+                !signature.ret.is_aggregate(),
+                "aggregates are returned with return_values"
+            );
+            let typ = value.map(|value| self.procedure.value(value).typ());
+            assert_eq!(
+                typ.unwrap_or(Type::Void),
+                signature.ret,
+                "return type does not match the signature"
+            );
+        }
+
         let args = value.as_ref().map(std::slice::from_ref).unwrap_or(&[]);
         let value = Value::new(
             Opcode::Return,
@@ -1252,6 +1602,75 @@ impl<'a> BasicBlockBuilder<'a> {
         self.add_value(value);
     }
 
+    /// Return several values from the current function. The procedure signature must return an
+    /// aggregate type and `values` are its elements in order.
+    ///
+    /// Aggregates of at most 16 bytes are returned in registers, larger ones are stored to memory
+    /// provided by the caller, see [`classify_aggregate`].
+    ///
+    /// [`classify_aggregate`]: crate::air::ccalling_convention::classify_aggregate
+    pub fn return_values(&mut self, values: &[ValueId]) {
+        {
+            let signature = self
+                .procedure
+                .signature()
+                .expect("procedure has no signature");
+            let aggregate = self.procedure.aggregate(signature.ret);
+
+            assert_eq!(
+                values.len(),
+                aggregate.num_elements(),
+                "return values do not match the signature"
+            );
+
+            for (index, &value) in values.iter().enumerate() {
+                let typ = aggregate.element_type(index);
+                assert!(!typ.is_aggregate(), "nested aggregates cannot be returned");
+                assert_eq!(
+                    self.procedure.value(value).typ(),
+                    typ,
+                    "return values do not match the signature"
+                );
+            }
+        }
+
+        let value = Value::new(
+            Opcode::Return,
+            Type::Void,
+            NumChildren::VarArgs,
+            values,
+            ValueData::None,
+        );
+
+        let value = self.procedure.add(value);
+
+        self.add_value(value);
+    }
+
+    /// Element `index` of `tuple`, which is a value of aggregate type returned by
+    /// [`ccall`](Self::ccall).
+    pub fn extract(&mut self, tuple: ValueId, index: usize) -> ValueId {
+        let typ = self
+            .procedure
+            .aggregate(self.procedure.value(tuple).typ())
+            .element_type(index);
+        assert!(!typ.is_aggregate(), "cannot extract nested aggregates");
+
+        let value = Value::new(
+            Opcode::Extract,
+            typ,
+            NumChildren::One,
+            &[tuple],
+            ValueData::Extract(index),
+        );
+
+        let value = self.procedure.add(value);
+
+        self.add_value(value);
+
+        value
+    }
+
     /// Create patchpoint that returns `typ`.
     ///
     /// Fill in the patchpoint using `stackmap_*` and `patchpoint_*` methods on [`Procedure`](super::Procedure).
@@ -1314,6 +1733,41 @@ impl<'a> BasicBlockBuilder<'a> {
         value
     }
 
+    /// Parameter `index` of the procedure signature, see [`Procedure::set_signature`].
+    ///
+    /// Unlike [`argument`](Self::argument) this does not require knowing where the parameter is
+    /// passed, it is located according to the C calling convention and may be passed on stack.
+    /// Parameters of aggregate type are tuples whose elements are read with
+    /// [`extract`](Self::extract).
+    pub fn parameter(&mut self, index: usize) -> ValueId {
+        let typ = {
+            let signature = self
+                .procedure
+                .signature()
+                .expect("procedure has no signature");
+            assert!(
+                index < signature.params.len(),
+                "parameter {} is out of bounds",
+                index
+            );
+            signature.params[index]
+        };
+
+        let value = Value::new(
+            Opcode::Parameter,
+            typ,
+            NumChildren::Zero,
+            &[],
+            ValueData::Parameter(index),
+        );
+
+        let value = self.procedure.add(value);
+
+        self.add_value(value);
+
+        value
+    }
+
     /// Argument access. `Reg` is register where the argument is passed, `Type` is the type of the argument.
     pub fn argument(&mut self, reg: Reg, typ: Type) -> ValueId {
         if reg.is_gpr() {
@@ -1354,6 +1808,70 @@ impl<'a> BasicBlockBuilder<'a> {
         value
     }
 
+    /// Returns a zero of the same type as `phantom` that the compiler cannot see through, so that
+    /// loads using it as part of their address are ordered after the loads that `phantom` depends
+    /// on. See [`Opcode::Depend`].
+    pub fn depend(&mut self, phantom: ValueId) -> ValueId {
+        let typ = self.procedure.value(phantom).typ();
+        assert!(typ.is_int());
+        let value = Value::new(
+            Opcode::Depend,
+            typ,
+            NumChildren::One,
+            &[phantom],
+            ValueData::None,
+        );
+
+        let value = self.procedure.add(value);
+
+        self.add_value(value);
+
+        value
+    }
+
+    /// Get address of procedure `id`.
+    ///
+    /// The address is resolved when the procedure is compiled as part of a [`Module`](crate::module::Module),
+    /// or from [`Procedure::set_procedure_address`] when compiling a standalone procedure.
+    pub fn procedure_addr(&mut self, id: ProcedureId) -> ValueId {
+        let value = Value::new(
+            Opcode::ProcedureAddr,
+            Type::Int64,
+            NumChildren::Zero,
+            &[],
+            ValueData::Procedure(id),
+        );
+
+        let value = self.procedure.add(value);
+
+        self.add_value(value);
+
+        value
+    }
+
+    /// Get address of global `id`.
+    ///
+    /// See [`Module::add_global`](crate::module::Module::add_global).
+    pub fn global_addr(&mut self, id: GlobalId) -> ValueId {
+        let value = Value::new(
+            Opcode::GlobalAddr,
+            Type::Int64,
+            NumChildren::Zero,
+            &[],
+            ValueData::Global(id),
+        );
+
+        let value = self.procedure.add(value);
+
+        self.add_value(value);
+
+        value
+    }
+
+    /// Allocate stack memory for a value of type `typ` and get its address.
+    ///
+    /// Allocas that are only loaded from and stored to are promoted to variables, the rest are
+    /// lowered to stack slots.
     pub fn alloca(&mut self, typ: Type) -> ValueId {
         let value = Value::new(
             Opcode::Alloca,
@@ -1369,4 +1887,49 @@ impl<'a> BasicBlockBuilder<'a> {
 
         value
     }
+
+    /// Compute address of an element inside of an aggregate of type `typ` that `base` points to.
+    ///
+    /// Each of `indices` selects a struct field or an array element, descending one level into
+    /// the aggregate. Struct field indices must be constants, array indices can be any integer
+    /// value. This performs address calculation only and does not access memory.
+    pub fn get_element_ptr(&mut self, typ: Type, base: ValueId, indices: &[ValueId]) -> ValueId {
+        let mut current = typ;
+        for &index in indices {
+            let aggregate = self.procedure.aggregate(current);
+            assert!(self.procedure.value(index).typ().is_int());
+
+            current = if aggregate.is_struct() {
+                let field = self
+                    .procedure
+                    .value(index)
+                    .as_int()
+                    .expect("struct field index must be a constant");
+                assert!(
+                    (field as usize) < aggregate.num_elements(),
+                    "struct field index out of bounds"
+                );
+                aggregate.element_type(field as usize)
+            } else {
+                aggregate.element_type(0)
+            };
+        }
+
+        let value = Value::new(
+            Opcode::GetElementPtr,
+            Type::Int64,
+            NumChildren::VarArgs,
+            std::iter::once(base)
+                .chain(indices.iter().copied())
+                .collect::<Vec<_>>()
+                .as_slice(),
+            ValueData::GetElementPtr(typ),
+        );
+
+        let value = self.procedure.add(value);
+
+        self.add_value(value);
+
+        value
+    }
 }
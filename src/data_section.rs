@@ -1,23 +1,52 @@
 pub struct DataSection {
     pub data: Vec<u8>,
+    start: usize,
+    size: usize,
+    alignment: usize,
 }
 
 impl DataSection {
     pub fn new(size: usize) -> DataSection {
         DataSection {
             data: vec![0; size],
+            start: 0,
+            size,
+            alignment: 1,
+        }
+    }
+
+    /// Create a zero-initialized data section whose start is aligned to `alignment` bytes.
+    pub fn new_aligned(size: usize, alignment: usize) -> DataSection {
+        assert!(alignment.is_power_of_two(), "alignment must be a power of two");
+        let data = vec![0; size + alignment - 1];
+        let start = data.as_ptr().align_offset(alignment);
+
+        DataSection {
+            data,
+            start,
+            size,
+            alignment,
         }
     }
 
     pub fn data(&self) -> &[u8] {
-        &self.data
+        &self.data[self.start..self.start + self.size]
     }
 
     pub fn data_mut(&mut self) -> &mut [u8] {
-        &mut self.data
+        &mut self.data[self.start..self.start + self.size]
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.data_mut().as_mut_ptr()
     }
 
     pub fn size(&self) -> usize {
-        self.data.len()
+        self.size
+    }
+
+    /// Alignment the section was created with.
+    pub fn alignment(&self) -> usize {
+        self.alignment
     }
 }
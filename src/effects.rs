@@ -1,5 +1,7 @@
 use std::ops::Range;
 
+use crate::utils::RangeExt;
+
 #[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub struct Effects {
     /// True if this cannot continue execution in the current block.
@@ -77,12 +79,9 @@ impl Effects {
             || interferes_with_writes_local_state(other, self)
             || interferes_with_exit_sideways(other, self)
             || interferes_with_terminal(other, self)
-            || ((self.writes != (0..0) && other.writes != (0..0))
-                && (self.writes.start < other.writes.end && self.writes.end > other.writes.start))
-            || ((self.writes != (0..0) && other.writes != (0..0))
-                && (self.writes.start < other.reads.end && self.writes.end > other.reads.start))
-            || ((self.reads != (0..0) && other.writes != (0..0))
-                && (self.reads.start < other.writes.end && self.reads.end > other.writes.start))
+            || ranges_overlap(&self.writes, &other.writes)
+            || ranges_overlap(&self.writes, &other.reads)
+            || ranges_overlap(&self.reads, &other.writes)
             || (self.fence && other.fence)
     }
 }
@@ -110,3 +109,7 @@ fn interferes_with_writes_local_state(writes_local_state: &Effects, other: &Effe
 
     other.writes_local_state || other.reads_local_state
 }
+
+fn ranges_overlap(a: &Range<usize>, b: &Range<usize>) -> bool {
+    !a.is_empty() && !b.is_empty() && a.overlaps(b)
+}
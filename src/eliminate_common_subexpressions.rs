@@ -0,0 +1,561 @@
+use std::{collections::HashMap, ops::Range};
+
+use tinyvec::TinyVec;
+
+use crate::{
+    analysis::dominators::{Dominators, GraphNodeWorklist},
+    blocks_in_post_order,
+    effects::Effects,
+    insertion_set::InsertionSet,
+    pure_cse::PureCSE,
+    utils::{phase_scope::phase_scope, RangeExt},
+    BlockId, NumChildren, Opcode, Procedure, Type, Value, ValueData, ValueId,
+};
+
+/// Does global common subexpression elimination, including redundant load elimination and
+/// redundant and dead store elimination.
+///
+/// Pure values are handled by [`PureCSE`]. A load is replaced with an earlier load or store of the
+/// same pointer and offset if no write to an overlapping heap range can happen in between. If the
+/// earlier memory values are in several predecessors, the load is replaced with a `Get` of a new
+/// variable that is `Set` after each of them, so the caller has to run `fix_ssa` if this returns
+/// true. A store is removed if the location is already known to hold the stored value, or if a
+/// later store in the same block overwrites it before anything can read it.
+pub fn eliminate_common_subexpressions(proc: &mut Procedure) -> bool {
+    phase_scope("b3::eliminate_common_subexpressions", || {
+        let mut cse = CSE::new(proc);
+        cse.run()
+    })
+}
+
+type MemoryMatches = TinyVec<[ValueId; 1]>;
+
+/// Memory values, keyed by their pointer.
+#[derive(Clone, Default)]
+struct MemoryValueMap {
+    map: HashMap<ValueId, MemoryMatches>,
+}
+
+impl MemoryValueMap {
+    fn add(&mut self, proc: &Procedure, memory: ValueId) {
+        let ptr = *proc.value(memory).children.last().unwrap();
+        let matches = self.map.entry(ptr).or_default();
+
+        if !matches.contains(&memory) {
+            matches.push(memory);
+        }
+    }
+
+    fn remove_if(&mut self, mut f: impl FnMut(ValueId) -> bool) {
+        self.map.retain(|_, matches| {
+            matches.retain(|&memory| !f(memory));
+            !matches.is_empty()
+        });
+    }
+
+    fn find(
+        &self,
+        proc: &Procedure,
+        ptr: ValueId,
+        filter: &impl Fn(&Procedure, ValueId) -> bool,
+    ) -> Option<ValueId> {
+        self.map
+            .get(&ptr)?
+            .iter()
+            .copied()
+            .find(|&candidate| filter(proc, candidate))
+    }
+}
+
+#[derive(Clone, Default)]
+struct ImpureBlockData {
+    writes: Vec<Range<usize>>,
+    memory_values_at_tail: MemoryValueMap,
+}
+
+impl ImpureBlockData {
+    fn writes_overlap(&self, range: &Range<usize>) -> bool {
+        self.writes.iter().any(|writes| writes.overlaps(range))
+    }
+}
+
+struct CSE<'a> {
+    proc: &'a mut Procedure,
+    dominators: Dominators<Procedure>,
+    pure_cse: PureCSE,
+    impure_block_data: Vec<ImpureBlockData>,
+    data: ImpureBlockData,
+    /// Stores in the current block that nothing has read since they executed.
+    pending_stores: Vec<ValueId>,
+    block: BlockId,
+    index: usize,
+    value: ValueId,
+    /// Values to insert right after a memory value in some other block.
+    sets: HashMap<ValueId, Vec<ValueId>>,
+    insertion_set: InsertionSet,
+    changed: bool,
+}
+
+impl<'a> CSE<'a> {
+    fn new(proc: &'a mut Procedure) -> Self {
+        proc.dominators_or_compute();
+        let dominators = proc.dominators().clone();
+        let num_blocks = proc.blocks.len();
+
+        Self {
+            proc,
+            dominators,
+            pure_cse: PureCSE::new(),
+            impure_block_data: vec![ImpureBlockData::default(); num_blocks],
+            data: ImpureBlockData::default(),
+            pending_stores: Vec::new(),
+            block: BlockId(0),
+            index: 0,
+            value: ValueId(0),
+            sets: HashMap::new(),
+            insertion_set: InsertionSet::new(),
+            changed: false,
+        }
+    }
+
+    fn run(&mut self) -> bool {
+        self.proc.reset_value_owners();
+
+        // Summarize the impure effects of each block, and the impure values available at the end
+        // of each block. This doesn't edit code yet.
+        for block in (0..self.proc.blocks.len()).map(BlockId) {
+            let mut data = ImpureBlockData::default();
+
+            for i in 0..block.size(self.proc) {
+                let value = block.value(self.proc, i);
+                let writes = self.proc.value(value).effects().writes;
+
+                if !writes.is_empty() {
+                    clobber(self.proc, &mut data, writes);
+                }
+
+                if self.proc.value(value).kind.opcode().is_load_store() {
+                    data.memory_values_at_tail.add(self.proc, value);
+                }
+            }
+
+            self.impure_block_data[block.0] = data;
+        }
+
+        // Perform CSE. This edits code.
+        for block in blocks_in_post_order(BlockId(0), self.proc)
+            .into_iter()
+            .rev()
+        {
+            self.block = block;
+            self.data = ImpureBlockData::default();
+            self.pending_stores.clear();
+
+            self.index = 0;
+            while self.index < block.size(self.proc) {
+                self.value = block.value(self.proc, self.index);
+                self.process();
+                self.index += 1;
+            }
+
+            self.insertion_set.execute(self.proc, block);
+            self.impure_block_data[block.0] = std::mem::take(&mut self.data);
+        }
+
+        // The previous pass might have requested that we insert code in some basic block other
+        // than the one that it was looking at. This inserts them.
+        if !self.sets.is_empty() {
+            for block in (0..self.proc.blocks.len()).map(BlockId) {
+                for value_index in 0..block.size(self.proc) {
+                    let value = block.value(self.proc, value_index);
+
+                    if let Some(sets) = self.sets.get(&value) {
+                        for &set in sets.iter() {
+                            self.insertion_set.insert_value(value_index + 1, set);
+                        }
+                    }
+                }
+
+                self.insertion_set.execute(self.proc, block);
+            }
+        }
+
+        self.changed
+    }
+
+    fn process(&mut self) {
+        Value::perform_substitution(self.value, self.proc);
+
+        if self
+            .pure_cse
+            .process(self.proc, self.value, &self.dominators)
+        {
+            self.changed = true;
+            return;
+        }
+
+        let effects = self.proc.value(self.value).effects();
+        let is_memory = self.proc.value(self.value).kind.opcode().is_load_store();
+
+        if is_memory && self.process_memory_before_clobber() {
+            return;
+        }
+
+        self.process_dead_stores(&effects);
+
+        if !effects.writes.is_empty() {
+            clobber(self.proc, &mut self.data, effects.writes);
+        }
+
+        if is_memory {
+            self.process_memory_after_clobber();
+        }
+    }
+
+    /// Returns true if we got rid of the operation.
+    fn process_memory_before_clobber(&mut self) -> bool {
+        let opcode = self.proc.value(self.value).kind.opcode();
+
+        if !opcode.is_store() {
+            return false;
+        }
+
+        let value = self.value.child(self.proc, 0);
+        let ptr = self.value.child(self.proc, 1);
+        let (offset, range, _) = self.proc.value(self.value).memory_value().unwrap();
+
+        let (load_z, load_s) = match opcode {
+            Opcode::Store8 => (Opcode::Load8Z, Opcode::Load8S),
+            Opcode::Store16 => (Opcode::Load16Z, Opcode::Load16S),
+            _ => (Opcode::Load, Opcode::Load),
+        };
+
+        // The store is redundant if the location is known to hold the value already, either
+        // because the same value was stored there or because the value was loaded from there.
+        let matches =
+            self.find_memory_value(ptr, &range, &|proc: &Procedure, candidate: ValueId| {
+                let candidate_opcode = proc.value(candidate).kind.opcode();
+
+                memory_offset(proc, candidate) == offset
+                    && ((candidate_opcode == opcode && candidate.child(proc, 0) == value)
+                        || ((candidate_opcode == load_z || candidate_opcode == load_s)
+                            && candidate == value))
+            });
+
+        if matches.is_empty() {
+            return false;
+        }
+
+        self.proc.value_mut(self.value).replace_with_nop();
+        self.changed = true;
+        true
+    }
+
+    fn process_memory_after_clobber(&mut self) {
+        let opcode = self.proc.value(self.value).kind.opcode();
+
+        if opcode.is_store() {
+            self.data.memory_values_at_tail.add(self.proc, self.value);
+            return;
+        }
+
+        let ptr = self.value.child(self.proc, 0);
+        let (offset, range, _) = self.proc.value(self.value).memory_value().unwrap();
+        let typ = self.proc.value(self.value).typ();
+
+        // TODO: Insert more casts and shifts. For example, a `Load8Z` could match a `Store` at a
+        // nearby offset by shifting and masking the stored value.
+        match opcode {
+            Opcode::Load8Z | Opcode::Load16Z => {
+                let (store, mask) = if opcode == Opcode::Load8Z {
+                    (Opcode::Store8, 0xff)
+                } else {
+                    (Opcode::Store16, 0xffff)
+                };
+
+                self.handle_memory_value(
+                    ptr,
+                    &range,
+                    &|proc: &Procedure, candidate: ValueId| {
+                        let candidate_opcode = proc.value(candidate).kind.opcode();
+
+                        memory_offset(proc, candidate) == offset
+                            && (candidate_opcode == opcode || candidate_opcode == store)
+                    },
+                    &|proc: &mut Procedure, matched: ValueId, fixups: &mut Vec<ValueId>| {
+                        if proc.value(matched).kind.opcode() != store {
+                            return None;
+                        }
+
+                        let mask = proc.add_int_constant(Type::Int32, mask);
+                        fixups.push(mask);
+                        let zext = proc.add(Value::new(
+                            Opcode::BitAnd,
+                            Type::Int32,
+                            NumChildren::Two,
+                            &[matched.child(proc, 0), mask],
+                            ValueData::None,
+                        ));
+                        fixups.push(zext);
+                        Some(zext)
+                    },
+                );
+            }
+
+            Opcode::Load8S | Opcode::Load16S => {
+                let (store, sext) = if opcode == Opcode::Load8S {
+                    (Opcode::Store8, Opcode::SExt8)
+                } else {
+                    (Opcode::Store16, Opcode::SExt16)
+                };
+
+                self.handle_memory_value(
+                    ptr,
+                    &range,
+                    &|proc: &Procedure, candidate: ValueId| {
+                        let candidate_opcode = proc.value(candidate).kind.opcode();
+
+                        memory_offset(proc, candidate) == offset
+                            && (candidate_opcode == opcode || candidate_opcode == store)
+                    },
+                    &|proc: &mut Procedure, matched: ValueId, fixups: &mut Vec<ValueId>| {
+                        if proc.value(matched).kind.opcode() != store {
+                            return None;
+                        }
+
+                        let sext = proc.add(Value::new(
+                            sext,
+                            Type::Int32,
+                            NumChildren::One,
+                            &[matched.child(proc, 0)],
+                            ValueData::None,
+                        ));
+                        fixups.push(sext);
+                        Some(sext)
+                    },
+                );
+            }
+
+            Opcode::Load => {
+                self.handle_memory_value(
+                    ptr,
+                    &range,
+                    &|proc: &Procedure, candidate: ValueId| {
+                        if memory_offset(proc, candidate) != offset {
+                            return false;
+                        }
+
+                        match proc.value(candidate).kind.opcode() {
+                            Opcode::Load => proc.value(candidate).typ() == typ,
+                            Opcode::Store => proc.value(candidate.child(proc, 0)).typ() == typ,
+                            _ => false,
+                        }
+                    },
+                    &no_replacement,
+                );
+            }
+
+            _ => unreachable!("bad memory value: {:?}", opcode),
+        }
+    }
+
+    /// Removes stores that a later store in this block overwrites before anything could observe
+    /// them, and records the current value if it is a store that later ones may overwrite.
+    fn process_dead_stores(&mut self, effects: &Effects) {
+        if effects.fence || effects.exit_sideways {
+            self.pending_stores.clear();
+        } else if !effects.reads.is_empty() {
+            let proc = &*self.proc;
+            let reads = &effects.reads;
+
+            self.pending_stores
+                .retain(|&store| !memory_range(proc, store).overlaps(reads));
+        }
+
+        let opcode = self.proc.value(self.value).kind.opcode();
+
+        if !opcode.is_store() || effects.fence {
+            return;
+        }
+
+        let ptr = self.value.child(self.proc, 1);
+        let (offset, range, _) = self.proc.value(self.value).memory_value().unwrap();
+        let width = self.proc.value(self.value).access_width(self.proc);
+        let mut index = 0;
+
+        while index < self.pending_stores.len() {
+            let store = self.pending_stores[index];
+
+            // The earlier store is only dead if this one writes all of its bytes. Requiring the
+            // ranges to overlap makes sure that the clobber of this store drops the dead one from
+            // the memory values that loads may still be matched against.
+            if self.proc.value(store).access_width(self.proc) <= width
+                && store.child(self.proc, 1) == ptr
+                && memory_offset(self.proc, store) == offset
+                && memory_range(self.proc, store).overlaps(&range)
+            {
+                self.proc.value_mut(store).replace_with_nop();
+                self.pending_stores.swap_remove(index);
+                // The Nop is no longer a memory value, so nothing may match or clobber it.
+                self.data
+                    .memory_values_at_tail
+                    .remove_if(|memory| memory == store);
+                self.changed = true;
+            } else {
+                index += 1;
+            }
+        }
+
+        // A trapping store is never dead. It exits sideways, so the pending stores were already
+        // dropped above and it does not make earlier stores dead either.
+        if !effects.exit_sideways {
+            self.pending_stores.push(self.value);
+        }
+    }
+
+    fn handle_memory_value(
+        &mut self,
+        ptr: ValueId,
+        range: &Range<usize>,
+        filter: &impl Fn(&Procedure, ValueId) -> bool,
+        replace: &impl Fn(&mut Procedure, ValueId, &mut Vec<ValueId>) -> Option<ValueId>,
+    ) {
+        let matches = self.find_memory_value(ptr, range, filter);
+
+        if self.replace_memory_value(&matches, replace) {
+            return;
+        }
+
+        self.data.memory_values_at_tail.add(self.proc, self.value);
+    }
+
+    fn replace_memory_value(
+        &mut self,
+        matches: &MemoryMatches,
+        replace: &impl Fn(&mut Procedure, ValueId, &mut Vec<ValueId>) -> Option<ValueId>,
+    ) -> bool {
+        if matches.is_empty() {
+            return false;
+        }
+
+        self.changed = true;
+
+        if matches.len() == 1 {
+            let dominating_match = matches[0];
+            let owner = self.proc.value(dominating_match).owner.unwrap();
+            assert!(self.dominators.dominates(owner, self.block));
+
+            let mut extra_values = Vec::new();
+            let value = match replace(self.proc, dominating_match, &mut extra_values) {
+                Some(value) => {
+                    for extra_value in extra_values {
+                        self.insertion_set.insert_value(self.index, extra_value);
+                    }
+
+                    value
+                }
+
+                None => stored_or_loaded_value(self.proc, dominating_match),
+            };
+
+            self.proc.value_mut(self.value).replace_with_identity(value);
+            return true;
+        }
+
+        // We rely on `fix_ssa` running after this phase to turn the variable into SSA form.
+        let typ = self.proc.value(self.value).typ();
+        let variable = self.proc.add_variable(typ);
+
+        let get = self.proc.add_variable_get(variable);
+        self.insertion_set.insert_value(self.index, get);
+        self.proc.value_mut(self.value).replace_with_identity(get);
+
+        for &matched in matches.iter() {
+            let mut sets = self.sets.remove(&matched).unwrap_or_default();
+
+            let value = replace(self.proc, matched, &mut sets)
+                .unwrap_or_else(|| stored_or_loaded_value(self.proc, matched));
+            let set = self.proc.add_variable_set(variable, value);
+            sets.push(set);
+
+            self.sets.insert(matched, sets);
+        }
+
+        true
+    }
+
+    fn find_memory_value(
+        &self,
+        ptr: ValueId,
+        range: &Range<usize>,
+        filter: &impl Fn(&Procedure, ValueId) -> bool,
+    ) -> MemoryMatches {
+        let mut matches = MemoryMatches::new();
+
+        if self.proc.value(self.value).has_fence() {
+            return matches;
+        }
+
+        if let Some(matched) = self.data.memory_values_at_tail.find(self.proc, ptr, filter) {
+            matches.push(matched);
+            return matches;
+        }
+
+        if self.data.writes_overlap(range) {
+            return matches;
+        }
+
+        let mut worklist = GraphNodeWorklist::new();
+        worklist.push_all(self.block.predecessor_list(self.proc).iter().copied());
+
+        while let Some(block) = worklist.pop() {
+            let data = &self.impure_block_data[block.0];
+
+            let matched = data.memory_values_at_tail.find(self.proc, ptr, filter);
+            if let Some(matched) = matched.filter(|&matched| matched != self.value) {
+                matches.push(matched);
+                continue;
+            }
+
+            if data.writes_overlap(range) {
+                return MemoryMatches::new();
+            }
+
+            // This essentially proves that the value is live at the prologue, so we cannot
+            // reliably optimize this case.
+            if block.predecessor_list(self.proc).is_empty() {
+                return MemoryMatches::new();
+            }
+
+            worklist.push_all(block.predecessor_list(self.proc).iter().copied());
+        }
+
+        matches
+    }
+}
+
+fn clobber(proc: &Procedure, data: &mut ImpureBlockData, writes: Range<usize>) {
+    data.memory_values_at_tail
+        .remove_if(|memory| memory_range(proc, memory).overlaps(&writes));
+    data.writes.push(writes);
+}
+
+fn memory_offset(proc: &Procedure, memory: ValueId) -> i32 {
+    proc.value(memory).memory_value().unwrap().0
+}
+
+fn memory_range(proc: &Procedure, memory: ValueId) -> Range<usize> {
+    proc.value(memory).memory_value().unwrap().1
+}
+
+fn stored_or_loaded_value(proc: &Procedure, memory: ValueId) -> ValueId {
+    if proc.value(memory).kind.opcode().is_store() {
+        memory.child(proc, 0)
+    } else {
+        memory
+    }
+}
+
+fn no_replacement(_: &mut Procedure, _: ValueId, _: &mut Vec<ValueId>) -> Option<ValueId> {
+    None
+}
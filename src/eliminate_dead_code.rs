@@ -1,101 +1,111 @@
 use tinyvec::TinyVec;
 
 use crate::{
-    analysis::dominators::GraphNodeWorklist, effects::Effects, utils::index_set::IndexSet,
-    variable::VariableId, BlockId, Opcode, Procedure, ValueId,
+    analysis::dominators::GraphNodeWorklist,
+    effects::Effects,
+    utils::{index_set::IndexSet, phase_scope::phase_scope},
+    variable::VariableId,
+    BlockId, Opcode, Procedure, ValueId,
 };
 
-#[allow(dead_code)]
+/// Removes values whose results are never used and that have no effects that must execute, along
+/// with variables that are no longer read or written.
+///
+/// A value is kept if it is used or if its effects [must execute](Effects::must_execute), which
+/// covers terminals, stores, fences, `Set`, trapping values, checks, and calls and patchpoints that
+/// may write memory or exit. Phis and Upsilons are kept only if the Phi is used. Values that are
+/// not in any block, like the ones `reset_reachability` takes out of unreachable blocks, are
+/// deleted as well.
 pub fn eliminate_dead_code(proc: &mut Procedure) -> bool {
-    let mut changed = false;
-    let mut worklist = GraphNodeWorklist::new();
-    let mut upsilons = TinyVec::<[ValueId; 64]>::new();
+    phase_scope("b3::eliminate_dead_code", || {
+        let mut changed = false;
+        let mut worklist = GraphNodeWorklist::new();
+        let mut upsilons = TinyVec::<[ValueId; 64]>::new();
 
-    for block in (0..proc.blocks.len()).map(BlockId) {
-        for i in 0..block.size(proc) {
-            let value = block.value(proc, i);
+        for block in (0..proc.blocks.len()).map(BlockId) {
+            for i in 0..block.size(proc) {
+                let value = block.value(proc, i);
 
-            let mut effects = Effects::default();
+                let mut effects = Effects::default();
 
-            if proc.value(value).kind.opcode() != Opcode::Phi
-                && proc.value(value).kind.opcode() != Opcode::Upsilon
-            {
-                effects = proc.value(value).effects();
-            }
+                // Phis and Upsilons write local state, but they are only live if the Phi is used.
+                if proc.value(value).kind.opcode() != Opcode::Phi
+                    && proc.value(value).kind.opcode() != Opcode::Upsilon
+                {
+                    effects = proc.value(value).effects();
+                }
 
-            if effects.must_execute() {
-                worklist.push(value);
-            }
+                if effects.must_execute() {
+                    worklist.push(value);
+                }
 
-            if let Some(_) = proc.value(value).phi() {
-                upsilons.push(value);
+                if proc.value(value).phi().is_some() {
+                    upsilons.push(value);
+                }
             }
         }
-    }
 
-    loop {
-        while let Some(value) = worklist.pop() {
-            for child in proc.value(value).children.iter().copied() {
-                worklist.push(child);
+        loop {
+            while let Some(value) = worklist.pop() {
+                for child in proc.value(value).children.iter().copied() {
+                    worklist.push(child);
+                }
             }
-        }
 
-        let mut did_push = false;
+            let num_upsilons = upsilons.len();
 
-        let mut upsilon_ix = 0;
-        while upsilon_ix < upsilons.len() {
-            let upsilon = upsilons[upsilon_ix];
+            upsilons.retain(|&upsilon| {
+                if worklist.saw(proc.value(upsilon).phi().unwrap()) {
+                    worklist.push(upsilon);
+                    false
+                } else {
+                    true
+                }
+            });
 
-            if worklist.saw(proc.value(upsilon).phi().unwrap()) {
-                worklist.push(upsilon);
-                upsilons[upsilon_ix] = upsilons.last().unwrap().clone();
-                upsilons.pop();
-                upsilon_ix = upsilon_ix.wrapping_sub(1);
-                did_push = true;
+            if upsilons.len() == num_upsilons {
+                break;
             }
-            upsilon_ix = upsilon_ix.wrapping_add(1);
         }
 
-        if !did_push {
-            break;
-        }
-    }
+        let mut live_variables = IndexSet::new();
 
-    let mut live_variables = IndexSet::new();
+        for block in (0..proc.blocks.len()).map(BlockId) {
+            let mut source_index = 0;
+            let mut target_index = 0;
 
-    for block in (0..proc.blocks.len()).map(BlockId) {
-        let mut source_index = 0;
-        let mut target_index = 0;
+            while source_index < block.size(proc) {
+                let value = block.value(proc, source_index);
+                source_index += 1;
 
-        while source_index < block.size(proc) {
-            let value = block.value(proc, source_index);
-            source_index += 1;
+                if worklist.saw(value) {
+                    if let Some(var) = proc.value(value).as_variable() {
+                        live_variables.insert(var);
+                    }
 
-            if worklist.saw(value) {
-                if let Some(var) = proc.value(value).as_variable() {
-                    live_variables.insert(var);
+                    proc.block_mut(block).values[target_index] = value;
+                    target_index += 1;
                 }
+            }
 
-                proc.block_mut(block).values[target_index] = value;
-                target_index += 1;
-            } else {
-                {
-                    let mut out = String::new();
-                    proc.value(value).fmt(&mut out, proc).unwrap();
-                }
-                proc.values.remove(value);
+            proc.block_mut(block).values.truncate(target_index);
+        }
+
+        // This also deletes the values that `reset_reachability` took out of dead blocks.
+        for value in (0..proc.values.size()).map(ValueId) {
+            if proc.values.at(value).is_some() && !worklist.saw(value) {
+                proc.delete_value(value);
                 changed = true;
             }
         }
 
-        proc.block_mut(block).values.truncate(target_index);
-    }
-
-    for var in (0..proc.variables.size()).map(VariableId) {
-        if !live_variables.contains(&var) {
-            proc.delete_variable(var);
+        for var in (0..proc.variables.size()).map(VariableId) {
+            if proc.variables.at(var).is_some() && !live_variables.contains(&var) {
+                proc.delete_variable(var);
+                changed = true;
+            }
         }
-    }
 
-    changed
+        changed
+    })
 }
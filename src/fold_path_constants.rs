@@ -1,12 +1,28 @@
-#![allow(dead_code, unused_variables)]
-use crate::{insertion_set::InsertionSet, BlockId, Procedure, ValueId};
+use indexmap::IndexMap;
+
+use crate::{
+    analysis::dominators::Dominators, insertion_set::InsertionSet, utils::phase_scope::phase_scope,
+    BlockId, Opcode, Procedure, ValueId,
+};
 
 /// Does very basic simplification of uses of values that were branched on by a dominating branch.
-pub fn fold_path_constants(proc: &mut Procedure) {}
+///
+/// After `Branch(x)`, `x` is known to be non-zero in the taken successor and zero in the other
+/// one. After `Switch(x)`, `x` is known to be the case value in a successor that only that case
+/// jumps to. Uses of `x` in blocks dominated by such a successor are replaced with the constant,
+/// and branches on `x` and comparisons of `x` with zero are folded if `x` is non-zero. The folded
+/// branches are left for `reduce_strength` to clean up.
+pub fn fold_path_constants(proc: &mut Procedure) -> bool {
+    phase_scope("b3::fold_path_constants", || {
+        let mut fold_path_constants = FoldPathConstants::new(proc);
+        fold_path_constants.run()
+    })
+}
 
 struct FoldPathConstants<'a> {
     proc: &'a mut Procedure,
     insertion_set: InsertionSet,
+    overrides: IndexMap<ValueId, Vec<Override>>,
 }
 
 impl<'a> FoldPathConstants<'a> {
@@ -14,8 +30,182 @@ impl<'a> FoldPathConstants<'a> {
         Self {
             proc,
             insertion_set: InsertionSet::new(),
+            overrides: IndexMap::new(),
         }
     }
+
+    fn run(&mut self) -> bool {
+        self.proc.dominators_or_compute();
+        let dominators = self.proc.dominators().clone();
+
+        // Find all of the values that are the subject of a branch or switch. For any successor
+        // that we dominate, install a value override at that block.
+        for block in (0..self.proc.blocks.len()).map(BlockId) {
+            let branch = match self.proc.block(block).last() {
+                Some(&branch) => branch,
+                None => continue,
+            };
+
+            match self.proc.value(branch).kind.opcode() {
+                Opcode::Branch => {
+                    let subject = branch.child(self.proc, 0);
+                    let taken = self.proc.block(block).taken().0;
+                    let not_taken = self.proc.block(block).not_taken().0;
+
+                    if taken == not_taken {
+                        continue;
+                    }
+
+                    self.add_override(block, subject, Override::non_zero(taken));
+                    self.add_override(block, subject, Override::constant(not_taken, 0));
+                }
+
+                Opcode::Switch => {
+                    let subject = branch.child(self.proc, 0);
+                    let cases = self.proc.value(branch).switch_cases().unwrap().to_vec();
+                    let successors = self.proc.block(block).successor_list().clone();
+
+                    for (index, &case) in cases.iter().enumerate() {
+                        let target = successors[index].0;
+
+                        // The fall through is the last successor, so it is counted here too.
+                        if successors
+                            .iter()
+                            .filter(|(other, _)| *other == target)
+                            .count()
+                            != 1
+                        {
+                            continue;
+                        }
+
+                        self.add_override(block, subject, Override::constant(target, case));
+                    }
+                }
+
+                _ => (),
+            }
+        }
+
+        if self.overrides.is_empty() {
+            return false;
+        }
+
+        // Install the constants in the override blocks. We use one-shot insertion sets because
+        // each block will get at most one thing inserted into it anyway.
+        for index in 0..self.overrides.len() {
+            let subject = *self.overrides.get_index(index).unwrap().0;
+
+            for i in 0..self.overrides[index].len() {
+                let target = self.overrides[index][i];
+
+                if !target.has_value {
+                    continue;
+                }
+
+                let block = target.block.unwrap();
+                let typ = self.proc.value(subject).typ();
+                let node = self
+                    .insertion_set
+                    .insert_int_constant(0, typ, target.value, self.proc);
+                self.insertion_set.execute(self.proc, block);
+                self.overrides[index][i].value_node = Some(node);
+            }
+        }
+
+        // Replace all uses of a value that has an override with that override, if appropriate.
+        // Certain instructions get special treatment.
+        let mut changed = false;
+        let mut changed_cfg = false;
+
+        for block in (0..self.proc.blocks.len()).map(BlockId) {
+            for value_index in 0..self.proc.block(block).len() {
+                let value = self.proc.block(block)[value_index];
+
+                match self.proc.value(value).kind.opcode() {
+                    Opcode::Branch => {
+                        let subject = value.child(self.proc, 0);
+
+                        if self.get_override(&dominators, block, subject).is_non_zero {
+                            let taken = self.proc.block(block).taken();
+                            self.proc.value_mut(value).replace_with_jump(block, taken);
+                            self.proc.block_mut(block).set_successors(taken);
+                            changed = true;
+                            changed_cfg = true;
+                        }
+                    }
+
+                    opcode @ (Opcode::Equal | Opcode::NotEqual) => {
+                        let subject = value.child(self.proc, 0);
+
+                        if self.proc.value(value.child(self.proc, 1)).is_int_of(0)
+                            && self.get_override(&dominators, block, subject).is_non_zero
+                        {
+                            let result = (opcode == Opcode::NotEqual) as i64;
+                            let constant = self.insertion_set.insert_int_constant_like(
+                                value_index,
+                                value,
+                                result,
+                                self.proc,
+                            );
+                            self.proc.value_mut(value).replace_with_identity(constant);
+                            changed = true;
+                        }
+                    }
+
+                    _ => (),
+                }
+
+                for i in 0..self.proc.value(value).children.len() {
+                    let child = self.proc.value(value).children[i];
+
+                    if let Some(node) = self.get_override(&dominators, block, child).value_node {
+                        self.proc.value_mut(value).children[i] = node;
+                        changed = true;
+                    }
+                }
+            }
+
+            self.insertion_set.execute(self.proc, block);
+        }
+
+        if changed_cfg {
+            self.proc.reset_reachability();
+            self.proc.invalidate_cfg();
+        }
+
+        changed
+    }
+
+    fn add_override(&mut self, from: BlockId, value: ValueId, target: Override) {
+        if self.proc.block(target.block.unwrap()).predecessor_list()[..] != [from] {
+            return;
+        }
+
+        let for_value = self.overrides.entry(value).or_default();
+        debug_assert!(for_value.iter().all(|other| other.block != target.block));
+        for_value.push(target);
+    }
+
+    fn get_override(
+        &self,
+        dominators: &Dominators<Procedure>,
+        block: BlockId,
+        value: ValueId,
+    ) -> Override {
+        let mut result = Override::default();
+
+        if let Some(for_value) = self.overrides.get(&value) {
+            for &target in for_value.iter() {
+                if dominators.dominates(target.block.unwrap(), block)
+                    && target.is_better_than(&result)
+                {
+                    result = target;
+                }
+            }
+        }
+
+        result
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
@@ -24,5 +214,29 @@ struct Override {
     has_value: bool,
     is_non_zero: bool,
     value: i64,
-    value_node: ValueId,
+    value_node: Option<ValueId>,
+}
+
+impl Override {
+    fn constant(block: BlockId, value: i64) -> Self {
+        Self {
+            block: Some(block),
+            has_value: true,
+            is_non_zero: value != 0,
+            value,
+            value_node: None,
+        }
+    }
+
+    fn non_zero(block: BlockId) -> Self {
+        Self {
+            block: Some(block),
+            is_non_zero: true,
+            ..Default::default()
+        }
+    }
+
+    fn is_better_than(&self, other: &Override) -> bool {
+        (self.has_value && !other.has_value) || (self.is_non_zero && !other.is_non_zero)
+    }
 }
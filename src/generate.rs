@@ -2,16 +2,25 @@ use macroassembler::assembler::TargetMacroAssembler;
 
 use crate::{
     air::{self, code::Code},
+    alloca_to_reg::alloca_to_reg,
+    eliminate_common_subexpressions::eliminate_common_subexpressions,
+    eliminate_dead_code::eliminate_dead_code,
     estimate_static_exec_counts::estimate_static_execution_counts,
     fix_ssa::fix_ssa,
+    fold_path_constants::fold_path_constants,
     hoist_loop_invariant_values::hoist_loop_invariant_values,
     infer_switches::infer_switches,
     legalize_memory_offsets::legalize_memory_offsets,
+    lower_aggregates::lower_aggregates,
     lower_macros::lower_macros,
     lower_to_air::lower_to_air,
     move_constants::move_constants,
     procedure::Procedure,
+    reduce_loop_strength::reduce_loop_strength,
     reduce_strength::reduce_strength,
+    sccp::sccp,
+    tail_calls_to_loops::tail_calls_to_loops,
+    validate::validate,
     OptLevel,
 };
 
@@ -23,15 +32,36 @@ pub fn prepare_for_generation<'a>(proc: &'a mut Procedure) -> Code<'a> {
 
 pub fn generate_to_air<'a>(proc: &'a mut Procedure) -> Code<'a> {
     proc.reset_reachability();
+    validate_after_phase(proc, "reset_reachability");
+
+    if proc.options.opt_level >= OptLevel::O1 {
+        // Turn self tail calls into loops while arguments can still be rewritten into variables.
+        tail_calls_to_loops(proc);
+        validate_after_phase(proc, "tail_calls_to_loops");
+
+        // Promote allocas that are only loaded from and stored to into variables,
+        // `fix_ssa` turns them into SSA values later.
+        alloca_to_reg(proc);
+        validate_after_phase(proc, "alloca_to_reg");
+    }
+
+    // Turn `GetElementPtr` into plain arithmetic and allocas into stack slots so
+    // that the rest of the pipeline only sees regular B3 values.
+    lower_aggregates(proc);
+    validate_after_phase(proc, "lower_aggregates");
+
     proc.dominators_or_compute();
     if proc.options.opt_level >= OptLevel::O2 {
         // Convert to SSA form.
         fix_ssa(proc);
+        validate_after_phase(proc, "fix_ssa");
 
         hoist_loop_invariant_values(proc);
-        // SCCP is quite expensive and untested pass. We do not run it by default.
-        if proc.options.enable_sccp {
-            crate::sccp::sccp(proc);
+        validate_after_phase(proc, "hoist_loop_invariant_values");
+        // SCCP is quite expensive, so it only runs by default at O3.
+        if proc.options.opt_level >= OptLevel::O3 || proc.options.enable_sccp {
+            sccp(proc);
+            validate_after_phase(proc, "sccp");
         }
 
         // TODO: Should we run `fix_ssa` after or before `reduce_strength`?
@@ -39,25 +69,59 @@ pub fn generate_to_air<'a>(proc: &'a mut Procedure) -> Code<'a> {
         // Running it before seems more beneficial because `reduce_strength`
         // can simplify SSA form and entirely remove some phi nodes.
 
+        // Replace values that a dominating branch or switch already tested with what the test
+        // proved. `reduce_strength` then folds the constants and removes the dead branches.
+        fold_path_constants(proc);
+        validate_after_phase(proc, "fold_path_constants");
+
         // Reduces strength until fixpoint.
         reduce_strength(proc);
+        validate_after_phase(proc, "reduce_strength");
+
+        // Turn scaled induction variables, like array indices, into pointer increments.
+        if reduce_loop_strength(proc) {
+            validate_after_phase(proc, "reduce_loop_strength");
+
+            reduce_strength(proc);
+            validate_after_phase(proc, "reduce_strength");
+        }
+
+        // Remove redundant loads and stores, and whatever pure values `reduce_strength` left.
+        if eliminate_common_subexpressions(proc) {
+            validate_after_phase(proc, "eliminate_common_subexpressions");
+
+            // Loads that were available from several predecessors now go through variables.
+            fix_ssa(proc);
+            validate_after_phase(proc, "fix_ssa");
+
+            reduce_strength(proc);
+            validate_after_phase(proc, "reduce_strength");
+        }
 
         // convet sequence of branches to switches when possible
         infer_switches(proc);
+        validate_after_phase(proc, "infer_switches");
     } else if proc.options.opt_level >= OptLevel::O1 {
         // Reduces strength in one pass.
         reduce_strength(proc);
+        validate_after_phase(proc, "reduce_strength");
     }
 
     lower_macros(proc);
+    validate_after_phase(proc, "lower_macros");
 
     legalize_memory_offsets(proc);
+    validate_after_phase(proc, "legalize_memory_offsets");
     // Move constants to places where program might benefit from them
     // Plus eliminates `ConstFloat` and `ConstDouble` opcodes
     // replacing them with loads from data section.
     move_constants(proc);
+    validate_after_phase(proc, "move_constants");
     legalize_memory_offsets(proc);
-    //eliminate_dead_code(proc);
+    validate_after_phase(proc, "legalize_memory_offsets");
+    // Remove values that are never used and have no effects, so they don't reach Air.
+    eliminate_dead_code(proc);
+    validate_after_phase(proc, "eliminate_dead_code");
 
     if proc.options.estimate_static_execution_counts {
         // Estimate frequency of each basic block based on loop analysis.
@@ -76,6 +140,22 @@ pub fn generate_to_air<'a>(proc: &'a mut Procedure) -> Code<'a> {
     code
 }
 
+/// Panics if `validate_b3_at_each_phase` is enabled and `phase` left the procedure malformed.
+fn validate_after_phase(proc: &Procedure, phase: &str) {
+    if !proc.options.validate_b3_at_each_phase {
+        return;
+    }
+
+    if let Err(error) = validate(proc) {
+        panic!(
+            "B3 validation failed after {}: {}\n{}",
+            phase,
+            error,
+            proc.display()
+        );
+    }
+}
+
 pub fn generate<'a, 'b>(air: &'a mut Code<'b>, jit: &mut TargetMacroAssembler) {
     air::generate::generate(air, jit);
 }
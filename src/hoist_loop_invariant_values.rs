@@ -152,18 +152,20 @@ pub fn hoist_loop_invariant_values(proc: &mut Procedure) -> bool {
                 if loop_data
                     .writes
                     .iter()
-                    .any(|write| write.overlaps(&effects.writes))
+                    .any(|write| !write.is_empty() && write.overlaps(&effects.reads))
                 {
                     continue;
                 }
 
                 proc.block_mut(loop_data.pre_header.unwrap())
                     .append_non_terminal(value);
+                proc.value_mut(value).owner = loop_data.pre_header;
                 let nop = proc.add_nop();
 
                 proc.block_mut(block)[value_index] = nop;
                 proc.value_mut(nop).owner = Some(block);
                 changed = true;
+                break;
             }
         }
     }
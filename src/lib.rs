@@ -20,6 +20,7 @@ pub mod compile;
 pub mod compute_division_magic;
 pub mod data_section;
 pub mod effects;
+pub mod eliminate_common_subexpressions;
 pub mod eliminate_dead_code;
 pub mod ensure_loop_pre_headers;
 pub mod estimate_static_exec_counts;
@@ -32,26 +33,33 @@ pub mod insertion_set;
 pub mod jit;
 pub mod kind;
 pub mod legalize_memory_offsets;
+pub mod lower_aggregates;
 pub mod lower_macros;
 pub mod lower_to_air;
 pub mod module;
 pub mod move_constants;
 pub mod opcode;
+pub mod parser;
 pub mod patchpoint_special;
 pub mod patchpoint_value;
 pub mod procedure;
 pub mod pure_cse;
+pub mod reduce_loop_strength;
 pub mod reduce_strength;
 pub mod rpo;
 pub mod sccp;
+pub mod serialize;
+pub mod simd;
 pub mod sparse_collection;
 pub mod ssa_calculator;
 pub mod stackmap_generation_params;
 pub mod stackmap_special;
 pub mod stackmap_value;
+pub mod tail_calls_to_loops;
 pub mod typ;
 pub mod uses;
 pub mod utils;
+pub mod validate;
 pub mod value;
 pub mod variable;
 pub mod width;
@@ -146,6 +154,8 @@ pub enum OptLevel {
     ///
     /// Might use graph coloring to reduce register pressure.
     O2,
+    /// Perform all of the O2 optimizations plus the more expensive ones, like sparse conditional
+    /// constant propagation.
     O3,
 }
 
@@ -213,7 +223,14 @@ pub struct Options {
     /// Turn this option off if you provide your own frequency estimates. By default
     /// it is set to true.
     pub estimate_static_execution_counts: bool,
+    /// Run sparse conditional constant propagation at O2 too. It always runs at O3.
     pub enable_sccp: bool,
+    /// Run [`validate`] after every B3 phase and panic with the procedure dump if it finds an
+    /// error. Useful to find out which phase produced malformed IR.
+    pub validate_b3_at_each_phase: bool,
+    /// Run [`air::validate()`](crate::air::validate::validate) after every Air phase in
+    /// `prepare_for_generation` and panic with the code dump if it finds an error.
+    pub validate_air_at_each_phase: bool,
 }
 
 impl Default for Options {
@@ -235,6 +252,8 @@ impl Default for Options {
             dump_air_at_each_phase: false,
             dump_b3_reduce_strength: false,
             enable_sccp: false,
+            validate_b3_at_each_phase: false,
+            validate_air_at_each_phase: false,
         }
     }
 }
@@ -248,7 +267,10 @@ pub use jit::compilation::Compilation;
 pub use jit::reg::*;
 pub use macroassembler;
 pub use opcode::*;
+pub use parser::*;
 pub use procedure::*;
+pub use serialize::*;
 pub use typ::*;
+pub use validate::*;
 pub use value::*;
 pub use width::*;
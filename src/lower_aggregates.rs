@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+
+use crate::{
+    air::{ccalling_convention::returns_in_memory, stack_slot::StackSlotKind},
+    bank::Bank,
+    block::BlockId,
+    insertion_set::InsertionSet,
+    jit::reg::Reg,
+    opcode::Opcode,
+    procedure::Procedure,
+    typ::Type,
+    utils::phase_scope::phase_scope,
+    value::{NumChildren, Value, ValueData, ValueId},
+};
+
+/// Lowers `GetElementPtr` to address arithmetic and allocates stack slots for all `Alloca`s that
+/// are still present in the procedure.
+///
+/// Aggregates that the C calling convention returns in memory are also lowered here. `CCall`s
+/// returning them get a pointer to a stack slot for the result, and `Extract`s and calls taking
+/// the result as an argument load from that slot. `Return`s store their values through the
+/// pointer our caller passed us and return it.
+pub fn lower_aggregates(proc: &mut Procedure) -> bool {
+    phase_scope("b3::lower_aggregates", || {
+        let mut lowering = LowerAggregates {
+            proc,
+            insertion_set: InsertionSet::new(),
+            results_in_memory: HashMap::new(),
+            changed: false,
+        };
+
+        lowering.run();
+        lowering.changed
+    })
+}
+
+struct LowerAggregates<'a> {
+    proc: &'a mut Procedure,
+    insertion_set: InsertionSet,
+    /// Calls returning in memory, mapped to the `SlotBase` of their result and the result type.
+    results_in_memory: HashMap<ValueId, (ValueId, Type)>,
+    changed: bool,
+}
+
+impl<'a> LowerAggregates<'a> {
+    fn run(&mut self) {
+        let return_pointer = self.return_pointer();
+
+        for block in (0..self.proc.blocks.len()).map(BlockId) {
+            for index in 0..self.proc.block(block).len() {
+                let value = self.proc.block(block)[index];
+                let typ = self.proc.value(value).typ();
+
+                match value.opcode(self.proc) {
+                    Opcode::Alloca => self.lower_alloca(value),
+                    Opcode::GetElementPtr => self.lower_get_element_ptr(index, value),
+                    Opcode::CCall if returns_in_memory(self.proc, typ) => {
+                        self.lower_ccall_in_memory(index, value)
+                    }
+                    Opcode::Return => match return_pointer {
+                        Some(pointer) => self.lower_return_in_memory(index, value, pointer),
+                        None => continue,
+                    },
+                    _ => continue,
+                }
+
+                self.changed = true;
+            }
+
+            self.insertion_set.execute(self.proc, block);
+        }
+
+        if self.results_in_memory.is_empty() {
+            return;
+        }
+
+        // Calls don't necessarily come before their users in block order, so these are lowered
+        // once all calls are.
+        for block in (0..self.proc.blocks.len()).map(BlockId) {
+            for index in 0..self.proc.block(block).len() {
+                let value = self.proc.block(block)[index];
+
+                match value.opcode(self.proc) {
+                    Opcode::Extract => self.lower_extract_in_memory(index, value),
+                    Opcode::CCall => self.lower_arguments_in_memory(index, value),
+                    _ => (),
+                }
+            }
+
+            self.insertion_set.execute(self.proc, block);
+        }
+    }
+
+    /// If the procedure returns in memory, reads the pointer to that memory at the entry. It is
+    /// passed as a hidden first argument.
+    fn return_pointer(&mut self) -> Option<ValueId> {
+        let ret = self.proc.signature()?.ret;
+
+        if !returns_in_memory(self.proc, ret) {
+            return None;
+        }
+
+        let reg = Reg::new_gpr(Bank::GP.to_argument_register(0));
+        let pointer = self.proc.add_argument(Type::Int64, reg);
+        self.insertion_set.insert_value(0, pointer);
+        self.changed = true;
+
+        Some(pointer)
+    }
+
+    fn lower_alloca(&mut self, value: ValueId) {
+        let typ = self.proc.value(value).alloca().unwrap();
+        // Zero-sized allocas still need a unique address.
+        let size = self.proc.size_of(typ).max(1);
+        let alignment = self.proc.alignment_of(typ);
+        let slot = self
+            .proc
+            .add_aligned_stack_slot(size, alignment, StackSlotKind::Locked);
+
+        let value = self.proc.value_mut(value);
+        value.replace_with(Opcode::SlotBase, Type::Int64, value.owner);
+        value.data = ValueData::SlotBase(slot);
+    }
+
+    fn lower_get_element_ptr(&mut self, index: usize, value: ValueId) {
+        let mut typ = self.proc.value(value).element_ptr_type().unwrap();
+        let children = self.proc.value(value).children.to_vec();
+
+        let mut address = children[0];
+        let mut offset = 0i64;
+
+        for &child in children[1..].iter() {
+            let aggregate = self.proc.aggregate(typ).clone();
+
+            if aggregate.is_struct() {
+                let field = self.proc.value(child).as_int().unwrap() as usize;
+                offset += aggregate.offset_of(field) as i64;
+                typ = aggregate.element_type(field);
+                continue;
+            }
+
+            let stride = aggregate.stride() as i64;
+            typ = aggregate.element_type(0);
+
+            if let Some(element) = self.proc.value(child).as_int() {
+                offset += element * stride;
+                continue;
+            }
+
+            let mut element = child;
+            if self.proc.value(element).typ() == Type::Int32 {
+                element = self.insert(
+                    index,
+                    Value::new(
+                        Opcode::SExt32,
+                        Type::Int64,
+                        NumChildren::One,
+                        &[element],
+                        ValueData::None,
+                    ),
+                );
+            }
+
+            if stride != 1 {
+                let stride = self
+                    .insertion_set
+                    .insert_int_constant(index, Type::Int64, stride, self.proc);
+                element = self.insert(
+                    index,
+                    Value::new(
+                        Opcode::Mul,
+                        Type::Int64,
+                        NumChildren::Two,
+                        &[element, stride],
+                        ValueData::None,
+                    ),
+                );
+            }
+
+            address = self.insert(
+                index,
+                Value::new(
+                    Opcode::Add,
+                    Type::Int64,
+                    NumChildren::Two,
+                    &[address, element],
+                    ValueData::None,
+                ),
+            );
+        }
+
+        if offset != 0 {
+            let offset = self
+                .insertion_set
+                .insert_int_constant(index, Type::Int64, offset, self.proc);
+            address = self.insert(
+                index,
+                Value::new(
+                    Opcode::Add,
+                    Type::Int64,
+                    NumChildren::Two,
+                    &[address, offset],
+                    ValueData::None,
+                ),
+            );
+        }
+
+        self.proc.value_mut(value).replace_with_identity(address);
+    }
+
+    fn lower_ccall_in_memory(&mut self, index: usize, value: ValueId) {
+        let typ = self.proc.value(value).typ();
+        let size = self.proc.size_of(typ);
+        let slot = self.proc.add_stack_slot(size, StackSlotKind::Locked);
+        let base = self.insert(
+            index,
+            Value::new(
+                Opcode::SlotBase,
+                Type::Int64,
+                NumChildren::Zero,
+                &[],
+                ValueData::SlotBase(slot),
+            ),
+        );
+
+        // The callee returns the pointer it was passed.
+        let call = self.proc.value_mut(value);
+        call.typ = Type::Int64;
+        call.children.insert(1, base);
+
+        self.results_in_memory.insert(value, (base, typ));
+    }
+
+    fn lower_extract_in_memory(&mut self, index: usize, value: ValueId) {
+        let tuple = value.child(self.proc, 0);
+        let (base, typ) = match self.results_in_memory.get(&tuple) {
+            Some(&result) => result,
+            None => return,
+        };
+
+        let element = self.proc.value(value).extract_index().unwrap();
+        let offset = self.proc.aggregate(typ).offset_of(element);
+        let element_type = self.proc.value(value).typ();
+        let load = self.proc.add_load(
+            Opcode::Load.into(),
+            element_type,
+            base,
+            offset as i32,
+            0..usize::MAX,
+            0..0,
+        );
+        self.insertion_set.insert_value(index, load);
+
+        let value = self.proc.value_mut(value);
+        value.replace_with_identity(load);
+        value.num_children = NumChildren::One;
+        self.changed = true;
+    }
+
+    /// Results returned in memory that are passed on to another call are loaded from their slot.
+    fn lower_arguments_in_memory(&mut self, index: usize, value: ValueId) {
+        for argument in 1..self.proc.value(value).children.len() {
+            let child = self.proc.value(value).children[argument];
+            let (base, typ) = match self.results_in_memory.get(&child) {
+                Some(&result) => result,
+                None => continue,
+            };
+
+            let load = self
+                .proc
+                .add_load(Opcode::Load.into(), typ, base, 0, 0..usize::MAX, 0..0);
+            self.insertion_set.insert_value(index, load);
+            self.proc.value_mut(value).children[argument] = load;
+            self.changed = true;
+        }
+    }
+
+    fn lower_return_in_memory(&mut self, index: usize, value: ValueId, pointer: ValueId) {
+        let ret = self.proc.signature().unwrap().ret;
+        let aggregate = self.proc.aggregate(ret).clone();
+        let children = self.proc.value(value).children.to_vec();
+
+        for (element, &child) in children.iter().enumerate() {
+            let store = self.proc.add_store(
+                Opcode::Store.into(),
+                child,
+                pointer,
+                aggregate.offset_of(element) as i32,
+                0..usize::MAX,
+                0..0,
+            );
+            self.insertion_set.insert_value(index, store);
+        }
+
+        let value = self.proc.value_mut(value);
+        value.children.clear();
+        value.children.push(pointer);
+        value.num_children = NumChildren::One;
+    }
+
+    fn insert(&mut self, index: usize, value: Value) -> ValueId {
+        let value = self.proc.add(value);
+        self.insertion_set.insert_value(index, value)
+    }
+}
@@ -5,21 +5,33 @@ use macroassembler::assembler::{
     abstract_macro_assembler::{BaseIndex, Extend, Scale},
     TargetMacroAssembler,
 };
+use tinyvec::tiny_vec;
 
 use crate::{
+    air::{
+        ccalling_convention::{
+            ccall_argument_bank_and_width, check_tail_call_stack_arguments, parameter_locations,
+        },
+        stack_slot::StackSlotKind,
+    },
     analysis::use_counts::UseCounts,
+    bank::Bank,
     effects::Effects,
     infer_switches::CaseCollection,
     insertion_set::InsertionSet,
     jit::{reg::Reg, register_set::RegisterSetBuilder},
+    module::ProcedureAddress,
+    patchpoint_value::PatchpointValue,
+    stackmap_value::StackMapValue,
     update_predecessors_after,
     utils::bitvector::BitVector,
-    BlockId, Frequency, FrequentBlock, NumChildren, Opcode, Procedure, Type, Value, ValueData,
-    ValueId, Width,
+    BasicBlockBuilder, BlockId, Frequency, FrequentBlock, NumChildren, Opcode, Procedure, Type,
+    Value, ValueData, ValueId, ValueRep, ValueRepKind, Width,
 };
 
 /// Lowers high-level operations that it's easier to deal with once they are broken up. Currently
-/// this includes Switch and ChillDiv.
+/// this includes Switch, ChillDiv, ProcedureAddr, GlobalAddr, Depend, Parameter and the atomic bit
+/// operations. Tail calls that need more stack arguments than we were passed are rejected here.
 pub fn lower_macros(proc: &mut Procedure) -> bool {
     let mut lowerer = LowerMacros::new(proc);
     lowerer.run()
@@ -47,9 +59,13 @@ impl<'a> LowerMacros<'a> {
     }
 
     fn run(&mut self) -> bool {
-        for block in (0..self.proc.blocks.len()).map(BlockId) {
-            self.block = block;
+        // Lowering may split blocks, so the number of blocks can grow while we iterate.
+        let mut block = 0;
+        while block < self.proc.blocks.len() {
+            self.block = BlockId(block);
             self.process_current_block();
+            self.insertion_set.execute(self.proc, self.block);
+            block += 1;
         }
 
         if self.changed {
@@ -66,6 +82,14 @@ impl<'a> LowerMacros<'a> {
             self.value = value;
 
             match self.value.opcode(self.proc) {
+                Opcode::AtomicXchgAnd | Opcode::AtomicXchgOr | Opcode::AtomicXchgXor => {
+                    self.lower_atomic_bit_op(index);
+                    self.changed = true;
+                    // The rest of the block was moved to the continuation block, which is
+                    // processed later.
+                    return;
+                }
+
                 Opcode::Switch => {
                     let mut cases = vec![];
                     let case_collection = CaseCollection::new(
@@ -87,11 +111,291 @@ impl<'a> LowerMacros<'a> {
                     update_predecessors_after(self.block, &mut self.proc.blocks);
                     self.changed = true;
                 }
+
+                Opcode::ProcedureAddr => {
+                    self.lower_procedure_addr();
+                    self.changed = true;
+                }
+
+                Opcode::Parameter => {
+                    self.lower_parameter(index);
+                    self.changed = true;
+                }
+
+                Opcode::TailCCall => {
+                    if let Err(message) = check_tail_call_stack_arguments(self.proc, self.value) {
+                        panic!("{} at v@{}", message, self.value.0);
+                    }
+                }
+
+                Opcode::GlobalAddr => {
+                    let id = self.proc.value(self.value).global().unwrap();
+                    let address = self
+                        .proc
+                        .global_address(id)
+                        .unwrap_or_else(|| panic!("address of global {:?} is not known", id));
+
+                    let value = self.proc.value_mut(self.value);
+                    value.replace_with(Opcode::Const64, Type::Int64, value.owner);
+                    value.data = ValueData::Const64(address as i64);
+                    self.changed = true;
+                }
+
+                Opcode::Depend => {
+                    // x86 does not reorder loads with other loads, so all Depend has to do is
+                    // stop the compiler from doing it. That takes a load-load fence, which emits
+                    // no code, and the result is just zero.
+                    let fence = self.proc.add(Value::new(
+                        Opcode::Fence,
+                        Type::Void,
+                        NumChildren::Zero,
+                        &[],
+                        ValueData::Fence {
+                            read: 0..0,
+                            write: 0..usize::MAX,
+                        },
+                    ));
+                    self.insertion_set.insert_value(index, fence);
+
+                    let value = self.proc.value_mut(self.value);
+                    let typ = value.typ();
+                    let (opcode, data) = if typ == Type::Int32 {
+                        (Opcode::Const32, ValueData::Const32(0))
+                    } else {
+                        (Opcode::Const64, ValueData::Const64(0))
+                    };
+                    value.replace_with(opcode, typ, value.owner);
+                    value.num_children = NumChildren::Zero;
+                    value.data = data;
+                    self.changed = true;
+                }
                 _ => (),
             }
         }
     }
 
+    /// x86 has no instruction that atomically ands, ors or xors memory and returns the old value,
+    /// so these are lowered to a loop around `AtomicStrongCAS`:
+    ///
+    /// ```text
+    /// BB#current:
+    ///     @initial = Load(@ptr)
+    ///     Upsilon(@initial, ^phi)
+    ///     Jump(#loop)
+    /// BB#loop:
+    ///     @phi = Phi()
+    ///     @new = BitAnd(@phi, @operand)
+    ///     @old = AtomicStrongCAS(@phi, @new, @ptr)
+    ///     Upsilon(@old, ^phi)
+    ///     Branch(Equal(@old, @phi)), Then:#continuation, Else:#loop
+    /// BB#continuation:
+    ///     @value = Identity(@old)
+    ///     ...
+    /// ```
+    fn lower_atomic_bit_op(&mut self, index: usize) {
+        let value = self.value;
+        let binary = match value.opcode(self.proc) {
+            Opcode::AtomicXchgAnd => Opcode::BitAnd,
+            Opcode::AtomicXchgOr => Opcode::BitOr,
+            Opcode::AtomicXchgXor => Opcode::BitXor,
+            _ => unreachable!(),
+        };
+
+        let typ = self.proc.value(value).typ();
+        let width = self.proc.value(value).atomic_width().unwrap();
+        let (offset, range, fence_range) = self.proc.value(value).memory_value().unwrap();
+        let operand = value.child(self.proc, 0);
+        let ptr = value.child(self.proc, 1);
+
+        let frequency = self.proc.block(self.block).frequency();
+        let loop_block = self.proc.add_block(frequency);
+        let continuation = self.proc.add_block(frequency);
+
+        // Everything starting with the atomic itself moves to the continuation.
+        let tail = self.proc.block_mut(self.block).values.split_off(index);
+        self.proc.block_mut(continuation).values = tail;
+
+        let successors = std::mem::take(&mut self.proc.block_mut(self.block).successor_list);
+        for &(successor, _) in successors.iter() {
+            self.proc
+                .block_mut(successor)
+                .replace_predecessor(self.block, continuation);
+        }
+        self.proc.block_mut(continuation).successor_list = successors;
+
+        let mut builder = BasicBlockBuilder::new(self.proc, self.block);
+
+        // The initial value is sign-extended the same way AtomicStrongCAS sign-extends its
+        // result, so that the comparison in the loop is exact.
+        let initial = match width {
+            Width::W8 => builder.load8s(ptr, offset, Some(range.clone()), Some(0..0)),
+            Width::W16 => builder.load16s(ptr, offset, Some(range.clone()), Some(0..0)),
+            _ => builder.load(typ, ptr, offset, Some(range.clone()), Some(0..0)),
+        };
+
+        builder.block = loop_block;
+        let phi = builder.phi(typ);
+
+        builder.block = self.block;
+        builder.upsilon(initial, Some(phi));
+        builder.jump(Some(loop_block));
+
+        builder.block = loop_block;
+        let new_value = builder.binary(binary, phi, operand);
+        let old = builder.atomic_strong_cas(
+            width,
+            phi,
+            new_value,
+            ptr,
+            offset,
+            Some(range),
+            Some(fence_range),
+        );
+        builder.upsilon(old, Some(phi));
+        let done = builder.binary(Opcode::Equal, old, phi);
+        builder.branch(done, continuation, (loop_block, Frequency::Normal));
+
+        self.proc.value_mut(value).replace_with_identity(old);
+    }
+
+    /// `Parameter` is read from where the C calling convention passes it: either an argument
+    /// register or the stack argument area of our caller, which starts right above the saved frame
+    /// pointer and the return address. If we return in memory, the pointer to that memory is passed
+    /// before all parameters.
+    fn lower_parameter(&mut self, index: usize) {
+        let parameter = self.proc.value(self.value).parameter_index().unwrap();
+        let locations = parameter_locations(self.proc, parameter);
+        let typ = self.proc.value(self.value).typ();
+
+        if !typ.is_aggregate() && locations[0].is_reg() {
+            let value = self.proc.value_mut(self.value);
+            value.replace_with(Opcode::ArgumentReg, typ, value.owner);
+            value.data = ValueData::Argument(locations[0].reg());
+            return;
+        }
+
+        let (address, offset) = match locations.first() {
+            Some(location) if !location.is_reg() => {
+                // Parameters on stack, including all eightbytes of an aggregate, are contiguous.
+                let frame_pointer = self.proc.add(Value::new(
+                    Opcode::FramePointer,
+                    Type::Int64,
+                    NumChildren::Zero,
+                    &[],
+                    ValueData::None,
+                ));
+                self.insertion_set.insert_value(index, frame_pointer);
+
+                (frame_pointer, 16 + location.offset() as i32)
+            }
+
+            _ => {
+                // Aggregates passed in registers are spilled one eightbyte at a time, so that
+                // they can be loaded like any other aggregate.
+                let size = (self.proc.size_of(typ) + 7) & !7;
+                let slot = self.proc.add_stack_slot(size.max(1), StackSlotKind::Locked);
+                let base = self.proc.add(Value::new(
+                    Opcode::SlotBase,
+                    Type::Int64,
+                    NumChildren::Zero,
+                    &[],
+                    ValueData::SlotBase(slot),
+                ));
+                self.insertion_set.insert_value(index, base);
+
+                for (eightbyte, location) in locations.iter().enumerate() {
+                    let typ = match ccall_argument_bank_and_width(self.proc, typ, eightbyte).0 {
+                        Bank::GP => Type::Int64,
+                        Bank::FP => Type::Double,
+                    };
+                    let argument = self.proc.add_argument(typ, location.reg());
+                    self.insertion_set.insert_value(index, argument);
+
+                    let store = self.proc.add_store(
+                        Opcode::Store.into(),
+                        argument,
+                        base,
+                        8 * eightbyte as i32,
+                        0..usize::MAX,
+                        0..0,
+                    );
+                    self.insertion_set.insert_value(index, store);
+                }
+
+                (base, 0)
+            }
+        };
+
+        let value = self.proc.value_mut(self.value);
+        value.replace_with(Opcode::Load, typ, value.owner);
+        value.num_children = NumChildren::One;
+        value.children.push(address);
+        value.data = ValueData::MemoryValue {
+            offset,
+            range: 0..usize::MAX,
+            fence_range: 0..0,
+        };
+    }
+
+    fn lower_procedure_addr(&mut self) {
+        let id = self.proc.value(self.value).procedure_addr().unwrap();
+        let address = self
+            .proc
+            .procedure_address(id)
+            .cloned()
+            .unwrap_or_else(|| panic!("address of procedure {:?} is not known", id));
+
+        match address {
+            ProcedureAddress::Static(address) => {
+                let value = self.proc.value_mut(self.value);
+                value.replace_with(Opcode::Const64, Type::Int64, value.owner);
+                value.data = ValueData::Const64(address as i64);
+            }
+
+            ProcedureAddress::Pending(labels) => {
+                // The callee is emitted into the same assembler but its address is not known until
+                // the code is linked. Move a placeholder into the result and patch the real address
+                // in once the entrypoint of the callee is finalized.
+                let value = self.proc.value_mut(self.value);
+                value.replace_with(Opcode::Patchpoint, Type::Int64, value.owner);
+                value.data = ValueData::Patchpoint(PatchpointValue {
+                    base: StackMapValue {
+                        reps: vec![],
+                        generator: None,
+                        early_clobbered: Default::default(),
+                        late_clobbered: Default::default(),
+                        used_registers: Default::default(),
+                    },
+                    // The move is patched before the code can run, so it is just a constant.
+                    effects: Effects::none(),
+                    result_constraints: tiny_vec!([ValueRep; 1] =>
+                        ValueRep::new(ValueRepKind::SomeRegister)),
+                    num_fp_scratch_registers: 0,
+                    num_gp_scratch_registers: 0,
+                });
+
+                self.proc.stackmap_set_generator(
+                    self.value,
+                    Rc::new(move |jit, params| {
+                        let patch = jit.move_with_patch(0i64, params[0].gpr());
+
+                        // `Module::compile` refuses to link procedures that are never generated,
+                        // so the labels are populated before link tasks run.
+                        let labels = labels.clone();
+                        jit.add_link_task(Box::new(move |link_buffer| {
+                            let entrypoint = *labels
+                                .borrow()
+                                .first()
+                                .expect("procedure was never generated");
+                            let address = link_buffer.rx_location_of(entrypoint);
+                            link_buffer.patch(patch, address as *mut u8);
+                        }));
+                    }),
+                );
+            }
+        }
+    }
+
     fn recursively_build_switch(
         &mut self,
         cases: Vec<(i64, FrequentBlock)>,
@@ -3,21 +3,29 @@ use std::collections::HashMap;
 
 use crate::air;
 use crate::air::arg::{is_representable_as, ArgSignedness};
+use crate::air::ccalling_convention::{
+    aggregate_return_registers, ccall_argument_bank_and_width, ccall_argument_register_count,
+    returns_in_memory,
+};
 use crate::air::form_table::{is_valid_form, is_x86};
 use crate::air::helpers::{move_for_type, relaxed_move_for_type};
 use crate::air::insertion_set::InsertionSet;
 use crate::air::kind::Kind;
 use crate::air::opcode::{Opcode as AirOpcode, NUM_OPCODES};
 use crate::air::special::{Special, SpecialId, SpecialKind};
+use crate::air::stack_slot::{StackSlotId, StackSlotKind};
 use crate::bank::Bank;
 use crate::block::{blocks_in_pre_order, Frequency};
 use crate::check_special::CheckSpecial;
 use crate::patchpoint_special::PatchpointSpecial;
+use crate::simd::{
+    element_width, is_floating_point_lane, simd_info, SIMDInfo, SIMDLane, SIMDSignMode,
+};
 use crate::stackmap_special::RoleMode;
 use crate::typ::TypeKind;
 use crate::utils::phase_scope;
-use crate::value::{Value, ValueData, ValueRep, ValueRepKind};
-use crate::width::Width;
+use crate::value::{Value, ValueRep, ValueRepKind};
+use crate::width::{width_for_type, Width};
 use crate::{
     air::{
         arg::{Arg, ArgKind},
@@ -38,6 +46,7 @@ use crate::{
     value::ValueId,
     variable::VariableId,
 };
+use macroassembler::assembler::macro_assembler_x86_common::StatusCondition;
 use macroassembler::assembler::{DoubleCondition, RelationalCondition, ResultCondition};
 use macroassembler::jit::fpr_info::RETURN_VALUE_FPR;
 use macroassembler::jit::gpr_info::RETURN_VALUE_GPR;
@@ -96,6 +105,8 @@ struct LowerToAir<'a> {
     phi_to_tmp: Vec<Tmp>,
     block_to_block: Vec<BasicBlockId>,
     variable_to_tmps: HashMap<VariableId, Vec<Tmp>>,
+    /// Stack slots that tuples are stored to, so that `Extract` can load their elements.
+    tuple_slots: HashMap<ValueId, StackSlotId>,
     phi_children: PhiChildren,
     use_counts: UseCounts,
     dominators: Dominators<Procedure>,
@@ -131,6 +142,7 @@ impl<'a> LowerToAir<'a> {
             dominators: code.proc.dominators().clone(),
             phi_children: PhiChildren::new(code.proc),
             variable_to_tmps: HashMap::new(),
+            tuple_slots: HashMap::new(),
             code,
             fast_worklist: GraphNodeWorklist::new(),
             insts: vec![],
@@ -256,6 +268,54 @@ impl<'a> LowerToAir<'a> {
         tmp
     }
 
+    fn tuple_slot(&mut self, value: ValueId) -> StackSlotId {
+        if let Some(&slot) = self.tuple_slots.get(&value) {
+            return slot;
+        }
+
+        let typ = self.value(value).typ();
+        let slot = self.add_tuple_slot(typ);
+        self.tuple_slots.insert(value, slot);
+        slot
+    }
+
+    /// Tuples are moved to and from registers as whole eightbytes, so their slots are rounded up
+    /// to eightbytes.
+    fn add_tuple_slot(&mut self, typ: Type) -> StackSlotId {
+        let size = (self.code.proc.size_of(typ) + 7) & !7;
+        self.code.add_stack_slot(size, StackSlotKind::Locked)
+    }
+
+    /// Appends the arguments of the current call to `inst`, one for each register they need.
+    fn append_ccall_arguments(&mut self, inst: &mut Inst) {
+        for i in 1..self.value(self.value).children.len() {
+            let child = self.child_id(self.value, i);
+            let typ = self.value(child).typ();
+
+            if !typ.is_aggregate() {
+                let arg = self.imm_or_tmp(child);
+                inst.args.push(arg);
+                continue;
+            }
+
+            // Aggregates are passed by value one eightbyte at a time.
+            let slot = self.tuple_slot(child);
+
+            for index in 0..ccall_argument_register_count(&self.code, child) {
+                let (bank, _) = ccall_argument_bank_and_width(self.code.proc, typ, index);
+                let tmp = self.code.new_tmp(bank);
+                let opcode = match bank {
+                    Bank::GP => AirOpcode::Move,
+                    Bank::FP => AirOpcode::MoveDouble,
+                };
+                let eightbyte = Arg::new_stack(slot, 8 * index as i32);
+
+                self.append(opcode, &[eightbyte, Arg::new_tmp(tmp)]);
+                inst.args.push(Arg::new_tmp(tmp));
+            }
+        }
+    }
+
     fn tmp_promise(&mut self, value: Option<ValueId>) -> ArgPromise {
         ArgPromise::new(Arg::default(), value)
     }
@@ -475,16 +535,7 @@ impl<'a> LowerToAir<'a> {
     // This gives you the address of the given Load or Store. If it's not a Load or Store, then
     // it returns Arg().
     fn addr(&mut self, memory_value: ValueId, mode: AddrRequestMode) -> Option<Arg> {
-        if let ValueData::MemoryValue {
-            offset,
-            ref range,
-            ref fence_range,
-        } = self.value(memory_value).data
-        {
-            let offset = offset;
-            let _range = range.clone();
-            let _fence_range = fence_range.clone();
-
+        if let Some((offset, _range, _fence_range)) = self.value(memory_value).memory_value() {
             let width = self.value(memory_value).access_width(self.code.proc);
 
             if mode == AddrRequestMode::PreferSimpleAddr {
@@ -519,6 +570,11 @@ impl<'a> LowerToAir<'a> {
             return ArgPromise::new(Arg::default(), None);
         }
 
+        // Load-acquires have to stay their own instruction.
+        if self.value(load_value).has_fence() {
+            return ArgPromise::new(Arg::default(), None);
+        }
+
         let load_addr = self.addr(load_value, mode);
 
         let mut result = ArgPromise::new(load_addr.unwrap(), Some(load_value));
@@ -615,6 +671,132 @@ impl<'a> LowerToAir<'a> {
         func(self, tmp, typ, 0);
     }
 
+    /// Aggregate loads are copied to the tuple slot of the loaded value, which calls read their
+    /// arguments from. The copy never reads past the end of the aggregate.
+    fn lower_aggregate_load(&mut self) {
+        assert!(
+            !self.value(self.value).has_fence(),
+            "aggregate loads cannot be fenced"
+        );
+
+        let size = self.code.proc.size_of(self.value(self.value).typ());
+        let (offset, _, _) = self.value(self.value).memory_value().unwrap();
+        let base = self.tmp(self.child_id(self.value, 0));
+        let slot = self.tuple_slot(self.value);
+
+        let mut copied = 0;
+
+        while copied < size {
+            let (load, store, chunk) = match size - copied {
+                8.. => (AirOpcode::Move, AirOpcode::Move, 8),
+                4..=7 => (AirOpcode::Move32, AirOpcode::Move32, 4),
+                2..=3 => (AirOpcode::Load16, AirOpcode::Store16, 2),
+                _ => (AirOpcode::Load8, AirOpcode::Store8, 1),
+            };
+            let tmp = Arg::new_tmp(self.code.new_tmp(Bank::GP));
+            let source = Arg::new_addr(base, offset as i64 + copied as i64);
+
+            self.append(load, &[source, tmp]);
+            self.append(store, &[tmp, Arg::new_stack(slot, copied as i32)]);
+            copied += chunk;
+        }
+    }
+
+    /// Returns the run of Upsilons ending at the current value if it directly precedes the
+    /// terminal of the block, otherwise an empty list.
+    fn upsilons_before_terminal(&self) -> Vec<ValueId> {
+        let block = self.code.proc.block(self.block);
+        if self.index + 2 != block.len() {
+            return vec![];
+        }
+
+        let mut upsilons = vec![];
+        for index in (0..=self.index).rev() {
+            let value = block[index];
+            if self.value(value).kind.opcode() != Opcode::Upsilon || self.locked.contains(&value)
+            {
+                break;
+            }
+
+            let phi = self.value(value).phi();
+            if upsilons.iter().any(|&upsilon| self.value(upsilon).phi() == phi) {
+                break;
+            }
+
+            upsilons.push(value);
+        }
+
+        upsilons.reverse();
+        upsilons
+    }
+
+    /// All Upsilons at the end of a block take effect at the block boundary, so they are lowered to
+    /// a single Shuffle into the Phi tmps instead of a sequence of moves.
+    fn lower_upsilons_to_shuffle(&mut self, upsilons: &[ValueId]) {
+        let mut args = Vec::with_capacity(upsilons.len() * 3);
+
+        for &upsilon in upsilons {
+            let value = self.child_id(upsilon, 0);
+            let phi = self.value(upsilon).phi().unwrap();
+            let width = width_for_type(self.value(value).typ());
+
+            args.push(self.imm_or_tmp(value));
+            args.push(Arg::new_tmp(self.phi_to_tmp[phi.0]));
+            args.push(Arg::new_width_arg(width));
+
+            if upsilon != self.value {
+                self.commit_internal(Some(upsilon));
+            }
+        }
+
+        self.append(AirOpcode::Shuffle, &args);
+    }
+
+    fn returns_tuple_in_registers(&self) -> bool {
+        match self.code.proc.signature() {
+            Some(signature) => {
+                signature.ret.is_aggregate() && !returns_in_memory(self.code.proc, signature.ret)
+            }
+            None => false,
+        }
+    }
+
+    /// Elements of the returned tuple are stored to a stack slot at their offsets and then loaded
+    /// into the return registers one eightbyte at a time.
+    fn lower_tuple_return(&mut self) {
+        let typ = self.code.proc.signature().unwrap().ret;
+        let aggregate = self.code.proc.aggregate(typ).clone();
+        let registers = aggregate_return_registers(self.code.proc, typ);
+        let mut args = vec![];
+
+        if !registers.is_empty() {
+            let slot = self.add_tuple_slot(typ);
+
+            for index in 0..aggregate.num_elements() {
+                let child = self.child_id(self.value, index);
+                let opcode = move_for_type(self.value(child).typ());
+                let source = self.tmp(child);
+                let element = Arg::new_stack(slot, aggregate.offset_of(index) as i32);
+
+                self.append(opcode, &[Arg::new_tmp(source), element]);
+            }
+
+            for (index, &reg) in registers.iter().enumerate() {
+                let opcode = if reg.is_gpr() {
+                    AirOpcode::Move
+                } else {
+                    AirOpcode::MoveDouble
+                };
+                let result = Arg::new_tmp(Tmp::from_reg(reg));
+
+                self.append(opcode, &[Arg::new_stack(slot, 8 * index as i32), result]);
+                args.push(result);
+            }
+        }
+
+        self.append(AirOpcode::RetTuple, &args);
+    }
+
     fn append(&mut self, opcode: impl Into<air::kind::Kind>, args: &[Arg]) {
         self.insts
             .last_mut()
@@ -1190,7 +1372,7 @@ impl<'a> LowerToAir<'a> {
             self.value(left).typ(),
         );
 
-        if self.value(self.value).memory_value().unwrap().1.start != 0 {
+        if self.value(self.value).has_fence() {
             return false;
         }
 
@@ -1369,17 +1551,15 @@ impl<'a> LowerToAir<'a> {
             Width::W128 => {
                 assert_eq!(bank, Bank::FP);
 
-                todo!()
+                AirOpcode::MoveVector
             }
         }
     }
 
     fn append_store(&mut self, value: ValueId, dest: &Arg) {
-        let (_offset, fence, _fence_range) = self.value(value).memory_value().unwrap();
-
         let mut kind;
 
-        if fence.start != 0 {
+        if self.value(value).has_fence() {
             if is_x86() {
                 kind = Kind {
                     opcode: opcode_for_width!(Xchg, self.value(value).access_width(self.code.proc)),
@@ -2737,6 +2917,465 @@ impl<'a> LowerToAir<'a> {
         );
     }
 
+    /// Sign-extend the result of a subwidth atomic operation, as B3 atomics require.
+    fn append_atomic_result(&mut self, width: Width, source: Tmp, result: Tmp) {
+        let opcode = match width {
+            Width::W8 => AirOpcode::SignExtend8To32,
+            Width::W16 => AirOpcode::SignExtend16To32,
+            Width::W32 => AirOpcode::Move32,
+            Width::W64 => AirOpcode::Move,
+            Width::W128 => unreachable!(),
+        };
+
+        self.append(opcode, &[Arg::new_tmp(source), Arg::new_tmp(result)]);
+    }
+
+    fn append_atomic_cas(&mut self) {
+        let width = self.value(self.value).atomic_width().unwrap();
+        let opcode = opcode_for_width!(AtomicStrongCAS, width);
+
+        let expected = self.tmp(self.child_id(self.value, 0));
+        let new_value = self.tmp(self.child_id(self.value, 1));
+        let address = self.addr(self.value, AddrRequestMode::NoRestriction).unwrap();
+
+        // `cmpxchg` takes the expected value in rax and leaves the old value there.
+        self.append(
+            AirOpcode::Move,
+            &[Arg::new_tmp(expected), Arg::new_tmp(self.eax)],
+        );
+
+        if self.value(self.value).kind.opcode() == Opcode::AtomicWeakCAS {
+            let result = self.tmp(self.value);
+            self.append(
+                opcode,
+                &[
+                    Arg::new_status_cond(StatusCondition::Success),
+                    Arg::new_tmp(self.eax),
+                    Arg::new_tmp(new_value),
+                    address,
+                    Arg::new_tmp(result),
+                ],
+            );
+            return;
+        }
+
+        let success = self.code.new_tmp(Bank::GP);
+        self.append(
+            opcode,
+            &[
+                Arg::new_status_cond(StatusCondition::Success),
+                Arg::new_tmp(self.eax),
+                Arg::new_tmp(new_value),
+                address,
+                Arg::new_tmp(success),
+            ],
+        );
+
+        let result = self.tmp(self.value);
+        self.append_atomic_result(width, self.eax, result);
+    }
+
+    fn append_atomic_xchg(&mut self) {
+        let op = self.value(self.value).kind.opcode();
+        let width = self.value(self.value).atomic_width().unwrap();
+
+        let opcode = if op == Opcode::AtomicXchg {
+            opcode_for_width!(Xchg, width)
+        } else {
+            opcode_for_width!(AtomicXchgAdd, width)
+        };
+
+        let operand = self.imm_or_tmp(self.child_id(self.value, 0));
+        let address = self.addr(self.value, AddrRequestMode::NoRestriction).unwrap();
+        let result = self.tmp(self.value);
+
+        self.append(AirOpcode::Move, &[operand, Arg::new_tmp(result)]);
+
+        // x86 has no fetch-and-sub, so we add the negated operand instead.
+        if op == Opcode::AtomicXchgSub {
+            let neg = if width == Width::W64 {
+                AirOpcode::Neg64
+            } else {
+                AirOpcode::Neg32
+            };
+            self.append(neg, &[Arg::new_tmp(result)]);
+        }
+
+        self.append(opcode, &[Arg::new_tmp(result), address]);
+
+        if width < Width::W32 {
+            self.append_atomic_result(width, result, result);
+        }
+    }
+
+    /// Materializes a vector with every lane of `lane` set to `bits`.
+    fn vector_splat_bits(&mut self, lane: SIMDLane, bits: i64) -> Tmp {
+        let opcode = match element_width(lane) {
+            Width::W32 => AirOpcode::VectorSplatInt32,
+            Width::W64 => AirOpcode::VectorSplatInt64,
+            _ => unreachable!(),
+        };
+
+        let scratch = self.code.new_tmp(Bank::GP);
+        let result = self.code.new_tmp(Bank::FP);
+        self.append(AirOpcode::Move, &[Arg::new_bigimm(bits), Arg::new_tmp(scratch)]);
+        self.append(opcode, &[Arg::new_tmp(scratch), Arg::new_tmp(result)]);
+        result
+    }
+
+    fn vector_all_ones(&mut self) -> Tmp {
+        let result = self.code.new_tmp(Bank::FP);
+        let scratch = self.code.new_tmp(Bank::FP);
+        self.append(AirOpcode::MoveZeroToVector, &[Arg::new_tmp(result)]);
+        self.append(
+            AirOpcode::CompareIntegerVector,
+            &[
+                Arg::new_rel_cond(RelationalCondition::Equal),
+                Arg::new_simd_info(simd_info(SIMDLane::I8x16, SIMDSignMode::None)),
+                Arg::new_tmp(result),
+                Arg::new_tmp(result),
+                Arg::new_tmp(result),
+                Arg::new_tmp(scratch),
+            ],
+        );
+        result
+    }
+
+    fn append_vector_binary(
+        &mut self,
+        opcode: AirOpcode,
+        info: SIMDInfo,
+        left: Tmp,
+        right: Tmp,
+        result: Tmp,
+    ) {
+        self.append(
+            opcode,
+            &[
+                Arg::new_simd_info(info),
+                Arg::new_tmp(left),
+                Arg::new_tmp(right),
+                Arg::new_tmp(result),
+            ],
+        );
+    }
+
+    /// `minps` and `maxps` return their second operand if either lane is NaN or both lanes are
+    /// zero, so the operation is done both ways round. ORing the results picks -0.0 as the minimum
+    /// of two zeros, ANDing them picks +0.0 as the maximum. Lanes where either result is NaN get
+    /// all bits set, which is a NaN too.
+    fn append_vector_float_min_max(&mut self) {
+        let op = self.value(self.value).kind.opcode();
+        let info = self.value(self.value).simd_info().unwrap();
+        let left = self.tmp(self.child_id(self.value, 0));
+        let right = self.tmp(self.child_id(self.value, 1));
+        let result = self.tmp(self.value);
+
+        let (opcode, combine) = match op {
+            Opcode::VectorMin => (AirOpcode::VectorMin, AirOpcode::VectorOr),
+            _ => (AirOpcode::VectorMax, AirOpcode::VectorAnd),
+        };
+
+        let forward = self.code.new_tmp(Bank::FP);
+        let backward = self.code.new_tmp(Bank::FP);
+        let unordered = self.code.new_tmp(Bank::FP);
+
+        self.append_vector_binary(opcode, info, left, right, forward);
+        self.append_vector_binary(opcode, info, right, left, backward);
+        self.append(
+            AirOpcode::CompareFloatingPointVector,
+            &[
+                Arg::new_double_cond(DoubleCondition::NotEqualOrUnordered),
+                Arg::new_simd_info(info),
+                Arg::new_tmp(forward),
+                Arg::new_tmp(backward),
+                Arg::new_tmp(unordered),
+            ],
+        );
+        self.append_vector_binary(combine, info, forward, backward, result);
+        self.append_vector_binary(AirOpcode::VectorOr, info, result, unordered, result);
+    }
+
+    /// SSE has no byte multiply. The low byte of a word product only depends on the low bytes of
+    /// the operands, so `pmullw` gives the even bytes. The odd bytes are shifted down, multiplied
+    /// and shifted back up, which also clears the even bytes of that product.
+    fn append_vector_mul_int8(&mut self) {
+        let left = self.tmp(self.child_id(self.value, 0));
+        let right = self.tmp(self.child_id(self.value, 1));
+        let result = self.tmp(self.value);
+        let words = simd_info(SIMDLane::I16x8, SIMDSignMode::None);
+
+        let shift = self.code.new_tmp(Bank::FP);
+        let scratch = self.code.new_tmp(Bank::GP);
+        self.append(AirOpcode::Move, &[Arg::new_imm(8), Arg::new_tmp(scratch)]);
+        self.append(AirOpcode::Move64ToDouble, &[Arg::new_tmp(scratch), Arg::new_tmp(shift)]);
+        let low_bytes = self.vector_splat_bits(SIMDLane::I32x4, 0x00ff_00ff);
+
+        let even = self.code.new_tmp(Bank::FP);
+        self.append_vector_binary(AirOpcode::VectorMul, words, left, right, even);
+        self.append_vector_binary(AirOpcode::VectorAnd, words, even, low_bytes, even);
+
+        let left_odd = self.code.new_tmp(Bank::FP);
+        let right_odd = self.code.new_tmp(Bank::FP);
+        self.append_vector_binary(AirOpcode::VectorUshr, words, left, shift, left_odd);
+        self.append_vector_binary(AirOpcode::VectorUshr, words, right, shift, right_odd);
+        self.append_vector_binary(AirOpcode::VectorMul, words, left_odd, right_odd, result);
+        self.append_vector_binary(AirOpcode::VectorUshl, words, result, shift, result);
+
+        self.append_vector_binary(AirOpcode::VectorOr, words, result, even, result);
+    }
+
+    /// SSE has no multiply of 64-bit lanes, and Air has no `pmuludq` to build one from 32-bit
+    /// halves, so each lane is multiplied in a GPR.
+    fn append_vector_mul_int64(&mut self) {
+        let left = self.tmp(self.child_id(self.value, 0));
+        let right = self.tmp(self.child_id(self.value, 1));
+        let result = self.tmp(self.value);
+
+        let product = self.code.new_tmp(Bank::GP);
+        let factor = self.code.new_tmp(Bank::GP);
+        self.append(AirOpcode::MoveVector, &[Arg::new_tmp(left), Arg::new_tmp(result)]);
+
+        for lane in 0..2 {
+            self.append(
+                AirOpcode::VectorExtractLaneInt64,
+                &[Arg::new_imm(lane), Arg::new_tmp(left), Arg::new_tmp(product)],
+            );
+            self.append(
+                AirOpcode::VectorExtractLaneInt64,
+                &[Arg::new_imm(lane), Arg::new_tmp(right), Arg::new_tmp(factor)],
+            );
+            self.append(AirOpcode::Mul64, &[Arg::new_tmp(factor), Arg::new_tmp(product)]);
+            self.append(
+                AirOpcode::VectorReplaceLaneInt64,
+                &[Arg::new_imm(lane), Arg::new_tmp(product), Arg::new_tmp(result)],
+            );
+        }
+    }
+
+    /// SSE has no min or max of 64-bit lanes. Compare with `pcmpgtq` and blend the operands with
+    /// the mask. Unsigned lanes are compared with their sign bits flipped.
+    fn append_vector_min_max_int64(&mut self) {
+        let op = self.value(self.value).kind.opcode();
+        let info = self.value(self.value).simd_info().unwrap();
+        let left = self.tmp(self.child_id(self.value, 0));
+        let right = self.tmp(self.child_id(self.value, 1));
+        let result = self.tmp(self.value);
+        let signed = simd_info(SIMDLane::I64x2, SIMDSignMode::Signed);
+
+        let (compared_left, compared_right) = if info.sign_mode == SIMDSignMode::Unsigned {
+            let sign_bits = self.vector_splat_bits(SIMDLane::I64x2, i64::MIN);
+            let flipped_left = self.code.new_tmp(Bank::FP);
+            let flipped_right = self.code.new_tmp(Bank::FP);
+            self.append_vector_binary(AirOpcode::VectorXor, info, left, sign_bits, flipped_left);
+            self.append_vector_binary(AirOpcode::VectorXor, info, right, sign_bits, flipped_right);
+            (flipped_left, flipped_right)
+        } else {
+            (left, right)
+        };
+
+        let greater = self.code.new_tmp(Bank::FP);
+        let scratch = self.code.new_tmp(Bank::FP);
+        self.append(
+            AirOpcode::CompareIntegerVector,
+            &[
+                Arg::new_rel_cond(RelationalCondition::GreaterThan),
+                Arg::new_simd_info(signed),
+                Arg::new_tmp(compared_left),
+                Arg::new_tmp(compared_right),
+                Arg::new_tmp(greater),
+                Arg::new_tmp(scratch),
+            ],
+        );
+
+        // Take `selected` where `left > right` and `other` everywhere else.
+        let (selected, other) = match op {
+            Opcode::VectorMin => (right, left),
+            _ => (left, right),
+        };
+
+        let rest = self.code.new_tmp(Bank::FP);
+        self.append_vector_binary(AirOpcode::VectorAnd, info, selected, greater, result);
+        self.append_vector_binary(AirOpcode::VectorAndnot, info, other, greater, rest);
+        self.append_vector_binary(AirOpcode::VectorOr, info, result, rest, result);
+    }
+
+    fn append_vector_compare(&mut self, info: SIMDInfo) {
+        let op = self.value(self.value).kind.opcode();
+        let left = self.tmp(self.child_id(self.value, 0));
+        let right = self.tmp(self.child_id(self.value, 1));
+        let result = self.tmp(self.value);
+
+        if is_floating_point_lane(info.lane) {
+            let cond = match op {
+                Opcode::VectorEqual => DoubleCondition::EqualAndOrdered,
+                Opcode::VectorNotEqual => DoubleCondition::NotEqualOrUnordered,
+                Opcode::VectorLessThan => DoubleCondition::LessThanAndOrdered,
+                Opcode::VectorLessThanOrEqual => DoubleCondition::LessThanOrEqualAndOrdered,
+                Opcode::VectorGreaterThan => DoubleCondition::GreaterThanAndOrdered,
+                Opcode::VectorGreaterThanOrEqual => DoubleCondition::GreaterThanOrEqualAndOrdered,
+                _ => unreachable!("{:?} is not a floating point comparison", op),
+            };
+
+            self.append(
+                AirOpcode::CompareFloatingPointVector,
+                &[
+                    Arg::new_double_cond(cond),
+                    Arg::new_simd_info(info),
+                    Arg::new_tmp(left),
+                    Arg::new_tmp(right),
+                    Arg::new_tmp(result),
+                ],
+            );
+            return;
+        }
+
+        let cond = match op {
+            Opcode::VectorEqual => RelationalCondition::Equal,
+            Opcode::VectorNotEqual => RelationalCondition::NotEqual,
+            Opcode::VectorLessThan => RelationalCondition::LessThan,
+            Opcode::VectorLessThanOrEqual => RelationalCondition::LessThanOrEqual,
+            Opcode::VectorGreaterThan => RelationalCondition::GreaterThan,
+            Opcode::VectorGreaterThanOrEqual => RelationalCondition::GreaterThanOrEqual,
+            Opcode::VectorBelow => RelationalCondition::Below,
+            Opcode::VectorBelowOrEqual => RelationalCondition::BelowOrEqual,
+            Opcode::VectorAbove => RelationalCondition::Above,
+            Opcode::VectorAboveOrEqual => RelationalCondition::AboveOrEqual,
+            _ => unreachable!(),
+        };
+
+        let scratch = self.code.new_tmp(Bank::FP);
+        self.append(
+            AirOpcode::CompareIntegerVector,
+            &[
+                Arg::new_rel_cond(cond),
+                Arg::new_simd_info(info),
+                Arg::new_tmp(left),
+                Arg::new_tmp(right),
+                Arg::new_tmp(result),
+                Arg::new_tmp(scratch),
+            ],
+        );
+    }
+
+    fn append_vector_shift(&mut self, info: SIMDInfo) {
+        let op = self.value(self.value).kind.opcode();
+        let input = self.tmp(self.child_id(self.value, 0));
+        let amount = self.tmp(self.child_id(self.value, 1));
+        let result = self.tmp(self.value);
+
+        // SSE shifts saturate instead of wrapping, so mask the amount to the lane width first. The
+        // count is read from the low 64 bits of an XMM register.
+        let masked = self.code.new_tmp(Bank::GP);
+        let bits = element_width(info.lane).bytes() as i64 * 8;
+        self.append(AirOpcode::Move32, &[Arg::new_tmp(amount), Arg::new_tmp(masked)]);
+        self.append(AirOpcode::And32, &[Arg::new_imm(bits - 1), Arg::new_tmp(masked)]);
+        let shift = self.code.new_tmp(Bank::FP);
+        self.append(AirOpcode::Move64ToDouble, &[Arg::new_tmp(masked), Arg::new_tmp(shift)]);
+
+        let arithmetic = op == Opcode::VectorShr && info.sign_mode == SIMDSignMode::Signed;
+
+        // There are no byte shifts, the macro assembler widens to words and narrows back.
+        if info.lane == SIMDLane::I8x16 {
+            let opcode = match op {
+                Opcode::VectorShl => AirOpcode::VectorUshl8,
+                _ if arithmetic => AirOpcode::VectorSshr8,
+                _ => AirOpcode::VectorUshr8,
+            };
+
+            let scratch1 = self.code.new_tmp(Bank::FP);
+            let scratch2 = self.code.new_tmp(Bank::FP);
+            self.append(
+                opcode,
+                &[
+                    Arg::new_tmp(input),
+                    Arg::new_tmp(shift),
+                    Arg::new_tmp(result),
+                    Arg::new_tmp(scratch1),
+                    Arg::new_tmp(scratch2),
+                ],
+            );
+            return;
+        }
+
+        // Without AVX-512 there is no arithmetic shift of 64-bit lanes. Shift logically and sign
+        // extend from the shifted sign bit: ((x >>> n) ^ m) - m where m = (1 << 63) >>> n.
+        if arithmetic && info.lane == SIMDLane::I64x2 {
+            let mask = self.vector_splat_bits(SIMDLane::I64x2, i64::MIN);
+            self.append_vector_binary(AirOpcode::VectorUshr, info, mask, shift, mask);
+            self.append_vector_binary(AirOpcode::VectorUshr, info, input, shift, result);
+            self.append_vector_binary(AirOpcode::VectorXor, info, result, mask, result);
+            self.append_vector_binary(AirOpcode::VectorSub, info, result, mask, result);
+            return;
+        }
+
+        let opcode = match op {
+            Opcode::VectorShl => AirOpcode::VectorUshl,
+            _ if arithmetic => AirOpcode::VectorSshr,
+            _ => AirOpcode::VectorUshr,
+        };
+
+        self.append_vector_binary(opcode, info, input, shift, result);
+    }
+
+    fn append_vector_unary(&mut self, info: SIMDInfo) {
+        let op = self.value(self.value).kind.opcode();
+        let input = self.tmp(self.child_id(self.value, 0));
+        let result = self.tmp(self.value);
+        let float = is_floating_point_lane(info.lane);
+        let sign_bit = match element_width(info.lane) {
+            Width::W32 => i32::MIN as u32 as i64,
+            _ => i64::MIN,
+        };
+
+        match op {
+            Opcode::VectorNot => {
+                let ones = self.vector_all_ones();
+                self.append_vector_binary(AirOpcode::VectorXor, info, input, ones, result);
+            }
+
+            // Floating point negation and absolute value only touch the sign bit.
+            Opcode::VectorNeg if float => {
+                let mask = self.vector_splat_bits(info.lane, sign_bit);
+                self.append_vector_binary(AirOpcode::VectorXor, info, input, mask, result);
+            }
+
+            Opcode::VectorNeg => {
+                let zero = self.code.new_tmp(Bank::FP);
+                self.append(AirOpcode::MoveZeroToVector, &[Arg::new_tmp(zero)]);
+                self.append_vector_binary(AirOpcode::VectorSub, info, zero, input, result);
+            }
+
+            Opcode::VectorAbs if float => {
+                let mask = self.vector_splat_bits(info.lane, !sign_bit);
+                self.append_vector_binary(AirOpcode::VectorAnd, info, input, mask, result);
+            }
+
+            Opcode::VectorAbs if info.lane == SIMDLane::I64x2 => {
+                let scratch = self.code.new_tmp(Bank::FP);
+                self.append(
+                    AirOpcode::VectorAbsInt64,
+                    &[Arg::new_tmp(input), Arg::new_tmp(result), Arg::new_tmp(scratch)],
+                );
+            }
+
+            Opcode::VectorAbs => self.append(
+                AirOpcode::VectorAbs,
+                &[Arg::new_simd_info(info), Arg::new_tmp(input), Arg::new_tmp(result)],
+            ),
+
+            Opcode::VectorSqrt => {
+                assert!(float, "VectorSqrt needs floating point lanes");
+                self.append(
+                    AirOpcode::VectorSqrt,
+                    &[Arg::new_simd_info(info), Arg::new_tmp(input), Arg::new_tmp(result)],
+                );
+            }
+
+            _ => unreachable!(),
+        }
+    }
+
     fn try_append_bin_op_with_shift(
         &mut self,
         left: ValueId,
@@ -2791,11 +3430,13 @@ impl<'a> LowerToAir<'a> {
     fn lower(&mut self) {
         match self.value(self.value).kind.opcode() {
             Opcode::Nop => (),
-            Opcode::Load => {
-                let memory = self.value(self.value).memory_value().unwrap();
+            Opcode::Load if self.value(self.value).typ().is_aggregate() => {
+                self.lower_aggregate_load();
+            }
 
+            Opcode::Load => {
                 let mut kind: Kind = move_for_type(self.value(self.value).typ()).into();
-                if memory.1.start != 0 {
+                if self.value(self.value).has_fence() {
                     if is_x86() {
                         kind.effects = true;
                     } else {
@@ -2827,9 +3468,7 @@ impl<'a> LowerToAir<'a> {
             Opcode::Load8S => {
                 let mut kind: Kind = AirOpcode::Load8SignedExtendTo32.into();
 
-                let memory = self.value(self.value).memory_value().unwrap();
-
-                if memory.1.start != 0 {
+                if self.value(self.value).has_fence() {
                     if is_x86() {
                         kind.effects = true;
                     } else {
@@ -2851,9 +3490,7 @@ impl<'a> LowerToAir<'a> {
             Opcode::Load8Z => {
                 let mut kind: Kind = AirOpcode::Load8.into();
 
-                let memory = self.value(self.value).memory_value().unwrap();
-
-                if memory.1.start != 0 {
+                if self.value(self.value).has_fence() {
                     if is_x86() {
                         kind.effects = true;
                     } else {
@@ -2875,9 +3512,7 @@ impl<'a> LowerToAir<'a> {
             Opcode::Load16S => {
                 let mut kind: Kind = AirOpcode::Load16SignedExtendTo32.into();
 
-                let memory = self.value(self.value).memory_value().unwrap();
-
-                if memory.1.start != 0 {
+                if self.value(self.value).has_fence() {
                     if is_x86() {
                         kind.effects = true;
                     } else {
@@ -2899,9 +3534,7 @@ impl<'a> LowerToAir<'a> {
             Opcode::Load16Z => {
                 let mut kind: Kind = AirOpcode::Load16.into();
 
-                let memory = self.value(self.value).memory_value().unwrap();
-
-                if memory.1.start != 0 {
+                if self.value(self.value).has_fence() {
                     if is_x86() {
                         kind.effects = true;
                     } else {
@@ -3065,13 +3698,30 @@ impl<'a> LowerToAir<'a> {
                 self.append_bin_op::<{ AirOpcode::Xor32 as i16 }, { AirOpcode::Xor64 as i16 }, { AirOpcode::XorFloat as i16 }, { AirOpcode::XorDouble as i16 }, false>(left, right);
             }
 
-            Opcode::Depend => {
-                /*assert!(is_arm64());
-                let left = self.child_id(self.value, 0);
+            Opcode::Fence => {
+                let (read, write) = self.value(self.value).fence_ranges().unwrap();
+
+                if read.is_empty() && write.is_empty() {
+                    return;
+                }
+
+                // A fence that only reads protects the motion of stores.
+                if write.is_empty() {
+                    self.append(AirOpcode::StoreFence, &[]);
+                    return;
+                }
+
+                // A fence that only writes protects the motion of loads.
+                if read.is_empty() {
+                    self.append(AirOpcode::LoadFence, &[]);
+                    return;
+                }
 
-                self.append_un_op::<{ AirOpcode::Depend32 }, { AirOpcode::Depend64 }, { AirOpcode::Oops }, { AirOpcode::Oops }>(left);*/
+                self.append(AirOpcode::MemoryFence, &[]);
+            }
 
-                todo!()
+            Opcode::Depend => {
+                unreachable!("Depend should have been lowered by lower_macros")
             }
 
             Opcode::Shl => {
@@ -3221,6 +3871,20 @@ impl<'a> LowerToAir<'a> {
                 self.append_store(self.value, &addr.unwrap());
             }
 
+            Opcode::AtomicWeakCAS | Opcode::AtomicStrongCAS => {
+                assert!(is_x86(), "atomics are only supported on x86");
+                self.append_atomic_cas();
+            }
+
+            Opcode::AtomicXchgAdd | Opcode::AtomicXchgSub | Opcode::AtomicXchg => {
+                assert!(is_x86(), "atomics are only supported on x86");
+                self.append_atomic_xchg();
+            }
+
+            Opcode::AtomicXchgAnd | Opcode::AtomicXchgOr | Opcode::AtomicXchgXor => {
+                unreachable!("lower_macros should have replaced these with a CAS loop")
+            }
+
             Opcode::Trunc => (),
             Opcode::SExt8 => {
                 self.append_un_op::<{ AirOpcode::SignExtend8To32 as i16 }, { AirOpcode::Oops as i16 }, { AirOpcode::Oops as i16 }, { AirOpcode::Oops as i16 }>(self.child_id(self.value, 0));
@@ -3289,6 +3953,179 @@ impl<'a> LowerToAir<'a> {
                 unreachable!("move_constants phase should fuse these");
             }
 
+            Opcode::Const128 => {
+                let bits = self.value(self.value).as_int128().unwrap() as u128;
+                let low = bits as u64 as i64;
+                let high = (bits >> 64) as u64 as i64;
+                let result = self.tmp(self.value);
+
+                if low == 0 && high == 0 {
+                    self.append(AirOpcode::MoveZeroToVector, &[Arg::new_tmp(result)]);
+                    return;
+                }
+
+                // Materialize the low half with a GPR to XMM move, which zeroes the high half, and
+                // then insert the high half if it's not zero.
+                let scratch = self.code.new_tmp(Bank::GP);
+                self.append(AirOpcode::Move, &[Arg::new_bigimm(low), Arg::new_tmp(scratch)]);
+                self.append(
+                    AirOpcode::Move64ToDouble,
+                    &[Arg::new_tmp(scratch), Arg::new_tmp(result)],
+                );
+
+                if high != 0 {
+                    self.append(AirOpcode::Move, &[Arg::new_bigimm(high), Arg::new_tmp(scratch)]);
+                    self.append(
+                        AirOpcode::VectorReplaceLaneInt64,
+                        &[Arg::new_imm(1), Arg::new_tmp(scratch), Arg::new_tmp(result)],
+                    );
+                }
+            }
+
+            Opcode::VectorMin | Opcode::VectorMax
+                if is_x86()
+                    && is_floating_point_lane(self.value(self.value).simd_info().unwrap().lane) =>
+            {
+                self.append_vector_float_min_max();
+            }
+
+            Opcode::VectorMul
+                if is_x86()
+                    && self.value(self.value).simd_info().unwrap().lane == SIMDLane::I8x16 =>
+            {
+                self.append_vector_mul_int8();
+            }
+
+            Opcode::VectorMul
+                if is_x86()
+                    && self.value(self.value).simd_info().unwrap().lane == SIMDLane::I64x2 =>
+            {
+                self.append_vector_mul_int64();
+            }
+
+            Opcode::VectorMin | Opcode::VectorMax
+                if is_x86()
+                    && self.value(self.value).simd_info().unwrap().lane == SIMDLane::I64x2 =>
+            {
+                self.append_vector_min_max_int64();
+            }
+
+            Opcode::VectorAdd
+            | Opcode::VectorSub
+            | Opcode::VectorMul
+            | Opcode::VectorMin
+            | Opcode::VectorMax
+            | Opcode::VectorAnd
+            | Opcode::VectorAndnot
+            | Opcode::VectorOr
+            | Opcode::VectorXor => {
+                let info = self.value(self.value).simd_info().unwrap();
+                let opcode = match self.value(self.value).kind.opcode() {
+                    Opcode::VectorAdd => AirOpcode::VectorAdd,
+                    Opcode::VectorSub => AirOpcode::VectorSub,
+                    Opcode::VectorMul => AirOpcode::VectorMul,
+                    Opcode::VectorMin => AirOpcode::VectorMin,
+                    Opcode::VectorMax => AirOpcode::VectorMax,
+                    Opcode::VectorAnd => AirOpcode::VectorAnd,
+                    Opcode::VectorAndnot => AirOpcode::VectorAndnot,
+                    Opcode::VectorOr => AirOpcode::VectorOr,
+                    _ => AirOpcode::VectorXor,
+                };
+
+                let left = self.tmp(self.child_id(self.value, 0));
+                let right = self.tmp(self.child_id(self.value, 1));
+                let result = self.tmp(self.value);
+                self.append_vector_binary(opcode, info, left, right, result);
+            }
+
+            Opcode::VectorEqual
+            | Opcode::VectorNotEqual
+            | Opcode::VectorLessThan
+            | Opcode::VectorLessThanOrEqual
+            | Opcode::VectorGreaterThan
+            | Opcode::VectorGreaterThanOrEqual
+            | Opcode::VectorBelow
+            | Opcode::VectorBelowOrEqual
+            | Opcode::VectorAbove
+            | Opcode::VectorAboveOrEqual => {
+                let info = self.value(self.value).simd_info().unwrap();
+                self.append_vector_compare(info);
+            }
+
+            Opcode::VectorShl | Opcode::VectorShr => {
+                let info = self.value(self.value).simd_info().unwrap();
+                self.append_vector_shift(info);
+            }
+
+            Opcode::VectorNot | Opcode::VectorNeg | Opcode::VectorAbs | Opcode::VectorSqrt => {
+                let info = self.value(self.value).simd_info().unwrap();
+                self.append_vector_unary(info);
+            }
+
+            Opcode::VectorSplat => {
+                let info = self.value(self.value).simd_info().unwrap();
+                let opcode = match info.lane {
+                    SIMDLane::I8x16 => AirOpcode::VectorSplatInt8,
+                    SIMDLane::I16x8 => AirOpcode::VectorSplatInt16,
+                    SIMDLane::I32x4 => AirOpcode::VectorSplatInt32,
+                    SIMDLane::I64x2 => AirOpcode::VectorSplatInt64,
+                    SIMDLane::F32x4 => AirOpcode::VectorSplatFloat32,
+                    SIMDLane::F64x2 => AirOpcode::VectorSplatFloat64,
+                    SIMDLane::V128 => unreachable!(),
+                };
+
+                let scalar = self.tmp(self.child_id(self.value, 0));
+                let result = self.tmp(self.value);
+                self.append(opcode, &[Arg::new_tmp(scalar), Arg::new_tmp(result)]);
+            }
+
+            Opcode::VectorExtractLane => {
+                let info = self.value(self.value).simd_info().unwrap();
+                let lane = self.value(self.value).simd_lane().unwrap();
+                let signed = info.sign_mode == SIMDSignMode::Signed;
+                let opcode = match info.lane {
+                    SIMDLane::I8x16 if signed => AirOpcode::VectorExtractLaneSignedInt8,
+                    SIMDLane::I8x16 => AirOpcode::VectorExtractLaneUnsignedInt8,
+                    SIMDLane::I16x8 if signed => AirOpcode::VectorExtractLaneSignedInt16,
+                    SIMDLane::I16x8 => AirOpcode::VectorExtractLaneUnsignedInt16,
+                    SIMDLane::I32x4 => AirOpcode::VectorExtractLaneInt32,
+                    SIMDLane::I64x2 => AirOpcode::VectorExtractLaneInt64,
+                    SIMDLane::F32x4 => AirOpcode::VectorExtractLaneFloat32,
+                    SIMDLane::F64x2 => AirOpcode::VectorExtractLaneFloat64,
+                    SIMDLane::V128 => unreachable!(),
+                };
+
+                let vector = self.tmp(self.child_id(self.value, 0));
+                let result = self.tmp(self.value);
+                self.append(
+                    opcode,
+                    &[Arg::new_imm(lane as i64), Arg::new_tmp(vector), Arg::new_tmp(result)],
+                );
+            }
+
+            Opcode::VectorReplaceLane => {
+                let info = self.value(self.value).simd_info().unwrap();
+                let lane = self.value(self.value).simd_lane().unwrap();
+                let opcode = match info.lane {
+                    SIMDLane::I8x16 => AirOpcode::VectorReplaceLaneInt8,
+                    SIMDLane::I16x8 => AirOpcode::VectorReplaceLaneInt16,
+                    SIMDLane::I32x4 => AirOpcode::VectorReplaceLaneInt32,
+                    SIMDLane::I64x2 => AirOpcode::VectorReplaceLaneInt64,
+                    SIMDLane::F32x4 => AirOpcode::VectorReplaceLaneFloat32,
+                    SIMDLane::F64x2 => AirOpcode::VectorReplaceLaneFloat64,
+                    SIMDLane::V128 => unreachable!(),
+                };
+
+                let vector = self.tmp(self.child_id(self.value, 0));
+                let scalar = self.tmp(self.child_id(self.value, 1));
+                let result = self.tmp(self.value);
+                self.append(AirOpcode::MoveVector, &[Arg::new_tmp(vector), Arg::new_tmp(result)]);
+                self.append(
+                    opcode,
+                    &[Arg::new_imm(lane as i64), Arg::new_tmp(scalar), Arg::new_tmp(result)],
+                );
+            }
+
             Opcode::FramePointer => (),
             Opcode::SlotBase => {
                 let slot = self.value(self.value).slot_base_value().unwrap();
@@ -3349,6 +4186,12 @@ impl<'a> LowerToAir<'a> {
             }
 
             Opcode::Upsilon => {
+                let upsilons = self.upsilons_before_terminal();
+                if upsilons.len() > 1 {
+                    self.lower_upsilons_to_shuffle(&upsilons);
+                    return;
+                }
+
                 let value = self.child_id(self.value, 0);
                 let phi = self.value(self.value).phi().unwrap();
 
@@ -3412,6 +4255,10 @@ impl<'a> LowerToAir<'a> {
                 self.append(AirOpcode::Jump, &[]);
             }
 
+            Opcode::Return if self.returns_tuple_in_registers() => {
+                self.lower_tuple_return();
+            }
+
             Opcode::Return => {
                 if self.value(self.value).children.is_empty() {
                     self.append(AirOpcode::RetVoid, &[]);
@@ -3610,15 +4457,63 @@ impl<'a> LowerToAir<'a> {
                 let callee = self.tmp(self.child_id(self.value, 0));
                 inst.args.push(Arg::new_tmp(callee));
 
-                if self.value(self.value).typ.kind() != TypeKind::Void {
+                let typ = self.value(self.value).typ;
+                let mut tuple_results = vec![];
+
+                if typ.is_aggregate() {
+                    // Tuples are returned as eightbytes, one result for each.
+                    for reg in aggregate_return_registers(self.code.proc, typ) {
+                        let tmp = self.code.new_tmp(if reg.is_gpr() { Bank::GP } else { Bank::FP });
+                        inst.args.push(Arg::new_tmp(tmp));
+                        tuple_results.push(tmp);
+                    }
+                } else if typ.kind() != TypeKind::Void {
                     let arg = self.imm_or_tmp(self.value);
                     inst.args.push(arg);
                 }
 
-                for i in 1..self.value(self.value).children.len() {
-                    let arg = self.imm_or_tmp(self.child_id(self.value, i));
-                    inst.args.push(arg);
+                self.append_ccall_arguments(&mut inst);
+
+                self.insts.last_mut().unwrap().push(inst);
+
+                if !tuple_results.is_empty() && self.use_counts.num_uses(self.value) != 0 {
+                    let slot = self.tuple_slot(self.value);
+
+                    for (index, &tmp) in tuple_results.iter().enumerate() {
+                        let opcode = match tmp.bank() {
+                            Bank::GP => AirOpcode::Move,
+                            Bank::FP => AirOpcode::MoveDouble,
+                        };
+                        let eightbyte = Arg::new_stack(slot, 8 * index as i32);
+                        self.append(opcode, &[Arg::new_tmp(tmp), eightbyte]);
+                    }
                 }
+            }
+
+            Opcode::Extract => {
+                let tuple = self.child_id(self.value, 0);
+                let slot = self.tuple_slot(tuple);
+                let index = self.value(self.value).extract_index().unwrap();
+                let offset = self
+                    .code
+                    .proc
+                    .aggregate(self.value(tuple).typ())
+                    .offset_of(index);
+                let typ = self.value(self.value).typ();
+                let result = self.tmp(self.value);
+
+                self.append(
+                    move_for_type(typ),
+                    &[Arg::new_stack(slot, offset as i32), Arg::new_tmp(result)],
+                );
+            }
+
+            Opcode::TailCCall => {
+                let callee = self.tmp(self.child_id(self.value, 0));
+                let mut inst =
+                    Inst::new(AirOpcode::TailCall.into(), self.value, &[Arg::new_tmp(callee)]);
+
+                self.append_ccall_arguments(&mut inst);
 
                 self.insts.last_mut().unwrap().push(inst);
             }
@@ -1,7 +1,464 @@
 //! B3 Module implementation.
 //!
 //! Used to connect multiple procedures together.
+//!
+//! A [`Module`] owns a set of procedures, each with a symbol name and a [`Linkage`]. All procedures
+//! of a module are emitted into a single executable region and calls between them are resolved
+//! by the module linker, so procedures are free to call each other (including mutual recursion)
+//! regardless of the order they were defined in.
+//!
+//! ```mustfail
+//! let mut module = Module::new();
+//! let is_even = module.declare("is_even", Linkage::Export);
+//! let is_odd = module.declare("is_odd", Linkage::Internal);
+//! // ... build `is_even` and `is_odd` using `builder.procedure_addr` to call each other ...
+//! module.define(is_even, even_proc);
+//! module.define(is_odd, odd_proc);
+//! let compilation = module.compile().unwrap();
+//! let is_even = compilation.lookup("is_even").unwrap();
+//! ```
+//!
+//! Modules also own globals declared with [`Module::add_global`]. Each global lives in its own
+//! [`DataSection`] that is kept alive by the [`ModuleCompilation`], procedures get address of a
+//! global with `GlobalAddr`.
+
+use std::{cell::RefCell, rc::Rc, sync::Arc};
+
+use indexmap::IndexMap;
+use macroassembler::assembler::{
+    abstract_macro_assembler::Label, link_buffer::LinkBuffer, TargetMacroAssembler,
+};
+
+use crate::{
+    data_section::DataSection,
+    generate::{generate, prepare_for_generation},
+    jit::compilation::Compilation,
+    procedure::{GlobalId, Procedure, ProcedureId},
+};
+
+/// Describes how a procedure of a module is visible to the outside world.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Linkage {
+    /// Procedure can be looked up by its name in [`ModuleCompilation`].
+    Export,
+    /// Procedure is only callable from other procedures of the same module.
+    Internal,
+    /// Procedure is defined outside of the module and its address is already known.
+    ImportStatic(*const u8),
+}
+
+/// Tells how `ProcedureAddr` is lowered.
+#[derive(Debug, Clone)]
+pub enum ProcedureAddress {
+    /// Address of the procedure is known, `ProcedureAddr` is lowered to `Const64`.
+    Static(*const u8),
+    /// Procedure is emitted into the same code region but not yet finalized. `ProcedureAddr` is
+    /// lowered to a patchable move of the address that is fixed up once the entrypoints of the
+    /// procedure are linked.
+    ///
+    /// Labels are filled in when the procedure is generated.
+    Pending(Rc<RefCell<Vec<Label>>>),
+}
+
+/// Error returned by [`Module::compile`] when a procedure refers to a procedure that the module
+/// can't resolve.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkError {
+    /// Procedure that could not be resolved.
+    pub procedure: ProcedureId,
+    pub message: String,
+}
+
+impl std::fmt::Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+/// State of a procedure inside of a module.
+pub enum ProcedureState {
+    /// Procedure was declared but its body is not yet defined.
+    Declared,
+    /// Procedure is defined and will be compiled by [`Module::compile`].
+    Defined(Procedure),
+    /// Procedure is imported from outside of the module.
+    Imported(*const u8),
+}
+
+struct ModuleProcedure {
+    name: String,
+    linkage: Linkage,
+    state: ProcedureState,
+}
+
+struct ModuleGlobal {
+    name: String,
+    section: DataSection,
+    /// Start of the global, taken from a mutable borrow of `section` when the global is added.
+    /// The section is never resized, so the address stays valid for as long as the section is
+    /// alive.
+    address: *mut u8,
+}
+
+/// A set of procedures and globals that are compiled and linked together.
+pub struct Module {
+    procedures: Vec<ModuleProcedure>,
+    symbols: IndexMap<String, ProcedureId>,
+    globals: Vec<ModuleGlobal>,
+    global_symbols: IndexMap<String, GlobalId>,
+}
+
+impl Default for Module {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Module {
+    pub fn new() -> Self {
+        Self {
+            procedures: vec![],
+            symbols: IndexMap::new(),
+            globals: vec![],
+            global_symbols: IndexMap::new(),
+        }
+    }
+
+    /// Declare a new procedure. Its body should be provided later with [`Module::define`].
+    ///
+    /// Panics if a procedure with the same name already exists.
+    pub fn declare(&mut self, name: impl Into<String>, linkage: Linkage) -> ProcedureId {
+        let name = name.into();
+        assert!(
+            !self.symbols.contains_key(&name),
+            "procedure '{}' is already declared",
+            name
+        );
+
+        let id = ProcedureId(self.procedures.len());
+
+        let state = match linkage {
+            Linkage::ImportStatic(address) => ProcedureState::Imported(address),
+            _ => ProcedureState::Declared,
+        };
+
+        self.procedures.push(ModuleProcedure {
+            name: name.clone(),
+            linkage,
+            state,
+        });
+        self.symbols.insert(name, id);
+        id
+    }
+
+    /// Declare a procedure that lives outside of the module at `address`.
+    pub fn import(&mut self, name: impl Into<String>, address: *const u8) -> ProcedureId {
+        self.declare(name, Linkage::ImportStatic(address))
+    }
+
+    /// Provide a body for the declared procedure.
+    pub fn define(&mut self, id: ProcedureId, proc: Procedure) {
+        let procedure = &mut self.procedures[id.0];
+        match procedure.state {
+            ProcedureState::Declared => procedure.state = ProcedureState::Defined(proc),
+            ProcedureState::Defined(_) => {
+                panic!("procedure '{}' is already defined", procedure.name)
+            }
+            ProcedureState::Imported(_) => {
+                panic!("procedure '{}' is imported", procedure.name)
+            }
+        }
+    }
+
+    /// Declare and define a procedure at once.
+    pub fn add_procedure(
+        &mut self,
+        name: impl Into<String>,
+        linkage: Linkage,
+        proc: Procedure,
+    ) -> ProcedureId {
+        let id = self.declare(name, linkage);
+        self.define(id, proc);
+        id
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<ProcedureId> {
+        self.symbols.get(name).copied()
+    }
+
+    pub fn name(&self, id: ProcedureId) -> &str {
+        &self.procedures[id.0].name
+    }
+
+    pub fn linkage(&self, id: ProcedureId) -> Linkage {
+        self.procedures[id.0].linkage
+    }
+
+    pub fn state(&self, id: ProcedureId) -> &ProcedureState {
+        &self.procedures[id.0].state
+    }
+
+    pub fn procedure(&self, id: ProcedureId) -> Option<&Procedure> {
+        match self.procedures[id.0].state {
+            ProcedureState::Defined(ref proc) => Some(proc),
+            _ => None,
+        }
+    }
+
+    pub fn procedure_mut(&mut self, id: ProcedureId) -> Option<&mut Procedure> {
+        match self.procedures[id.0].state {
+            ProcedureState::Defined(ref mut proc) => Some(proc),
+            _ => None,
+        }
+    }
+
+    pub fn num_procedures(&self) -> usize {
+        self.procedures.len()
+    }
+
+    /// Declare a new global of `size` bytes aligned to `alignment` bytes. The global is
+    /// zero-initialized unless `initializer` is provided, in which case its contents are copied to
+    /// the start of the global.
+    ///
+    /// Panics if a global with the same name already exists or the initializer does not fit.
+    pub fn add_global(
+        &mut self,
+        name: impl Into<String>,
+        size: usize,
+        alignment: usize,
+        initializer: Option<&[u8]>,
+    ) -> GlobalId {
+        let name = name.into();
+        assert!(
+            !self.global_symbols.contains_key(&name),
+            "global '{}' is already declared",
+            name
+        );
+
+        let mut section = DataSection::new_aligned(size, alignment);
+
+        if let Some(initializer) = initializer {
+            assert!(
+                initializer.len() <= size,
+                "initializer of global '{}' is larger than the global",
+                name
+            );
+            section.data_mut()[..initializer.len()].copy_from_slice(initializer);
+        }
+
+        let address = section.as_mut_ptr();
+        let id = GlobalId(self.globals.len());
+        self.globals.push(ModuleGlobal {
+            name: name.clone(),
+            section,
+            address,
+        });
+        self.global_symbols.insert(name, id);
+        id
+    }
+
+    pub fn lookup_global(&self, name: &str) -> Option<GlobalId> {
+        self.global_symbols.get(name).copied()
+    }
+
+    pub fn global_name(&self, id: GlobalId) -> &str {
+        &self.globals[id.0].name
+    }
+
+    /// Address of global `id`. It does not change after the module is compiled.
+    pub fn global_address(&self, id: GlobalId) -> *mut u8 {
+        self.globals[id.0].address
+    }
+
+    /// Compile all procedures of the module into a single executable region and link them together.
+    ///
+    /// Returns an error if some procedure was declared but never defined, or if `ProcedureAddr`
+    /// refers to a procedure that is not part of the module.
+    pub fn compile(mut self) -> Result<ModuleCompilation, LinkError> {
+        self.check_procedures_are_resolved()?;
+
+        let labels = (0..self.procedures.len())
+            .map(|_| Rc::new(RefCell::new(Vec::new())))
+            .collect::<Vec<_>>();
+
+        let addresses = self
+            .procedures
+            .iter()
+            .zip(labels.iter())
+            .map(|(procedure, labels)| match procedure.state {
+                ProcedureState::Imported(address) => ProcedureAddress::Static(address),
+                _ => ProcedureAddress::Pending(labels.clone()),
+            })
+            .collect::<Vec<_>>();
+
+        let global_addresses = self
+            .globals
+            .iter()
+            .map(|global| global.address)
+            .collect::<Vec<_>>();
+
+        let mut jit = TargetMacroAssembler::new();
+        let mut byproducts = vec![];
+
+        for (index, procedure) in self.procedures.iter_mut().enumerate() {
+            let proc = match procedure.state {
+                ProcedureState::Defined(ref mut proc) => proc,
+                ProcedureState::Imported(_) => continue,
+                ProcedureState::Declared => unreachable!(),
+            };
+
+            proc.set_procedure_id(ProcedureId(index));
+
+            for (id, address) in addresses.iter().enumerate() {
+                proc.set_procedure_address(ProcedureId(id), address.clone());
+            }
+
+            for (id, address) in global_addresses.iter().enumerate() {
+                proc.set_global_address(GlobalId(id), *address);
+            }
+
+            let entrypoint_labels = {
+                let mut air = prepare_for_generation(proc);
+                jit.comment(format!("procedure {}:", procedure.name));
+                generate(&mut air, &mut jit);
+                std::mem::take(&mut air.entrypoint_labels)
+            };
+
+            *labels[index].borrow_mut() = entrypoint_labels;
+            byproducts.append(&mut proc.data_sections);
+        }
+
+        let mut link_buffer =
+            LinkBuffer::from_macro_assembler(&mut jit).expect("failed to create link buffer");
+
+        let mut entrypoints = vec![];
+        let mut entrypoint_offsets = Vec::with_capacity(self.procedures.len());
+
+        for (procedure, labels) in self.procedures.iter().zip(labels.iter()) {
+            entrypoint_offsets.push(entrypoints.len());
+
+            match procedure.state {
+                ProcedureState::Imported(address) => {
+                    entrypoints.push(address);
+                }
+                _ => {
+                    for label in labels.borrow().iter() {
+                        entrypoints.push(link_buffer.rx_location_of(*label));
+                    }
+                }
+            }
+        }
+
+        let code = link_buffer.finalize_without_disassembly();
+
+        byproducts.extend(self.globals.into_iter().map(|global| global.section));
+
+        Ok(ModuleCompilation {
+            compilation: Compilation::new(code, byproducts, entrypoints),
+            entrypoint_offsets,
+            linkages: self.procedures.iter().map(|proc| proc.linkage).collect(),
+            symbols: self.symbols,
+            global_addresses,
+            global_symbols: self.global_symbols,
+        })
+    }
+
+    /// Checks that every procedure has a body or an address, and that every `ProcedureAddr` refers
+    /// to a procedure of this module. Otherwise linking would have nothing to patch the address
+    /// with.
+    fn check_procedures_are_resolved(&self) -> Result<(), LinkError> {
+        for (index, procedure) in self.procedures.iter().enumerate() {
+            let proc = match procedure.state {
+                ProcedureState::Defined(ref proc) => proc,
+                ProcedureState::Imported(_) => continue,
+                ProcedureState::Declared => {
+                    return Err(LinkError {
+                        procedure: ProcedureId(index),
+                        message: format!(
+                            "procedure '{}' is declared but not defined",
+                            procedure.name
+                        ),
+                    })
+                }
+            };
+
+            for value in proc.values.iter() {
+                match value.procedure_addr() {
+                    Some(id) if id.0 >= self.procedures.len() => {
+                        return Err(LinkError {
+                            procedure: id,
+                            message: format!(
+                                "procedure '{}' refers to procedure {} which is not part of the \
+                                 module",
+                                procedure.name, id.0
+                            ),
+                        })
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Result of [`Module::compile`]. Keep it alive for as long as you want to run the code of any
+/// procedure from the module.
+pub struct ModuleCompilation {
+    compilation: Compilation,
+    entrypoint_offsets: Vec<usize>,
+    linkages: Vec<Linkage>,
+    symbols: IndexMap<String, ProcedureId>,
+    global_addresses: Vec<*mut u8>,
+    global_symbols: IndexMap<String, GlobalId>,
+}
+
+impl ModuleCompilation {
+    /// Entrypoint of procedure `id`. For procedures with multiple entrypoints this returns the
+    /// first one, use [`ModuleCompilation::entrypoint_at`] to get the others.
+    pub fn entrypoint(&self, id: ProcedureId) -> *const u8 {
+        self.entrypoint_at(id, 0)
+    }
+
+    pub fn entrypoint_at(&self, id: ProcedureId, at: usize) -> *const u8 {
+        self.compilation
+            .entrypoint(self.entrypoint_offsets[id.0] + at)
+    }
+
+    /// Find entrypoint of an exported procedure by its name.
+    pub fn lookup(&self, name: &str) -> Option<*const u8> {
+        let id = *self.symbols.get(name)?;
+
+        match self.linkages[id.0] {
+            Linkage::Export => Some(self.entrypoint(id)),
+            _ => None,
+        }
+    }
+
+    /// Address of global `id`.
+    pub fn global(&self, id: GlobalId) -> *mut u8 {
+        self.global_addresses[id.0]
+    }
+
+    /// Find address of a global by its name.
+    pub fn lookup_global(&self, name: &str) -> Option<*mut u8> {
+        self.global_symbols
+            .get(name)
+            .map(|id| self.global_addresses[id.0])
+    }
+
+    pub fn compilation(&self) -> &Compilation {
+        &self.compilation
+    }
 
-pub struct Module {}
+    pub fn byproducts(&self) -> &[Arc<DataSection>] {
+        self.compilation.byproducts()
+    }
 
-pub enum ProcedureState {}
+    pub fn disassembly(&self) -> String {
+        self.compilation.disassembly()
+    }
+}
@@ -273,8 +273,9 @@ pub enum Opcode {
     /// stack.
     Patchpoint,
 
-    /// This is a projection out of a tuple. Currently only Patchpoints, Get, Phi, and BottomTuple can produce tuples.
-    /// It's assumumed that each entry in a tuple has a fixed Numeric Type (i.e. not Void or Tuple).
+    /// This is a projection out of a tuple. Currently only CCalls returning an aggregate type can
+    /// produce tuples. The index of the element is stored in the value. It's assumed that each
+    /// entry in a tuple has a fixed Numeric Type (i.e. not Void or Tuple).
     Extract,
 
     /// Checked math. Use the CheckValue class. Like a Patchpoint, this takes a code generation
@@ -310,7 +311,7 @@ pub enum Opcode {
     /// WarmAny. It will not have an output constraint.
     Check,
 
-    /// SIMD instructions
+    /// SIMD instructions. These carry the lane type they operate on, see `ValueData::SIMD`.
     VectorExtractLane,
     VectorReplaceLane,
 
@@ -347,6 +348,7 @@ pub enum Opcode {
 
     VectorNot,
     VectorAnd,
+    /// `a & !b`.
     VectorAndnot,
     VectorOr,
     VectorXor,
@@ -419,9 +421,10 @@ pub enum Opcode {
     /// Might be lowered to `Const64` if the procedure address is known already and linkage is `ImportStatic`.
     ProcedureAddr,
 
-    /// Get global address. This is lowered to `Patchpoint`.
+    /// Get global address. This is lowered to `Const64`.
     ///
-    /// Globals are stored in data-section.
+    /// Globals are stored in data-section that is allocated when the global is declared and
+    /// is kept alive by the compilation.
     GlobalAddr,
 
     /// Allocates `N` bytes on the stack. This is eliminated when lowering, instead stack slots are allocated.
@@ -434,10 +437,15 @@ pub enum Opcode {
     /// It performs address calculation only and does not access memory.
     GetElementPtr,
 
-    /// This is a regular tail C-call. It is lowered to patchpoint. Can be used to optimize recursive calls into loops.
+    /// A C-call in tail position. This is a terminal: the frame is torn down and control is
+    /// transferred to the callee, which returns directly to our caller.
+    ///
+    /// Tail calls of the procedure to itself are turned into loops.
     TailCCall,
 
-    /// Get parameter value. This is lowered to `Argument` or to `FramePointer` with `Load` if the parameter is passed on stack.
+    /// Get parameter value. This is lowered to `ArgumentReg` or to `FramePointer` with `Load` if
+    /// the parameter is passed on stack. Requires the procedure to have a signature, see
+    /// [`Procedure::set_signature`](crate::Procedure::set_signature).
     ///
     /// Exist to simplify emitting high-level code and to simplify inlining.
     Parameter,
@@ -477,6 +485,39 @@ impl Opcode {
         )
     }
 
+    /// Lane-wise vector operations that take two `V128` operands.
+    pub const fn is_vector_binary(self) -> bool {
+        use Opcode::*;
+        matches!(
+            self,
+            VectorAdd
+                | VectorSub
+                | VectorMul
+                | VectorMin
+                | VectorMax
+                | VectorAnd
+                | VectorAndnot
+                | VectorOr
+                | VectorXor
+                | VectorEqual
+                | VectorNotEqual
+                | VectorLessThan
+                | VectorLessThanOrEqual
+                | VectorBelow
+                | VectorBelowOrEqual
+                | VectorGreaterThan
+                | VectorGreaterThanOrEqual
+                | VectorAbove
+                | VectorAboveOrEqual
+        )
+    }
+
+    /// Lane-wise vector operations that take a single `V128` operand.
+    pub const fn is_vector_unary(self) -> bool {
+        use Opcode::*;
+        matches!(self, VectorNot | VectorAbs | VectorNeg | VectorSqrt)
+    }
+
     pub const fn is_check_math(self) -> bool {
         matches!(self, Self::CheckAdd | Self::CheckSub | Self::CheckMul)
     }
@@ -650,6 +691,177 @@ impl std::fmt::Display for Opcode {
     }
 }
 
+impl std::str::FromStr for Opcode {
+    type Err = ();
+
+    /// Parse the name printed by `Display`.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Ok(match name {
+            "Nop" => Opcode::Nop,
+            "Identity" => Opcode::Identity,
+            "Opaque" => Opcode::Opaque,
+            "Const32" => Opcode::Const32,
+            "Const64" => Opcode::Const64,
+            "ConstDouble" => Opcode::ConstDouble,
+            "ConstFloat" => Opcode::ConstFloat,
+            "Const128" => Opcode::Const128,
+            "BottomTuple" => Opcode::BottomTuple,
+            "Set" => Opcode::Set,
+            "Get" => Opcode::Get,
+            "SlotBase" => Opcode::SlotBase,
+            "ArgumentReg" => Opcode::ArgumentReg,
+            "FramePointer" => Opcode::FramePointer,
+            "Add" => Opcode::Add,
+            "Sub" => Opcode::Sub,
+            "Mul" => Opcode::Mul,
+            "Div" => Opcode::Div,
+            "UDiv" => Opcode::UDiv,
+            "Mod" => Opcode::Mod,
+            "UMod" => Opcode::UMod,
+            "Neg" => Opcode::Neg,
+            "BitAnd" => Opcode::BitAnd,
+            "BitOr" => Opcode::BitOr,
+            "BitXor" => Opcode::BitXor,
+            "Shl" => Opcode::Shl,
+            "SShr" => Opcode::SShr,
+            "ZShr" => Opcode::ZShr,
+            "RotR" => Opcode::RotR,
+            "RotL" => Opcode::RotL,
+            "Clz" => Opcode::Clz,
+            "Abs" => Opcode::Abs,
+            "Ceil" => Opcode::Ceil,
+            "Floor" => Opcode::Floor,
+            "Sqrt" => Opcode::Sqrt,
+            "FMax" => Opcode::FMax,
+            "FMin" => Opcode::FMin,
+            "BitwiseCast" => Opcode::BitwiseCast,
+            "SExt8" => Opcode::SExt8,
+            "SExt16" => Opcode::SExt16,
+            "SExt8To64" => Opcode::SExt8To64,
+            "SExt16To64" => Opcode::SExt16To64,
+            "SExt32" => Opcode::SExt32,
+            "ZExt32" => Opcode::ZExt32,
+            "Trunc" => Opcode::Trunc,
+            "IToD" => Opcode::IToD,
+            "IToF" => Opcode::IToF,
+            "FToI" => Opcode::FToI,
+            "DToI" => Opcode::DToI,
+            "FloatToDouble" => Opcode::FloatToDouble,
+            "DoubleToFloat" => Opcode::DoubleToFloat,
+            "Equal" => Opcode::Equal,
+            "NotEqual" => Opcode::NotEqual,
+            "LessThan" => Opcode::LessThan,
+            "GreaterThan" => Opcode::GreaterThan,
+            "LessEqual" => Opcode::LessEqual,
+            "GreaterEqual" => Opcode::GreaterEqual,
+            "Above" => Opcode::Above,
+            "Below" => Opcode::Below,
+            "AboveEqual" => Opcode::AboveEqual,
+            "BelowEqual" => Opcode::BelowEqual,
+            "EqualOrUnordered" => Opcode::EqualOrUnordered,
+            "Select" => Opcode::Select,
+            "Load8Z" => Opcode::Load8Z,
+            "Load8S" => Opcode::Load8S,
+            "Load16Z" => Opcode::Load16Z,
+            "Load16S" => Opcode::Load16S,
+            "Load" => Opcode::Load,
+            "Store8" => Opcode::Store8,
+            "Store16" => Opcode::Store16,
+            "Store" => Opcode::Store,
+            "AtomicWeakCAS" => Opcode::AtomicWeakCAS,
+            "AtomicStrongCAS" => Opcode::AtomicStrongCAS,
+            "AtomicXchgAdd" => Opcode::AtomicXchgAdd,
+            "AtomicXchgAnd" => Opcode::AtomicXchgAnd,
+            "AtomicXchgOr" => Opcode::AtomicXchgOr,
+            "AtomicXchgSub" => Opcode::AtomicXchgSub,
+            "AtomicXchgXor" => Opcode::AtomicXchgXor,
+            "AtomicXchg" => Opcode::AtomicXchg,
+            "Depend" => Opcode::Depend,
+            "WasmAddress" => Opcode::WasmAddress,
+            "Fence" => Opcode::Fence,
+            "CCall" => Opcode::CCall,
+            "Patchpoint" => Opcode::Patchpoint,
+            "Extract" => Opcode::Extract,
+            "CheckAdd" => Opcode::CheckAdd,
+            "CheckSub" => Opcode::CheckSub,
+            "CheckMul" => Opcode::CheckMul,
+            "Check" => Opcode::Check,
+            "VectorExtractLane" => Opcode::VectorExtractLane,
+            "VectorReplaceLane" => Opcode::VectorReplaceLane,
+            "VectorDupElement" => Opcode::VectorDupElement,
+            "VectorSplat" => Opcode::VectorSplat,
+            "VectorEqual" => Opcode::VectorEqual,
+            "VectorNotEqual" => Opcode::VectorNotEqual,
+            "VectorLessThan" => Opcode::VectorLessThan,
+            "VectorLessThanOrEqual" => Opcode::VectorLessThanOrEqual,
+            "VectorBelow" => Opcode::VectorBelow,
+            "VectorBelowOrEqual" => Opcode::VectorBelowOrEqual,
+            "VectorGreaterThan" => Opcode::VectorGreaterThan,
+            "VectorGreaterThanOrEqual" => Opcode::VectorGreaterThanOrEqual,
+            "VectorAbove" => Opcode::VectorAbove,
+            "VectorAboveOrEqual" => Opcode::VectorAboveOrEqual,
+            "VectorAdd" => Opcode::VectorAdd,
+            "VectorSub" => Opcode::VectorSub,
+            "VectorAddSat" => Opcode::VectorAddSat,
+            "VectorSubSat" => Opcode::VectorSubSat,
+            "VectorMul" => Opcode::VectorMul,
+            "VectorDotProduct" => Opcode::VectorDotProduct,
+            "VectorDiv" => Opcode::VectorDiv,
+            "VectorMin" => Opcode::VectorMin,
+            "VectorMax" => Opcode::VectorMax,
+            "VectorPmin" => Opcode::VectorPmin,
+            "VectorPmax" => Opcode::VectorPmax,
+            "VectorNarrow" => Opcode::VectorNarrow,
+            "VectorNot" => Opcode::VectorNot,
+            "VectorAnd" => Opcode::VectorAnd,
+            "VectorAndnot" => Opcode::VectorAndnot,
+            "VectorOr" => Opcode::VectorOr,
+            "VectorXor" => Opcode::VectorXor,
+            "VectorShl" => Opcode::VectorShl,
+            "VectorShr" => Opcode::VectorShr,
+            "VectorAbs" => Opcode::VectorAbs,
+            "VectorNeg" => Opcode::VectorNeg,
+            "VectorPopcnt" => Opcode::VectorPopcnt,
+            "VectorCeil" => Opcode::VectorCeil,
+            "VectorFloor" => Opcode::VectorFloor,
+            "VectorTrunc" => Opcode::VectorTrunc,
+            "VectorTruncSat" => Opcode::VectorTruncSat,
+            "VectorConvert" => Opcode::VectorConvert,
+            "VectorConvertLow" => Opcode::VectorConvertLow,
+            "VectorNearest" => Opcode::VectorNearest,
+            "VectorSqrt" => Opcode::VectorSqrt,
+            "VectorExtendLow" => Opcode::VectorExtendLow,
+            "VectorExtendHigh" => Opcode::VectorExtendHigh,
+            "VectorPromote" => Opcode::VectorPromote,
+            "VectorDemote" => Opcode::VectorDemote,
+            "VectorAnyTrue" => Opcode::VectorAnyTrue,
+            "VectorAllTrue" => Opcode::VectorAllTrue,
+            "VectorAvgRound" => Opcode::VectorAvgRound,
+            "VectorBitmask" => Opcode::VectorBitmask,
+            "VectorBitwiseSelect" => Opcode::VectorBitwiseSelect,
+            "VectorExtaddPairwise" => Opcode::VectorExtaddPairwise,
+            "VectorMulSat" => Opcode::VectorMulSat,
+            "VectorSwizzle" => Opcode::VectorSwizzle,
+            "VectorMulByElement" => Opcode::VectorMulByElement,
+            "Upsilon" => Opcode::Upsilon,
+            "Phi" => Opcode::Phi,
+            "Jump" => Opcode::Jump,
+            "Branch" => Opcode::Branch,
+            "Switch" => Opcode::Switch,
+            "EntrySwitch" => Opcode::EntrySwitch,
+            "Return" => Opcode::Return,
+            "Oops" => Opcode::Oops,
+            "ProcedureAddr" => Opcode::ProcedureAddr,
+            "GlobalAddr" => Opcode::GlobalAddr,
+            "Alloca" => Opcode::Alloca,
+            "GetElementPtr" => Opcode::GetElementPtr,
+            "TailCCall" => Opcode::TailCCall,
+            "Parameter" => Opcode::Parameter,
+            _ => return Err(()),
+        })
+    }
+}
+
 impl Into<Kind> for Opcode {
     fn into(self) -> Kind {
         Kind::new(self)
@@ -0,0 +1,741 @@
+use std::ops::Range;
+
+use crate::{
+    air::stack_slot::{StackSlotId, StackSlotKind},
+    block::{BlockId, Frequency, FrequentBlock},
+    effects::Effects,
+    jit::reg::Reg,
+    kind::Kind,
+    opcode::Opcode,
+    procedure::{GlobalId, Procedure, ProcedureId},
+    simd::{simd_info, SIMD_LANES, SIMD_SIGN_MODES},
+    sparse_collection::SparseCollection,
+    typ::Type,
+    value::{NumChildren, Value, ValueData, ValueId},
+    variable::{Variable, VariableId},
+    width::Width,
+    Options,
+};
+
+/// Error reported by [`parse_procedure`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Line of the input the error was found on, starting at 1.
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Builds a procedure from the text printed by [`Procedure::display`].
+///
+/// Block, value and variable numbers are kept, so displaying the result prints `text` again.
+/// Predecessor lists are taken as written; call [`Procedure::reset_reachability`] if they may be
+/// stale. Values that carry Rust closures (`Patchpoint`, `Check`, `CheckAdd`, `CheckSub` and
+/// `CheckMul`) cannot be parsed. Addresses of `ProcedureAddr` and `GlobalAddr` have to be set on
+/// the result before it is compiled.
+pub fn parse_procedure(text: &str, options: Options) -> Result<Procedure, ParseError> {
+    let lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim_end()))
+        .filter(|(_, line)| !line.is_empty())
+        .collect::<Vec<_>>();
+
+    let mut parser = Parser {
+        proc: Procedure::new(options),
+        line: lines.first().map_or(1, |&(line, _)| line),
+        values: vec![],
+        value_refs: vec![],
+        block_refs: vec![],
+    };
+
+    if lines.first().map(|&(_, line)| line) != Some("Procedure {") {
+        return parser.error("expected `Procedure {`");
+    }
+
+    parser.line = lines.last().unwrap().0;
+    if lines.len() < 2 || lines.last().unwrap().1 != "}" {
+        return parser.error("expected `}` at the end of the procedure");
+    }
+
+    let body = &lines[1..lines.len() - 1];
+
+    // Sections after the blocks declare the types and variables that values refer to, so they
+    // are parsed first.
+    let sections = body
+        .iter()
+        .position(|&(_, line)| !line.starts_with(' ') && !line.starts_with("BB"))
+        .unwrap_or(body.len());
+
+    parser.parse_sections(&body[sections..], true)?;
+    parser.parse_sections(&body[sections..], false)?;
+    parser.parse_blocks(&body[..sections])?;
+    parser.finish()
+}
+
+struct Parser {
+    proc: Procedure,
+    /// Line that is being parsed, used for errors.
+    line: usize,
+    /// Parsed values, indexed by their number in the text.
+    values: Vec<Option<Value>>,
+    /// Values and blocks referred to by each line. They may be defined after their use, so they
+    /// are checked once everything is parsed.
+    value_refs: Vec<(usize, ValueId)>,
+    block_refs: Vec<(usize, BlockId)>,
+}
+
+impl Parser {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            line: self.line,
+            message: message.into(),
+        })
+    }
+
+    fn parse_number<T: std::str::FromStr>(&self, text: &str) -> Result<T, ParseError> {
+        match text.parse() {
+            Ok(number) => Ok(number),
+            Err(_) => self.error(format!("expected a number, got `{}`", text)),
+        }
+    }
+
+    /// Parse a `$`-prefixed hexadecimal number.
+    fn parse_hex(&self, text: &str) -> Result<u128, ParseError> {
+        match text
+            .strip_prefix('$')
+            .and_then(|digits| u128::from_str_radix(digits, 16).ok())
+        {
+            Some(number) => Ok(number),
+            None => self.error(format!("expected a hexadecimal number, got `{}`", text)),
+        }
+    }
+
+    /// Parse an `id@N` reference.
+    fn parse_id(&self, text: &str, prefix: &str) -> Result<usize, ParseError> {
+        match text.strip_prefix(prefix) {
+            Some(number) => self.parse_number(number),
+            None => self.error(format!("expected {}N, got `{}`", prefix, text)),
+        }
+    }
+
+    fn parse_list<T>(
+        &mut self,
+        text: &str,
+        mut parse: impl FnMut(&mut Self, &str) -> Result<T, ParseError>,
+    ) -> Result<Vec<T>, ParseError> {
+        if text.is_empty() {
+            return Ok(vec![]);
+        }
+
+        text.split(", ").map(|item| parse(self, item)).collect()
+    }
+
+    fn parse_range(&self, text: &str) -> Result<Range<usize>, ParseError> {
+        match text.split_once("..") {
+            Some((start, end)) => Ok(self.parse_number(start)?..self.parse_number(end)?),
+            None => self.error(format!("expected a range, got `{}`", text)),
+        }
+    }
+
+    fn parse_type(&self, text: &str) -> Result<Type, ParseError> {
+        Ok(match text {
+            "Void" => Type::Void,
+            "Int32" => Type::Int32,
+            "Int64" => Type::Int64,
+            "Float" => Type::Float,
+            "Double" => Type::Double,
+            "V128" => Type::V128,
+            _ => {
+                let index = self.parse_id(text, "agg@")?;
+                if index >= self.proc.aggregates.len() {
+                    return self.error(format!("{} is not defined", text));
+                }
+
+                Type::aggregate(index as u32)
+            }
+        })
+    }
+
+    fn parse_width(&self, text: &str) -> Result<Width, ParseError> {
+        Ok(match text {
+            "Width8" => Width::W8,
+            "Width16" => Width::W16,
+            "Width32" => Width::W32,
+            "Width64" => Width::W64,
+            "Width128" => Width::W128,
+            _ => return self.error(format!("unknown width `{}`", text)),
+        })
+    }
+
+    fn parse_kind(&self, text: &str) -> Result<Kind, ParseError> {
+        let (name, flags) = match text.split_once('<') {
+            Some((name, flags)) => match flags.strip_suffix('>') {
+                Some(flags) => (name, flags),
+                None => return self.error(format!("unterminated flags in `{}`", text)),
+            },
+            None => (text, ""),
+        };
+
+        let opcode: Opcode = match name.parse() {
+            Ok(opcode) => opcode,
+            Err(()) => return self.error(format!("unknown opcode `{}`", name)),
+        };
+
+        let mut kind = Kind::new(opcode);
+
+        for flag in flags.split(", ").filter(|flag| !flag.is_empty()) {
+            match flag {
+                "Chill" => kind.set_is_chill(true),
+                "Trapping" => kind.set_traps(true),
+                "SensitiveToNaN" => kind.set_sensitive_to_nan(true),
+                _ => return self.error(format!("unknown flag `{}`", flag)),
+            }
+        }
+
+        Ok(kind)
+    }
+
+    fn parse_block(&mut self, text: &str) -> Result<BlockId, ParseError> {
+        let block = BlockId(self.parse_id(text, "BB")?);
+        self.block_refs.push((self.line, block));
+        Ok(block)
+    }
+
+    fn parse_frequent_block(&mut self, text: &str) -> Result<FrequentBlock, ParseError> {
+        match text.strip_prefix("Rare:") {
+            Some(block) => Ok((self.parse_block(block)?, Frequency::Rare)),
+            None => Ok((self.parse_block(text)?, Frequency::Normal)),
+        }
+    }
+
+    fn parse_value_ref(&mut self, text: &str) -> Result<ValueId, ParseError> {
+        let value = ValueId(self.parse_id(text, "v@")?);
+        self.value_refs.push((self.line, value));
+        Ok(value)
+    }
+
+    /// Parse the `Types:`, `Variables:` and `Stack slots:` sections and the `Signature:` and
+    /// `Entrypoints:` lines. Aggregate types may be used by everything else, so they are parsed
+    /// in a separate pass selected by `types`.
+    fn parse_sections(&mut self, lines: &[(usize, &str)], types: bool) -> Result<(), ParseError> {
+        let mut section = "";
+        let mut variables = vec![];
+
+        for &(line, text) in lines {
+            self.line = line;
+
+            if !text.starts_with(' ') {
+                section = text;
+
+                if let Some(signature) = text.strip_prefix("Signature: ") {
+                    if !types {
+                        self.parse_signature(signature)?;
+                    }
+                } else if let Some(count) = text.strip_prefix("Entrypoints: ") {
+                    if !types {
+                        let count = self.parse_number(count)?;
+                        self.proc.set_num_entrypoints(count);
+                    }
+                } else if !matches!(text, "Variables:" | "Types:" | "Stack slots:") {
+                    return self.error(format!("unexpected `{}`", text));
+                }
+
+                continue;
+            }
+
+            let (name, definition) = match text.trim_start().split_once(": ") {
+                Some(pair) => pair,
+                None => return self.error(format!("expected a definition, got `{}`", text)),
+            };
+
+            match (section, types) {
+                ("Types:", true) => self.parse_aggregate(name, definition)?,
+                ("Variables:", false) => {
+                    let index = self.parse_id(name, "var@")?;
+                    variables.push((index, self.parse_type(definition)?));
+                }
+                ("Stack slots:", false) => self.parse_stack_slot(name, definition)?,
+                ("Types:", false) | (_, true) => {}
+                _ => return self.error(format!("unexpected `{}`", text)),
+            }
+        }
+
+        if types {
+            return Ok(());
+        }
+
+        // Keep variable numbers, leaving the gaps free.
+        let mut slots = vec![];
+        for (index, typ) in variables {
+            if slots.len() <= index {
+                slots.resize_with(index + 1, || None);
+            }
+
+            slots[index] = Some(Variable::new(index, typ));
+        }
+
+        self.proc.variables = SparseCollection::from_vec(slots);
+        Ok(())
+    }
+
+    fn parse_aggregate(&mut self, name: &str, definition: &str) -> Result<(), ParseError> {
+        let index = self.parse_id(name, "agg@")?;
+
+        let typ = if let Some(fields) = definition
+            .strip_prefix("struct {")
+            .and_then(|fields| fields.strip_suffix('}'))
+        {
+            let fields = self.parse_list(fields, |this, field| this.parse_type(field))?;
+            self.proc.add_struct_type(&fields)
+        } else if let Some((element, length)) = definition
+            .strip_prefix("array [")
+            .and_then(|array| array.strip_suffix(']'))
+            .and_then(|array| array.split_once("; "))
+        {
+            let element = self.parse_type(element)?;
+            let length = self.parse_number(length)?;
+            self.proc.add_array_type(element, length)
+        } else {
+            return self.error(format!(
+                "expected a struct or array type, got `{}`",
+                definition
+            ));
+        };
+
+        if typ.aggregate_index() as usize != index {
+            return self.error(format!("{} is defined out of order", name));
+        }
+
+        Ok(())
+    }
+
+    fn parse_stack_slot(&mut self, name: &str, definition: &str) -> Result<(), ParseError> {
+        let index = self.parse_id(name, "slot@")?;
+
+        let (definition, alignment) = match definition.split_once(" align ") {
+            Some((definition, alignment)) => (definition, self.parse_number(alignment)?),
+            None => (definition, 1),
+        };
+
+        if !alignment.is_power_of_two() || alignment > 16 {
+            return self.error(format!("invalid stack slot alignment {}", alignment));
+        }
+
+        let (size, kind) = match definition.split_once(' ') {
+            Some((size, "Locked")) => (size, StackSlotKind::Locked),
+            Some((size, "Spill")) => (size, StackSlotKind::Spill),
+            _ => return self.error(format!("expected a size and a kind, got `{}`", definition)),
+        };
+
+        let size = self.parse_number(size)?;
+        if self.proc.add_aligned_stack_slot(size, alignment, kind).0 != index {
+            return self.error(format!("{} is defined out of order", name));
+        }
+
+        Ok(())
+    }
+
+    fn parse_signature(&mut self, text: &str) -> Result<(), ParseError> {
+        let (params, ret) = match text
+            .strip_prefix('(')
+            .and_then(|text| text.split_once(") -> "))
+        {
+            Some(pair) => pair,
+            None => return self.error(format!("expected `(params) -> ret`, got `{}`", text)),
+        };
+
+        let params = self.parse_list(params, |this, param| this.parse_type(param))?;
+        let ret = self.parse_type(ret)?;
+        self.proc.set_signature(&params, ret);
+        Ok(())
+    }
+
+    fn parse_blocks(&mut self, lines: &[(usize, &str)]) -> Result<(), ParseError> {
+        let mut current = None;
+
+        for &(line, text) in lines {
+            self.line = line;
+
+            if let Some(header) = text.strip_prefix("BB") {
+                let (index, frequency) = match header
+                    .split_once(": ; frequency = ")
+                    .and_then(|(index, rest)| Some((index, rest.strip_suffix(':')?)))
+                {
+                    Some(pair) => pair,
+                    None => return self.error(format!("malformed block header `{}`", text)),
+                };
+
+                let index: usize = self.parse_number(index)?;
+                let frequency = self.parse_number(frequency)?;
+                let block = self.proc.add_block(frequency);
+                if block.0 != index {
+                    return self.error(format!("expected BB{}, got BB{}", block.0, index));
+                }
+
+                current = Some(block);
+                continue;
+            }
+
+            let block = match current {
+                Some(block) => block,
+                None => return self.error("expected a block header"),
+            };
+
+            let text = text.trim_start();
+
+            if let Some(list) = text.strip_prefix("Predecessors: ") {
+                let predecessors = self.parse_list(list, Self::parse_block)?;
+                self.proc.block_mut(block).predecessor_list = predecessors;
+            } else if let Some(list) = text.strip_prefix("Successors: ") {
+                let successors = match list
+                    .strip_prefix("Then: ")
+                    .and_then(|list| list.split_once(", Else: "))
+                {
+                    Some((taken, not_taken)) => vec![
+                        self.parse_frequent_block(taken)?,
+                        self.parse_frequent_block(not_taken)?,
+                    ],
+                    None => self.parse_list(list, Self::parse_frequent_block)?,
+                };
+                self.proc.block_mut(block).successor_list = successors;
+            } else {
+                let value = self.parse_value(text, block)?;
+                self.proc.block_mut(block).values.push(value);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parse a value line like `Int32 v@3 = Add(v@1, v@2)`.
+    fn parse_value(&mut self, text: &str, block: BlockId) -> Result<ValueId, ParseError> {
+        let (typ, index, kind, arguments) = match text.split_once(" v@").and_then(|(typ, rest)| {
+            let (index, rest) = rest.split_once(" = ")?;
+            let (kind, arguments) = rest.split_once('(')?;
+            Some((typ, index, kind, arguments.strip_suffix(')')?))
+        }) {
+            Some(parts) => parts,
+            None => return self.error(format!("expected a value, got `{}`", text)),
+        };
+
+        let typ = self.parse_type(typ)?;
+        let index: usize = self.parse_number(index)?;
+        let kind = self.parse_kind(kind)?;
+
+        let mut children = vec![];
+        let mut rest = arguments;
+
+        while rest.starts_with("v@") {
+            let end = rest[2..]
+                .find(|c: char| !c.is_ascii_digit())
+                .map_or(rest.len(), |end| end + 2);
+            children.push(self.parse_value_ref(&rest[..end])?);
+            rest = &rest[end..];
+
+            match rest.strip_prefix(", ") {
+                Some(next) if next.starts_with("v@") => rest = next,
+                _ => break,
+            }
+        }
+
+        let data = match rest.strip_prefix(' ') {
+            Some(data) => data,
+            None if rest.is_empty() => "",
+            None => return self.error(format!("unexpected `{}`", rest)),
+        };
+
+        let data = self.parse_data(kind.opcode(), data)?;
+
+        let num_children = match (kind.opcode(), children.len()) {
+            (Opcode::CCall | Opcode::TailCCall, _) => NumChildren::VarArgs,
+            (_, 0) => NumChildren::Zero,
+            (_, 1) => NumChildren::One,
+            (_, 2) => NumChildren::Two,
+            (_, 3) => NumChildren::Three,
+            _ => NumChildren::VarArgs,
+        };
+
+        let mut value = Value::new(kind, typ, num_children, &children, data);
+        value.owner = Some(block);
+
+        if self.values.len() <= index {
+            self.values.resize_with(index + 1, || None);
+        }
+
+        if self.values[index].is_some() {
+            return self.error(format!("v@{} is defined twice", index));
+        }
+
+        self.values[index] = Some(value);
+        Ok(ValueId(index))
+    }
+
+    /// Parse whatever follows the children of a value of `opcode`.
+    fn parse_data(&mut self, opcode: Opcode, data: &str) -> Result<ValueData, ParseError> {
+        let mut tokens = data.split(' ');
+
+        Ok(match opcode {
+            Opcode::Const32 => ValueData::Const32(self.parse_hex(data)? as u32 as i32),
+            Opcode::Const64 => ValueData::Const64(self.parse_hex(data)? as u64 as i64),
+            Opcode::Const128 => ValueData::Const128(self.parse_hex(data)? as i128),
+            Opcode::ConstFloat => ValueData::Float(self.parse_hex(data)? as u32),
+            Opcode::ConstDouble => ValueData::Double(self.parse_hex(data)? as u64),
+
+            _ if opcode.is_load_store() => {
+                let offset = self.parse_hex(tokens.next().unwrap())? as u32 as i32;
+                let (range, fence_range) = self.parse_heap_ranges(tokens)?;
+                ValueData::MemoryValue {
+                    offset,
+                    range,
+                    fence_range,
+                }
+            }
+
+            _ if opcode.is_atomic() => {
+                let width = self.parse_width(tokens.next().unwrap())?;
+                let offset = match tokens.next() {
+                    Some(offset) => self.parse_hex(offset)? as u32 as i32,
+                    None => return self.error("expected an offset"),
+                };
+                let (range, fence_range) = self.parse_heap_ranges(tokens)?;
+                ValueData::AtomicValue {
+                    width,
+                    offset,
+                    range,
+                    fence_range,
+                }
+            }
+
+            Opcode::Fence => match data
+                .strip_prefix("read=")
+                .and_then(|data| data.split_once(", write="))
+            {
+                Some((read, write)) => ValueData::Fence {
+                    read: self.parse_range(read)?,
+                    write: self.parse_range(write)?,
+                },
+                None => return self.error(format!("expected fence ranges, got `{}`", data)),
+            },
+
+            Opcode::ArgumentReg => {
+                let mut reg = Reg::first();
+
+                while reg.to_string() != data {
+                    if reg == Reg::last() {
+                        return self.error(format!("unknown register `{}`", data));
+                    }
+
+                    reg = reg.next();
+                }
+
+                ValueData::Argument(reg)
+            }
+
+            Opcode::Parameter => ValueData::Parameter(self.parse_id(data, "#")?),
+            Opcode::Extract => ValueData::Extract(self.parse_id(data, "#")?),
+
+            Opcode::Get | Opcode::Set => {
+                let variable = VariableId(self.parse_id(data, "var@")?);
+                if self.proc.variables.at(variable).is_none() {
+                    return self.error(format!("{} is not defined", data));
+                }
+
+                ValueData::Variable(variable)
+            }
+
+            Opcode::Upsilon => match data {
+                "phi=none" => ValueData::Upsilon(None),
+                _ => match data.strip_prefix("phi=") {
+                    Some(phi) => ValueData::Upsilon(Some(self.parse_value_ref(phi)?)),
+                    None => return self.error(format!("expected phi=v@N, got `{}`", data)),
+                },
+            },
+
+            Opcode::SlotBase => {
+                let slot = self.parse_id(data, "slot@")?;
+                if slot >= self.proc.stack_slots.len() {
+                    return self.error(format!("{} is not defined", data));
+                }
+
+                ValueData::SlotBase(StackSlotId(slot))
+            }
+
+            Opcode::Switch => match data
+                .strip_prefix("cases=[")
+                .and_then(|cases| cases.strip_suffix(']'))
+            {
+                Some(cases) => {
+                    ValueData::Switch(self.parse_list(cases, |this, case| this.parse_number(case))?)
+                }
+                None => return self.error(format!("expected cases=[..], got `{}`", data)),
+            },
+
+            Opcode::Alloca => ValueData::Alloca(self.parse_type(data)?),
+            Opcode::GetElementPtr => ValueData::GetElementPtr(self.parse_type(data)?),
+            Opcode::ProcedureAddr => {
+                ValueData::Procedure(ProcedureId(self.parse_id(data, "proc@")?))
+            }
+            Opcode::GlobalAddr => ValueData::Global(GlobalId(self.parse_id(data, "global@")?)),
+
+            Opcode::CCall => {
+                let mut rest = data;
+                let variadic = match rest.strip_prefix("variadic") {
+                    Some(after) => {
+                        rest = after.trim_start();
+                        true
+                    }
+                    None => false,
+                };
+
+                let effects = match rest.strip_prefix("effects=") {
+                    Some(effects) => self.parse_effects(effects)?,
+                    None if rest.is_empty() => Effects::for_call(),
+                    None => return self.error(format!("unexpected `{}`", rest)),
+                };
+
+                ValueData::CCallValue { effects, variadic }
+            }
+
+            Opcode::TailCCall if data == "variadic" => ValueData::CCallValue {
+                effects: Effects::for_call(),
+                variadic: true,
+            },
+
+            Opcode::Patchpoint
+            | Opcode::Check
+            | Opcode::CheckAdd
+            | Opcode::CheckSub
+            | Opcode::CheckMul => {
+                return self.error(format!("{} needs a generator and cannot be parsed", opcode))
+            }
+
+            _ if data.is_empty() => ValueData::None,
+
+            _ => self.parse_simd(opcode, data)?,
+        })
+    }
+
+    fn parse_heap_ranges<'a>(
+        &self,
+        tokens: impl Iterator<Item = &'a str>,
+    ) -> Result<(Range<usize>, Range<usize>), ParseError> {
+        let mut range = 0..usize::MAX;
+        let mut fence_range = 0..0;
+
+        for token in tokens {
+            if let Some(text) = token.strip_prefix("range=") {
+                range = self.parse_range(text)?;
+            } else if let Some(text) = token.strip_prefix("fence=") {
+                fence_range = self.parse_range(text)?;
+            } else {
+                return self.error(format!("unexpected `{}`", token));
+            }
+        }
+
+        Ok((range, fence_range))
+    }
+
+    /// Parse the `Debug` output of [`Effects`].
+    fn parse_effects(&self, text: &str) -> Result<Effects, ParseError> {
+        let fields = match text
+            .strip_prefix("Effects { ")
+            .and_then(|text| text.strip_suffix(" }"))
+        {
+            Some(fields) => fields,
+            None => return self.error(format!("expected effects, got `{}`", text)),
+        };
+
+        let mut effects = Effects::none();
+
+        for field in fields.split(", ") {
+            let (name, value) = match field.split_once(": ") {
+                Some(pair) => pair,
+                None => return self.error(format!("malformed effect `{}`", field)),
+            };
+
+            let flag = || match value {
+                "true" => Ok(true),
+                "false" => Ok(false),
+                _ => self.error(format!("expected true or false, got `{}`", value)),
+            };
+
+            match name {
+                "terminal" => effects.terminal = flag()?,
+                "exit_sideways" => effects.exit_sideways = flag()?,
+                "control_dependent" => effects.control_dependent = flag()?,
+                "writes_local_state" => effects.writes_local_state = flag()?,
+                "reads_local_state" => effects.reads_local_state = flag()?,
+                "fence" => effects.fence = flag()?,
+                "writes" => effects.writes = self.parse_range(value)?,
+                "reads" => effects.reads = self.parse_range(value)?,
+                _ => return self.error(format!("unknown effect `{}`", name)),
+            }
+        }
+
+        Ok(effects)
+    }
+
+    fn parse_simd(&self, opcode: Opcode, data: &str) -> Result<ValueData, ParseError> {
+        let mut tokens = data.split(' ');
+        let lane = tokens.next().unwrap();
+        let sign_mode = tokens.next().unwrap_or("");
+
+        let lane = match SIMD_LANES.iter().find(|x| format!("{:?}", x) == lane) {
+            Some(&lane) => lane,
+            None => return self.error(format!("unexpected `{}` in {}", data, opcode)),
+        };
+
+        let sign_mode = match SIMD_SIGN_MODES
+            .iter()
+            .find(|x| format!("{:?}", x) == sign_mode)
+        {
+            Some(&sign_mode) => sign_mode,
+            None => return self.error(format!("unknown sign mode `{}`", sign_mode)),
+        };
+
+        let immediate = match tokens.next() {
+            Some(token) => self.parse_id(token, "lane=")? as u8,
+            None => 0,
+        };
+
+        Ok(ValueData::SIMD {
+            info: simd_info(lane, sign_mode),
+            immediate,
+        })
+    }
+
+    fn finish(mut self) -> Result<Procedure, ParseError> {
+        let values = &self.values;
+        if let Some(&(line, value)) = self
+            .value_refs
+            .iter()
+            .find(|(_, value)| !matches!(values.get(value.0), Some(Some(_))))
+        {
+            self.line = line;
+            return self.error(format!("v@{} is not defined", value.0));
+        }
+
+        let num_blocks = self.proc.blocks.len();
+        if let Some(&(line, block)) = self
+            .block_refs
+            .iter()
+            .find(|(_, block)| block.0 >= num_blocks)
+        {
+            self.line = line;
+            return self.error(format!("BB{} is not defined", block.0));
+        }
+
+        // Keep value numbers, leaving the gaps free.
+        self.proc.values = SparseCollection::from_vec(std::mem::take(&mut self.values));
+
+        Ok(self.proc)
+    }
+}
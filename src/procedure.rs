@@ -1,5 +1,6 @@
 use std::{ops::Range, rc::Rc};
 
+use indexmap::IndexMap;
 use macroassembler::assembler::TargetMacroAssembler;
 use tinyvec::tiny_vec;
 
@@ -18,16 +19,19 @@ use crate::{
         register_set::{RegisterSetBuilder, ScalarRegisterSet},
     },
     kind::Kind,
+    module::ProcedureAddress,
     opcode::Opcode,
     patchpoint_value::PatchpointValue,
     rpo::rpo_sort,
     sparse_collection::SparseCollection,
     stackmap_generation_params::StackmapGenerationParams,
     stackmap_value::StackMapValue,
-    typ::{Type, TypeKind},
+    jit::register_at_offset::round_up_to_multiple_of,
+    typ::{size_of_type, Aggregate, AggregateType, Type, TypeKind},
     utils::index_set::KeyIndex,
     value::{NumChildren, Value, ValueData, ValueId},
     variable::{Variable, VariableId},
+    width::Width,
     ConstrainedValue, ValueRep, ValueRepKind,
 };
 use crate::{Frequency, Options};
@@ -43,6 +47,11 @@ pub struct Procedure {
     pub(crate) data_sections: Vec<DataSection>,
     pub(crate) num_entrypoints: usize,
     pub(crate) pinned_regs: ScalarRegisterSet,
+    pub(crate) procedure_addresses: IndexMap<ProcedureId, ProcedureAddress>,
+    pub(crate) procedure_id: Option<ProcedureId>,
+    pub(crate) signature: Option<Signature>,
+    pub(crate) global_addresses: IndexMap<GlobalId, *mut u8>,
+    pub(crate) aggregates: Vec<AggregateType>,
 }
 
 impl Graph for Procedure {
@@ -93,6 +102,11 @@ impl Procedure {
             options,
             data_sections: vec![],
             pinned_regs: ScalarRegisterSet::default(),
+            procedure_addresses: IndexMap::new(),
+            procedure_id: None,
+            signature: None,
+            global_addresses: IndexMap::new(),
+            aggregates: vec![],
         }
     }
 
@@ -107,6 +121,123 @@ impl Procedure {
             .push((successor, Frequency::Normal));
     }
 
+    /// Set how `ProcedureAddr` values that refer to `id` are resolved. [`Module`](crate::module::Module)
+    /// does this automatically for all procedures it owns.
+    pub fn set_procedure_address(&mut self, id: ProcedureId, address: ProcedureAddress) {
+        self.procedure_addresses.insert(id, address);
+    }
+
+    pub fn procedure_address(&self, id: ProcedureId) -> Option<&ProcedureAddress> {
+        self.procedure_addresses.get(&id)
+    }
+
+    /// Set id under which this procedure itself is known. It is used to recognize tail calls of the
+    /// procedure to itself. [`Module`](crate::module::Module) does this automatically.
+    pub fn set_procedure_id(&mut self, id: ProcedureId) {
+        self.procedure_id = Some(id);
+    }
+
+    pub fn procedure_id(&self) -> Option<ProcedureId> {
+        self.procedure_id
+    }
+
+    /// Declare parameter and return types of the procedure. Parameters are then read with
+    /// `Parameter` values, which are placed according to the C calling convention.
+    pub fn set_signature(&mut self, params: &[Type], ret: Type) {
+        self.signature = Some(Signature {
+            params: params.to_vec(),
+            ret,
+        });
+    }
+
+    pub fn signature(&self) -> Option<&Signature> {
+        self.signature.as_ref()
+    }
+
+    /// Set address of global `id` used to lower `GlobalAddr`. [`Module`](crate::module::Module)
+    /// does this automatically for all globals it owns.
+    pub fn set_global_address(&mut self, id: GlobalId, address: *mut u8) {
+        self.global_addresses.insert(id, address);
+    }
+
+    pub fn global_address(&self, id: GlobalId) -> Option<*mut u8> {
+        self.global_addresses.get(&id).copied()
+    }
+
+    /// Register a struct type with `fields` laid out in order. Each field is aligned to its natural
+    /// alignment and the size of the struct is padded to the largest alignment of its fields.
+    pub fn add_struct_type(&mut self, fields: &[Type]) -> Type {
+        let mut offsets = Vec::with_capacity(fields.len());
+        let mut size = 0;
+        let mut alignment = 1;
+
+        for &field in fields {
+            let field_alignment = self.alignment_of(field);
+            size = round_up_to_multiple_of(field_alignment as _, size as _) as usize;
+            offsets.push(size);
+            size += self.size_of(field);
+            alignment = alignment.max(field_alignment);
+        }
+
+        let size = round_up_to_multiple_of(alignment as _, size as _) as usize;
+
+        self.add_aggregate_type(AggregateType {
+            aggregate: Aggregate::Struct(fields.to_vec()),
+            size,
+            alignment,
+            offsets,
+        })
+    }
+
+    /// Register an array type of `length` elements of type `element`.
+    pub fn add_array_type(&mut self, element: Type, length: usize) -> Type {
+        let alignment = self.alignment_of(element);
+        let stride = round_up_to_multiple_of(alignment as _, self.size_of(element) as _) as usize;
+
+        self.add_aggregate_type(AggregateType {
+            aggregate: Aggregate::Array(element, length),
+            size: stride * length,
+            alignment,
+            offsets: vec![],
+        })
+    }
+
+    fn add_aggregate_type(&mut self, aggregate: AggregateType) -> Type {
+        if let Some(index) = self.aggregates.iter().position(|x| *x == aggregate) {
+            return Type::aggregate(index as _);
+        }
+
+        self.aggregates.push(aggregate);
+        Type::aggregate(self.aggregates.len() as u32 - 1)
+    }
+
+    pub fn aggregate(&self, typ: Type) -> &AggregateType {
+        assert!(typ.is_aggregate(), "{} is not an aggregate type", typ);
+        &self.aggregates[typ.aggregate_index() as usize]
+    }
+
+    pub fn aggregates(&self) -> &[AggregateType] {
+        &self.aggregates
+    }
+
+    /// Size of `typ` in bytes. Unlike [`size_of_type`] this knows sizes of aggregate types.
+    pub fn size_of(&self, typ: Type) -> usize {
+        if typ.is_aggregate() {
+            self.aggregate(typ).size()
+        } else {
+            size_of_type(typ) as usize
+        }
+    }
+
+    /// Alignment of `typ` in bytes.
+    pub fn alignment_of(&self, typ: Type) -> usize {
+        if typ.is_aggregate() {
+            self.aggregate(typ).alignment()
+        } else {
+            (size_of_type(typ) as usize).max(1)
+        }
+    }
+
     pub fn num_entrypoints(&self) -> usize {
         self.num_entrypoints
     }
@@ -132,8 +263,28 @@ impl Procedure {
     }
 
     pub fn add_stack_slot(&mut self, size: usize, stack_slot_kind: StackSlotKind) -> StackSlotId {
+        self.add_aligned_stack_slot(size, 1, stack_slot_kind)
+    }
+
+    /// Add a stack slot of `size` bytes that is aligned to at least `alignment` bytes.
+    ///
+    /// Panics if `alignment` is not a power of two or is larger than the 16 bytes the frame is
+    /// aligned to.
+    pub fn add_aligned_stack_slot(
+        &mut self,
+        size: usize,
+        alignment: usize,
+        stack_slot_kind: StackSlotKind,
+    ) -> StackSlotId {
+        assert!(
+            alignment.is_power_of_two() && alignment <= 16,
+            "invalid stack slot alignment {}",
+            alignment
+        );
+
         let slot = StackSlot {
             byte_size: size as _,
+            min_alignment: alignment as _,
             kind: stack_slot_kind,
             index: self.stack_slots.len(),
             offset_from_fp: 0,
@@ -264,6 +415,7 @@ impl Procedure {
             TypeKind::Int64 => self.add(Value::make_const64(val)),
             TypeKind::Float => self.add(Value::make_const_float(val as _)),
             TypeKind::Double => self.add(Value::make_const_double(val as _)),
+            TypeKind::V128 => self.add(Value::make_const128(val as _)),
 
             _ => panic!("Invalid type for constant"),
         }
@@ -276,6 +428,7 @@ impl Procedure {
             TypeKind::Int64 => self.add(Value::make_const64(val as _)),
             TypeKind::Float => self.add(Value::make_const_float(f32::from_bits(val as _))),
             TypeKind::Double => self.add(Value::make_const_double(f64::from_bits(val as _))),
+            TypeKind::V128 => self.add(Value::make_const128(val as _)),
 
             _ => panic!("Invalid type for constant"),
         }
@@ -429,6 +582,55 @@ impl Procedure {
         ))
     }
 
+    /// Add an atomic operation. `children` are `[expected, new_value, pointer]` for
+    /// `AtomicWeakCAS`/`AtomicStrongCAS` and `[operand, pointer]` for `AtomicXchg*`.
+    pub fn add_atomic(
+        &mut self,
+        kind: Kind,
+        width: Width,
+        children: &[ValueId],
+        offset: i32,
+        range: Range<usize>,
+        fence_range: Range<usize>,
+    ) -> ValueId {
+        let opcode = kind.opcode();
+        assert!(opcode.is_atomic(), "Opcode is not atomic: {:?}", opcode);
+
+        let num_children = if opcode.is_atomic_xchg() {
+            NumChildren::Two
+        } else {
+            NumChildren::Three
+        };
+
+        let operand_type = self.value(children[0]).typ();
+        assert!(
+            operand_type == if width == Width::W64 { Type::Int64 } else { Type::Int32 },
+            "{:?} of {} does not match operand type {}",
+            opcode,
+            width,
+            operand_type
+        );
+
+        let typ = if opcode == Opcode::AtomicWeakCAS {
+            Type::Int32
+        } else {
+            operand_type
+        };
+
+        self.add(Value::new(
+            kind,
+            typ,
+            num_children,
+            children,
+            ValueData::AtomicValue {
+                width,
+                offset,
+                range,
+                fence_range,
+            },
+        ))
+    }
+
     pub fn add_argument(&mut self, typ: Type, reg: Reg) -> ValueId {
         self.add(Value::new(
             Opcode::ArgumentReg,
@@ -591,7 +793,10 @@ impl Procedure {
                 .chain(args.iter().copied())
                 .collect::<Vec<_>>()
                 .as_slice(),
-            ValueData::CCallValue(effects),
+            ValueData::CCallValue {
+                effects,
+                variadic: false,
+            },
         ))
     }
 
@@ -818,11 +1023,66 @@ impl std::fmt::Display for ProcedureDisplay<'_> {
             }
         }
 
+        if !self.procedure.aggregates.is_empty() {
+            writeln!(f, "Types:")?;
+            for (i, aggregate) in self.procedure.aggregates.iter().enumerate() {
+                write!(f, "  agg@{}: ", i)?;
+                match aggregate.aggregate() {
+                    Aggregate::Struct(fields) => {
+                        write!(f, "struct {{")?;
+                        for (j, field) in fields.iter().enumerate() {
+                            if j != 0 {
+                                write!(f, ", ")?;
+                            }
+                            write!(f, "{}", field)?;
+                        }
+                        writeln!(f, "}}")?;
+                    }
+                    Aggregate::Array(element, length) => {
+                        writeln!(f, "array [{}; {}]", element, length)?
+                    }
+                }
+            }
+        }
+
+        if !self.procedure.stack_slots.is_empty() {
+            writeln!(f, "Stack slots:")?;
+            for slot in self.procedure.stack_slots.iter() {
+                write!(f, "  slot@{}: {} {:?}", slot.index(), slot.byte_size(), slot.kind())?;
+                if slot.min_alignment > 1 {
+                    write!(f, " align {}", slot.min_alignment)?;
+                }
+                writeln!(f)?;
+            }
+        }
+
+        if let Some(signature) = self.procedure.signature() {
+            write!(f, "Signature: (")?;
+            for (i, param) in signature.params.iter().enumerate() {
+                if i != 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", param)?;
+            }
+            writeln!(f, ") -> {}", signature.ret)?;
+        }
+
+        if self.procedure.num_entrypoints != 1 {
+            writeln!(f, "Entrypoints: {}", self.procedure.num_entrypoints)?;
+        }
+
         writeln!(f, "}}")?;
         Ok(())
     }
 }
 
+/// Parameter and return types of a procedure, see [`Procedure::set_signature`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Signature {
+    pub params: Vec<Type>,
+    pub ret: Type,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ProcedureId(pub usize);
 
@@ -843,3 +1103,24 @@ impl From<usize> for ProcedureId {
         ProcedureId(id)
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GlobalId(pub usize);
+
+impl KeyIndex for GlobalId {
+    fn index(&self) -> usize {
+        self.0
+    }
+}
+
+impl From<GlobalId> for usize {
+    fn from(id: GlobalId) -> Self {
+        id.0
+    }
+}
+
+impl From<usize> for GlobalId {
+    fn from(id: usize) -> Self {
+        GlobalId(id)
+    }
+}
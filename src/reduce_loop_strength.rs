@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+
+use indexmap::IndexMap;
+
+use crate::{
+    analysis::{
+        induction_variables::{bits_of, wrap_to, InductionVariables},
+        natural_loops::NaturalLoop,
+        use_counts::UseCounts,
+    },
+    ensure_loop_pre_headers::ensure_loop_pre_headers,
+    insertion_set::InsertionSet,
+    utils::phase_scope::phase_scope,
+    BlockId, NumChildren, Opcode, Procedure, Type, Value, ValueData, ValueId,
+};
+
+/// Strength reduces the induction variables of loops in SSA form.
+///
+/// - Basic induction variables of a loop that start at the same value and have the same step are
+///   merged into one.
+/// - Values of the form `base + i * scale` or `i * scale`, where `i` is a basic induction variable,
+///   `scale` is a constant and `base` is loop invariant, become induction variables of their own
+///   that start at `base + init * scale` and go up by `step * scale`. This turns array address
+///   computations into pointer increments.
+/// - If the latch exits the loop once `i < n` fails, where `i` starts at a constant, has a positive
+///   step and `n` is a constant, the exit test is replaced with a `NotEqual` on one of the derived
+///   induction variables, so that `i` is no longer needed if nothing else uses it. This is only
+///   done when the derived induction variable can't wrap around to the limit early.
+///
+/// Derived values must have the type of the induction variable, since sign extension doesn't
+/// commute with wrapping around. The values that are no longer used are left for
+/// `eliminate_dead_code`.
+pub fn reduce_loop_strength(proc: &mut Procedure) -> bool {
+    phase_scope("b3::reduce_loop_strength", || {
+        ensure_loop_pre_headers(proc);
+
+        let natural_loops = proc.natural_loops_or_compute();
+        if natural_loops.num_loops() == 0 {
+            return false;
+        }
+
+        proc.reset_value_owners();
+
+        let mut upsilons = HashMap::<ValueId, Vec<ValueId>>::new();
+
+        for block in proc.blocks.iter() {
+            for &value in block.iter() {
+                if let Some(phi) = proc.value(value).phi() {
+                    upsilons.entry(phi).or_default().push(value);
+                }
+            }
+        }
+
+        let mut changed = false;
+
+        for loop_index in 0..natural_loops.num_loops() {
+            let loop_ = natural_loops.loop_(loop_index);
+            changed |= ReduceLoopStrength::new(proc, loop_, &upsilons).run();
+        }
+
+        changed
+    })
+}
+
+struct ReduceLoopStrength<'a> {
+    proc: &'a mut Procedure,
+    loop_: &'a NaturalLoop<Procedure>,
+    upsilons: &'a HashMap<ValueId, Vec<ValueId>>,
+    insertion_sets: HashMap<BlockId, InsertionSet>,
+    /// The induction variables created for `(basic, scale, base)` so far, as their Phi and their
+    /// increment.
+    reduced: IndexMap<(usize, i64, Option<ValueId>), (ValueId, ValueId)>,
+}
+
+impl<'a> ReduceLoopStrength<'a> {
+    fn new(
+        proc: &'a mut Procedure,
+        loop_: &'a NaturalLoop<Procedure>,
+        upsilons: &'a HashMap<ValueId, Vec<ValueId>>,
+    ) -> Self {
+        Self {
+            proc,
+            loop_,
+            upsilons,
+            insertion_sets: HashMap::new(),
+            reduced: IndexMap::new(),
+        }
+    }
+
+    fn run(&mut self) -> bool {
+        let mut ivs = match InductionVariables::new(self.proc, self.loop_, self.upsilons) {
+            Some(ivs) if !ivs.basic.is_empty() => ivs,
+            _ => return false,
+        };
+
+        let mut changed = false;
+
+        if self.merge_redundant_induction_variables(&ivs) {
+            changed = true;
+
+            for &block in self.loop_.body() {
+                for index in 0..self.proc.block(block).len() {
+                    let value = self.proc.block(block)[index];
+                    Value::perform_substitution(value, self.proc);
+                }
+            }
+
+            // The CFG didn't change, so the loop still has a pre-header and a single latch.
+            ivs = InductionVariables::new(self.proc, self.loop_, self.upsilons).unwrap();
+        }
+
+        // Reduce `base + i * scale` first, so that `i * scale` is only reduced on its own if
+        // something else uses it too.
+        for derived in ivs.derived.iter().filter(|derived| derived.base.is_some()) {
+            let (phi, _) =
+                self.induction_variable_for(&ivs, derived.basic, derived.scale, derived.base);
+            self.proc
+                .value_mut(derived.value)
+                .replace_with_identity(phi);
+            changed = true;
+        }
+
+        if ivs.derived.iter().any(|derived| derived.base.is_none()) {
+            let use_counts = UseCounts::new(self.proc);
+
+            for derived in ivs.derived.iter().filter(|derived| derived.base.is_none()) {
+                if use_counts.num_uses(derived.value) == 0 {
+                    continue;
+                }
+
+                let (phi, _) =
+                    self.induction_variable_for(&ivs, derived.basic, derived.scale, None);
+                self.proc
+                    .value_mut(derived.value)
+                    .replace_with_identity(phi);
+                changed = true;
+            }
+        }
+
+        changed |= self.replace_exit_test(&ivs);
+
+        for (block, mut insertion_set) in std::mem::take(&mut self.insertion_sets) {
+            insertion_set.execute(self.proc, block);
+        }
+
+        changed
+    }
+
+    /// Replaces basic induction variables with an earlier one that has the same start and step.
+    fn merge_redundant_induction_variables(&mut self, ivs: &InductionVariables) -> bool {
+        let mut changed = false;
+
+        for (index, iv) in ivs.basic.iter().enumerate() {
+            let same = ivs.basic[..index].iter().find(|other| {
+                other.step == iv.step
+                    && self.proc.value(other.phi).typ() == self.proc.value(iv.phi).typ()
+                    && (other.init == iv.init
+                        || (self.proc.value(other.init).as_int().is_some()
+                            && self.proc.value(other.init).as_int()
+                                == self.proc.value(iv.init).as_int()))
+            });
+
+            if let Some(same) = same {
+                self.proc.value_mut(iv.init_upsilon).replace_with_nop();
+                self.proc.value_mut(iv.latch_upsilon).replace_with_nop();
+                self.proc.value_mut(iv.phi).replace_with_identity(same.phi);
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    /// Returns the Phi and the increment of the induction variable that computes
+    /// `base + ivs.basic[basic] * scale`, creating it if needed.
+    fn induction_variable_for(
+        &mut self,
+        ivs: &InductionVariables,
+        basic: usize,
+        scale: i64,
+        base: Option<ValueId>,
+    ) -> (ValueId, ValueId) {
+        if let Some(&reduced) = self.reduced.get(&(basic, scale, base)) {
+            return reduced;
+        }
+
+        let iv = ivs.basic[basic];
+        let typ = self.proc.value(iv.phi).typ();
+        let bits = bits_of(self.proc, iv.phi).unwrap();
+
+        let pre_header = ivs.pre_header;
+        let pre_header_end = self.proc.block(pre_header).len() - 1;
+
+        let scale_value = self.insert_int_constant(pre_header, pre_header_end, typ, scale);
+        let mut init = self.insert_binary(
+            pre_header,
+            pre_header_end,
+            Opcode::Mul,
+            typ,
+            iv.init,
+            scale_value,
+        );
+
+        if let Some(base) = base {
+            let base = self.base_in_pre_header(ivs, base);
+            init = self.insert_binary(pre_header, pre_header_end, Opcode::Add, typ, base, init);
+        }
+
+        let phi = self.insert(
+            self.loop_.header(),
+            0,
+            Value::new(Opcode::Phi, typ, NumChildren::Zero, &[], ValueData::None),
+        );
+        self.insert_upsilon(pre_header, pre_header_end, init, phi);
+
+        // Step the new induction variable right where the basic one is stepped.
+        let latch = ivs.latch;
+        let latch_index = self
+            .proc
+            .block(latch)
+            .iter()
+            .position(|&value| value == iv.latch_upsilon)
+            .unwrap();
+
+        let step = wrap_to(bits, iv.step.wrapping_mul(scale));
+        let step_value = self.insert_int_constant(latch, latch_index, typ, step);
+        let increment = self.insert_binary(latch, latch_index, Opcode::Add, typ, phi, step_value);
+        self.insert_upsilon(latch, latch_index, increment, phi);
+
+        self.reduced.insert((basic, scale, base), (phi, increment));
+
+        (phi, increment)
+    }
+
+    /// Rewrites `Branch(LessThan(x, n))` in the latch into `Branch(NotEqual(y, limit))`, where `x`
+    /// is a basic induction variable or its increment and `y` is the matching value of a derived
+    /// induction variable.
+    fn replace_exit_test(&mut self, ivs: &InductionVariables) -> bool {
+        let latch = ivs.latch;
+        let branch = match self.proc.block(latch).last() {
+            Some(&branch) if self.proc.value(branch).kind.opcode() == Opcode::Branch => branch,
+            _ => return false,
+        };
+
+        // The loop has to go around while the test holds, and leave once it fails.
+        if self.proc.block(latch).taken().0 != self.loop_.header()
+            || self.loop_.contains(self.proc.block(latch).not_taken().0)
+        {
+            return false;
+        }
+
+        let test = self.proc.value(branch).children[0];
+        let children = &self.proc.value(test).children;
+
+        let (x, limit) = match self.proc.value(test).kind.opcode() {
+            Opcode::LessThan => (children[0], children[1]),
+            Opcode::GreaterThan => (children[1], children[0]),
+            _ => return false,
+        };
+
+        let limit = match self.proc.value(limit).as_int() {
+            Some(limit) => limit as i128,
+            None => return false,
+        };
+
+        for (basic, iv) in ivs.basic.iter().enumerate() {
+            let is_increment = if x == iv.phi {
+                false
+            } else if iv.is_incremented_by(self.proc, x) {
+                true
+            } else {
+                continue;
+            };
+
+            let init = match self.proc.value(iv.init).as_int() {
+                Some(init) if iv.step > 0 => init as i128,
+                _ => continue,
+            };
+
+            let (&(_, scale, base), &(phi, increment)) = match self
+                .reduced
+                .iter()
+                .find(|((other, _, _), _)| *other == basic)
+            {
+                Some(reduced) => reduced,
+                None => continue,
+            };
+
+            // Work out the first value of `x` for which the test fails. The values of `x` the test
+            // sees before it may not wrap around, and neither may the derived values.
+            let bits = bits_of(self.proc, iv.phi).unwrap();
+            let max = (1i128 << (bits - 1)) - 1;
+            let step = iv.step as i128;
+            let first = if is_increment { init + step } else { init };
+            let last = if first >= limit {
+                first
+            } else {
+                first + (limit - first + step - 1) / step * step
+            };
+
+            if last > max || (last - first) * (scale as i128).abs() >= 1i128 << bits {
+                continue;
+            }
+
+            let typ = self.proc.value(iv.phi).typ();
+            let end = self.proc.block(latch).len() - 1;
+            let last_scaled = wrap_to(bits, (last as i64).wrapping_mul(scale));
+
+            let new_limit = match base {
+                Some(base) => {
+                    let pre_header = ivs.pre_header;
+                    let pre_header_end = self.proc.block(pre_header).len() - 1;
+                    let base = self.base_in_pre_header(ivs, base);
+                    let offset =
+                        self.insert_int_constant(pre_header, pre_header_end, typ, last_scaled);
+                    self.insert_binary(pre_header, pre_header_end, Opcode::Add, typ, base, offset)
+                }
+
+                None => self.insert_int_constant(latch, end, typ, last_scaled),
+            };
+
+            let y = if is_increment { increment } else { phi };
+            let new_test =
+                self.insert_binary(latch, end, Opcode::NotEqual, Type::Int32, y, new_limit);
+            self.proc.value_mut(branch).children[0] = new_test;
+
+            return true;
+        }
+
+        false
+    }
+
+    /// Returns a value for the loop invariant `base` that can be used in the pre-header.
+    fn base_in_pre_header(&mut self, ivs: &InductionVariables, base: ValueId) -> ValueId {
+        // Constants may still live inside the loop, so they get a copy.
+        match self.proc.value(base).as_int() {
+            Some(constant) => {
+                let typ = self.proc.value(base).typ();
+                let pre_header_end = self.proc.block(ivs.pre_header).len() - 1;
+                self.insert_int_constant(ivs.pre_header, pre_header_end, typ, constant)
+            }
+
+            None => base,
+        }
+    }
+
+    fn insert(&mut self, block: BlockId, index: usize, value: Value) -> ValueId {
+        let value = self.proc.add(value);
+
+        self.insertion_sets
+            .entry(block)
+            .or_insert_with(InsertionSet::new)
+            .insert_value(index, value)
+    }
+
+    fn insert_int_constant(
+        &mut self,
+        block: BlockId,
+        index: usize,
+        typ: Type,
+        value: i64,
+    ) -> ValueId {
+        let value = self.proc.add_int_constant(typ, value);
+
+        self.insertion_sets
+            .entry(block)
+            .or_insert_with(InsertionSet::new)
+            .insert_value(index, value)
+    }
+
+    fn insert_binary(
+        &mut self,
+        block: BlockId,
+        index: usize,
+        opcode: Opcode,
+        typ: Type,
+        left: ValueId,
+        right: ValueId,
+    ) -> ValueId {
+        self.insert(
+            block,
+            index,
+            Value::new(
+                opcode,
+                typ,
+                NumChildren::Two,
+                &[left, right],
+                ValueData::None,
+            ),
+        )
+    }
+
+    fn insert_upsilon(&mut self, block: BlockId, index: usize, input: ValueId, phi: ValueId) {
+        self.insert(
+            block,
+            index,
+            Value::new(
+                Opcode::Upsilon,
+                Type::Void,
+                NumChildren::One,
+                &[input],
+                ValueData::Upsilon(Some(phi)),
+            ),
+        );
+    }
+}
@@ -27,13 +27,17 @@
 //! In this way, sccp can discover optimization opportunities that cannot be found
 //! by just combining constant folding and constant propagation and dead code
 //! elimination separately.
+//!
+//! Values that are proven to be constant are replaced with the constant, `Branch` and `Switch` on
+//! a constant become `Jump`, and blocks that are no longer reachable are removed.
 
 use std::collections::{HashMap, VecDeque};
 
 use crate::{
     analysis::phi_children::PhiChildren,
-    utils::{bitvector::BitVector, index_set::IndexMap},
-    BlockId, Frequency, Opcode, Procedure, TriState, Value, ValueId,
+    invert,
+    utils::{bitvector::BitVector, index_set::IndexMap, phase_scope::phase_scope},
+    BlockId, Opcode, Procedure, TriState, TypeKind, Value, ValueId,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -50,327 +54,243 @@ struct Lattice {
 }
 
 impl Lattice {
+    const TOP: Self = Self {
+        level: LatticeLevel::Top,
+        val: None,
+    };
+
+    const BOTTOM: Self = Self {
+        level: LatticeLevel::Bottom,
+        val: None,
+    };
+
+    fn constant(val: ValueId) -> Self {
+        Self {
+            level: LatticeLevel::Constant,
+            val: Some(val),
+        }
+    }
+
     fn equals(&self, other: &Self, proc: &Procedure) -> bool {
         if self.level != other.level {
             return false;
         }
 
         if self.level == LatticeLevel::Constant {
-            let v1 = self.val.unwrap();
-            let v2 = other.val.unwrap();
-
+            let v1 = proc.value(self.val.unwrap());
+            let v2 = proc.value(other.val.unwrap());
 
-            return proc.value(v1).kind.opcode() == proc.value(v2).kind.opcode()
-                && proc.value(v1).as_int().unwrap() == proc.value(v2).as_int().unwrap();
+            return v1.kind.opcode() == v2.kind.opcode() && v1.data == v2.data;
         }
 
         true
     }
+
+    /// Computes `self ∩ other`.
+    fn meet(&self, other: &Self, proc: &Procedure) -> Self {
+        match (self.level, other.level) {
+            (LatticeLevel::Top, _) => *other,
+            (_, LatticeLevel::Top) => *self,
+            (LatticeLevel::Constant, LatticeLevel::Constant) if self.equals(other, proc) => *self,
+            _ => Self::BOTTOM,
+        }
+    }
 }
 
 /// SCCP worklist
 pub struct Worklist<'a> {
     proc: &'a mut Procedure,
     phi_children: PhiChildren,
+    /// Blocks that were found to be executable but were not visited yet.
     edges: VecDeque<BlockId>,
+    /// Values that have to be visited again because the lattice of one of their inputs changed.
     uses: VecDeque<ValueId>,
-    visited: BitVector,
+    visited_block: BitVector,
     lattice: IndexMap<Lattice, ValueId>,
-    /// def-use chains for some values
+    /// def-use chains for values that can become constants
     def_use: HashMap<ValueId, Vec<ValueId>>,
-    /// Use blocks of def
-    def_block: HashMap<ValueId, Vec<BlockId>>,
-    visited_block: BitVector,
+    /// Constants created while folding. They are not in any block.
+    constants: Vec<ValueId>,
 }
 
-pub fn sccp<'a>(proc: &'a mut Procedure) {
-    let visited = BitVector::with_capacity(proc.blocks.len());
-    let visited_block = BitVector::with_capacity(proc.blocks.len());
-    let phi_children = PhiChildren::new(proc);
-    let mut t = Worklist {
-        proc,
-        phi_children,
-        edges: VecDeque::new(),
-        uses: VecDeque::new(),
-        visited,
-        lattice: IndexMap::new(),
-        def_use: HashMap::new(),
-        def_block: HashMap::new(),
-        visited_block,
-    };
-    t.edges.push_back(BlockId(0));
+/// Runs sparse conditional constant propagation. Returns true if it changed anything.
+pub fn sccp(proc: &mut Procedure) -> bool {
+    phase_scope("b3::sccp", || {
+        let mut t = Worklist::new(proc);
+        t.run();
+        let (replace_cnt, rewire_cnt) = t.replace_consts();
+
+        if proc.options.dump_b3_at_each_phase {
+            eprintln!(
+                "SCCP: replaced {} constants, rewired {} blocks",
+                replace_cnt, rewire_cnt
+            );
+        }
 
-    for i in 0..t.proc.values.size() {
-        t.def_use.insert(ValueId(i), vec![]);
-        t.def_block.insert(ValueId(i), vec![]);
+        if rewire_cnt != 0 {
+            proc.reset_reachability();
+            proc.invalidate_cfg();
+        }
+
+        replace_cnt != 0 || rewire_cnt != 0
+    })
+}
+
+impl<'a> Worklist<'a> {
+    fn new(proc: &'a mut Procedure) -> Self {
+        proc.reset_value_owners();
+
+        let visited_block = BitVector::with_capacity(proc.blocks.len());
+        let phi_children = PhiChildren::new(proc);
+
+        Self {
+            proc,
+            phi_children,
+            edges: VecDeque::new(),
+            uses: VecDeque::new(),
+            visited_block,
+            lattice: IndexMap::new(),
+            def_use: HashMap::new(),
+            constants: Vec::new(),
+        }
     }
-    t.build_def_uses();
 
-    // pick up either an edge or SSA value from worklilst, process it
+    /// Picks up either an edge or SSA value from the worklist and processes it until both are
+    /// empty.
+    fn run(&mut self) {
+        self.build_def_uses();
+        self.edges.push_back(BlockId(0));
 
-    loop {
-        if let Some(edge) = t.edges.pop_front() {
-            //if true || !t.visited.get(edge.0) {
-            let dest_visited = t.visited_block.get(edge.0);
-            t.visited.set(edge.0, true);
-            t.visited_block.set(edge.0, true);
+        loop {
+            if let Some(block) = self.edges.pop_front() {
+                if self.visited_block.get(block.0) {
+                    continue;
+                }
 
-            for i in 0..t.proc.block(edge).len() {
-                let value = t.proc.block(edge)[i];
+                self.visited_block.set(block.0, true);
 
-                if value.opcode(t.proc) == Opcode::Phi || !dest_visited {
-                    t.visit_value(value);
+                for i in 0..self.proc.block(block).len() {
+                    let value = self.proc.block(block)[i];
+                    self.visit_value(value);
                 }
-            }
 
-            if !dest_visited {
-                t.propagate(edge);
+                self.propagate(block);
+                continue;
             }
-            //}
-
-            continue;
-        }
 
-        if let Some(use_) = t.uses.pop_front() {
-            t.visit_value(use_);
-            continue;
-        }
+            if let Some(use_) = self.uses.pop_front() {
+                // Values in blocks that are not executable yet are visited once the block is.
+                let owner = self.proc.value(use_).owner.unwrap();
 
-        break;
-    }
+                if self.visited_block.get(owner.0) {
+                    self.visit_value(use_);
+                }
 
-    let (replace_cnt, rewire_cnt) = t.replace_consts();
+                continue;
+            }
 
-    if proc.options.dump_b3_at_each_phase {
-        eprintln!(
-            "SCCP: replaced {} constants, rewired {} blocks",
-            replace_cnt, rewire_cnt
-        );
+            break;
+        }
     }
-}
 
-impl<'a> Worklist<'a> {
     fn compute_lattice(&mut self, val: ValueId, args: &[ValueId]) -> Lattice {
         use Opcode::*;
 
-        let wrap_tri = |val: TriState| match val {
-            TriState::True => Some(Value::make_const32(1)),
-            TriState::False => Some(Value::make_const32(0)),
-            _ => None,
-        };
+        // Comparisons produce an integer of their own type, which is usually Int32.
+        let is_int64 = self.proc.value(val).typ().kind() == TypeKind::Int64;
+        let wrap_tri = |val: TriState| {
+            let result = match val {
+                TriState::True => 1,
+                TriState::False => 0,
+                _ => return None,
+            };
 
-        let result = match val.opcode(self.proc) {
-            Neg => self.proc.value(args[0]).neg_constant(),
-            Floor => self.proc.value(args[0]).floor_constant(),
+            Some(if is_int64 {
+                Value::make_const64(result)
+            } else {
+                Value::make_const32(result as i32)
+            })
+        };
 
-            Ceil => self.proc.value(args[0]).ceil_constant(),
+        let a = self.proc.value(args[0]);
+        let b = || self.proc.value(args[1]);
 
-            Trunc => self.proc.value(args[0]).trunc_constant(),
+        let result = match val.opcode(self.proc) {
+            Neg => a.neg_constant(),
+            Floor => a.floor_constant(),
+            Ceil => a.ceil_constant(),
+            Trunc => a.trunc_constant(),
+            ZExt32 => a.zext32_constant(),
+            SExt8 => a.sext8_constant(),
+            SExt16 => a.sext16_constant(),
+            SExt32 => a.sext32_constant(),
+            SExt8To64 => a.sext8to64_constant(),
+            SExt16To64 => a.sext16to64_constant(),
+            BitwiseCast => a.bitwise_cast_constant(),
+            IToD => a.i2d_constant(),
+            IToF => a.i2f_constant(),
+            DoubleToFloat => a.d2f_constant(),
+            FloatToDouble => a.f2d_constant(),
+
+            // Note that `Div` and `Mod` fold with chill semantics, like in `reduce_strength`.
+            Add => a.add_constant(b()),
+            Sub => a.sub_constant(b()),
+            Mul => a.mul_constant(b()),
+            Div => a.div_constant(b()),
+            UDiv => a.udiv_constant(b()),
+            Mod => a.mod_constant(b()),
+            UMod => a.umod_constant(b()),
+            BitAnd => a.bit_and_constant(b()),
+            BitOr => a.bit_or_constant(b()),
+            BitXor => a.bit_xor_constant(b()),
+            Shl => a.shl_constant(b()),
+            SShr => a.sshr_constant(b()),
+            ZShr => a.zshr_constant(b()),
+            RotR => a.rotr_constant(b()),
+            RotL => a.rotl_constant(b()),
+            FMax => a.fmax_constant(b()),
+            FMin => a.fmin_constant(b()),
+
+            Equal => wrap_tri(a.equal_constant(b())),
+            NotEqual => wrap_tri(invert(a.equal_constant(b()))),
+            EqualOrUnordered => wrap_tri(equal_or_unordered_constant(a, b())),
+            LessThan => wrap_tri(a.less_than_constant(b())),
+            GreaterThan => wrap_tri(a.greater_than_constant(b())),
+            LessEqual => wrap_tri(a.less_than_equal_constant(b())),
+            GreaterEqual => wrap_tri(a.greater_than_equal_constant(b())),
+            Above => wrap_tri(a.above_constant(b())),
+            AboveEqual => wrap_tri(a.above_equal_constant(b())),
+            Below => wrap_tri(a.below_constant(b())),
+            BelowEqual => wrap_tri(a.below_equal_constant(b())),
 
-            ZExt32 => self.proc.value(args[0]).zext32_constant(),
-
-            SExt16 => self.proc.value(args[0]).sext16_constant(),
-
-            SExt16To64 => self.proc.value(args[0]).sext16to64_constant(),
-
-            SExt32 => self.proc.value(args[0]).sext32_constant(),
-
-            SExt8 => self.proc.value(args[0]).sext8_constant(),
-
-            SExt8To64 => self.proc.value(args[0]).sext8to64_constant(),
-
-            DoubleToFloat => Some(Value::make_const_float(
-                self.proc.value(args[0]).as_double().unwrap() as f32,
-            )),
-
-            FloatToDouble => Some(Value::make_const_double(
-                self.proc.value(args[0]).as_float().unwrap() as f64,
-            )),
-
-            IToD => Some(Value::make_const_double(
-                self.proc.value(args[0]).as_int().unwrap() as f64,
-            )),
-
-            DToI => Some(Value::make_const64(
-                self.proc.value(args[0]).as_double().unwrap() as i64,
-            )),
-
-            IToF => Some(Value::make_const_float(
-                self.proc.value(args[0]).as_int().unwrap() as f32,
-            )),
-
-            FToI => Some(Value::make_const32(
-                self.proc.value(args[0]).as_float().unwrap() as i32,
-            )),
-
-            Add => self
-                .proc
-                .value(args[0])
-                .add_constant(self.proc.value(args[1])),
-
-            Sub => self
-                .proc
-                .value(args[0])
-                .sub_constant(self.proc.value(args[1])),
-
-            Mul => self
-                .proc
-                .value(args[0])
-                .mul_constant(self.proc.value(args[1])),
-
-            Div => self
-                .proc
-                .value(args[0])
-                .div_constant(self.proc.value(args[1])),
-
-            Mod => self
-                .proc
-                .value(args[0])
-                .mod_constant(self.proc.value(args[1])),
-
-            Shl => self
-                .proc
-                .value(args[0])
-                .shl_constant(self.proc.value(args[1])),
-
-            SShr => self
-                .proc
-                .value(args[0])
-                .sshr_constant(self.proc.value(args[1])),
-
-            ZShr => self
-                .proc
-                .value(args[0])
-                .zshr_constant(self.proc.value(args[1])),
-
-            BitAnd => self
-                .proc
-                .value(args[0])
-                .bit_and_constant(self.proc.value(args[1])),
-
-            BitOr => self
-                .proc
-                .value(args[0])
-                .bit_or_constant(self.proc.value(args[1])),
-
-            BitXor => self
-                .proc
-                .value(args[0])
-                .bit_xor_constant(self.proc.value(args[1])),
-
-            BitwiseCast => self.proc.value(args[0]).bitwise_cast_constant(),
-            Above => wrap_tri(
-                self.proc
-                    .value(args[0])
-                    .above_constant(self.proc.value(args[1])),
-            ),
-            AboveEqual => wrap_tri(
-                self.proc
-                    .value(args[0])
-                    .above_equal_constant(self.proc.value(args[1])),
-            ),
-            Below => wrap_tri(
-                self.proc
-                    .value(args[0])
-                    .below_constant(self.proc.value(args[1])),
-            ),
-            BelowEqual => wrap_tri(
-                self.proc
-                    .value(args[0])
-                    .below_equal_constant(self.proc.value(args[1])),
-            ),
-            GreaterThan => wrap_tri(
-                self.proc
-                    .value(args[0])
-                    .greater_than_constant(self.proc.value(args[1])),
-            ),
-            GreaterEqual => wrap_tri(
-                self.proc
-                    .value(args[0])
-                    .greater_than_equal_constant(self.proc.value(args[1])),
-            ),
-            LessThan => wrap_tri(
-                self.proc
-                    .value(args[0])
-                    .less_than_constant(self.proc.value(args[1])),
-            ),
-            LessEqual => wrap_tri(
-                self.proc
-                    .value(args[0])
-                    .less_than_equal_constant(self.proc.value(args[1])),
-            ),
-            _ => {
-                return Lattice {
-                    level: LatticeLevel::Bottom,
-                    val: None,
-                }
-            }
+            _ => None,
         };
 
-        if let Some(res) = result {
-            if res.is_constant() {
+        match result {
+            Some(res) if res.is_constant() && res.typ() == self.proc.value(val).typ() => {
                 let res = self.proc.add(res);
-                return Lattice {
-                    level: LatticeLevel::Constant,
-                    val: Some(res),
-                };
+                self.constants.push(res);
+                Lattice::constant(res)
             }
-        }
 
-        Lattice {
-            level: LatticeLevel::Bottom,
-            val: None,
+            _ => Lattice::BOTTOM,
         }
     }
 
     /// Meets all of phi arguments and computes result lattice
     fn meet(&mut self, val: ValueId) -> Lattice {
-        let mut optimistic_lt = Lattice {
-            level: LatticeLevel::Top,
-            val: None,
-        };
+        let mut optimistic_lt = Lattice::TOP;
 
-        let children = self.phi_children.at(val).iter().collect::<Vec<_>>();
-
-        for upsilon in children {
-            let arg = self.proc.value(upsilon).children[0];
-            let edge = self.proc.value(arg).owner.expect("upsilon must have owner");
-            // If incoming edge for phi is not visited, assume top optimistically.
-            // According to rules of meet:
+        for upsilon in self.phi_children.at(val).iter() {
+            // Upsilons are only visited once their block is executable, so until then their
+            // lattice is Top, which participates in meet() but does not affect the result:
             // 		Top ∩ any = any
-            // Top participates in meet() but does not affect the result, so here
-            // we will ignore Top and only take other lattices into consideration.
-
-            if self.visited.get(edge.0) {
-                let lt = self.get_lattice_cell(arg);
-
-                if lt.level == LatticeLevel::Constant {
-                    if optimistic_lt.level == LatticeLevel::Top {
-                        optimistic_lt = lt;
-                    } else {
-                        if !optimistic_lt.equals(&lt, self.proc) {
-                            // ConstantA ∩ ConstantB = Bottom
-                            return Lattice {
-                                level: LatticeLevel::Bottom,
-                                val: None,
-                            };
-                        }
-                    }
-                } else if lt.level == LatticeLevel::Bottom {
-                    // Bottom ∩ any = Bottom
-                    return Lattice {
-                        level: LatticeLevel::Bottom,
-                        val: None,
-                    };
-                } else {
-                    // Top ∩ any = any
-                    // Revisit this phi node later
-                    //self.uses.push_back(val);
-                }
-            } else {
-                // Top ∩ any = any
-                // Revisit this phi node later
-                //self.uses.push_back(val);
+            // The Phi is visited again once the Upsilon is.
+            let lt = self.get_lattice_cell(upsilon);
+            optimistic_lt = optimistic_lt.meet(&lt, self.proc);
+
+            if optimistic_lt.level == LatticeLevel::Bottom {
+                break;
             }
         }
 
@@ -385,324 +305,244 @@ impl<'a> Worklist<'a> {
             return;
         }
 
-        let old_lt = self.get_lattice_cell(val);
-
-        let cls = |this: &mut Self| {
-            let new_lt = this.get_lattice_cell(val);
-            if !new_lt.equals(&old_lt, this.proc) {
-                this.add_uses(val);
-            }
-        };
         use Opcode::*;
-        match val.opcode(self.proc) {
-            Opcode::Const32 | Opcode::Const64 | Opcode::ConstFloat | Opcode::ConstDouble => {
-                self.lattice.insert(
-                    val,
-                    Lattice {
-                        level: LatticeLevel::Constant,
-                        val: Some(val),
-                    },
-                );
-            }
-
-            Opcode::Identity => {
-                let arg = self.proc.value(val).children[0];
-                let arg_lt = self.get_lattice_cell(arg);
-
-                self.lattice.insert(val, arg_lt);
+        let new_lt = match val.opcode(self.proc) {
+            _ if is_const(self.proc, val) => Lattice::constant(val),
+
+            Jump | Branch | Switch => {
+                // The condition changed, so the block may have more executable successors.
+                let owner = self.proc.value(val).owner.unwrap();
+                self.propagate(owner);
+                return;
             }
 
-            Opcode::Phi => {
-                let lattice = self.meet(val);
-                self.lattice.insert(val, lattice);
-            }
+            Identity | Upsilon => self.get_lattice_cell(self.proc.value(val).children[0]),
 
-            Neg | Floor | Ceil | Trunc | ZExt32 | SExt32 | SExt16 | SExt8 | SExt16To64
-            | SExt8To64 | DoubleToFloat | FloatToDouble | IToD | DToI | IToF | FToI
-            | BitwiseCast => {
-                let lt = self.get_lattice_cell(self.proc.value(val).children[0]);
+            Phi => self.meet(val),
 
-                if lt.level == LatticeLevel::Constant {
-                    let nlt = self.compute_lattice(val, &[self.proc.value(val).children[0]]);
+            Select => {
+                let cond = self.get_lattice_cell(self.proc.value(val).children[0]);
+                let lt1 = self.get_lattice_cell(self.proc.value(val).children[1]);
+                let lt2 = self.get_lattice_cell(self.proc.value(val).children[2]);
 
-                    self.lattice.insert(val, nlt);
-                } else {
-                    self.lattice.insert(
-                        val,
-                        Lattice {
-                            level: lt.level,
-                            val: None,
-                        },
-                    );
+                match cond.level {
+                    LatticeLevel::Top => Lattice::TOP,
+                    LatticeLevel::Bottom => lt1.meet(&lt2, self.proc),
+                    LatticeLevel::Constant => match self.proc.value(cond.val.unwrap()).as_int() {
+                        Some(0) => lt2,
+                        Some(_) => lt1,
+                        None => lt1.meet(&lt2, self.proc),
+                    },
                 }
             }
 
-            Add | Sub | Mul | Div | Mod | Shl | SShr | ZShr | BitAnd | BitOr | BitXor | Above
-            | AboveEqual | Below | BelowEqual | LessEqual | LessThan | GreaterThan
-            | GreaterEqual => {
-                let lt1 = self.get_lattice_cell(self.proc.value(val).children[0]);
-                let lt2 = self.get_lattice_cell(self.proc.value(val).children[1]);
-
-                if lt1.level == LatticeLevel::Constant && lt2.level == LatticeLevel::Constant {
-                    let nlt = self.compute_lattice(
-                        val,
-                        &[
-                            self.proc.value(val).children[0],
-                            self.proc.value(val).children[1],
-                        ],
-                    );
-
-                    self.lattice.insert(val, nlt);
+            _ => {
+                let args = self.proc.value(val).children.to_vec();
+                let lts = args
+                    .iter()
+                    .map(|&arg| self.get_lattice_cell(arg))
+                    .collect::<Vec<_>>();
+
+                if lts.iter().any(|lt| lt.level == LatticeLevel::Bottom) {
+                    Lattice::BOTTOM
+                } else if lts.iter().any(|lt| lt.level == LatticeLevel::Top) {
+                    Lattice::TOP
                 } else {
-                    if lt1.level == LatticeLevel::Bottom || lt2.level == LatticeLevel::Bottom {
-                        self.lattice.insert(
-                            val,
-                            Lattice {
-                                level: LatticeLevel::Bottom,
-                                val: None,
-                            },
-                        );
-                    } else {
-                        self.lattice.insert(
-                            val,
-                            Lattice {
-                                level: LatticeLevel::Top,
-                                val: None,
-                            },
-                        );
-                    }
+                    let constants = lts.iter().map(|lt| lt.val.unwrap()).collect::<Vec<_>>();
+                    self.compute_lattice(val, &constants)
                 }
             }
+        };
 
-            _ => (),
-        }
+        let old_lt = self.get_lattice_cell(val);
+
+        // Lattices only ever go down. A constant that changes its value goes to Bottom.
+        let new_lt = match old_lt.level {
+            LatticeLevel::Top => new_lt,
+            _ if new_lt.level == LatticeLevel::Top => old_lt,
+            _ => old_lt.meet(&new_lt, self.proc),
+        };
 
-        cls(self);
+        if !new_lt.equals(&old_lt, self.proc) {
+            self.lattice.insert(val, new_lt);
+            self.add_uses(val);
+        }
     }
 
     /// build_def_uses builds def-use chain for some values early, because once the
     /// lattice of a value is changed, we need to update lattices of use. But we don't
     /// need all uses of it, only uses that can become constants would be added into
     /// re-visit worklist since no matter how many times they are revisited, uses which
-    /// can't become constants lattice remains unchanged, i.e. Bottom.
+    /// can't become constants lattice remains unchanged, i.e. Bottom. Branches and switches
+    /// are uses too, so that they can mark more successors as executable.
     fn build_def_uses(&mut self) {
-        for i in 0..self.proc.blocks.len() {
-            let block = BlockId(i);
-
-            for i in 0..self.proc.block(block).len() - 1 {
+        for block in (0..self.proc.blocks.len()).map(BlockId) {
+            for i in 0..self.proc.block(block).len() {
                 let val = self.proc.block(block)[i];
 
-                for i in 0..self.proc.value(val).children.len() {
-                    let arg = self.proc.value(val).children[i];
+                if !possible_const(self.proc, val) {
+                    continue;
+                }
 
-                    if possible_const(self.proc, arg) && possible_const(self.proc, val) {
-                        self.def_use.entry(arg).or_insert_with(Vec::new).push(val);
+                for &arg in self.proc.value(val).children.iter() {
+                    if possible_const(self.proc, arg) {
+                        self.def_use.entry(arg).or_default().push(val);
                     }
                 }
 
                 if val.opcode(self.proc) == Opcode::Phi {
-                    let upsilons = self.phi_children.at(val).iter().collect::<Vec<_>>();
-
-                    for upsilon in upsilons {
-                        self.def_use
-                            .entry(upsilon)
-                            .or_insert_with(Vec::new)
-                            .push(val);
+                    for upsilon in self.phi_children.at(val).iter() {
+                        self.def_use.entry(upsilon).or_default().push(val);
                     }
                 }
             }
-
-            let terminator = *self.proc.block(block).last().unwrap();
-            let control_value = match terminator.opcode(self.proc) {
-                Opcode::Branch | Opcode::Switch => Some(self.proc.value(terminator).children[0]),
-                _ => None,
-            };
-            if let Some(control_value) =
-                control_value.filter(|&control_value| possible_const(self.proc, control_value))
-            {
-                self.def_block
-                    .entry(control_value)
-                    .or_insert_with(Vec::new)
-                    .push(block);
-            }
         }
     }
+
     /// add_uses finds all uses of value and appends them into work list for further process
     fn add_uses(&mut self, val: ValueId) {
-        let mut uses = std::mem::take(&mut self.uses);
-
-        for &use_ in self.def_use[&val].iter() {
-            if val == use_ {
-                // phi may refer to itself as uses, ignore them to avoid
-                // re-visiting phi-nodes
-                continue;
-            }
-            uses.push_back(use_);
-        }
-
-        if val.opcode(self.proc) == Opcode::Phi {
-            let upsilons = self.phi_children.at(val);
-
-            for upsilon in upsilons.iter() {
-                if val == upsilon {
+        if let Some(uses) = self.def_use.get(&val) {
+            for &use_ in uses.iter() {
+                if val == use_ {
                     // phi may refer to itself as uses, ignore them to avoid
                     // re-visiting phi-nodes
                     continue;
                 }
-                uses.push_back(upsilon);
+
+                self.uses.push_back(use_);
             }
         }
-
-        self.uses = uses;
     }
+
     /// Propagates constants facts through CFG. If the block has single successor,
     /// add the successor anyway. If the block has multiple successors, only add the
     /// branch destination corresponding to lattice value of condition value.
     fn propagate(&mut self, block: BlockId) {
-        let control_value = *self.proc.block(block).last().unwrap();
+        let successors = self.proc.block(block).successor_list().clone();
+
+        match self.feasible_successor(block) {
+            Some(Some(successor)) => self.edges.push_back(successor),
+            Some(None) => (),
+            None => self
+                .edges
+                .extend(successors.iter().map(|&(successor, _)| successor)),
+        }
+    }
 
-        match self.proc.value(control_value).kind.opcode() {
-            Opcode::Jump => {
-                self.edges
-                    .push_back(self.proc.block(block).successor_list[0].0);
-            }
+    /// Returns the only successor that the terminator of `block` can go to, `Some(None)` if it is
+    /// not known yet where it goes, and `None` if it can go to any of its successors.
+    fn feasible_successor(&self, block: BlockId) -> Option<Option<BlockId>> {
+        let control_value = *self.proc.block(block).last()?;
+        let opcode = self.proc.value(control_value).kind.opcode();
 
-            Opcode::Branch | Opcode::Switch => {
-                let cond = self.proc.value(control_value).children[0];
-                let cond_lattice = self.get_lattice_cell(cond);
+        if opcode != Opcode::Branch && opcode != Opcode::Switch {
+            return None;
+        }
 
-                if cond_lattice.level == LatticeLevel::Bottom {
-                    for i in 0..self.proc.block(block).successor_list.len() {
-                        self.edges
-                            .push_back(self.proc.block(block).successor_list[i].0);
-                    }
-                } else if cond_lattice.level == LatticeLevel::Constant {
-                    let val = cond_lattice.val.unwrap();
-                    if self.proc.value(control_value).kind.opcode() == Opcode::Branch {
-                        let constant = self.proc.value(val).as_int().unwrap();
-                        if constant != 0 {
-                            self.edges
-                                .push_back(self.proc.block(block).successor_list[0].0);
-                        } else {
-                            self.edges
-                                .push_back(self.proc.block(block).successor_list[1].0);
-                        }
-                    } else {
-                        let branch_idx = self
-                            .proc
-                            .value(val)
-                            .as_int()
-                            .unwrap()
-                            .abs()
-                            .rem_euclid(self.proc.block(block).successor_list.len() as i64);
-
-                        self.edges.push_back(
-                            self.proc.block(block).successor_list[branch_idx as usize].0,
-                        );
-                    }
+        let cond = self.proc.value(control_value).children[0];
+        let cond_lattice = self.get_lattice_cell(cond);
+
+        match cond_lattice.level {
+            LatticeLevel::Top => Some(None),
+            LatticeLevel::Bottom => None,
+            LatticeLevel::Constant => {
+                let constant = self.proc.value(cond_lattice.val.unwrap()).as_int()?;
+                let successors = self.proc.block(block).successor_list();
+
+                if opcode == Opcode::Branch {
+                    let index = if constant != 0 { 0 } else { 1 };
+                    return Some(Some(successors[index].0));
                 }
-            }
 
-            Opcode::Return => {}
+                // Cases line up with the successors and the fall through is the last successor.
+                let cases = self.proc.value(control_value).switch_cases().unwrap();
+                let index = cases
+                    .iter()
+                    .position(|&case| case == constant)
+                    .unwrap_or(successors.len() - 1);
 
-            _ => unreachable!(),
+                Some(Some(successors[index].0))
+            }
         }
     }
 
-    fn get_lattice_cell(&mut self, val: ValueId) -> Lattice {
+    fn get_lattice_cell(&self, val: ValueId) -> Lattice {
         if !possible_const(self.proc, val) {
-            return Lattice {
-                level: LatticeLevel::Bottom,
-                val: None,
-            };
+            return Lattice::BOTTOM;
         }
 
-        self.lattice.get(&val).copied().unwrap_or(Lattice {
-            level: LatticeLevel::Top,
-            val: None,
-        })
+        self.lattice.get(&val).copied().unwrap_or(Lattice::TOP)
     }
 
     fn replace_consts(&mut self) -> (usize, usize) {
         let mut const_cnt = 0;
         let mut rewire_cnt = 0;
 
-        let cells = std::mem::take(&mut self.lattice);
-
-        for (val, lt) in cells.iter() {
-            let val = ValueId(val);
+        for block in (0..self.proc.blocks.len()).map(BlockId) {
+            // Blocks that are not executable become unreachable once branches are rewired.
+            if !self.visited_block.get(block.0) {
+                continue;
+            }
 
-            if lt.level == LatticeLevel::Constant {
-                if !is_const(self.proc, val) {
-                    let replacement = self.proc.value(lt.val.unwrap()).clone();
-                    let val = self.proc.value_mut(val);
+            for i in 0..self.proc.block(block).len() {
+                let val = self.proc.block(block)[i];
+                let lt = self.get_lattice_cell(val);
 
-                    val.kind = replacement.kind;
-                    val.data = replacement.data;
-                    val.children = replacement.children;
-                    val.num_children = replacement.num_children;
-                    const_cnt += 1;
+                if lt.level != LatticeLevel::Constant
+                    || is_const(self.proc, val)
+                    || self.proc.value(val).typ().kind() == TypeKind::Void
+                {
+                    continue;
                 }
 
-                if let Some(ctrl_block) = self.def_block.remove(&val) {
-                    for block in ctrl_block {
-                        if self.rewire_successor(block, val) {
-                            rewire_cnt += 1;
-                        }
+                if val.opcode(self.proc) == Opcode::Phi {
+                    for upsilon in self.phi_children.at(val).iter() {
+                        self.proc.value_mut(upsilon).replace_with_nop();
                     }
                 }
-            }
-        }
 
-        (const_cnt, rewire_cnt)
-    }
+                let replacement = self.proc.value(lt.val.unwrap()).clone();
+                let val = self.proc.value_mut(val);
 
-    fn rewire_successor(&mut self, block: BlockId, val: ValueId) -> bool {
-        let terminator = self.proc.block(block).last().copied().unwrap();
-
-        match terminator.opcode(self.proc) {
-            Opcode::Branch => {
-                let value = self.proc.value(val).as_int().unwrap();
-                let succ = if value != 0 {
-                    self.proc.block(block).successor_list[0].0
-                } else {
-                    self.proc.block(block).successor_list[1].0
-                };
-
-                self.proc
-                    .value_mut(terminator)
-                    .replace_with_jump(block, (succ, Frequency::Normal));
-                self.proc.block_mut(block).successor_list.clear();
-                self.proc
-                    .block_mut(block)
-                    .successor_list
-                    .push((succ, Frequency::Normal));
-                true
+                val.kind = replacement.kind;
+                val.data = replacement.data;
+                val.children = replacement.children;
+                val.num_children = replacement.num_children;
+                const_cnt += 1;
             }
 
-            Opcode::Switch => {
-                let value = self.proc.value(val).as_int().unwrap();
-                if value < 0 {
-                    return false;
-                }
-                if value >= self.proc.block(block).successor_list.len() as i64 {
-                    return false;
-                }
-                let succ = self.proc.block(block).successor_list[value as usize].0;
-
-                self.proc
-                    .value_mut(terminator)
-                    .replace_with_jump(block, (succ, Frequency::Normal));
-                self.proc.block_mut(block).successor_list.clear();
-                self.proc
-                    .block_mut(block)
-                    .successor_list
-                    .push((succ, Frequency::Normal));
-                true
+            if self.rewire_successor(block) {
+                rewire_cnt += 1;
             }
+        }
 
-            _ => false,
+        for constant in std::mem::take(&mut self.constants) {
+            self.proc.delete_value(constant);
         }
+
+        (const_cnt, rewire_cnt)
+    }
+
+    fn rewire_successor(&mut self, block: BlockId) -> bool {
+        let succ = match self.feasible_successor(block) {
+            Some(Some(succ)) => succ,
+            _ => return false,
+        };
+
+        let terminator = *self.proc.block(block).last().unwrap();
+        let target = *self
+            .proc
+            .block(block)
+            .successor_list()
+            .iter()
+            .find(|&&(successor, _)| successor == succ)
+            .unwrap();
+
+        self.proc
+            .value_mut(terminator)
+            .replace_with_jump(block, target);
+        self.proc.block_mut(block).set_successors(target);
+        true
     }
 }
 
@@ -719,17 +559,32 @@ fn possible_const(proc: &Procedure, val: ValueId) -> bool {
         Identity => true,
         Phi => true,
         Upsilon => true,
+        Select => true,
 
-        Abs | Neg | Floor | Ceil | Trunc | Sqrt | ZExt32 | SExt32 | SExt8 | SExt16 | SExt16To64
-        | SExt8To64 | BitwiseCast | DoubleToFloat | FloatToDouble | IToD | IToF | DToI | FToI => {
-            true
-        }
+        Neg | Floor | Ceil | Trunc | ZExt32 | SExt32 | SExt8 | SExt16 | SExt16To64 | SExt8To64
+        | BitwiseCast | DoubleToFloat | FloatToDouble | IToD | IToF => true,
 
         Jump | Branch | Switch => true,
 
-        Add | Sub | Mul | Div | Mod | Equal | EqualOrUnordered | NotEqual | Above | AboveEqual
-        | Below | BelowEqual | GreaterThan | GreaterEqual | LessThan | LessEqual | Shl | SShr
-        | ZShr | BitAnd | BitOr | BitXor => true,
+        Add | Sub | Mul | Div | UDiv | Mod | UMod | Equal | EqualOrUnordered | NotEqual | Above
+        | AboveEqual | Below | BelowEqual | GreaterThan | GreaterEqual | LessThan | LessEqual
+        | Shl | SShr | ZShr | RotR | RotL | BitAnd | BitOr | BitXor | FMax | FMin => true,
         _ => false,
     }
 }
+
+fn equal_or_unordered_constant(left: &Value, right: &Value) -> TriState {
+    let unordered = match (left.as_double(), right.as_double()) {
+        (Some(left), Some(right)) => left.is_nan() || right.is_nan(),
+        _ => match (left.as_float(), right.as_float()) {
+            (Some(left), Some(right)) => left.is_nan() || right.is_nan(),
+            _ => return TriState::Undeterminate,
+        },
+    };
+
+    if unordered {
+        TriState::True
+    } else {
+        left.equal_constant(right)
+    }
+}
@@ -0,0 +1,1208 @@
+use std::{ops::Range, rc::Rc};
+
+use indexmap::{IndexMap, IndexSet};
+use macroassembler::assembler::TargetMacroAssembler;
+use tinyvec::TinyVec;
+
+use crate::{
+    air::stack_slot::{StackSlotId, StackSlotKind},
+    block::{BlockId, Frequency},
+    data_section::DataSection,
+    effects::Effects,
+    jit::{reg::Reg, register_set::RegisterSetBuilder},
+    kind::Kind,
+    opcode::Opcode,
+    patchpoint_value::PatchpointValue,
+    procedure::{GlobalId, Procedure, ProcedureId},
+    simd::{simd_info, SIMD_LANES, SIMD_SIGN_MODES},
+    sparse_collection::SparseCollection,
+    stackmap_generation_params::StackmapGenerationParams,
+    stackmap_value::StackMapValue,
+    typ::{Aggregate, Type, TypeKind},
+    value::{NumChildren, Value, ValueData, ValueId, ValueRep, ValueRepKind},
+    variable::{Variable, VariableId},
+    width::Width,
+    Options,
+};
+
+/// Version of the format written by [`serialize_procedure`]. Bump it whenever the format changes;
+/// [`deserialize_procedure`] rejects data written with any other version.
+pub const SERIALIZATION_VERSION: u32 = 1;
+
+const MAGIC: &[u8; 4] = b"B3IR";
+
+/// Code generator of a Patchpoint or Check, see [`Procedure::stackmap_set_generator`].
+pub type StackmapGenerator = Rc<dyn Fn(&mut TargetMacroAssembler, &mut StackmapGenerationParams)>;
+
+/// Stackmap generators known by name.
+///
+/// Closures cannot be serialized, so [`serialize_procedure`] writes the name a generator was
+/// registered under and [`deserialize_procedure`] looks it up again. Use the generator returned by
+/// [`GeneratorRegistry::register`] when building the procedure so that it can be found.
+#[derive(Default, Clone)]
+pub struct GeneratorRegistry {
+    generators: IndexMap<String, StackmapGenerator>,
+}
+
+impl GeneratorRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `generator` under `name`, replacing any generator registered under the same name.
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        generator: impl Fn(&mut TargetMacroAssembler, &mut StackmapGenerationParams) + 'static,
+    ) -> StackmapGenerator {
+        let generator: StackmapGenerator = Rc::new(generator);
+        self.generators.insert(name.into(), generator.clone());
+        generator
+    }
+
+    pub fn get(&self, name: &str) -> Option<&StackmapGenerator> {
+        self.generators.get(name)
+    }
+
+    /// Name `generator` was registered under, compared by identity.
+    pub fn name_of(&self, generator: &StackmapGenerator) -> Option<&str> {
+        self.generators
+            .iter()
+            .find(|(_, registered)| {
+                Rc::as_ptr(registered).cast::<()>() == Rc::as_ptr(generator).cast::<()>()
+            })
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+/// Error reported by [`serialize_procedure`] and [`deserialize_procedure`]. `offset` is the
+/// position in the input at which deserialization failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerializationError {
+    pub offset: Option<usize>,
+    pub message: String,
+}
+
+impl std::fmt::Display for SerializationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+
+        if let Some(offset) = self.offset {
+            write!(f, " at byte {}", offset)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for SerializationError {}
+
+/// Writes `proc` in a compact binary format that [`deserialize_procedure`] reads back.
+///
+/// Values, blocks, variables, aggregate types, stack slots, data sections, the signature and
+/// pinned registers are kept, along with value and variable numbers. 64-bit constants that point
+/// into a data section are written relative to it, so they point into the new copy after
+/// deserialization. Stackmap generators are written by their name in `generators`. Options are
+/// not written, and addresses of `ProcedureAddr` and `GlobalAddr` have to be set again.
+///
+/// Registers are written by index, so the data can only be read on the same target.
+pub fn serialize_procedure(
+    proc: &Procedure,
+    generators: &GeneratorRegistry,
+) -> Result<Vec<u8>, SerializationError> {
+    let mut writer = Writer {
+        bytes: MAGIC.to_vec(),
+        proc,
+        generators,
+        opcodes: IndexSet::new(),
+    };
+
+    writer.uint(SERIALIZATION_VERSION as u64);
+    writer.string(std::env::consts::ARCH);
+    writer.procedure()?;
+    Ok(writer.bytes)
+}
+
+/// Reads a procedure written by [`serialize_procedure`], looking up stackmap generators by name in
+/// `generators`.
+pub fn deserialize_procedure(
+    bytes: &[u8],
+    options: Options,
+    generators: &GeneratorRegistry,
+) -> Result<Procedure, SerializationError> {
+    let mut reader = Reader {
+        bytes,
+        offset: 0,
+        proc: Procedure::new(options),
+        generators,
+        opcodes: vec![],
+        data_sections: vec![],
+        value_refs: vec![],
+        block_refs: vec![],
+    };
+
+    if !bytes.starts_with(MAGIC) {
+        return reader.error("not a serialized procedure");
+    }
+
+    reader.offset = MAGIC.len();
+
+    let version = reader.uint()?;
+    if version != SERIALIZATION_VERSION as u64 {
+        return reader.error(format!(
+            "unsupported version {}, expected {}",
+            version, SERIALIZATION_VERSION
+        ));
+    }
+
+    let arch = reader.string()?;
+    if arch != std::env::consts::ARCH {
+        return reader.error(format!("procedure was serialized on {}", arch));
+    }
+
+    reader.procedure()?;
+    reader.finish()
+}
+
+const WIDTHS: [Width; 5] = [Width::W8, Width::W16, Width::W32, Width::W64, Width::W128];
+
+const VALUE_REP_KINDS: [ValueRepKind; 12] = [
+    ValueRepKind::WarmAny,
+    ValueRepKind::ColdAny,
+    ValueRepKind::LateColdAny,
+    ValueRepKind::SomeRegister,
+    ValueRepKind::SomeRegisterWithClobber,
+    ValueRepKind::SomeEarlyRegister,
+    ValueRepKind::SomeLateRegister,
+    ValueRepKind::Register,
+    ValueRepKind::LateRegister,
+    ValueRepKind::Stack,
+    ValueRepKind::StackArgument,
+    ValueRepKind::Constant,
+];
+
+const NUM_CHILDREN: [NumChildren; 5] = [
+    NumChildren::Zero,
+    NumChildren::One,
+    NumChildren::Two,
+    NumChildren::Three,
+    NumChildren::VarArgs,
+];
+
+/// Tags of the `ValueData` variants. `DATA_ADDRESS` is a `Const64` that points into a data
+/// section.
+mod tag {
+    pub const NONE: u8 = 0;
+    pub const CONST32: u8 = 1;
+    pub const CONST64: u8 = 2;
+    pub const CONST128: u8 = 3;
+    pub const DOUBLE: u8 = 4;
+    pub const FLOAT: u8 = 5;
+    pub const MEMORY_VALUE: u8 = 6;
+    pub const ATOMIC_VALUE: u8 = 7;
+    pub const FENCE: u8 = 8;
+    pub const SIMD: u8 = 9;
+    pub const ARGUMENT: u8 = 10;
+    pub const PARAMETER: u8 = 11;
+    pub const EXTRACT: u8 = 12;
+    pub const CCALL_VALUE: u8 = 13;
+    pub const VARIABLE: u8 = 14;
+    pub const UPSILON: u8 = 15;
+    pub const STACKMAP: u8 = 16;
+    pub const PATCHPOINT: u8 = 17;
+    pub const SLOT_BASE: u8 = 18;
+    pub const SWITCH: u8 = 19;
+    pub const ALLOCA: u8 = 20;
+    pub const GET_ELEMENT_PTR: u8 = 21;
+    pub const PROCEDURE: u8 = 22;
+    pub const GLOBAL: u8 = 23;
+    pub const DATA_ADDRESS: u8 = 24;
+}
+
+/// Largest data section alignment accepted by [`deserialize_procedure`].
+const MAX_DATA_SECTION_ALIGNMENT: usize = 4096;
+
+struct Writer<'a> {
+    bytes: Vec<u8>,
+    proc: &'a Procedure,
+    generators: &'a GeneratorRegistry,
+    /// Opcodes are written by name once and referred to by their index in this set, so that
+    /// reordering `Opcode` does not change the format.
+    opcodes: IndexSet<Opcode>,
+}
+
+impl<'a> Writer<'a> {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    /// LEB128.
+    fn uint(&mut self, mut value: u64) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+
+            if value == 0 {
+                self.u8(byte);
+                return;
+            }
+
+            self.u8(byte | 0x80);
+        }
+    }
+
+    /// Zigzag encoded LEB128.
+    fn int(&mut self, value: i64) {
+        self.uint(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn usize(&mut self, value: usize) {
+        self.uint(value as u64);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.usize(bytes.len());
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn string(&mut self, string: &str) {
+        self.bytes(string.as_bytes());
+    }
+
+    fn range(&mut self, range: &Range<usize>) {
+        self.usize(range.start);
+        self.usize(range.end);
+    }
+
+    fn typ(&mut self, typ: Type) {
+        match typ.kind() {
+            TypeKind::Void => self.u8(0),
+            TypeKind::Int32 => self.u8(1),
+            TypeKind::Int64 => self.u8(2),
+            TypeKind::Float => self.u8(3),
+            TypeKind::Double => self.u8(4),
+            TypeKind::V128 => self.u8(5),
+            TypeKind::Aggregate => {
+                self.u8(6);
+                self.uint(typ.aggregate_index() as u64);
+            }
+        }
+    }
+
+    fn reg(&mut self, reg: Reg) {
+        self.u8(reg.index() as u8);
+    }
+
+    fn effects(&mut self, effects: &Effects) {
+        let flags = [
+            effects.terminal,
+            effects.exit_sideways,
+            effects.control_dependent,
+            effects.writes_local_state,
+            effects.reads_local_state,
+            effects.fence,
+        ];
+
+        self.u8(flags
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (i, &flag)| bits | (flag as u8) << i));
+        self.range(&effects.writes);
+        self.range(&effects.reads);
+    }
+
+    fn register_set(&mut self, set: &RegisterSetBuilder) {
+        let mut regs = vec![];
+        set.to_register_set()
+            .for_each_with_width_and_preserved(|reg, _, preserved| regs.push((reg, preserved)));
+
+        self.usize(regs.len());
+        for (reg, preserved) in regs {
+            self.reg(reg);
+            self.bool(preserved);
+        }
+    }
+
+    fn value_rep(&mut self, rep: &ValueRep) {
+        let kind = VALUE_REP_KINDS
+            .iter()
+            .position(|&kind| kind == rep.kind())
+            .unwrap();
+        self.usize(kind);
+
+        match rep.kind() {
+            ValueRepKind::Register | ValueRepKind::LateRegister => self.reg(rep.get_reg()),
+            ValueRepKind::Stack => self.int(rep.offset_from_fp() as i64),
+            ValueRepKind::StackArgument => self.int(rep.offset_from_sp() as i64),
+            ValueRepKind::Constant => self.int(rep.value()),
+            _ => {}
+        }
+    }
+
+    fn stackmap(
+        &mut self,
+        value: ValueId,
+        stackmap: &StackMapValue,
+    ) -> Result<(), SerializationError> {
+        self.usize(stackmap.reps.len());
+        for rep in stackmap.reps.iter() {
+            self.value_rep(rep);
+        }
+
+        let generators = self.generators;
+
+        match stackmap.generator {
+            Some(ref generator) => match generators.name_of(generator) {
+                Some(name) => {
+                    self.bool(true);
+                    self.string(name);
+                }
+                None => {
+                    return Err(SerializationError {
+                        offset: None,
+                        message: format!("generator of v@{} is not registered", value.0),
+                    })
+                }
+            },
+            None => self.bool(false),
+        }
+
+        self.register_set(&stackmap.early_clobbered);
+        self.register_set(&stackmap.late_clobbered);
+        self.register_set(&stackmap.used_registers);
+        Ok(())
+    }
+
+    fn procedure(&mut self) -> Result<(), SerializationError> {
+        let proc = self.proc;
+
+        self.usize(proc.aggregates.len());
+        for aggregate in proc.aggregates.iter() {
+            match aggregate.aggregate() {
+                Aggregate::Struct(fields) => {
+                    self.u8(0);
+                    self.usize(fields.len());
+                    for &field in fields.iter() {
+                        self.typ(field);
+                    }
+                }
+                Aggregate::Array(element, length) => {
+                    self.u8(1);
+                    self.typ(*element);
+                    self.usize(*length);
+                }
+            }
+        }
+
+        self.usize(proc.variables.size());
+        for index in 0..proc.variables.size() {
+            match proc.variables.at(VariableId(index)) {
+                Some(variable) => {
+                    self.bool(true);
+                    self.typ(variable.typ());
+                }
+                None => self.bool(false),
+            }
+        }
+
+        self.usize(proc.stack_slots.len());
+        for slot in proc.stack_slots.iter() {
+            self.uint(slot.byte_size() as u64);
+            self.uint(slot.min_alignment as u64);
+            self.bool(slot.kind() == StackSlotKind::Spill);
+        }
+
+        self.usize(proc.data_sections.len());
+        for section in proc.data_sections.iter() {
+            self.usize(section.alignment());
+            self.bytes(section.data());
+        }
+
+        match proc.signature() {
+            Some(signature) => {
+                self.bool(true);
+                self.usize(signature.params.len());
+                for &param in signature.params.iter() {
+                    self.typ(param);
+                }
+                self.typ(signature.ret);
+            }
+            None => self.bool(false),
+        }
+
+        self.usize(proc.num_entrypoints());
+
+        match proc.procedure_id() {
+            Some(id) => {
+                self.bool(true);
+                self.usize(id.0);
+            }
+            None => self.bool(false),
+        }
+
+        let mut pinned = vec![];
+        proc.pinned_regs
+            .to_register_set()
+            .for_each(|reg| pinned.push(reg));
+        self.usize(pinned.len());
+        for reg in pinned {
+            self.reg(reg);
+        }
+
+        for value in proc.values.iter() {
+            self.opcodes.insert(value.kind.opcode());
+        }
+
+        self.usize(self.opcodes.len());
+        for index in 0..self.opcodes.len() {
+            let name = self.opcodes[index].to_string();
+            self.string(&name);
+        }
+
+        self.usize(proc.blocks.len());
+        for block in proc.blocks.iter() {
+            self.bytes
+                .extend_from_slice(&block.frequency().to_bits().to_le_bytes());
+
+            self.usize(block.len());
+            for &value in block.iter() {
+                self.usize(value.0);
+            }
+
+            self.usize(block.predecessor_list().len());
+            for &predecessor in block.predecessor_list().iter() {
+                self.usize(predecessor.0);
+            }
+
+            self.usize(block.successor_list().len());
+            for &(successor, frequency) in block.successor_list().iter() {
+                self.usize(successor.0);
+                self.bool(frequency == Frequency::Rare);
+            }
+        }
+
+        self.usize(proc.values.size());
+        for index in 0..proc.values.size() {
+            match proc.values.at(ValueId(index)) {
+                Some(value) => {
+                    self.bool(true);
+                    self.value(value)?;
+                }
+                None => self.bool(false),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn value(&mut self, value: &Value) -> Result<(), SerializationError> {
+        let opcode = self.opcodes.get_index_of(&value.kind.opcode()).unwrap();
+        self.usize(opcode);
+        self.u8(value.kind.is_chill() as u8
+            | (value.kind.traps() as u8) << 1
+            | (value.kind.is_sensitive_to_nan() as u8) << 2);
+        self.typ(value.typ);
+
+        match value.owner {
+            Some(owner) => {
+                self.bool(true);
+                self.usize(owner.0);
+            }
+            None => self.bool(false),
+        }
+
+        let num_children = NUM_CHILDREN
+            .iter()
+            .position(|&num| num == value.num_children)
+            .unwrap();
+        self.usize(num_children);
+        self.usize(value.children.len());
+        for &child in value.children.iter() {
+            self.usize(child.0);
+        }
+
+        match value.data {
+            ValueData::None => self.u8(tag::NONE),
+            ValueData::Const32(x) => {
+                self.u8(tag::CONST32);
+                self.int(x as i64);
+            }
+            ValueData::Const64(x) => {
+                let section = self.proc.data_sections.iter().position(|section| {
+                    let start = section.data().as_ptr() as usize;
+                    (start..start + section.size()).contains(&(x as usize))
+                });
+
+                match section {
+                    Some(section) => {
+                        let start = self.proc.data_sections[section].data().as_ptr() as usize;
+                        self.u8(tag::DATA_ADDRESS);
+                        self.usize(section);
+                        self.usize(x as usize - start);
+                    }
+                    None => {
+                        self.u8(tag::CONST64);
+                        self.int(x);
+                    }
+                }
+            }
+            ValueData::Const128(x) => {
+                self.u8(tag::CONST128);
+                self.bytes.extend_from_slice(&x.to_le_bytes());
+            }
+            ValueData::Double(x) => {
+                self.u8(tag::DOUBLE);
+                self.bytes.extend_from_slice(&x.to_le_bytes());
+            }
+            ValueData::Float(x) => {
+                self.u8(tag::FLOAT);
+                self.bytes.extend_from_slice(&x.to_le_bytes());
+            }
+            ValueData::MemoryValue {
+                offset,
+                ref range,
+                ref fence_range,
+            } => {
+                self.u8(tag::MEMORY_VALUE);
+                self.int(offset as i64);
+                self.range(range);
+                self.range(fence_range);
+            }
+            ValueData::AtomicValue {
+                width,
+                offset,
+                ref range,
+                ref fence_range,
+            } => {
+                self.u8(tag::ATOMIC_VALUE);
+                self.usize(WIDTHS.iter().position(|&x| x == width).unwrap());
+                self.int(offset as i64);
+                self.range(range);
+                self.range(fence_range);
+            }
+            ValueData::Fence {
+                ref read,
+                ref write,
+            } => {
+                self.u8(tag::FENCE);
+                self.range(read);
+                self.range(write);
+            }
+            ValueData::SIMD { info, immediate } => {
+                self.u8(tag::SIMD);
+                let lane = SIMD_LANES.iter().position(|&x| x == info.lane).unwrap();
+                let sign_mode = SIMD_SIGN_MODES
+                    .iter()
+                    .position(|&x| x == info.sign_mode)
+                    .unwrap();
+                self.usize(lane);
+                self.usize(sign_mode);
+                self.u8(immediate);
+            }
+            ValueData::Argument(reg) => {
+                self.u8(tag::ARGUMENT);
+                self.reg(reg);
+            }
+            ValueData::Parameter(index) => {
+                self.u8(tag::PARAMETER);
+                self.usize(index);
+            }
+            ValueData::Extract(index) => {
+                self.u8(tag::EXTRACT);
+                self.usize(index);
+            }
+            ValueData::CCallValue {
+                ref effects,
+                variadic,
+            } => {
+                self.u8(tag::CCALL_VALUE);
+                self.effects(effects);
+                self.bool(variadic);
+            }
+            ValueData::Variable(variable) => {
+                self.u8(tag::VARIABLE);
+                self.usize(variable.0);
+            }
+            ValueData::Upsilon(phi) => {
+                self.u8(tag::UPSILON);
+                match phi {
+                    Some(phi) => {
+                        self.bool(true);
+                        self.usize(phi.0);
+                    }
+                    None => self.bool(false),
+                }
+            }
+            ValueData::StackMap(ref stackmap) => {
+                self.u8(tag::STACKMAP);
+                self.stackmap(ValueId(value.index), stackmap)?;
+            }
+            ValueData::Patchpoint(ref patchpoint) => {
+                self.u8(tag::PATCHPOINT);
+                self.stackmap(ValueId(value.index), &patchpoint.base)?;
+                self.effects(&patchpoint.effects);
+                self.usize(patchpoint.result_constraints.len());
+                for rep in patchpoint.result_constraints.iter() {
+                    self.value_rep(rep);
+                }
+                self.u8(patchpoint.num_gp_scratch_registers);
+                self.u8(patchpoint.num_fp_scratch_registers);
+            }
+            ValueData::SlotBase(slot) => {
+                self.u8(tag::SLOT_BASE);
+                self.usize(slot.0);
+            }
+            ValueData::Switch(ref cases) => {
+                self.u8(tag::SWITCH);
+                self.usize(cases.len());
+                for &case in cases.iter() {
+                    self.int(case);
+                }
+            }
+            ValueData::Alloca(typ) => {
+                self.u8(tag::ALLOCA);
+                self.typ(typ);
+            }
+            ValueData::GetElementPtr(typ) => {
+                self.u8(tag::GET_ELEMENT_PTR);
+                self.typ(typ);
+            }
+            ValueData::Procedure(id) => {
+                self.u8(tag::PROCEDURE);
+                self.usize(id.0);
+            }
+            ValueData::Global(id) => {
+                self.u8(tag::GLOBAL);
+                self.usize(id.0);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    proc: Procedure,
+    generators: &'a GeneratorRegistry,
+    opcodes: Vec<Opcode>,
+    /// Start address of every data section in the new procedure.
+    data_sections: Vec<usize>,
+    /// Values and blocks referred to at each offset. They may be defined after their use, so they
+    /// are checked once everything is read.
+    value_refs: Vec<(usize, ValueId)>,
+    block_refs: Vec<(usize, BlockId)>,
+}
+
+impl<'a> Reader<'a> {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, SerializationError> {
+        Err(SerializationError {
+            offset: Some(self.offset),
+            message: message.into(),
+        })
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], SerializationError> {
+        if self.bytes.len() - self.offset < count {
+            return self.error("unexpected end of data");
+        }
+
+        let bytes = &self.bytes[self.offset..self.offset + count];
+        self.offset += count;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], SerializationError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, SerializationError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, SerializationError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            x => self.error(format!("invalid bool {}", x)),
+        }
+    }
+
+    fn uint(&mut self) -> Result<u64, SerializationError> {
+        let mut value = 0u64;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.u8()?;
+
+            if shift == 63 && byte > 1 {
+                return self.error("integer is too large");
+            }
+
+            value |= ((byte & 0x7f) as u64) << shift;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+
+            shift += 7;
+        }
+    }
+
+    fn int(&mut self) -> Result<i64, SerializationError> {
+        let value = self.uint()?;
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    fn usize(&mut self) -> Result<usize, SerializationError> {
+        let value = self.uint()?;
+
+        match usize::try_from(value) {
+            Ok(value) => Ok(value),
+            Err(_) => self.error(format!("{} does not fit in usize", value)),
+        }
+    }
+
+    /// Read a length. Every element takes at least one byte, so longer lengths are rejected
+    /// before anything is allocated for them.
+    fn count(&mut self) -> Result<usize, SerializationError> {
+        let count = self.usize()?;
+
+        if count > self.bytes.len() - self.offset {
+            return self.error(format!("length {} is past the end of the data", count));
+        }
+
+        Ok(count)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], SerializationError> {
+        let count = self.count()?;
+        self.take(count)
+    }
+
+    fn string(&mut self) -> Result<String, SerializationError> {
+        let bytes = self.bytes()?;
+
+        match std::str::from_utf8(bytes) {
+            Ok(string) => Ok(string.to_string()),
+            Err(_) => self.error("invalid UTF-8"),
+        }
+    }
+
+    fn range(&mut self) -> Result<Range<usize>, SerializationError> {
+        Ok(self.usize()?..self.usize()?)
+    }
+
+    /// Read an index into a table of `len` entries.
+    fn index(&mut self, len: usize, what: &str) -> Result<usize, SerializationError> {
+        let index = self.usize()?;
+
+        if index >= len {
+            return self.error(format!("{} {} does not exist", what, index));
+        }
+
+        Ok(index)
+    }
+
+    fn typ(&mut self) -> Result<Type, SerializationError> {
+        Ok(match self.u8()? {
+            0 => Type::Void,
+            1 => Type::Int32,
+            2 => Type::Int64,
+            3 => Type::Float,
+            4 => Type::Double,
+            5 => Type::V128,
+            6 => Type::aggregate(self.index(self.proc.aggregates.len(), "aggregate")? as u32),
+            x => return self.error(format!("invalid type {}", x)),
+        })
+    }
+
+    fn reg(&mut self) -> Result<Reg, SerializationError> {
+        let index = self.u8()?;
+
+        if index as usize > Reg::last().index() {
+            return self.error(format!("invalid register {}", index));
+        }
+
+        Ok(Reg::from_index(index))
+    }
+
+    fn value_ref(&mut self) -> Result<ValueId, SerializationError> {
+        let offset = self.offset;
+        let value = ValueId(self.usize()?);
+        self.value_refs.push((offset, value));
+        Ok(value)
+    }
+
+    fn block_ref(&mut self) -> Result<BlockId, SerializationError> {
+        let offset = self.offset;
+        let block = BlockId(self.usize()?);
+        self.block_refs.push((offset, block));
+        Ok(block)
+    }
+
+    fn effects(&mut self) -> Result<Effects, SerializationError> {
+        let flags = self.u8()?;
+        let flag = |i: u32| flags & (1 << i) != 0;
+
+        Ok(Effects {
+            terminal: flag(0),
+            exit_sideways: flag(1),
+            control_dependent: flag(2),
+            writes_local_state: flag(3),
+            reads_local_state: flag(4),
+            fence: flag(5),
+            writes: self.range()?,
+            reads: self.range()?,
+        })
+    }
+
+    fn register_set(&mut self) -> Result<RegisterSetBuilder, SerializationError> {
+        let mut set = RegisterSetBuilder::new();
+
+        for _ in 0..self.count()? {
+            let reg = self.reg()?;
+            let width = if self.bool()? {
+                Width::W128
+            } else {
+                Width::W64
+            };
+            set.add(reg, width);
+        }
+
+        Ok(set)
+    }
+
+    fn value_rep(&mut self) -> Result<ValueRep, SerializationError> {
+        let kind = VALUE_REP_KINDS[self.index(VALUE_REP_KINDS.len(), "value rep kind")?];
+
+        Ok(match kind {
+            ValueRepKind::Register => ValueRep::reg(self.reg()?),
+            ValueRepKind::LateRegister => ValueRep::late_reg(self.reg()?),
+            ValueRepKind::Stack => ValueRep::stack(self.int()? as isize),
+            ValueRepKind::StackArgument => ValueRep::stack_argument(self.int()? as isize),
+            ValueRepKind::Constant => ValueRep::constant(self.int()?),
+            _ => ValueRep::new(kind),
+        })
+    }
+
+    fn stackmap(&mut self) -> Result<StackMapValue, SerializationError> {
+        let mut reps = vec![];
+        for _ in 0..self.count()? {
+            reps.push(self.value_rep()?);
+        }
+
+        let generator = if self.bool()? {
+            let name = self.string()?;
+            match self.generators.get(&name) {
+                Some(generator) => Some(generator.clone()),
+                None => return self.error(format!("generator `{}` is not registered", name)),
+            }
+        } else {
+            None
+        };
+
+        Ok(StackMapValue {
+            reps,
+            generator,
+            early_clobbered: self.register_set()?,
+            late_clobbered: self.register_set()?,
+            used_registers: self.register_set()?,
+        })
+    }
+
+    fn procedure(&mut self) -> Result<(), SerializationError> {
+        for _ in 0..self.count()? {
+            let index = self.proc.aggregates.len();
+
+            let typ = match self.u8()? {
+                0 => {
+                    let mut fields = vec![];
+                    for _ in 0..self.count()? {
+                        fields.push(self.typ()?);
+                    }
+                    self.proc.add_struct_type(&fields)
+                }
+                1 => {
+                    let element = self.typ()?;
+                    let length = self.usize()?;
+                    self.proc.add_array_type(element, length)
+                }
+                x => return self.error(format!("invalid aggregate {}", x)),
+            };
+
+            if typ.aggregate_index() as usize != index {
+                return self.error(format!("agg@{} is defined twice", typ.aggregate_index()));
+            }
+        }
+
+        let mut variables = vec![];
+        for index in 0..self.count()? {
+            variables.push(match self.bool()? {
+                true => Some(Variable::new(index, self.typ()?)),
+                false => None,
+            });
+        }
+        self.proc.variables = SparseCollection::from_vec(variables);
+
+        for _ in 0..self.count()? {
+            let size = self.usize()?;
+            let alignment = self.usize()?;
+            if !alignment.is_power_of_two() || alignment > 16 {
+                return self.error(format!("invalid stack slot alignment {}", alignment));
+            }
+
+            let kind = match self.bool()? {
+                true => StackSlotKind::Spill,
+                false => StackSlotKind::Locked,
+            };
+            self.proc.add_aligned_stack_slot(size, alignment, kind);
+        }
+
+        for _ in 0..self.count()? {
+            let alignment = self.usize()?;
+            if !alignment.is_power_of_two() || alignment > MAX_DATA_SECTION_ALIGNMENT {
+                return self.error(format!("invalid alignment {}", alignment));
+            }
+
+            let data = self.bytes()?;
+            let mut section = DataSection::new_aligned(data.len(), alignment);
+            section.data_mut().copy_from_slice(data);
+            self.data_sections.push(section.data().as_ptr() as usize);
+            self.proc.data_sections.push(section);
+        }
+
+        if self.bool()? {
+            let mut params = vec![];
+            for _ in 0..self.count()? {
+                params.push(self.typ()?);
+            }
+            let ret = self.typ()?;
+            self.proc.set_signature(&params, ret);
+        }
+
+        let num_entrypoints = self.usize()?;
+        self.proc.set_num_entrypoints(num_entrypoints);
+
+        if self.bool()? {
+            let id = self.usize()?;
+            self.proc.set_procedure_id(ProcedureId(id));
+        }
+
+        for _ in 0..self.count()? {
+            let reg = self.reg()?;
+            self.proc.pin_register(reg);
+        }
+
+        for _ in 0..self.count()? {
+            let name = self.string()?;
+            match name.parse() {
+                Ok(opcode) => self.opcodes.push(opcode),
+                Err(()) => return self.error(format!("unknown opcode `{}`", name)),
+            }
+        }
+
+        for _ in 0..self.count()? {
+            let frequency = f64::from_bits(u64::from_le_bytes(self.array()?));
+            let block = self.proc.add_block(frequency);
+
+            let mut values = vec![];
+            for _ in 0..self.count()? {
+                values.push(self.value_ref()?);
+            }
+
+            let mut predecessors = vec![];
+            for _ in 0..self.count()? {
+                predecessors.push(self.block_ref()?);
+            }
+
+            let mut successors = vec![];
+            for _ in 0..self.count()? {
+                let successor = self.block_ref()?;
+                let frequency = match self.bool()? {
+                    true => Frequency::Rare,
+                    false => Frequency::Normal,
+                };
+                successors.push((successor, frequency));
+            }
+
+            let basic_block = self.proc.block_mut(block);
+            basic_block.values = values;
+            basic_block.predecessor_list = predecessors;
+            basic_block.successor_list = successors;
+        }
+
+        let mut values = vec![];
+        for _ in 0..self.count()? {
+            values.push(match self.bool()? {
+                true => Some(self.value()?),
+                false => None,
+            });
+        }
+        self.proc.values = SparseCollection::from_vec(values);
+
+        if self.offset != self.bytes.len() {
+            return self.error("unexpected data after the procedure");
+        }
+
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Value, SerializationError> {
+        let opcode = self.index(self.opcodes.len(), "opcode")?;
+        let opcode = self.opcodes[opcode];
+        let flags = self.u8()?;
+        let mut kind = Kind::new(opcode);
+        kind.set_is_chill(flags & 1 != 0);
+        kind.set_traps(flags & 2 != 0);
+        kind.set_sensitive_to_nan(flags & 4 != 0);
+
+        let typ = self.typ()?;
+
+        let owner = match self.bool()? {
+            true => Some(self.block_ref()?),
+            false => None,
+        };
+
+        let num_children = NUM_CHILDREN[self.index(NUM_CHILDREN.len(), "child count")?];
+        let mut children = vec![];
+        for _ in 0..self.count()? {
+            children.push(self.value_ref()?);
+        }
+
+        if num_children != NumChildren::VarArgs && num_children as usize != children.len() {
+            return self.error(format!(
+                "{} children for {:?}",
+                children.len(),
+                num_children
+            ));
+        }
+
+        let data = match self.u8()? {
+            tag::NONE => ValueData::None,
+            tag::CONST32 => ValueData::Const32(self.int()? as i32),
+            tag::CONST64 => ValueData::Const64(self.int()?),
+            tag::CONST128 => ValueData::Const128(i128::from_le_bytes(self.array()?)),
+            tag::DOUBLE => ValueData::Double(u64::from_le_bytes(self.array()?)),
+            tag::FLOAT => ValueData::Float(u32::from_le_bytes(self.array()?)),
+            tag::MEMORY_VALUE => ValueData::MemoryValue {
+                offset: self.int()? as i32,
+                range: self.range()?,
+                fence_range: self.range()?,
+            },
+            tag::ATOMIC_VALUE => ValueData::AtomicValue {
+                width: WIDTHS[self.index(WIDTHS.len(), "width")?],
+                offset: self.int()? as i32,
+                range: self.range()?,
+                fence_range: self.range()?,
+            },
+            tag::FENCE => ValueData::Fence {
+                read: self.range()?,
+                write: self.range()?,
+            },
+            tag::SIMD => {
+                let lane = SIMD_LANES[self.index(SIMD_LANES.len(), "lane")?];
+                let sign_mode = SIMD_SIGN_MODES[self.index(SIMD_SIGN_MODES.len(), "sign mode")?];
+                ValueData::SIMD {
+                    info: simd_info(lane, sign_mode),
+                    immediate: self.u8()?,
+                }
+            }
+            tag::ARGUMENT => ValueData::Argument(self.reg()?),
+            tag::PARAMETER => ValueData::Parameter(self.usize()?),
+            tag::EXTRACT => ValueData::Extract(self.usize()?),
+            tag::CCALL_VALUE => ValueData::CCallValue {
+                effects: self.effects()?,
+                variadic: self.bool()?,
+            },
+            tag::VARIABLE => {
+                let variable = VariableId(self.usize()?);
+                if self.proc.variables.at(variable).is_none() {
+                    return self.error(format!("var@{} does not exist", variable.0));
+                }
+                ValueData::Variable(variable)
+            }
+            tag::UPSILON => ValueData::Upsilon(match self.bool()? {
+                true => Some(self.value_ref()?),
+                false => None,
+            }),
+            tag::STACKMAP => ValueData::StackMap(self.stackmap()?),
+            tag::PATCHPOINT => {
+                let base = self.stackmap()?;
+                let effects = self.effects()?;
+                let mut result_constraints = TinyVec::new();
+                for _ in 0..self.count()? {
+                    result_constraints.push(self.value_rep()?);
+                }
+
+                ValueData::Patchpoint(PatchpointValue {
+                    base,
+                    effects,
+                    result_constraints,
+                    num_gp_scratch_registers: self.u8()?,
+                    num_fp_scratch_registers: self.u8()?,
+                })
+            }
+            tag::SLOT_BASE => ValueData::SlotBase(StackSlotId(
+                self.index(self.proc.stack_slots.len(), "slot")?,
+            )),
+            tag::SWITCH => {
+                let mut cases = vec![];
+                for _ in 0..self.count()? {
+                    cases.push(self.int()?);
+                }
+                ValueData::Switch(cases)
+            }
+            tag::ALLOCA => ValueData::Alloca(self.typ()?),
+            tag::GET_ELEMENT_PTR => ValueData::GetElementPtr(self.typ()?),
+            tag::PROCEDURE => ValueData::Procedure(ProcedureId(self.usize()?)),
+            tag::GLOBAL => ValueData::Global(GlobalId(self.usize()?)),
+            tag::DATA_ADDRESS => {
+                let section = self.index(self.data_sections.len(), "data section")?;
+                let offset = self.usize()?;
+                if offset >= self.proc.data_sections[section].size() {
+                    return self.error(format!("offset {} is outside of the data section", offset));
+                }
+                ValueData::Const64((self.data_sections[section] + offset) as i64)
+            }
+            x => return self.error(format!("invalid value data {}", x)),
+        };
+
+        let mut value = Value::new(kind, typ, num_children, &children, data);
+        value.owner = owner;
+        Ok(value)
+    }
+
+    fn finish(self) -> Result<Procedure, SerializationError> {
+        let values = &self.proc.values;
+        if let Some(&(offset, value)) = self
+            .value_refs
+            .iter()
+            .find(|(_, value)| values.at(*value).is_none())
+        {
+            return Err(SerializationError {
+                offset: Some(offset),
+                message: format!("v@{} does not exist", value.0),
+            });
+        }
+
+        let num_blocks = self.proc.blocks.len();
+        if let Some(&(offset, block)) = self
+            .block_refs
+            .iter()
+            .find(|(_, block)| block.0 >= num_blocks)
+        {
+            return Err(SerializationError {
+                offset: Some(offset),
+                message: format!("BB{} does not exist", block.0),
+            });
+        }
+
+        Ok(self.proc)
+    }
+}
@@ -0,0 +1,61 @@
+pub use macroassembler::jit::simd_info::{SIMDInfo, SIMDLane, SIMDSignMode};
+
+use crate::{typ::Type, width::Width};
+
+/// All SIMD lanes.
+pub const SIMD_LANES: [SIMDLane; 7] = [
+    SIMDLane::V128,
+    SIMDLane::I8x16,
+    SIMDLane::I16x8,
+    SIMDLane::I32x4,
+    SIMDLane::I64x2,
+    SIMDLane::F32x4,
+    SIMDLane::F64x2,
+];
+
+/// All SIMD sign modes.
+pub const SIMD_SIGN_MODES: [SIMDSignMode; 3] = [
+    SIMDSignMode::None,
+    SIMDSignMode::Signed,
+    SIMDSignMode::Unsigned,
+];
+
+pub const fn simd_info(lane: SIMDLane, sign_mode: SIMDSignMode) -> SIMDInfo {
+    SIMDInfo { lane, sign_mode }
+}
+
+pub const fn element_count(lane: SIMDLane) -> usize {
+    match lane {
+        SIMDLane::V128 => 1,
+        SIMDLane::I8x16 => 16,
+        SIMDLane::I16x8 => 8,
+        SIMDLane::I32x4 | SIMDLane::F32x4 => 4,
+        SIMDLane::I64x2 | SIMDLane::F64x2 => 2,
+    }
+}
+
+pub const fn element_width(lane: SIMDLane) -> Width {
+    match lane {
+        SIMDLane::V128 => Width::W128,
+        SIMDLane::I8x16 => Width::W8,
+        SIMDLane::I16x8 => Width::W16,
+        SIMDLane::I32x4 | SIMDLane::F32x4 => Width::W32,
+        SIMDLane::I64x2 | SIMDLane::F64x2 => Width::W64,
+    }
+}
+
+pub const fn is_floating_point_lane(lane: SIMDLane) -> bool {
+    matches!(lane, SIMDLane::F32x4 | SIMDLane::F64x2)
+}
+
+/// B3 type of a single element of `lane`. Elements narrower than 32 bits are carried as
+/// `Type::Int32`.
+pub const fn scalar_type(lane: SIMDLane) -> Type {
+    match lane {
+        SIMDLane::V128 => Type::V128,
+        SIMDLane::I8x16 | SIMDLane::I16x8 | SIMDLane::I32x4 => Type::Int32,
+        SIMDLane::I64x2 => Type::Int64,
+        SIMDLane::F32x4 => Type::Float,
+        SIMDLane::F64x2 => Type::Double,
+    }
+}
@@ -16,6 +16,32 @@ impl<T: SparseElement> SparseCollection<T> {
         }
     }
 
+    /// Create a collection where every element keeps its position in `elements` as its ID. The
+    /// IDs of `None` entries are free and will be reused.
+    pub fn from_vec(elements: Vec<Option<T>>) -> Self {
+        let mut index_free_list = Vec::new();
+
+        let vector = elements
+            .into_iter()
+            .enumerate()
+            .map(|(index, element)| match element {
+                Some(mut element) => {
+                    element.set_id(index.into());
+                    Some(element)
+                }
+                None => {
+                    index_free_list.push(index);
+                    None
+                }
+            })
+            .collect();
+
+        Self {
+            vector,
+            index_free_list,
+        }
+    }
+
     pub fn add(&mut self, mut element: T) -> T::Id {
         let id = if let Some(id) = self.index_free_list.pop() {
             id
@@ -0,0 +1,171 @@
+use crate::{
+    air::ccalling_convention::{ccall_argument_location_count, ccall_argument_locations},
+    jit::reg::Reg,
+    utils::phase_scope::phase_scope,
+    BlockId, Frequency, NumChildren, Opcode, Procedure, ValueId,
+};
+
+/// Turns tail calls of the procedure to itself into jumps back to the entry.
+///
+/// The entry block is split: it keeps reading the arguments and stores them into fresh variables,
+/// the rest of it becomes the loop header and reads arguments from these variables. A self tail
+/// call then just assigns new values to the variables and jumps to the header. The variables are
+/// turned into SSA form by `fix_ssa` later.
+///
+/// A call is only rewritten if every `Parameter` and `ArgumentReg` read by the procedure is
+/// assigned by the call with the same type, and none of them is an aggregate. Other calls stay real
+/// tail calls.
+pub fn tail_calls_to_loops(proc: &mut Procedure) -> bool {
+    phase_scope("tail_calls_to_loops", || {
+        let self_id = match proc.procedure_id() {
+            Some(id) => id,
+            None => return false,
+        };
+
+        if proc.num_entrypoints() != 1 {
+            return false;
+        }
+
+        let mut arguments = vec![];
+
+        for block in (0..proc.blocks.len()).map(BlockId) {
+            for &value in proc.block(block).iter() {
+                if matches!(value.opcode(proc), Opcode::ArgumentReg | Opcode::Parameter) {
+                    arguments.push(value);
+                }
+            }
+        }
+
+        let mut sites = vec![];
+
+        for block in (0..proc.blocks.len()).map(BlockId) {
+            let call = match proc.block(block).last() {
+                Some(&call) if call.opcode(proc) == Opcode::TailCCall => call,
+                _ => continue,
+            };
+
+            let callee = call.child(proc, 0);
+
+            if callee.opcode(proc) != Opcode::ProcedureAddr
+                || proc.value(callee).procedure_addr() != Some(self_id)
+            {
+                continue;
+            }
+
+            // Parameters are matched by position, so the call has to follow the signature.
+            if let Some(signature) = proc.signature() {
+                if proc.value(call).children.len() - 1 != signature.params.len() {
+                    continue;
+                }
+            }
+
+            let registers = argument_registers(proc, call);
+
+            let new_values = arguments
+                .iter()
+                .map(|&argument| {
+                    let index = match proc.value(argument).parameter_index() {
+                        Some(index) => index,
+                        None => {
+                            let reg = proc.value(argument).argument_reg().unwrap();
+                            registers.iter().position(|&r| r == Some(reg))?
+                        }
+                    };
+                    let new_value = call.child(proc, index + 1);
+                    let typ = proc.value(argument).typ();
+
+                    // Variables can't hold aggregates.
+                    (proc.value(new_value).typ() == typ && !typ.is_aggregate()).then_some(new_value)
+                })
+                .collect::<Option<Vec<_>>>();
+
+            if let Some(new_values) = new_values {
+                sites.push((block, call, new_values));
+            }
+        }
+
+        if sites.is_empty() {
+            return false;
+        }
+
+        let root = proc.cfg_root();
+        let header = proc.add_block(proc.block(root).frequency());
+
+        proc.block_mut(header).values = std::mem::take(&mut proc.block_mut(root).values);
+        proc.block_mut(header).successor_list =
+            std::mem::take(&mut proc.block_mut(root).successor_list);
+
+        let mut variables = vec![];
+        let mut gets = vec![];
+
+        for &argument in arguments.iter() {
+            let typ = proc.value(argument).typ();
+            let variable = proc.add_variable(typ);
+
+            let entry_value = proc.clone(argument);
+            proc.add_to_block(root, entry_value);
+            let set = proc.add_variable_set(variable, entry_value);
+            proc.add_to_block(root, set);
+
+            let get = proc.add_variable_get(variable);
+            proc.value_mut(argument).replace_with_identity(get);
+            proc.value_mut(argument).num_children = NumChildren::One;
+
+            variables.push(variable);
+            gets.push(get);
+        }
+
+        proc.block_mut(header).values.splice(0..0, gets);
+
+        let jump = proc.add_jump();
+        proc.add_to_block(root, jump);
+        proc.successors_mut(root).push((header, Frequency::Normal));
+
+        for (block, call, new_values) in sites {
+            let block = if block == root { header } else { block };
+
+            proc.block_mut(block).values.pop();
+
+            for (&variable, &new_value) in variables.iter().zip(new_values.iter()) {
+                let set = proc.add_variable_set(variable, new_value);
+                proc.add_to_block(block, set);
+            }
+
+            let jump = proc.add_jump();
+            proc.add_to_block(block, jump);
+            proc.successors_mut(block).push((header, Frequency::Normal));
+
+            proc.delete_value(call);
+        }
+
+        proc.reset_reachability();
+        proc.invalidate_cfg();
+
+        true
+    })
+}
+
+/// Registers that receive the arguments of `call`, `None` for arguments passed on stack or in more
+/// than one location.
+fn argument_registers(proc: &Procedure, call: ValueId) -> Vec<Option<Reg>> {
+    let types = proc.value(call).children[1..]
+        .iter()
+        .map(|&child| proc.value(child).typ())
+        .collect::<Vec<_>>();
+
+    let (locations, _) = ccall_argument_locations(proc, &types);
+    let mut locations = locations.into_iter();
+
+    types
+        .iter()
+        .map(|&typ| {
+            let count = ccall_argument_location_count(proc, typ);
+            let argument_locations = locations.by_ref().take(count).collect::<Vec<_>>();
+
+            match argument_locations[..] {
+                [location] if location.is_reg() => Some(location.reg()),
+                _ => None,
+            }
+        })
+        .collect()
+}
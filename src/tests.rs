@@ -7,9 +7,16 @@ use macroassembler::{
 
 use crate::{
     self as b3,
-    air::{generate::emit_function_epilogue, stack_slot::StackSlotKind},
+    air::{
+        arg::Arg,
+        basic_block::BasicBlockId,
+        generate::emit_function_epilogue,
+        inst::Inst,
+        opcode::Opcode as AirOpcode,
+        stack_slot::{StackSlotId, StackSlotKind},
+    },
     jit::reg::Reg,
-    ValueRep, ValueRepKind,
+    ValueRep, ValueRepKind, Width,
 };
 
 #[test]
@@ -288,3 +295,2688 @@ fn test_factorial() {
     assert_eq!(lsra_func(4), 24);
     assert_eq!(lsra_func(5), 120);
 }
+
+#[test]
+fn test_module_mutual_recursion() {
+    use crate::module::{Linkage, Module};
+
+    let mut module = Module::new();
+
+    let is_even = module.declare("is_even", Linkage::Export);
+    let is_odd = module.declare("is_odd", Linkage::Internal);
+
+    // is_even(n) = n == 0 ? 1 : is_odd(n - 1)
+    // is_odd(n) = n == 0 ? 0 : is_even(n - 1)
+    let build = |on_zero: i64, callee: b3::ProcedureId| {
+        let mut proc = b3::Procedure::new(Default::default());
+
+        let entry = proc.add_block(1.0);
+        let zero = proc.add_block(1.0);
+        let recurse = proc.add_block(1.0);
+
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+        let n = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+        let c0 = builder.const64(0);
+        let is_zero = builder.binary(b3::Opcode::Equal, n, c0);
+        builder.branch(is_zero, zero, (recurse, b3::Frequency::Normal));
+
+        builder.switch_to_block(zero);
+        let result = builder.const64(on_zero);
+        builder.return_(Some(result));
+
+        builder.switch_to_block(recurse);
+        let one = builder.const64(1);
+        let n_minus_one = builder.binary(b3::Opcode::Sub, n, one);
+        let callee = builder.procedure_addr(callee);
+        let result = builder.ccall(
+            b3::Type::Int64,
+            callee,
+            &[n_minus_one],
+            b3::Effects::for_call(),
+        );
+        builder.return_(Some(result));
+
+        proc
+    };
+
+    let even_proc = build(1, is_odd);
+    let odd_proc = build(0, is_even);
+
+    module.define(is_even, even_proc);
+    module.define(is_odd, odd_proc);
+
+    let compilation = module.compile().unwrap();
+
+    eprintln!(
+        "test_module_mutual_recursion:\n{}",
+        compilation.disassembly()
+    );
+
+    assert!(compilation.lookup("is_odd").is_none());
+
+    let func: extern "C" fn(i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.lookup("is_even").unwrap()) };
+
+    assert_eq!(func(0), 1);
+    assert_eq!(func(1), 0);
+    assert_eq!(func(10), 1);
+    assert_eq!(func(15), 0);
+}
+
+#[test]
+fn test_module_import_static() {
+    use crate::module::{Linkage, Module};
+
+    extern "C" fn add_one(x: i64) -> i64 {
+        x + 1
+    }
+
+    let mut module = Module::new();
+
+    let add_one = module.import("add_one", add_one as *const u8);
+
+    let mut proc = b3::Procedure::new(Default::default());
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+    let x = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+    let callee = builder.procedure_addr(add_one);
+    let result = builder.ccall(b3::Type::Int64, callee, &[x], b3::Effects::for_call());
+    builder.return_(Some(result));
+
+    module.add_procedure("call_add_one", Linkage::Export, proc);
+
+    let compilation = module.compile().unwrap();
+
+    eprintln!("test_module_import_static:\n{}", compilation.disassembly());
+
+    let func: extern "C" fn(i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.lookup("call_add_one").unwrap()) };
+
+    assert_eq!(func(1), 2);
+    assert_eq!(func(41), 42);
+}
+
+#[test]
+fn test_module_globals() {
+    use crate::module::{Linkage, Module};
+
+    let mut module = Module::new();
+
+    let counter = module.add_global("counter", 8, 8, Some(&41i64.to_ne_bytes()));
+
+    // increment() -> i64 { counter += 1; counter }
+    let mut increment = b3::Procedure::new(Default::default());
+    let entry = increment.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut increment, entry);
+
+    let address = builder.global_addr(counter);
+    let value = builder.load(b3::Type::Int64, address, 0, None, None);
+    let one = builder.const64(1);
+    let value = builder.binary(b3::Opcode::Add, value, one);
+    builder.store(value, address, 0, None, None);
+    builder.return_(Some(value));
+
+    // get() -> i64 { counter }
+    let mut get = b3::Procedure::new(Default::default());
+    let entry = get.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut get, entry);
+
+    let address = builder.global_addr(counter);
+    let value = builder.load(b3::Type::Int64, address, 0, None, None);
+    builder.return_(Some(value));
+
+    module.add_procedure("increment", Linkage::Export, increment);
+    module.add_procedure("get", Linkage::Export, get);
+
+    let compilation = module.compile().unwrap();
+
+    eprintln!("test_module_globals:\n{}", compilation.disassembly());
+
+    let increment: extern "C" fn() -> i64 =
+        unsafe { std::mem::transmute(compilation.lookup("increment").unwrap()) };
+    let get: extern "C" fn() -> i64 =
+        unsafe { std::mem::transmute(compilation.lookup("get").unwrap()) };
+
+    assert_eq!(compilation.global(counter) as usize % 8, 0);
+    assert_eq!(get(), 41);
+    assert_eq!(increment(), 42);
+    assert_eq!(increment(), 43);
+    assert_eq!(get(), 43);
+    assert_eq!(unsafe { *compilation.lookup_global("counter").unwrap().cast::<i64>() }, 43);
+}
+
+#[test]
+fn test_tail_calls() {
+    use crate::module::{Linkage, Module};
+
+    extern "C" fn sum8(a: i64, b: i64, c: i64, d: i64, e: i64, f: i64, g: i64, h: i64) -> i64 {
+        a + 10 * b + 100 * c + 1000 * d + 10000 * e + 100000 * f + 1000000 * g + 10000000 * h
+    }
+
+    let mut module = Module::new();
+
+    let sum8 = module.import("sum8", sum8 as *const u8);
+    let sum = module.declare("sum", Linkage::Export);
+    let forward = module.declare("forward", Linkage::Export);
+
+    // sum(n, acc) = n == 0 ? acc : sum(n - 1, acc + n)
+    //
+    // This is a self tail call, so it becomes a loop and does not grow the stack.
+    {
+        let mut proc = b3::Procedure::new(Default::default());
+
+        let entry = proc.add_block(1.0);
+        let done = proc.add_block(1.0);
+        let recurse = proc.add_block(1.0);
+
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+        let n = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+        let acc = builder.argument(Reg::new_gpr(ARGUMENT_GPR1), b3::Type::Int64);
+        let c0 = builder.const64(0);
+        let is_zero = builder.binary(b3::Opcode::Equal, n, c0);
+        builder.branch(is_zero, done, (recurse, b3::Frequency::Normal));
+
+        builder.switch_to_block(done);
+        builder.return_(Some(acc));
+
+        builder.switch_to_block(recurse);
+        let one = builder.const64(1);
+        let n_minus_one = builder.binary(b3::Opcode::Sub, n, one);
+        let acc_plus_n = builder.binary(b3::Opcode::Add, acc, n);
+        let callee = builder.procedure_addr(sum);
+        builder.tail_ccall(callee, &[n_minus_one, acc_plus_n]);
+
+        module.define(sum, proc);
+    }
+
+    // forward(a, b, c, d, e, f, g, h) = sum8(f, e, d, c, b, a, a + 1, b + 2)
+    //
+    // The last two arguments are passed on stack, they replace `g` and `h` of our own caller.
+    {
+        let mut proc = b3::Procedure::new(Default::default());
+        proc.set_signature(&[b3::Type::Int64; 8], b3::Type::Int64);
+
+        let entry = proc.add_block(1.0);
+
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+        let args = (0..6)
+            .map(|i| {
+                builder.argument(
+                    Reg::new_gpr(b3::Bank::GP.to_argument_register(i)),
+                    b3::Type::Int64,
+                )
+            })
+            .collect::<Vec<_>>();
+        let one = builder.const64(1);
+        let two = builder.const64(2);
+        let g = builder.binary(b3::Opcode::Add, args[0], one);
+        let h = builder.binary(b3::Opcode::Add, args[1], two);
+        let callee = builder.procedure_addr(sum8);
+        builder.tail_ccall(callee, &[args[5], args[4], args[3], args[2], args[1], args[0], g, h]);
+
+        module.define(forward, proc);
+    }
+
+    let compilation = module.compile().unwrap();
+
+    eprintln!("test_tail_calls:\n{}", compilation.disassembly());
+
+    let sum: extern "C" fn(i64, i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.lookup("sum").unwrap()) };
+
+    assert_eq!(sum(0, 0), 0);
+    assert_eq!(sum(10, 0), 55);
+    assert_eq!(sum(10_000_000, 0), 50_000_005_000_000);
+
+    let forward: extern "C" fn(i64, i64, i64, i64, i64, i64, i64, i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.lookup("forward").unwrap()) };
+
+    assert_eq!(forward(1, 2, 3, 4, 5, 6, 0, 0), 42_123_456);
+}
+
+#[test]
+fn test_parameters() {
+    let mut proc = b3::Procedure::new(Default::default());
+
+    // The double takes an FP register, so the last two integers are passed on stack.
+    let mut params = vec![b3::Type::Int64; 8];
+    params.insert(1, b3::Type::Double);
+    proc.set_signature(&params, b3::Type::Int64);
+
+    let entry = proc.add_block(1.0);
+
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+    // Concatenate decimal digits of the integer parameters.
+    let ten = builder.const64(10);
+    let mut result = builder.parameter(0);
+    for index in 2..params.len() {
+        let param = builder.parameter(index);
+        let shifted = builder.binary(b3::Opcode::Mul, result, ten);
+        result = builder.binary(b3::Opcode::Add, shifted, param);
+    }
+
+    builder.return_(Some(result));
+
+    let compilation = b3::compile(proc);
+
+    eprintln!("test_parameters:\n{}", compilation.disassembly());
+
+    let func: extern "C" fn(i64, f64, i64, i64, i64, i64, i64, i64, i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    assert_eq!(func(1, 0.5, 2, 3, 4, 5, 6, 7, 8), 12345678);
+    assert_eq!(func(8, 0.5, 7, 6, 5, 4, 3, 2, 1), 87654321);
+}
+
+#[test]
+fn test_multiple_returns() {
+    #[repr(C)]
+    #[derive(Debug, PartialEq)]
+    struct Pair {
+        value: i64,
+        tag: i64,
+    }
+
+    #[repr(C)]
+    #[derive(Debug, PartialEq)]
+    struct Mixed {
+        value: f64,
+        tag: i32,
+    }
+
+    #[repr(C)]
+    #[derive(Debug, PartialEq)]
+    struct Triple(i64, i64, i64);
+
+    extern "C" fn make_pair(value: i64) -> Pair {
+        Pair { value, tag: 7 }
+    }
+
+    extern "C" fn make_triple(a: i64) -> Triple {
+        Triple(a, a * 10, a * 100)
+    }
+
+    // pair(a, b) = { a + b, a - b } is returned in RAX:RDX.
+    let mut proc = b3::Procedure::new(Default::default());
+    let pair = proc.add_struct_type(&[b3::Type::Int64, b3::Type::Int64]);
+    proc.set_signature(&[b3::Type::Int64, b3::Type::Int64], pair);
+
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+    let a = builder.parameter(0);
+    let b = builder.parameter(1);
+    let sum = builder.binary(b3::Opcode::Add, a, b);
+    let difference = builder.binary(b3::Opcode::Sub, a, b);
+    builder.return_values(&[sum, difference]);
+
+    let compilation = b3::compile(proc);
+    let func: extern "C" fn(i64, i64) -> Pair =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    assert_eq!(func(5, 3), Pair { value: 8, tag: 2 });
+
+    // mixed(x, tag) = { x * 2, tag } is returned in XMM0 and RAX.
+    let mut proc = b3::Procedure::new(Default::default());
+    let mixed = proc.add_struct_type(&[b3::Type::Double, b3::Type::Int32]);
+    proc.set_signature(&[b3::Type::Double, b3::Type::Int32], mixed);
+
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+    let x = builder.parameter(0);
+    let tag = builder.parameter(1);
+    let doubled = builder.binary(b3::Opcode::Add, x, x);
+    builder.return_values(&[doubled, tag]);
+
+    let compilation = b3::compile(proc);
+    let func: extern "C" fn(f64, i32) -> Mixed =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    assert_eq!(func(1.25, -3), Mixed { value: 2.5, tag: -3 });
+
+    // triple(a) = { a, a + 1, a + 2 } is too large for registers and is returned in memory.
+    let mut proc = b3::Procedure::new(Default::default());
+    let triple = proc.add_array_type(b3::Type::Int64, 3);
+    proc.set_signature(&[b3::Type::Int64], triple);
+
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+    let a = builder.parameter(0);
+    let one = builder.const64(1);
+    let two = builder.const64(2);
+    let b = builder.binary(b3::Opcode::Add, a, one);
+    let c = builder.binary(b3::Opcode::Add, a, two);
+    builder.return_values(&[a, b, c]);
+
+    let compilation = b3::compile(proc);
+    let func: extern "C" fn(i64) -> Triple =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    assert_eq!(func(40), Triple(40, 41, 42));
+
+    // Tuples returned by calls are projected with Extract.
+    let mut proc = b3::Procedure::new(Default::default());
+    let pair = proc.add_struct_type(&[b3::Type::Int64, b3::Type::Int64]);
+    let triple = proc.add_array_type(b3::Type::Int64, 3);
+
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+    let a = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+    let callee = builder.const64(make_pair as usize as i64);
+    let result = builder.ccall(pair, callee, &[a], b3::Effects::for_call());
+    let value = builder.extract(result, 0);
+    let tag = builder.extract(result, 1);
+    let callee = builder.const64(make_triple as usize as i64);
+    let result = builder.ccall(triple, callee, &[value], b3::Effects::for_call());
+    let hundreds = builder.extract(result, 2);
+    let sum = builder.binary(b3::Opcode::Add, hundreds, tag);
+    builder.return_(Some(sum));
+
+    let compilation = b3::compile(proc);
+
+    eprintln!("test_multiple_returns:\n{}", compilation.disassembly());
+
+    let func: extern "C" fn(i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    assert_eq!(func(3), 307);
+}
+
+#[test]
+fn test_ccall_arguments() {
+    #[repr(C)]
+    struct Point {
+        x: i64,
+        y: f64,
+    }
+
+    #[repr(C)]
+    struct Big([i64; 3]);
+
+    extern "C" fn weigh(point: Point, scale: f64) -> f64 {
+        point.x as f64 * scale + point.y
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    extern "C" fn sum(
+        a: i64,
+        b: i64,
+        c: i64,
+        d: i64,
+        e: i64,
+        f: i64,
+        g: i64,
+        big: Big,
+        h: i64,
+    ) -> i64 {
+        a + b + c + d + e + f + 10 * g + 100 * big.0[0] + 1000 * big.0[1] + 10000 * big.0[2]
+            + 100000 * h
+    }
+
+    // snprintf is variadic and gets two of its integers on stack.
+    let mut buffer = [0u8; 64];
+    let format = b"%ld %ld %ld %ld %ld %.1f %.1f\0";
+
+    let mut proc = b3::Procedure::new(Default::default());
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+    let callee = builder.const64(libc::snprintf as usize as i64);
+    let mut args = vec![
+        builder.const64(buffer.as_mut_ptr() as i64),
+        builder.const64(buffer.len() as i64),
+        builder.const64(format.as_ptr() as i64),
+    ];
+    args.extend((1..=5).map(|i| builder.const64(i)));
+    args.push(builder.const_double(0.5));
+    args.push(builder.const_double(2.5));
+    let length = builder.ccall_variadic(b3::Type::Int32, callee, &args, b3::Effects::for_call());
+    builder.return_(Some(length));
+
+    let compilation = b3::compile(proc);
+    let func: extern "C" fn() -> i32 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    assert_eq!(func(), 17);
+    assert_eq!(&buffer[..18], b"1 2 3 4 5 0.5 2.5\0");
+
+    // ldiv returns a pair of longs in RAX:RDX.
+    let mut proc = b3::Procedure::new(Default::default());
+    let ldiv_t = proc.add_struct_type(&[b3::Type::Int64, b3::Type::Int64]);
+    proc.set_signature(&[b3::Type::Int64, b3::Type::Int64], b3::Type::Int64);
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+    let a = builder.parameter(0);
+    let b = builder.parameter(1);
+    let callee = builder.const64(libc::ldiv as usize as i64);
+    let result = builder.ccall(ldiv_t, callee, &[a, b], b3::Effects::for_call());
+    let quotient = builder.extract(result, 0);
+    let remainder = builder.extract(result, 1);
+    let ten = builder.const64(10);
+    let shifted = builder.binary(b3::Opcode::Mul, quotient, ten);
+    let digits = builder.binary(b3::Opcode::Add, shifted, remainder);
+    builder.return_(Some(digits));
+
+    let compilation = b3::compile(proc);
+    let func: extern "C" fn(i64, i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    assert_eq!(func(17, 5), 32);
+
+    // Structs passed by value: `Point` in RDI and XMM0, `Big` on stack between `g` and `h`.
+    let mut proc = b3::Procedure::new(Default::default());
+    let point = proc.add_struct_type(&[b3::Type::Int64, b3::Type::Double]);
+    let big = proc.add_array_type(b3::Type::Int64, 3);
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+    let point_slot = builder.alloca(point);
+    let x = builder.const64(3);
+    let y = builder.const_double(0.5);
+    builder.store(x, point_slot, 0, None, None);
+    builder.store(y, point_slot, 8, None, None);
+    let point_value = builder.load(point, point_slot, 0, None, None);
+    let scale = builder.const_double(2.0);
+    let callee = builder.const64(weigh as usize as i64);
+    let weight = builder.ccall(
+        b3::Type::Double,
+        callee,
+        &[point_value, scale],
+        b3::Effects::for_call(),
+    );
+
+    let big_slot = builder.alloca(big);
+    for i in 0..3 {
+        let element = builder.const64(i as i64 + 3);
+        builder.store(element, big_slot, i * 8, None, None);
+    }
+    let big_value = builder.load(big, big_slot, 0, None, None);
+    let mut args = (0..6).map(|_| builder.const64(1)).collect::<Vec<_>>();
+    args.push(builder.const64(2));
+    args.push(big_value);
+    args.push(builder.const64(6));
+    let callee = builder.const64(sum as usize as i64);
+    let total = builder.ccall(b3::Type::Int64, callee, &args, b3::Effects::for_call());
+
+    let total = builder.i2d(total);
+    let result = builder.binary(b3::Opcode::Add, total, weight);
+    builder.return_(Some(result));
+
+    let compilation = b3::compile(proc);
+
+    eprintln!("test_ccall_arguments:\n{}", compilation.disassembly());
+
+    let func: extern "C" fn() -> f64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    assert_eq!(func(), 654326.0 + 6.5);
+}
+
+#[test]
+fn test_cold_ccall_and_shuffle() {
+    extern "C" fn twice(value: i64) -> i64 {
+        value * 2
+    }
+
+    // fib(n) computed by a loop whose Upsilons swap the variables, with a cold call for negative n.
+    let compile = |opt_level| {
+        let mut opts = b3::Options::default();
+        opts.opt_level = opt_level;
+
+        let mut proc = b3::Procedure::new(opts);
+
+        let entry = proc.add_block(1.0);
+
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+        let number = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+
+        let i = builder.procedure.add_variable(b3::Type::Int64);
+        let x = builder.procedure.add_variable(b3::Type::Int64);
+        let y = builder.procedure.add_variable(b3::Type::Int64);
+
+        let cold = builder.procedure.add_block(0.0);
+        let for_header = builder.procedure.add_block(1.0);
+        let for_body = builder.procedure.add_block(1.0);
+        let for_exit = builder.procedure.add_block(1.0);
+
+        let zero = builder.const64(0);
+        let one = builder.const64(1);
+        builder.var_set(i, zero);
+        builder.var_set(x, zero);
+        builder.var_set(y, one);
+
+        let positive = builder.binary(b3::Opcode::GreaterEqual, number, zero);
+        builder.branch(positive, for_header, (cold, b3::Frequency::Rare));
+
+        builder.block = cold;
+
+        // Both products are live across the call, so they have to be saved around it.
+        let three = builder.const64(3);
+        let five = builder.const64(5);
+        let tripled = builder.binary(b3::Opcode::Mul, number, three);
+        let quintupled = builder.binary(b3::Opcode::Mul, number, five);
+        let callee = builder.const64(twice as usize as i64);
+        let doubled = builder.ccall(b3::Type::Int64, callee, &[number], b3::Effects::for_call());
+        let sum = builder.binary(b3::Opcode::Add, tripled, quintupled);
+        let sum = builder.binary(b3::Opcode::Add, sum, doubled);
+        builder.return_(Some(sum));
+
+        builder.block = for_header;
+
+        let i_value = builder.var_get(i);
+        let cmp = builder.binary(b3::Opcode::LessThan, i_value, number);
+
+        builder.branch(cmp, for_body, (for_exit, b3::Frequency::Normal));
+
+        builder.block = for_body;
+
+        let x_value = builder.var_get(x);
+        let y_value = builder.var_get(y);
+        let next = builder.binary(b3::Opcode::Add, x_value, y_value);
+        builder.var_set(x, y_value);
+        builder.var_set(y, next);
+
+        let i_value = builder.var_get(i);
+        let one = builder.const64(1);
+        let add = builder.binary(b3::Opcode::Add, i_value, one);
+        builder.var_set(i, add);
+
+        builder.jump(Some(for_header));
+
+        builder.block = for_exit;
+
+        let x_value = builder.var_get(x);
+        builder.return_(Some(x_value));
+
+        b3::compile(proc)
+    };
+
+    for opt_level in [b3::OptLevel::O1, b3::OptLevel::O3] {
+        let compilation = compile(opt_level);
+
+        eprintln!(
+            "test_cold_ccall_and_shuffle({:?}):\n{}",
+            opt_level,
+            compilation.disassembly()
+        );
+
+        let func = unsafe {
+            std::mem::transmute::<_, extern "C" fn(i64) -> i64>(compilation.code_ref().start())
+        };
+
+        assert_eq!(func(0), 0);
+        assert_eq!(func(1), 1);
+        assert_eq!(func(10), 55);
+        assert_eq!(func(-4), -40);
+    }
+}
+
+#[test]
+fn test_aggregates() {
+    let mut proc = b3::Procedure::new(Default::default());
+
+    // struct Point { tag: i32, value: i64 }
+    let point = proc.add_struct_type(&[b3::Type::Int32, b3::Type::Int64]);
+    let points = proc.add_array_type(point, 4);
+
+    assert_eq!(proc.aggregate(point).offset_of(0), 0);
+    assert_eq!(proc.aggregate(point).offset_of(1), 8);
+    assert_eq!(proc.size_of(point), 16);
+    assert_eq!(proc.alignment_of(point), 8);
+    assert_eq!(proc.size_of(points), 64);
+    assert_eq!(proc.aggregate(points).offset_of(3), 48);
+
+    let entry = proc.add_block(1.0);
+
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+    let index = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+    let array = builder.alloca(points);
+
+    let field = builder.const32(1);
+    for i in 0..4 {
+        let element = builder.const32(i);
+        let address = builder.get_element_ptr(points, array, &[element, field]);
+        let value = builder.const64(i as i64 * 10);
+        builder.store(value, address, 0, None, None);
+    }
+
+    // points[index].value
+    let address = builder.get_element_ptr(points, array, &[index, field]);
+    let value = builder.load(b3::Type::Int64, address, 0, None, None);
+    builder.return_(Some(value));
+
+    let compilation = b3::compile(proc);
+
+    eprintln!("test_aggregates:\n{}", compilation.disassembly());
+
+    let func: extern "C" fn(i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    assert_eq!(func(0), 0);
+    assert_eq!(func(1), 10);
+    assert_eq!(func(3), 30);
+}
+
+#[test]
+fn test_alloca_to_reg() {
+    let build = |opt_level| {
+        let mut opts = b3::Options::default();
+        opts.opt_level = opt_level;
+
+        let mut proc = b3::Procedure::new(opts);
+
+        let entry = proc.add_block(1.0);
+
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+        let number = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int32);
+
+        let i = builder.alloca(b3::Type::Int32);
+        let factorial = builder.alloca(b3::Type::Int32);
+        // Only the low half is loaded back, so this alloca has to stay in memory.
+        let escaping = builder.alloca(b3::Type::Int64);
+
+        let for_header = builder.procedure.add_block(1.0);
+        let for_body = builder.procedure.add_block(1.0);
+        let for_exit = builder.procedure.add_block(1.0);
+
+        let one = builder.const32(1);
+        builder.store(one, factorial, 0, None, None);
+        builder.store(one, i, 0, None, None);
+        let zero = builder.const64(0);
+        builder.store(zero, escaping, 0, None, None);
+
+        builder.jump(Some(for_header));
+
+        builder.block = for_header;
+
+        let i_value = builder.load(b3::Type::Int32, i, 0, None, None);
+        let cmp = builder.binary(b3::Opcode::LessEqual, i_value, number);
+
+        builder.branch(cmp, for_body, (for_exit, b3::Frequency::Normal));
+
+        builder.block = for_body;
+
+        let i_value = builder.load(b3::Type::Int32, i, 0, None, None);
+        let factorial_value = builder.load(b3::Type::Int32, factorial, 0, None, None);
+        let mul = builder.binary(b3::Opcode::Mul, i_value, factorial_value);
+        builder.store(mul, factorial, 0, None, None);
+
+        let one = builder.const32(1);
+        let add = builder.binary(b3::Opcode::Add, i_value, one);
+        builder.store(add, i, 0, None, None);
+
+        builder.jump(Some(for_header));
+
+        builder.block = for_exit;
+
+        let factorial_value = builder.load(b3::Type::Int32, factorial, 0, None, None);
+        let low = builder.load(b3::Type::Int32, escaping, 0, None, None);
+        let result = builder.binary(b3::Opcode::Add, factorial_value, low);
+        builder.return_(Some(result));
+
+        (proc, [i, factorial, escaping])
+    };
+
+    let (mut proc, [i, factorial, escaping]) = build(b3::OptLevel::O1);
+    let (allocas, blacklisted) = b3::alloca_to_reg::get_allocas_without_identity(&mut proc);
+
+    assert!(allocas.contains(&i));
+    assert!(allocas.contains(&factorial));
+    assert!(blacklisted.contains(&escaping));
+
+    for opt_level in [b3::OptLevel::O1, b3::OptLevel::O2] {
+        let (proc, _) = build(opt_level);
+        let compilation = b3::compile(proc);
+
+        eprintln!(
+            "test_alloca_to_reg({:?}):\n{}",
+            opt_level,
+            compilation.disassembly()
+        );
+
+        let func =
+            unsafe { std::mem::transmute::<_, fn(i32) -> i32>(compilation.code_ref().start()) };
+
+        assert_eq!(func(0), 1);
+        assert_eq!(func(3), 6);
+        assert_eq!(func(5), 120);
+    }
+}
+
+#[test]
+fn test_atomics() {
+    fn compile(
+        typ: b3::Type,
+        build: impl FnOnce(&mut b3::BasicBlockBuilder<'_>, b3::ValueId, b3::ValueId) -> b3::ValueId,
+    ) -> b3::Compilation {
+        let mut proc = b3::Procedure::new(Default::default());
+        let entry = proc.add_block(1.0);
+
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+        let ptr = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+        let operand = builder.argument(Reg::new_gpr(ARGUMENT_GPR1), typ);
+        let result = build(&mut builder, ptr, operand);
+        builder.return_(Some(result));
+
+        b3::compile(proc)
+    }
+
+    let xchg_add64 = compile(b3::Type::Int64, |builder, ptr, operand| {
+        builder.atomic_xchg_op(
+            b3::Opcode::AtomicXchgAdd,
+            b3::Width::W64,
+            operand,
+            ptr,
+            0,
+            None,
+            None,
+        )
+    });
+    let func: extern "C" fn(*mut i64, i64) -> i64 =
+        unsafe { std::mem::transmute(xchg_add64.code_ref().start()) };
+    let mut cell = 40i64;
+    assert_eq!(func(&mut cell, 2), 40);
+    assert_eq!(cell, 42);
+
+    let xchg_sub32 = compile(b3::Type::Int32, |builder, ptr, operand| {
+        builder.atomic_xchg_op(
+            b3::Opcode::AtomicXchgSub,
+            b3::Width::W32,
+            operand,
+            ptr,
+            0,
+            None,
+            None,
+        )
+    });
+    let func: extern "C" fn(*mut i32, i32) -> i32 =
+        unsafe { std::mem::transmute(xchg_sub32.code_ref().start()) };
+    let mut cell = 10i32;
+    assert_eq!(func(&mut cell, 3), 10);
+    assert_eq!(cell, 7);
+
+    // Subwidth results are sign-extended.
+    let xchg16 = compile(b3::Type::Int32, |builder, ptr, operand| {
+        builder.atomic_xchg(b3::Width::W16, operand, ptr, 0, None, None)
+    });
+    let func: extern "C" fn(*mut i16, i32) -> i32 =
+        unsafe { std::mem::transmute(xchg16.code_ref().start()) };
+    let mut cell = -1i16;
+    assert_eq!(func(&mut cell, 5), -1);
+    assert_eq!(cell, 5);
+
+    let xchg_add8 = compile(b3::Type::Int32, |builder, ptr, operand| {
+        builder.atomic_xchg_op(
+            b3::Opcode::AtomicXchgAdd,
+            b3::Width::W8,
+            operand,
+            ptr,
+            0,
+            None,
+            None,
+        )
+    });
+    let func: extern "C" fn(*mut i8, i32) -> i32 =
+        unsafe { std::mem::transmute(xchg_add8.code_ref().start()) };
+    let mut cell = 127i8;
+    assert_eq!(func(&mut cell, 1), 127);
+    assert_eq!(func(&mut cell, 1), -128);
+    assert_eq!(cell, -127);
+
+    // There is no fetch-and-and on x86, this goes through a CAS loop.
+    let xchg_and32 = compile(b3::Type::Int32, |builder, ptr, operand| {
+        builder.atomic_xchg_op(
+            b3::Opcode::AtomicXchgAnd,
+            b3::Width::W32,
+            operand,
+            ptr,
+            0,
+            None,
+            None,
+        )
+    });
+    let func: extern "C" fn(*mut i32, i32) -> i32 =
+        unsafe { std::mem::transmute(xchg_and32.code_ref().start()) };
+    let mut cell = 0b1100i32;
+    assert_eq!(func(&mut cell, 0b1010), 0b1100);
+    assert_eq!(cell, 0b1000);
+
+    let xchg_xor8 = compile(b3::Type::Int32, |builder, ptr, operand| {
+        builder.atomic_xchg_op(
+            b3::Opcode::AtomicXchgXor,
+            b3::Width::W8,
+            operand,
+            ptr,
+            0,
+            None,
+            None,
+        )
+    });
+    let func: extern "C" fn(*mut i8, i32) -> i32 =
+        unsafe { std::mem::transmute(xchg_xor8.code_ref().start()) };
+    let mut cell = -1i8;
+    assert_eq!(func(&mut cell, 0x0f), -1);
+    assert_eq!(cell, -16);
+
+    let strong_cas64 = compile(b3::Type::Int64, |builder, ptr, operand| {
+        let new_value = builder.const64(100);
+        builder.atomic_strong_cas(b3::Width::W64, operand, new_value, ptr, 0, None, None)
+    });
+    let func: extern "C" fn(*mut i64, i64) -> i64 =
+        unsafe { std::mem::transmute(strong_cas64.code_ref().start()) };
+    let mut cell = 7i64;
+    assert_eq!(func(&mut cell, 7), 7);
+    assert_eq!(cell, 100);
+    assert_eq!(func(&mut cell, 7), 100);
+    assert_eq!(cell, 100);
+
+    let weak_cas8 = compile(b3::Type::Int32, |builder, ptr, operand| {
+        let new_value = builder.const32(3);
+        builder.atomic_weak_cas(b3::Width::W8, operand, new_value, ptr, 0, None, None)
+    });
+    let func: extern "C" fn(*mut i8, i32) -> i32 =
+        unsafe { std::mem::transmute(weak_cas8.code_ref().start()) };
+    let mut cell = -2i8;
+    assert_eq!(func(&mut cell, 0), 0);
+    assert_eq!(cell, -2);
+    // Irrelevant bits of the expected value are ignored.
+    assert_eq!(func(&mut cell, 0xfe), 1);
+    assert_eq!(cell, 3);
+}
+
+#[test]
+fn test_fences() {
+    let mut proc = b3::Procedure::new(Default::default());
+    let entry = proc.add_block(1.0);
+
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+    let ptr = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+    let value = builder.argument(Reg::new_gpr(ARGUMENT_GPR1), b3::Type::Int64);
+    builder.store(value, ptr, 0, Some(0..8), Some(0..16));
+    builder.fence(None, None);
+    builder.fence(Some(0..16), Some(0..0));
+    builder.fence(Some(0..0), Some(0..16));
+    let loaded = builder.load(b3::Type::Int64, ptr, 8, Some(8..16), Some(0..16));
+    let plain = builder.load(b3::Type::Int64, ptr, 8, Some(8..16), None);
+    let sum = builder.binary(b3::Opcode::Add, loaded, plain);
+    builder.return_(Some(sum));
+
+    let effects_of = |opcode: b3::Opcode, nth: usize| {
+        let value = proc
+            .block(entry)
+            .iter()
+            .copied()
+            .filter(|&value| value.opcode(&proc) == opcode)
+            .nth(nth)
+            .unwrap();
+        proc.value(value).effects()
+    };
+
+    let store = effects_of(b3::Opcode::Store, 0);
+    let full_fence = effects_of(b3::Opcode::Fence, 0);
+    let acquire = effects_of(b3::Opcode::Load, 0);
+    let plain_load = effects_of(b3::Opcode::Load, 1);
+
+    assert!(store.fence && acquire.fence && full_fence.fence);
+    assert!(!plain_load.fence && !plain_load.must_execute());
+    // The load does not alias the store, but the store-release and load-acquire are ordered.
+    assert!(store.interferes(&acquire));
+    assert!(!store.interferes(&plain_load));
+    assert!(acquire.interferes(&plain_load));
+    assert!(full_fence.interferes(&plain_load));
+
+    let compilation = b3::compile(proc);
+    let func: extern "C" fn(*mut i64, i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+    let mut cells = [0i64, 20];
+    assert_eq!(func(cells.as_mut_ptr(), 1), 40);
+    assert_eq!(cells, [1, 20]);
+}
+
+#[test]
+fn test_depend() {
+    let build = || {
+        let mut proc = b3::Procedure::new(Default::default());
+        let entry = proc.add_block(1.0);
+
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+        let ptr = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+        let first = builder.load(b3::Type::Int64, ptr, 0, None, None);
+        let zero = builder.depend(first);
+        let dependent_ptr = builder.binary(b3::Opcode::Add, ptr, zero);
+        let second = builder.load(b3::Type::Int64, dependent_ptr, 8, None, None);
+        let result = builder.binary(b3::Opcode::Add, first, second);
+        builder.return_(Some(result));
+
+        proc
+    };
+
+    // The dependency survives as a load fence between the two loads.
+    let mut proc = build();
+    let code = b3::generate_to_air(&mut proc);
+    let insts = code.blocks.iter().flat_map(|block| block.insts.iter()).collect::<Vec<_>>();
+    let fence = insts.iter().position(|inst| inst.kind.opcode == AirOpcode::LoadFence);
+    let fence = fence.unwrap_or_else(|| panic!("no LoadFence in:\n{}", code));
+    assert!(insts[..fence].iter().any(|inst| inst.to_string().contains("(%")), "{}", code);
+    assert!(insts[fence + 1..].iter().any(|inst| inst.to_string().contains("(%")), "{}", code);
+
+    let compilation = b3::compile(build());
+    let func: extern "C" fn(*const i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+    assert_eq!(func([40i64, 2].as_ptr()), 42);
+}
+
+#[test]
+fn test_vectors() {
+    extern "C" fn clobber() {}
+
+    let compile = |opt_level| {
+        let mut opts = b3::Options::default();
+        opts.opt_level = opt_level;
+
+        let mut proc = b3::Procedure::new(opts);
+        let entry = proc.add_block(1.0);
+        let then_block = proc.add_block(1.0);
+        let else_block = proc.add_block(1.0);
+        let join = proc.add_block(1.0);
+
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+        let src = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+        let dst = builder.argument(Reg::new_gpr(ARGUMENT_GPR1), b3::Type::Int64);
+        let flag = builder.argument(Reg::new_gpr(ARGUMENT_GPR2), b3::Type::Int32);
+
+        let loaded = builder.load(b3::Type::V128, src, 0, None, None);
+        // All vector registers are caller-saved, so `loaded` has to be spilled across the call.
+        let callee = builder.const64(clobber as usize as i64);
+        builder.ccall(b3::Type::Void, callee, &[], b3::Effects::for_call());
+        builder.branch(flag, then_block, (else_block, b3::Frequency::Normal));
+
+        builder.block = join;
+        let phi = builder.phi(b3::Type::V128);
+
+        builder.block = then_block;
+        let constant = builder.const128(0x0f0e0d0c0b0a09080706050403020100);
+        builder.upsilon(constant, Some(phi));
+        builder.jump(Some(join));
+
+        builder.block = else_block;
+        builder.upsilon(loaded, Some(phi));
+        builder.jump(Some(join));
+
+        builder.block = join;
+        builder.store(phi, dst, 0, None, None);
+        let zero = builder.const128(0);
+        builder.store(zero, dst, 16, None, None);
+        builder.return_(None);
+
+        b3::compile(proc)
+    };
+
+    for opt_level in [b3::OptLevel::O1, b3::OptLevel::O2] {
+        let compilation = compile(opt_level);
+        let func: extern "C" fn(*const u8, *mut u8, i32) =
+            unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+        let src: [u8; 16] = std::array::from_fn(|i| 0xf0 | i as u8);
+        let mut dst = [0xffu8; 32];
+
+        func(src.as_ptr(), dst.as_mut_ptr(), 0);
+        assert_eq!(dst[..16], src);
+        assert_eq!(dst[16..], [0; 16]);
+
+        func(src.as_ptr(), dst.as_mut_ptr(), 1);
+        assert_eq!(dst[..16], std::array::from_fn::<u8, 16, _>(|i| i as u8));
+    }
+}
+
+#[test]
+fn test_vector_arithmetic() {
+    use b3::simd::{simd_info, SIMDLane, SIMDSignMode};
+
+    #[repr(C, align(16))]
+    struct Inputs {
+        a: [u8; 16],
+        b: [u8; 16],
+        f: [f32; 4],
+        d: [f64; 2],
+    }
+
+    let info = |lane| simd_info(lane, SIMDSignMode::None);
+    let signed = |lane| simd_info(lane, SIMDSignMode::Signed);
+    let unsigned = |lane| simd_info(lane, SIMDSignMode::Unsigned);
+
+    let mut proc = b3::Procedure::new(Default::default());
+    let entry = proc.add_block(1.0);
+
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+    let src = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+    let dst = builder.argument(Reg::new_gpr(ARGUMENT_GPR1), b3::Type::Int64);
+    let shift = builder.argument(Reg::new_gpr(ARGUMENT_GPR2), b3::Type::Int32);
+
+    let a = builder.load(b3::Type::V128, src, 0, None, None);
+    let b = builder.load(b3::Type::V128, src, 16, None, None);
+    let f = builder.load(b3::Type::V128, src, 32, None, None);
+    let d = builder.load(b3::Type::V128, src, 48, None, None);
+
+    let one_and_a_half = builder.const_double(1.5);
+    let splat = builder.vector_splat(info(SIMDLane::F64x2), one_and_a_half);
+    let lane = builder.vector_extract_lane(info(SIMDLane::I32x4), 1, b);
+
+    let results = [
+        builder.vector_binary(b3::Opcode::VectorAdd, info(SIMDLane::I8x16), a, b),
+        builder.vector_binary(b3::Opcode::VectorSub, info(SIMDLane::I16x8), a, b),
+        builder.vector_binary(b3::Opcode::VectorMul, info(SIMDLane::I32x4), a, b),
+        builder.vector_binary(b3::Opcode::VectorMin, unsigned(SIMDLane::I8x16), a, b),
+        builder.vector_binary(b3::Opcode::VectorMax, signed(SIMDLane::I16x8), a, b),
+        builder.vector_binary(b3::Opcode::VectorAndnot, info(SIMDLane::V128), a, b),
+        builder.vector_unary(b3::Opcode::VectorNot, info(SIMDLane::V128), a),
+        builder.vector_shift(b3::Opcode::VectorShl, info(SIMDLane::I32x4), a, shift),
+        builder.vector_shift(b3::Opcode::VectorShr, signed(SIMDLane::I8x16), a, shift),
+        builder.vector_shift(b3::Opcode::VectorShr, signed(SIMDLane::I64x2), a, shift),
+        builder.vector_unary(b3::Opcode::VectorNeg, info(SIMDLane::I16x8), a),
+        builder.vector_unary(b3::Opcode::VectorAbs, info(SIMDLane::I64x2), a),
+        builder.vector_binary(b3::Opcode::VectorLessThan, signed(SIMDLane::I32x4), a, b),
+        builder.vector_binary(b3::Opcode::VectorAbove, unsigned(SIMDLane::I8x16), a, b),
+        builder.vector_binary(b3::Opcode::VectorAdd, info(SIMDLane::F32x4), f, f),
+        builder.vector_unary(b3::Opcode::VectorSqrt, info(SIMDLane::F32x4), f),
+        builder.vector_unary(b3::Opcode::VectorNeg, info(SIMDLane::F64x2), d),
+        builder.vector_unary(b3::Opcode::VectorAbs, info(SIMDLane::F64x2), d),
+        builder.vector_binary(b3::Opcode::VectorLessThan, info(SIMDLane::F64x2), d, splat),
+        builder.vector_replace_lane(info(SIMDLane::I32x4), 2, a, lane),
+    ];
+
+    for (i, result) in results.iter().enumerate() {
+        builder.store(*result, dst, i as i32 * 16, None, None);
+    }
+
+    let extracted = builder.vector_extract_lane(signed(SIMDLane::I16x8), 3, a);
+    builder.store(extracted, dst, results.len() as i32 * 16, None, None);
+    let extracted = builder.vector_extract_lane(info(SIMDLane::I64x2), 1, b);
+    builder.store(extracted, dst, results.len() as i32 * 16 + 8, None, None);
+    builder.return_(None);
+
+    let compilation = b3::compile(proc);
+    let func: extern "C" fn(*const Inputs, *mut u8, i32) =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    let inputs = Inputs {
+        a: std::array::from_fn(|i| (i as u8).wrapping_mul(37).wrapping_add(200)),
+        b: std::array::from_fn(|i| (i as u8).wrapping_mul(29).wrapping_add(5)),
+        f: [1.5, 4.0, 9.0, 0.25],
+        d: [-2.5, 3.0],
+    };
+
+    fn lanes<const N: usize>(bytes: &[u8]) -> impl Iterator<Item = [u8; N]> + '_ {
+        bytes.chunks_exact(N).map(|chunk| chunk.try_into().unwrap())
+    }
+
+    fn map2<const N: usize>(
+        a: &[u8],
+        b: &[u8],
+        f: impl Fn([u8; N], [u8; N]) -> [u8; N],
+    ) -> Vec<u8> {
+        lanes::<N>(a).zip(lanes::<N>(b)).flat_map(|(a, b)| f(a, b)).collect()
+    }
+
+    fn mask(condition: bool) -> u8 {
+        if condition {
+            0xff
+        } else {
+            0
+        }
+    }
+
+    let (a, b) = (&inputs.a[..], &inputs.b[..]);
+    let f: Vec<u8> = inputs.f.iter().flat_map(|x| x.to_le_bytes()).collect();
+    let d: Vec<u8> = inputs.d.iter().flat_map(|x| x.to_le_bytes()).collect();
+
+    for amount in [3, 35] {
+        let mut out = [0u8; 21 * 16];
+        func(&inputs, out.as_mut_ptr(), amount);
+
+        let expected: [Vec<u8>; 20] = [
+            map2::<1>(a, b, |a, b| [a[0].wrapping_add(b[0])]),
+            map2::<2>(a, b, |a, b| {
+                i16::from_le_bytes(a).wrapping_sub(i16::from_le_bytes(b)).to_le_bytes()
+            }),
+            map2::<4>(a, b, |a, b| {
+                i32::from_le_bytes(a).wrapping_mul(i32::from_le_bytes(b)).to_le_bytes()
+            }),
+            map2::<1>(a, b, |a, b| [a[0].min(b[0])]),
+            map2::<2>(a, b, |a, b| i16::from_le_bytes(a).max(i16::from_le_bytes(b)).to_le_bytes()),
+            map2::<1>(a, b, |a, b| [a[0] & !b[0]]),
+            a.iter().map(|x| !x).collect(),
+            map2::<4>(a, a, |a, _| i32::from_le_bytes(a).wrapping_shl(amount as u32).to_le_bytes()),
+            map2::<1>(a, a, |a, _| [(a[0] as i8).wrapping_shr(amount as u32) as u8]),
+            map2::<8>(a, a, |a, _| i64::from_le_bytes(a).wrapping_shr(amount as u32).to_le_bytes()),
+            map2::<2>(a, a, |a, _| i16::from_le_bytes(a).wrapping_neg().to_le_bytes()),
+            map2::<8>(a, a, |a, _| i64::from_le_bytes(a).wrapping_abs().to_le_bytes()),
+            map2::<4>(a, b, |a, b| [mask(i32::from_le_bytes(a) < i32::from_le_bytes(b)); 4]),
+            map2::<1>(a, b, |a, b| [mask(a[0] > b[0])]),
+            map2::<4>(&f, &f, |x, _| (f32::from_le_bytes(x) * 2.0).to_le_bytes()),
+            map2::<4>(&f, &f, |x, _| f32::from_le_bytes(x).sqrt().to_le_bytes()),
+            map2::<8>(&d, &d, |x, _| (-f64::from_le_bytes(x)).to_le_bytes()),
+            map2::<8>(&d, &d, |x, _| f64::from_le_bytes(x).abs().to_le_bytes()),
+            map2::<8>(&d, &d, |x, _| [mask(f64::from_le_bytes(x) < 1.5); 8]),
+            [&a[..8], &b[4..8], &a[12..]].concat(),
+        ];
+
+        for (i, expected) in expected.iter().enumerate() {
+            assert_eq!(&out[i * 16..(i + 1) * 16], &expected[..], "result {}", i);
+        }
+
+        let scalars = &out[20 * 16..];
+        let lane3 = i16::from_le_bytes([a[6], a[7]]) as i32;
+        assert_eq!(scalars[..4], lane3.to_le_bytes());
+        assert_eq!(scalars[8..], b[8..]);
+    }
+}
+
+#[test]
+fn test_validate() {
+    // max(a, b) with a Phi at the join, compiled with validation after every phase.
+    let build = || {
+        let mut opts = b3::Options::default();
+        opts.validate_b3_at_each_phase = true;
+
+        let mut proc = b3::Procedure::new(opts);
+        let entry = proc.add_block(1.0);
+        let then = proc.add_block(1.0);
+        let otherwise = proc.add_block(1.0);
+        let join = proc.add_block(1.0);
+
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+        let a = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+        let b = builder.argument(Reg::new_gpr(ARGUMENT_GPR1), b3::Type::Int64);
+        let cmp = builder.binary(b3::Opcode::GreaterThan, a, b);
+        builder.branch(cmp, then, (otherwise, b3::Frequency::Normal));
+
+        builder.block = join;
+        let phi = builder.phi(b3::Type::Int64);
+        builder.return_(Some(phi));
+
+        builder.block = then;
+        let then_upsilon = builder.upsilon(a, Some(phi));
+        builder.jump(Some(join));
+
+        builder.block = otherwise;
+        let otherwise_upsilon = builder.upsilon(b, Some(phi));
+        builder.jump(Some(join));
+
+        (proc, [entry, then, otherwise, join], [cmp, phi, then_upsilon, otherwise_upsilon])
+    };
+
+    let (proc, _, _) = build();
+    assert_eq!(b3::validate(&proc), Ok(()));
+
+    let compilation = b3::compile(proc);
+    let func: extern "C" fn(i64, i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    assert_eq!(func(3, 7), 7);
+    assert_eq!(func(7, 3), 7);
+
+    // A Phi without Upsilons.
+    let (mut proc, [_, then, otherwise, _], [_, phi, then_upsilon, otherwise_upsilon]) = build();
+    proc.block_mut(then).retain(|&value| value != then_upsilon);
+    proc.block_mut(otherwise).retain(|&value| value != otherwise_upsilon);
+    let error = b3::validate(&proc).unwrap_err();
+    assert_eq!(error.value, Some(phi));
+    assert_eq!(error.message, "Phi has no Upsilons");
+
+    // Children of different types.
+    let (mut proc, [entry, ..], [cmp, ..]) = build();
+    let narrow = proc.add_int_constant(b3::Type::Int32, 1);
+    proc.value_mut(narrow).owner = Some(entry);
+    proc.block_mut(entry).insert(0, narrow);
+    proc.value_mut(cmp).children[1] = narrow;
+    let error = b3::validate(&proc).unwrap_err();
+    assert_eq!(error.value, Some(cmp));
+    assert_eq!(error.message, "child 1 of GreaterThan must be Int64, got Int32");
+
+    // A terminal in the middle of a block.
+    let (mut proc, [_, then, ..], _) = build();
+    let oops = proc.add(b3::Value::new(
+        b3::Opcode::Oops,
+        b3::Type::Void,
+        b3::NumChildren::Zero,
+        &[],
+        b3::ValueData::None,
+    ));
+    proc.value_mut(oops).owner = Some(then);
+    proc.block_mut(then).insert(0, oops);
+    let error = b3::validate(&proc).unwrap_err();
+    assert_eq!(error.value, Some(oops));
+    assert_eq!(error.message, "terminal in the middle of a block");
+
+    // A successor that doesn't know about its predecessor.
+    let (mut proc, [_, then, _, join], _) = build();
+    proc.block_mut(join).predecessor_list.retain(|&block| block != then);
+    let error = b3::validate(&proc).unwrap_err();
+    assert_eq!(error.block, Some(then));
+    assert_eq!(error.message, format!("successor BB{} does not list it as a predecessor", join.0));
+
+    // A use that isn't dominated by its def.
+    let (mut proc, [_, then, otherwise, _], [cmp, _, _, otherwise_upsilon]) = build();
+    let a = proc.value(cmp).children[0];
+    let doubled = proc.add_binary(b3::Opcode::Add.into(), a, a);
+    proc.value_mut(doubled).owner = Some(then);
+    proc.block_mut(then).insert(0, doubled);
+    proc.value_mut(otherwise_upsilon).children[0] = doubled;
+    let error = b3::validate(&proc).unwrap_err();
+    assert_eq!(error.value, Some(otherwise_upsilon));
+    assert_eq!(error.block, Some(otherwise));
+    assert_eq!(error.message, format!("child v@{} does not dominate its use", doubled.0));
+}
+
+#[test]
+fn test_hoist_loop_invariant_values() {
+    // sum = 0; for i in 0..n { for j in 0..n { sum += x * 3 + 5 } }
+    let build = || {
+        let mut opts = b3::Options::default();
+        opts.validate_b3_at_each_phase = true;
+
+        let mut proc = b3::Procedure::new(opts);
+        let entry = proc.add_block(1.0);
+        let outer_header = proc.add_block(1.0);
+        let outer_body = proc.add_block(1.0);
+        let inner_header = proc.add_block(1.0);
+        let inner_body = proc.add_block(1.0);
+        let outer_latch = proc.add_block(1.0);
+        let exit = proc.add_block(1.0);
+
+        let i = proc.add_variable(b3::Type::Int64);
+        let j = proc.add_variable(b3::Type::Int64);
+        let sum = proc.add_variable(b3::Type::Int64);
+
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+        let x = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+        let n = builder.argument(Reg::new_gpr(ARGUMENT_GPR1), b3::Type::Int64);
+        let zero = builder.const64(0);
+        let one = builder.const64(1);
+        let three = builder.const64(3);
+        let five = builder.const64(5);
+        builder.var_set(i, zero);
+        builder.var_set(sum, zero);
+        builder.jump(Some(outer_header));
+
+        builder.block = outer_header;
+        let i_value = builder.var_get(i);
+        let test = builder.binary(b3::Opcode::LessThan, i_value, n);
+        builder.branch(test, outer_body, (exit, b3::Frequency::Normal));
+
+        builder.block = outer_body;
+        builder.var_set(j, zero);
+        builder.jump(Some(inner_header));
+
+        builder.block = inner_header;
+        let j_value = builder.var_get(j);
+        let test = builder.binary(b3::Opcode::LessThan, j_value, n);
+        builder.branch(test, inner_body, (outer_latch, b3::Frequency::Normal));
+
+        builder.block = inner_body;
+        let scaled = builder.binary(b3::Opcode::Mul, x, three);
+        let offset = builder.binary(b3::Opcode::Add, scaled, five);
+        let sum_value = builder.var_get(sum);
+        let new_sum = builder.binary(b3::Opcode::Add, sum_value, offset);
+        builder.var_set(sum, new_sum);
+        let j_value = builder.var_get(j);
+        let next = builder.binary(b3::Opcode::Add, j_value, one);
+        builder.var_set(j, next);
+        builder.jump(Some(inner_header));
+
+        builder.block = outer_latch;
+        let i_value = builder.var_get(i);
+        let next = builder.binary(b3::Opcode::Add, i_value, one);
+        builder.var_set(i, next);
+        builder.jump(Some(outer_header));
+
+        builder.block = exit;
+        let sum_value = builder.var_get(sum);
+        builder.return_(Some(sum_value));
+
+        (proc, [scaled, offset])
+    };
+
+    // Both values are hoisted out of both loops, and each of them only once.
+    let (mut proc, [scaled, offset]) = build();
+    proc.reset_reachability();
+    assert!(b3::hoist_loop_invariant_values::hoist_loop_invariant_values(&mut proc));
+    assert_eq!(b3::validate(&proc), Ok(()));
+
+    let owner = proc.value(scaled).owner.unwrap();
+    assert_eq!(proc.value(offset).owner, Some(owner));
+    assert!(proc.natural_loops_or_compute().loops_of(owner).is_empty());
+
+    for value in [scaled, offset] {
+        let count = proc.blocks.iter().filter(|block| block.contains(&value)).count();
+        assert_eq!(count, 1, "v@{} is in {} blocks", value.0, count);
+    }
+
+    let compilation = b3::compile(build().0);
+    let func: extern "C" fn(i64, i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    assert_eq!(func(2, 3), 99);
+    assert_eq!(func(2, 0), 0);
+}
+
+#[test]
+fn test_air_validate() {
+    // max(a, b), compiled with Air validation after every phase.
+    let build = |opt_level| {
+        let mut opts = b3::Options::default();
+        opts.opt_level = opt_level;
+        opts.validate_air_at_each_phase = true;
+
+        let mut proc = b3::Procedure::new(opts);
+        let entry = proc.add_block(1.0);
+        let then = proc.add_block(1.0);
+        let otherwise = proc.add_block(1.0);
+        let join = proc.add_block(1.0);
+
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+        let a = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+        let b = builder.argument(Reg::new_gpr(ARGUMENT_GPR1), b3::Type::Int64);
+        let cmp = builder.binary(b3::Opcode::GreaterThan, a, b);
+        builder.branch(cmp, then, (otherwise, b3::Frequency::Normal));
+
+        builder.block = join;
+        let phi = builder.phi(b3::Type::Int64);
+        builder.return_(Some(phi));
+
+        builder.block = then;
+        builder.upsilon(a, Some(phi));
+        builder.jump(Some(join));
+
+        builder.block = otherwise;
+        builder.upsilon(b, Some(phi));
+        builder.jump(Some(join));
+
+        proc
+    };
+
+    for opt_level in [b3::OptLevel::O1, b3::OptLevel::O3] {
+        let compilation = b3::compile(build(opt_level));
+        let func: extern "C" fn(i64, i64) -> i64 =
+            unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+        assert_eq!(func(3, 7), 7);
+        assert_eq!(func(7, 3), 7);
+    }
+
+    let root = BasicBlockId(0);
+
+    let mut proc = build(b3::OptLevel::O2);
+    let mut code = b3::generate_to_air(&mut proc);
+    code.reset_reachability();
+    assert_eq!(b3::air::validate(&code), Ok(()));
+
+    let origin = code.block(root)[0].origin;
+    let gp = code.new_tmp(b3::Bank::GP);
+    let other_gp = code.new_tmp(b3::Bank::GP);
+    let spill = code.add_stack_slot(4, StackSlotKind::Spill);
+    let successor = code.block(root).successors[0].0;
+
+    let check = |inst: Inst, message: &str| {
+        let mut proc = build(b3::OptLevel::O2);
+        let mut code = b3::generate_to_air(&mut proc);
+        code.reset_reachability();
+        code.new_tmp(b3::Bank::GP);
+        code.new_tmp(b3::Bank::GP);
+        code.add_stack_slot(4, StackSlotKind::Spill);
+        code.block_mut(root).insert(0, inst);
+
+        let error = b3::air::validate(&code).unwrap_err();
+        assert_eq!(error.block, Some(root));
+        assert_eq!(error.inst, Some(0));
+        assert!(error.message.starts_with(message), "{}", error);
+    };
+
+    // A terminal in the middle of a block.
+    check(
+        code.block(root).last().unwrap().clone(),
+        "terminal in the middle of a block",
+    );
+
+    // A GP Tmp that is wider than a pointer.
+    let wide = [Arg::new_tmp(gp), Arg::new_tmp(other_gp), Arg::new_width_arg(Width::W128)];
+    check(
+        Inst::new(AirOpcode::Shuffle.into(), origin, &wide),
+        &format!("{} used at Width128", gp),
+    );
+
+    // A stack slot that doesn't exist, and an access past the end of a spill slot.
+    let from_missing = [Arg::new_stack(StackSlotId(1000), 0), Arg::new_tmp(gp)];
+    check(
+        Inst::new(AirOpcode::Move.into(), origin, &from_missing),
+        "stack slot 1000 does not exist",
+    );
+    let from_spill = [Arg::new_stack(spill, 0), Arg::new_tmp(gp)];
+    check(
+        Inst::new(AirOpcode::Move.into(), origin, &from_spill),
+        &format!("Width64 access at offset 0 is outside of spill slot {}", spill.0),
+    );
+
+    // A branch with a single successor.
+    let mut proc = build(b3::OptLevel::O2);
+    let mut branch_code = b3::generate_to_air(&mut proc);
+    branch_code.reset_reachability();
+    assert_eq!(branch_code.block(root).successors.len(), 2);
+    branch_code.block_mut(root).successors.truncate(1);
+    let error = b3::air::validate(&branch_code).unwrap_err();
+    assert_eq!(error.block, Some(root));
+    assert!(error.message.ends_with("must have 2 successors, got 1"), "{}", error);
+
+    // A successor that doesn't know about its predecessor.
+    code.block_mut(successor).predecessors.retain(|&block| block != root);
+    let error = b3::air::validate(&code).unwrap_err();
+    assert_eq!(error.block, Some(root));
+    assert_eq!(
+        error.message,
+        format!("successor BB{} does not list it as a predecessor", successor.0)
+    );
+}
+
+#[test]
+fn test_parse_procedure() {
+    // Printing, parsing and printing again gives the same text.
+    let mut proc = b3::Procedure::new(Default::default());
+    let pair = proc.add_struct_type(&[b3::Type::Int64, b3::Type::Int32]);
+    let x = proc.add_variable(b3::Type::Int64);
+    proc.add_variable(pair);
+    let slot = proc.add_stack_slot(8, StackSlotKind::Locked);
+
+    let entry = proc.add_block(1.0);
+    let small = proc.add_block(0.5);
+    let call = proc.add_block(0.0);
+    let otherwise = proc.add_block(0.5);
+
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+    let a = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+    builder.var_set(x, a);
+    let base = builder.stack_addr(slot);
+    builder.store(a, base, 0, Some(0..8), None);
+    let switch = builder.switch(a);
+    builder.procedure.switch_append_case(switch, (1, (small, b3::Frequency::Normal)));
+    builder.procedure.switch_append_case(switch, (2, (call, b3::Frequency::Rare)));
+    let default = (otherwise, b3::Frequency::Normal);
+    builder.procedure.block_mut(entry).successor_list_mut().push(default);
+
+    builder.block = small;
+    let loaded = builder.load(b3::Type::Int64, base, 0, Some(0..8), None);
+    let value = builder.var_get(x);
+    let quotient = builder.binary(b3::Opcode::Div, loaded, value);
+    builder.procedure.value_mut(quotient).kind.set_is_chill(true);
+    builder.return_(Some(quotient));
+
+    builder.block = call;
+    let callee = builder.const64(0);
+    let narrow = builder.const32(-1);
+    let result = builder.ccall_variadic(b3::Type::Int64, callee, &[narrow], b3::Effects::none());
+    builder.return_(Some(result));
+
+    builder.block = otherwise;
+    let half = builder.const_double(0.5);
+    let truncated = builder.d2i(half);
+    builder.return_(Some(truncated));
+
+    proc.reset_reachability();
+
+    let text = proc.display().to_string();
+    let parsed = b3::parse_procedure(&text, Default::default()).unwrap();
+    assert_eq!(parsed.display().to_string(), text);
+
+    // Value and variable numbers are kept, gaps included.
+    let text = format!(
+        "Procedure {{
+BB0: ; frequency = 1:
+    Int64 v@0 = ArgumentReg( {})
+    Int64 v@1 = Const64( $a)
+    Int64 v@5 = Mul(v@0, v@1)
+    Void v@6 = Set(v@5 var@2)
+    Void v@7 = Jump()
+  Successors: BB1
+BB1: ; frequency = 1:
+  Predecessors: BB0
+    Int64 v@8 = Get( var@2)
+    Void v@9 = Return(v@8)
+Variables:
+  var@2: Int64
+}}
+",
+        Reg::new_gpr(ARGUMENT_GPR0)
+    );
+    let proc = b3::parse_procedure(&text, Default::default()).unwrap();
+    assert_eq!(proc.display().to_string(), text);
+
+    let compilation = b3::compile(proc);
+    let func: extern "C" fn(i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    assert_eq!(func(4), 40);
+
+    let text = "Procedure {\nBB0: ; frequency = 1:\n    Void v@0 = Frobnicate()\n}\n";
+    let error = b3::parse_procedure(text, Default::default()).unwrap_err();
+    assert_eq!(error.line, 3);
+    assert_eq!(error.message, "unknown opcode `Frobnicate`");
+}
+
+#[test]
+fn test_serialize_procedure() {
+    let mut generators = b3::GeneratorRegistry::new();
+    let square = generators.register("square", |jit, params| {
+        let output = params[0];
+        let input = params[1];
+
+        jit.mul64(input.get_reg().gpr(), input.get_reg().gpr());
+        jit.store64(
+            input.get_reg().gpr(),
+            Address::new(CALL_FRAME_REGISTER, output.offset_from_fp() as i32),
+        );
+    });
+
+    // x * x + data[0], with the argument round-tripped through a variable.
+    let mut proc = b3::Procedure::new(Default::default());
+    let (_, data) = proc.add_data_section(8);
+    unsafe { *(data as *mut i64) = 5 };
+    let x = proc.add_variable(b3::Type::Int64);
+
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+    let a = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+    builder.var_set(x, a);
+    let value = builder.var_get(x);
+
+    let patchpoint = builder.patchpoint(b3::Type::Int64);
+    builder
+        .procedure
+        .stackmap_append(patchpoint, value, ValueRep::new(ValueRepKind::SomeRegister));
+    builder
+        .procedure
+        .patchpoint_set_result_constraints(patchpoint, ValueRep::new(ValueRepKind::StackArgument));
+    builder.procedure.stackmap_set_generator(patchpoint, square);
+
+    let address = builder.const64(data as i64);
+    let loaded = builder.load(b3::Type::Int64, address, 0, None, None);
+    let sum = builder.binary(b3::Opcode::Add, patchpoint, loaded);
+    builder.return_(Some(sum));
+
+    let bytes = b3::serialize_procedure(&proc, &generators).unwrap();
+    let copy = b3::deserialize_procedure(&bytes, Default::default(), &generators).unwrap();
+
+    // The constant pointing into the data section points into the copy, and is written the same.
+    assert_eq!(b3::serialize_procedure(&copy, &generators).unwrap(), bytes);
+
+    let compilation = b3::compile(copy);
+    let func: extern "C" fn(i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    assert_eq!(func(3), 14);
+    assert_eq!(func(-4), 21);
+
+    let error = b3::serialize_procedure(&proc, &b3::GeneratorRegistry::new()).unwrap_err();
+    assert_eq!(error.message, format!("generator of v@{} is not registered", patchpoint.0));
+
+    let truncated = &bytes[..bytes.len() - 1];
+    let error = b3::deserialize_procedure(truncated, Default::default(), &generators).unwrap_err();
+    assert_eq!(error.message, "unexpected end of data");
+}
+
+#[test]
+fn test_parse_air_code() {
+    let (a, b, result) = (
+        Reg::new_gpr(ARGUMENT_GPR0),
+        Reg::new_gpr(ARGUMENT_GPR1),
+        Reg::new_gpr(RETURN_VALUE_GPR),
+    );
+
+    // The spill in BB0 is reloaded in BB1 while `a` still holds it.
+    let text = format!(
+        "BB0: ; frequency = 1
+     Move {a}, ($stack0)
+     Move ({a},{b},8), {b}
+     Branch64 Equal, {b}, $3
+  Successors: BB1, Rare:BB2
+BB1: ; frequency = 1
+  Predecessors: BB0
+     Move ($stack0), {result}
+     Ret64 {result}
+BB2: ; frequency = 0.5
+  Predecessors: BB0
+     Oops
+Stack slots:
+  stack0: 8 Spill
+"
+    );
+
+    let mut proc = b3::Procedure::new(Default::default());
+    let mut code = b3::air::parse_code(&mut proc, &text).unwrap();
+    assert_eq!(code.to_string(), text);
+    assert_eq!(b3::air::validate(&code), Ok(()));
+
+    b3::air::fix_obvious_spills::fix_obvious_spills(&mut code);
+    assert_eq!(code.block(BasicBlockId(1))[0].to_string(), format!("Move {}, {}", a, result));
+
+    let mut proc = b3::Procedure::new(Default::default());
+    let error = b3::air::parse_code(&mut proc, "BB0: ; frequency = 1\n     Move $1, %foo\n")
+        .err()
+        .unwrap();
+    assert_eq!(error.line, 2);
+    assert_eq!(error.message, "unknown register `%foo`");
+
+    // Calls need the B3 value they were lowered from.
+    let mut proc = b3::Procedure::new(Default::default());
+    let error = b3::air::parse_code(&mut proc, "BB0: ; frequency = 1\n     CCall %rax\n")
+        .err()
+        .unwrap();
+    assert_eq!(error.line, 2);
+    assert_eq!(error.message, "`CCall` instructions cannot be parsed");
+}
+
+#[test]
+fn test_fold_path_constants() {
+    // A tag that is tested again after the branch on it.
+    let build = || {
+        let mut opts = b3::Options::default();
+        opts.opt_level = b3::OptLevel::O2;
+        opts.validate_b3_at_each_phase = true;
+
+        let mut proc = b3::Procedure::new(opts);
+        let entry = proc.add_block(1.0);
+        let tagged = proc.add_block(1.0);
+        let untagged = proc.add_block(1.0);
+        let retagged = proc.add_block(1.0);
+        let untagged_again = proc.add_block(1.0);
+
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+        let a = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+        let one = builder.const64(1);
+        let tag = builder.binary(b3::Opcode::BitAnd, a, one);
+        builder.branch(tag, tagged, (untagged, b3::Frequency::Normal));
+
+        builder.block = tagged;
+        let zero = builder.const64(0);
+        let is_untagged = builder.binary(b3::Opcode::Equal, tag, zero);
+        builder.branch(tag, retagged, (untagged_again, b3::Frequency::Normal));
+
+        builder.block = untagged;
+        let ten = builder.const64(10);
+        let sum = builder.binary(b3::Opcode::Add, tag, ten);
+        builder.return_(Some(sum));
+
+        builder.block = retagged;
+        builder.return_(Some(a));
+
+        builder.block = untagged_again;
+        let minus_one = builder.const64(-1);
+        builder.return_(Some(minus_one));
+
+        let retest = *proc.block(tagged).last().unwrap();
+        (proc, is_untagged, retest, sum)
+    };
+
+    let (mut proc, is_untagged, retest, sum) = build();
+    proc.reset_reachability();
+    assert!(b3::fold_path_constants::fold_path_constants(&mut proc));
+
+    assert_eq!(proc.value(is_untagged).kind.opcode(), b3::Opcode::Identity);
+    assert_eq!(proc.value(retest).kind.opcode(), b3::Opcode::Jump);
+    let tag = sum.child(&proc, 0);
+    assert!(proc.value(tag).is_int_of(0));
+
+    let compilation = b3::compile(build().0);
+    let func: extern "C" fn(i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    assert_eq!(func(3), 3);
+    assert_eq!(func(4), 10);
+}
+
+#[test]
+fn test_eliminate_dead_code() {
+    let mut proc = b3::Procedure::new(Default::default());
+    let written = proc.add_variable(b3::Type::Int64);
+    let unused = proc.add_variable(b3::Type::Int64);
+    let entry = proc.add_block(1.0);
+
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+    let a = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+    let ptr = builder.argument(Reg::new_gpr(ARGUMENT_GPR1), b3::Type::Int64);
+
+    // Nothing uses these and they have no effects that must execute.
+    let sum = builder.binary(b3::Opcode::Add, a, a);
+    let load = builder.load(b3::Type::Int64, ptr, 0, None, None);
+    let pure_patchpoint = builder.patchpoint(b3::Type::Int64);
+    *builder.procedure.patchpoint_effects_mut(pure_patchpoint) = b3::Effects::none();
+    let pure_call = builder.ccall(b3::Type::Int64, ptr, &[a], b3::Effects::none());
+    let dead = [a, sum, load, pure_patchpoint, pure_call];
+
+    // These have no users either, but must execute.
+    let mut live = vec![ptr];
+    let trapping_load = builder.load(b3::Type::Int64, ptr, 8, None, None);
+    builder.procedure.value_mut(trapping_load).kind.set_traps(true);
+    live.push(trapping_load);
+    live.push(builder.patchpoint(b3::Type::Void));
+    live.push(builder.ccall(b3::Type::Int64, ptr, &[], b3::Effects::for_call()));
+    let zero = builder.const64(0);
+    live.push(zero);
+    live.push(builder.check(zero));
+    builder.fence(None, None);
+    live.push(*builder.procedure.block(entry).last().unwrap());
+    builder.store(zero, ptr, 0, None, None);
+    live.push(*builder.procedure.block(entry).last().unwrap());
+    builder.var_set(written, zero);
+    live.push(*builder.procedure.block(entry).last().unwrap());
+    builder.return_(None);
+    live.push(*builder.procedure.block(entry).last().unwrap());
+
+    assert!(b3::eliminate_dead_code::eliminate_dead_code(&mut proc));
+
+    for value in dead {
+        assert!(proc.values.at(value).is_none(), "v@{} was kept", value.0);
+    }
+
+    for &value in live.iter() {
+        assert!(proc.values.at(value).is_some(), "v@{} was deleted", value.0);
+    }
+
+    assert_eq!(proc.block(entry).len(), live.len());
+    assert!(proc.variables.at(written).is_some());
+    assert!(proc.variables.at(unused).is_none());
+    assert!(!b3::eliminate_dead_code::eliminate_dead_code(&mut proc));
+
+    // A store is all the function does.
+    let mut proc = b3::Procedure::new(Default::default());
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+    let a = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+    let ptr = builder.argument(Reg::new_gpr(ARGUMENT_GPR1), b3::Type::Int64);
+    let one = builder.const64(1);
+    builder.binary(b3::Opcode::Sub, a, one);
+    builder.store(a, ptr, 0, None, None);
+    builder.return_(None);
+
+    let compilation = b3::compile(proc);
+    let func: extern "C" fn(i64, *mut i64) =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    let mut slot = 0;
+    func(42, &mut slot);
+    assert_eq!(slot, 42);
+}
+
+#[test]
+fn test_eliminate_common_subexpressions() {
+    let mut proc = b3::Procedure::new(Default::default());
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+    let a = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+    let ptr = builder.argument(Reg::new_gpr(ARGUMENT_GPR1), b3::Type::Int64);
+
+    // Two fields that do not alias: the one at offset 0 in heap range 0..1 and the one at offset 8
+    // in heap range 1..2.
+    let first = builder.load(b3::Type::Int64, ptr, 0, Some(0..1), None);
+    builder.store(a, ptr, 8, Some(1..2), None);
+    let overwritten = *builder.procedure.block(entry).last().unwrap();
+    let reloaded = builder.load(b3::Type::Int64, ptr, 0, Some(0..1), None);
+    let sum = builder.binary(b3::Opcode::Add, first, reloaded);
+    builder.store(sum, ptr, 8, Some(1..2), None);
+    let stored = builder.load(b3::Type::Int64, ptr, 8, Some(1..2), None);
+    builder.return_(Some(stored));
+
+    assert!(b3::eliminate_common_subexpressions::eliminate_common_subexpressions(&mut proc));
+
+    // The store to the other field does not clobber the first load.
+    assert_eq!(proc.value(reloaded).kind.opcode(), b3::Opcode::Identity);
+    assert_eq!(reloaded.child(&proc, 0), first);
+    // The second store overwrites the first one before anything reads it.
+    assert_eq!(proc.value(overwritten).kind.opcode(), b3::Opcode::Nop);
+    // The load of the stored field gets the stored value.
+    assert_eq!(proc.value(stored).kind.opcode(), b3::Opcode::Identity);
+    assert_eq!(stored.child(&proc, 0), sum);
+
+    let compilation = b3::compile(proc);
+    let func: extern "C" fn(i64, *mut i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    let mut fields = [5, 0];
+    assert_eq!(func(7, fields.as_mut_ptr()), 10);
+    assert_eq!(fields, [5, 10]);
+}
+
+#[test]
+fn test_eliminate_dead_stores() {
+    let mut opts = b3::Options::default();
+    opts.validate_b3_at_each_phase = true;
+
+    let mut proc = b3::Procedure::new(opts);
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+    let ptr = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+    let one = builder.const64(1);
+    builder.store(one, ptr, 0, None, None);
+    let dead = *builder.procedure.block(entry).last().unwrap();
+    let two = builder.const64(2);
+    builder.store(two, ptr, 0, None, None);
+    let zero = builder.const64(0);
+    builder.return_(Some(zero));
+
+    assert!(b3::eliminate_common_subexpressions::eliminate_common_subexpressions(&mut proc));
+    assert_eq!(proc.value(dead).kind.opcode(), b3::Opcode::Nop);
+
+    let compilation = b3::compile(proc);
+    let func: extern "C" fn(*mut i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    let mut field = 0;
+    assert_eq!(func(&mut field), 0);
+    assert_eq!(field, 2);
+
+    // A narrow store does not cover a wide one, so the wide one stays.
+    let mut proc = b3::Procedure::new(Default::default());
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+    let ptr = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+    let wide = builder.const64(0x1111_1111_2222_2222);
+    builder.store(wide, ptr, 0, None, None);
+    let wide_store = *builder.procedure.block(entry).last().unwrap();
+    let narrow = builder.const32(0x3333_3333);
+    builder.store(narrow, ptr, 0, None, None);
+    let zero = builder.const64(0);
+    builder.return_(Some(zero));
+
+    b3::eliminate_common_subexpressions::eliminate_common_subexpressions(&mut proc);
+    assert_eq!(proc.value(wide_store).kind.opcode(), b3::Opcode::Store);
+
+    let compilation = b3::compile(proc);
+    let func: extern "C" fn(*mut i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    let mut field = 0;
+    assert_eq!(func(&mut field), 0);
+    assert_eq!(field, 0x1111_1111_3333_3333);
+}
+
+#[test]
+fn test_sccp_folds_constants() {
+    use b3::{Opcode::*, Value};
+
+    let int32 = Value::make_const32;
+    let int64 = Value::make_const64;
+    let float = Value::make_const_float;
+    let double = Value::make_const_double;
+
+    let fold = |opcode: b3::Opcode, args: &[Value], expected: Value| {
+        let mut proc = b3::Procedure::new(Default::default());
+        let entry = proc.add_block(1.0);
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+        let children = args
+            .iter()
+            .map(|arg| {
+                let constant = builder.procedure.add(arg.clone());
+                builder.add_value(constant);
+                constant
+            })
+            .collect::<Vec<_>>();
+        let num_children =
+            if children.len() == 1 { b3::NumChildren::One } else { b3::NumChildren::Two };
+        let value =
+            Value::new(opcode, expected.typ(), num_children, &children, b3::ValueData::None);
+        let result = builder.procedure.add(value);
+        builder.add_value(result);
+        builder.return_(Some(result));
+
+        proc.reset_reachability();
+        assert!(b3::sccp::sccp(&mut proc), "{:?} was not folded", opcode);
+        assert_eq!(proc.value(result).kind.opcode(), expected.kind.opcode(), "{:?}", opcode);
+        assert_eq!(proc.value(result).data, expected.data, "{:?}", opcode);
+        assert_eq!(b3::validate(&proc), Ok(()));
+    };
+
+    fold(Neg, &[int32(5)], int32(-5));
+    fold(Neg, &[int32(i32::MIN)], int32(i32::MIN));
+    fold(Neg, &[double(1.5)], double(-1.5));
+    fold(Floor, &[double(1.5)], double(1.0));
+    fold(Ceil, &[float(1.5)], float(2.0));
+    fold(Trunc, &[int64(0x1_0000_0002)], int32(2));
+    fold(SExt8, &[int32(0x80)], int32(-128));
+    fold(SExt16, &[int32(0x8000)], int32(-32768));
+    fold(SExt8To64, &[int32(0xff)], int64(-1));
+    fold(SExt16To64, &[int32(0xffff)], int64(-1));
+    fold(SExt32, &[int32(-1)], int64(-1));
+    fold(ZExt32, &[int32(-1)], int64(0xffff_ffff));
+    fold(BitwiseCast, &[double(1.0)], int64(0x3ff0_0000_0000_0000));
+    fold(BitwiseCast, &[int32(0x3f80_0000)], float(1.0));
+    fold(IToD, &[int32(3)], double(3.0));
+    fold(IToF, &[int64(3)], float(3.0));
+    fold(FloatToDouble, &[float(1.5)], double(1.5));
+    fold(DoubleToFloat, &[double(0.25)], float(0.25));
+
+    fold(Add, &[int32(5), int32(7)], int32(12));
+    fold(Add, &[int64(1 << 40), int64(1)], int64((1 << 40) + 1));
+    fold(Add, &[double(1.5), double(2.25)], double(3.75));
+    fold(Sub, &[int32(5), int32(7)], int32(-2));
+    fold(Mul, &[int32(i32::MAX), int32(2)], int32(-2));
+    fold(Div, &[int32(-7), int32(2)], int32(-3));
+    fold(UDiv, &[int32(-8), int32(2)], int32(0x7fff_fffc));
+    fold(Mod, &[int32(-7), int32(2)], int32(-1));
+    fold(UMod, &[int32(-7), int32(2)], int32(1));
+    fold(BitAnd, &[int32(6), int32(3)], int32(2));
+    fold(BitOr, &[int32(6), int32(3)], int32(7));
+    fold(BitXor, &[int32(6), int32(3)], int32(5));
+    fold(Shl, &[int32(3), int32(34)], int32(12));
+    fold(Shl, &[int64(1), int32(40)], int64(1 << 40));
+    fold(SShr, &[int32(-8), int32(1)], int32(-4));
+    fold(ZShr, &[int32(-8), int32(28)], int32(15));
+    fold(RotR, &[int32(1), int32(1)], int32(i32::MIN));
+    fold(RotR, &[int64(1), int32(0)], int64(1));
+    fold(RotL, &[int32(i32::MIN), int32(1)], int32(1));
+    fold(FMax, &[double(1.0), double(2.0)], double(2.0));
+    fold(FMin, &[float(1.0), float(2.0)], float(1.0));
+
+    fold(Equal, &[int32(5), int32(5)], int32(1));
+    fold(NotEqual, &[int32(5), int32(5)], int32(0));
+    fold(EqualOrUnordered, &[double(f64::NAN), double(1.0)], int32(1));
+    fold(EqualOrUnordered, &[double(1.0), double(2.0)], int32(0));
+    fold(LessThan, &[int32(-1), int32(1)], int32(1));
+    fold(LessThan, &[double(1.0), double(2.0)], int32(1));
+    fold(GreaterThan, &[int32(-1), int32(1)], int32(0));
+    fold(LessEqual, &[int64(1), int64(1)], int32(1));
+    fold(GreaterEqual, &[int32(0), int32(1)], int32(0));
+    fold(Above, &[int32(-1), int32(1)], int32(1));
+    fold(Below, &[int32(-1), int32(1)], int32(0));
+    fold(AboveEqual, &[int32(1), int32(1)], int32(1));
+    fold(BelowEqual, &[int32(2), int32(1)], int32(0));
+
+    // `as` saturates, but cvttsd2si and cvttss2si return the minimum integer for NaN and values
+    // out of range, so these are left alone.
+    for (opcode, arg, typ) in [
+        (DToI, double(f64::NAN), b3::Type::Int64),
+        (FToI, float(1e10), b3::Type::Int32),
+    ] {
+        let mut proc = b3::Procedure::new(Default::default());
+        let entry = proc.add_block(1.0);
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+        let constant = builder.procedure.add(arg);
+        builder.add_value(constant);
+        let value = Value::new(opcode, typ, b3::NumChildren::One, &[constant], b3::ValueData::None);
+        let result = builder.procedure.add(value);
+        builder.add_value(result);
+        builder.return_(Some(result));
+
+        proc.reset_reachability();
+        assert!(!b3::sccp::sccp(&mut proc), "{:?} was folded", opcode);
+        assert_eq!(proc.value(result).kind.opcode(), opcode);
+    }
+}
+
+#[test]
+fn test_sccp_branches_and_switches() {
+    let build = |opt_level| {
+        let mut opts = b3::Options::default();
+        opts.opt_level = opt_level;
+        opts.validate_b3_at_each_phase = true;
+
+        let mut proc = b3::Procedure::new(opts);
+        let entry = proc.add_block(1.0);
+        let then = proc.add_block(1.0);
+        let otherwise = proc.add_block(1.0);
+        let join = proc.add_block(1.0);
+        let three = proc.add_block(1.0);
+        let four = proc.add_block(1.0);
+        let other = proc.add_block(1.0);
+
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+        let a = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+        let two = builder.const64(2);
+        let one = builder.const64(1);
+        let sum = builder.binary(b3::Opcode::Add, two, one);
+        let cond = builder.binary(b3::Opcode::Equal, sum, one);
+        builder.branch(cond, otherwise, (then, b3::Frequency::Normal));
+
+        builder.block = join;
+        let phi = builder.phi(b3::Type::Int64);
+        let switch = builder.switch(sum);
+        builder.procedure.switch_append_case(switch, (3, (three, b3::Frequency::Normal)));
+        builder.procedure.switch_append_case(switch, (4, (four, b3::Frequency::Normal)));
+        let default = (other, b3::Frequency::Normal);
+        builder.procedure.block_mut(join).successor_list_mut().push(default);
+
+        builder.block = then;
+        let ten = builder.const64(10);
+        builder.upsilon(ten, Some(phi));
+        builder.jump(Some(join));
+
+        builder.block = otherwise;
+        builder.upsilon(a, Some(phi));
+        builder.jump(Some(join));
+
+        builder.block = three;
+        let result = builder.binary(b3::Opcode::Add, phi, a);
+        builder.return_(Some(result));
+
+        builder.block = four;
+        builder.return_(Some(one));
+
+        builder.block = other;
+        builder.return_(Some(two));
+
+        (proc, [cond, phi, switch])
+    };
+
+    let (mut proc, [cond, phi, switch]) = build(b3::OptLevel::O2);
+    proc.reset_reachability();
+    assert!(b3::sccp::sccp(&mut proc));
+
+    assert!(proc.value(cond).is_int_of(0));
+    // Only the Upsilon of the executable predecessor counts.
+    assert!(proc.value(phi).is_int_of(10));
+    assert_eq!(proc.value(switch).kind.opcode(), b3::Opcode::Jump);
+    // The unreachable side of the branch and the other switch cases are gone.
+    assert_eq!(proc.blocks.len(), 4);
+    assert_eq!(b3::validate(&proc), Ok(()));
+    assert!(!b3::sccp::sccp(&mut proc));
+
+    let compilation = b3::compile(build(b3::OptLevel::O3).0);
+    let func: extern "C" fn(i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    assert_eq!(func(5), 15);
+}
+
+#[test]
+fn test_reduce_loop_strength() {
+    // Sums every `step`th element of an array of 10 Int64s.
+    let build = |opt_level, step: i64| {
+        let mut opts = b3::Options::default();
+        opts.opt_level = opt_level;
+        opts.validate_b3_at_each_phase = true;
+
+        let mut proc = b3::Procedure::new(opts);
+        let entry = proc.add_block(1.0);
+        let body = proc.add_block(10.0);
+        let exit = proc.add_block(1.0);
+
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, body);
+        let i = builder.phi(b3::Type::Int64);
+        let sum = builder.phi(b3::Type::Int64);
+
+        builder.block = entry;
+        let array = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+        let zero = builder.const64(0);
+        builder.upsilon(zero, Some(i));
+        builder.upsilon(zero, Some(sum));
+        builder.jump(Some(body));
+
+        builder.block = body;
+        let eight = builder.const64(8);
+        let offset = builder.binary(b3::Opcode::Mul, i, eight);
+        let address = builder.binary(b3::Opcode::Add, array, offset);
+        let element = builder.load(b3::Type::Int64, address, 0, None, None);
+        let new_sum = builder.binary(b3::Opcode::Add, sum, element);
+        let step = builder.const64(step);
+        let next = builder.binary(b3::Opcode::Add, i, step);
+        let ten = builder.const64(10);
+        let test = builder.binary(b3::Opcode::LessThan, next, ten);
+        builder.upsilon(next, Some(i));
+        builder.upsilon(new_sum, Some(sum));
+        builder.branch(test, body, (exit, b3::Frequency::Normal));
+        let branch = *builder.procedure.block(body).last().unwrap();
+
+        builder.block = exit;
+        builder.return_(Some(new_sum));
+
+        (proc, [i, offset, address, branch])
+    };
+
+    let (mut proc, [i, offset, address, branch]) = build(b3::OptLevel::O2, 1);
+    proc.reset_reachability();
+    assert!(b3::reduce_loop_strength::reduce_loop_strength(&mut proc));
+    assert_eq!(b3::validate(&proc), Ok(()));
+
+    // The address is now a pointer that goes up by 8, and the loop exits when it reaches the end.
+    assert_eq!(proc.value(address).kind.opcode(), b3::Opcode::Identity);
+    let test = proc.value(branch).children[0];
+    assert_eq!(proc.value(test).kind.opcode(), b3::Opcode::NotEqual);
+
+    // Nothing needs the index anymore.
+    b3::eliminate_dead_code::eliminate_dead_code(&mut proc);
+    assert_eq!(b3::validate(&proc), Ok(()));
+    assert!(proc.values.at(i).is_none());
+    assert!(proc.values.at(offset).is_none());
+
+    let array = [1i64, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+
+    for (step, expected) in [(1, 55), (3, 1 + 4 + 7 + 10), (4, 1 + 5 + 9)] {
+        let compilation = b3::compile(build(b3::OptLevel::O2, step).0);
+        let func: extern "C" fn(*const i64) -> i64 =
+            unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+        assert_eq!(func(array.as_ptr()), expected);
+    }
+}
+
+#[test]
+fn test_tail_call_arguments() {
+    #[repr(C)]
+    struct Point {
+        x: i64,
+        y: f64,
+    }
+
+    extern "C" fn weigh(point: Point, scale: f64) -> f64 {
+        point.x as f64 * scale + point.y
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    extern "C" fn sum8(a: i64, b: i64, c: i64, d: i64, e: i64, f: i64, g: i64, h: i64) -> i64 {
+        a + 10 * b + 100 * c + 1000 * d + 10000 * e + 100000 * f + 1000000 * g + 10000000 * h
+    }
+
+    // The eightbytes of `Point` go into RDI and XMM0, and `scale` into XMM1.
+    let mut proc = b3::Procedure::new(Default::default());
+    let point = proc.add_struct_type(&[b3::Type::Int64, b3::Type::Double]);
+    proc.set_signature(&[b3::Type::Int64], b3::Type::Double);
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+    let slot = builder.alloca(point);
+    let x = builder.parameter(0);
+    let y = builder.const_double(0.5);
+    builder.store(x, slot, 0, None, None);
+    builder.store(y, slot, 8, None, None);
+    let point_value = builder.load(point, slot, 0, None, None);
+    let scale = builder.const_double(2.0);
+    let callee = builder.const64(weigh as usize as i64);
+    builder.tail_ccall(callee, &[point_value, scale]);
+
+    let compilation = b3::compile(proc);
+    let func: extern "C" fn(i64) -> f64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    assert_eq!(func(3), 6.5);
+
+    // Variadic callees get the number of vector registers in AL.
+    let mut buffer = [0u8; 16];
+    let format = b"%.1f\0";
+
+    let mut proc = b3::Procedure::new(Default::default());
+    proc.set_signature(&[b3::Type::Double], b3::Type::Int32);
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+    let args = [
+        builder.const64(buffer.as_mut_ptr() as i64),
+        builder.const64(buffer.len() as i64),
+        builder.const64(format.as_ptr() as i64),
+        builder.parameter(0),
+    ];
+    let callee = builder.const64(libc::snprintf as usize as i64);
+    builder.tail_ccall_variadic(callee, &args);
+
+    let compilation = b3::compile(proc);
+    let func: extern "C" fn(f64) -> i32 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    assert_eq!(func(2.5), 3);
+    assert_eq!(&buffer[..4], b"2.5\0");
+
+    // Our caller passes no stack arguments, but `sum8` needs two, so this is not a tail call.
+    let mut proc = b3::Procedure::new(Default::default());
+    proc.set_signature(&[b3::Type::Int64, b3::Type::Int64], b3::Type::Int64);
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+    let a = builder.parameter(0);
+    let b = builder.parameter(1);
+    let args = [a, b, a, b, a, b, a, b];
+    let callee = builder.const64(sum8 as usize as i64);
+    builder.tail_ccall(callee, &args);
+    let call = *builder.procedure.block(entry).last().unwrap();
+
+    let error = b3::validate(&proc).unwrap_err();
+    assert_eq!(error.value, Some(call));
+    assert_eq!(
+        error.message,
+        "tail call needs 16 bytes of stack arguments, but the procedure only receives 0"
+    );
+}
+
+#[test]
+fn test_aggregate_parameters() {
+    #[repr(C)]
+    struct Point {
+        x: i64,
+        y: f64,
+    }
+
+    #[repr(C)]
+    struct Big([i64; 3]);
+
+    // `Point` comes in RDI and XMM0, so `b` is in RSI. `Big` is passed on stack and `c` in RDX.
+    let mut proc = b3::Procedure::new(Default::default());
+    let point = proc.add_struct_type(&[b3::Type::Int64, b3::Type::Double]);
+    let big = proc.add_array_type(b3::Type::Int64, 3);
+    proc.set_signature(&[point, b3::Type::Int64, big, b3::Type::Int64], b3::Type::Double);
+
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+    let point_value = builder.parameter(0);
+    let b = builder.parameter(1);
+    let big_value = builder.parameter(2);
+    let c = builder.parameter(3);
+
+    let digits = [
+        builder.extract(point_value, 0),
+        b,
+        builder.extract(big_value, 0),
+        builder.extract(big_value, 1),
+        builder.extract(big_value, 2),
+        c,
+    ];
+
+    let ten = builder.const64(10);
+    let mut result = builder.const64(0);
+    for &digit in digits.iter().rev() {
+        let shifted = builder.binary(b3::Opcode::Mul, result, ten);
+        result = builder.binary(b3::Opcode::Add, shifted, digit);
+    }
+
+    let result = builder.i2d(result);
+    let y = builder.extract(point_value, 1);
+    let result = builder.binary(b3::Opcode::Add, result, y);
+    builder.return_(Some(result));
+
+    let compilation = b3::compile(proc);
+
+    eprintln!("test_aggregate_parameters:\n{}", compilation.disassembly());
+
+    let func: extern "C" fn(Point, i64, Big, i64) -> f64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    assert_eq!(func(Point { x: 1, y: 0.5 }, 2, Big([3, 4, 5]), 6), 654321.5);
+}
+
+#[test]
+fn test_vector_min_max() {
+    use b3::simd::{simd_info, SIMDLane, SIMDSignMode};
+
+    #[repr(C, align(16))]
+    struct Inputs {
+        fx: [f32; 4],
+        fy: [f32; 4],
+        dx: [f64; 2],
+        dy: [f64; 2],
+    }
+
+    #[repr(C, align(16))]
+    struct Aligned<const N: usize>([u64; N]);
+
+    let info = |lane| simd_info(lane, SIMDSignMode::None);
+
+    let mut proc = b3::Procedure::new(Default::default());
+    let entry = proc.add_block(1.0);
+
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+    let src = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+    let dst = builder.argument(Reg::new_gpr(ARGUMENT_GPR1), b3::Type::Int64);
+
+    let fx = builder.load(b3::Type::V128, src, 0, None, None);
+    let fy = builder.load(b3::Type::V128, src, 16, None, None);
+    let dx = builder.load(b3::Type::V128, src, 32, None, None);
+    let dy = builder.load(b3::Type::V128, src, 48, None, None);
+
+    let results = [
+        builder.vector_binary(b3::Opcode::VectorMin, info(SIMDLane::F32x4), fx, fy),
+        builder.vector_binary(b3::Opcode::VectorMax, info(SIMDLane::F32x4), fx, fy),
+        builder.vector_binary(b3::Opcode::VectorMin, info(SIMDLane::F64x2), dx, dy),
+        builder.vector_binary(b3::Opcode::VectorMax, info(SIMDLane::F64x2), dx, dy),
+    ];
+
+    for (i, result) in results.iter().enumerate() {
+        builder.store(*result, dst, i as i32 * 16, None, None);
+    }
+    builder.return_(None);
+
+    let compilation = b3::compile(proc);
+    let func: extern "C" fn(*const Inputs, *mut [u64; 8]) =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    // NaN in either operand, and zeros of both signs in both orders.
+    let inputs = Inputs {
+        fx: [1.0, -0.0, f32::NAN, 5.0],
+        fy: [2.0, 0.0, 1.0, f32::NAN],
+        dx: [0.0, 3.0],
+        dy: [-0.0, 3.5],
+    };
+
+    let mut out = [0u64; 8];
+    func(&inputs, &mut out);
+
+    let floats = |index: usize| {
+        let bytes = [out[index * 2].to_le_bytes(), out[index * 2 + 1].to_le_bytes()].concat();
+        bytes
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect::<Vec<_>>()
+    };
+    let doubles = |index: usize| {
+        [f64::from_bits(out[index * 2]), f64::from_bits(out[index * 2 + 1])]
+    };
+
+    let min = floats(0);
+    assert_eq!(min[0], 1.0);
+    assert!(min[1] == 0.0 && min[1].is_sign_negative());
+    assert!(min[2].is_nan() && min[3].is_nan());
+
+    let max = floats(1);
+    assert_eq!(max[0], 2.0);
+    assert!(max[1] == 0.0 && max[1].is_sign_positive());
+    assert!(max[2].is_nan() && max[3].is_nan());
+
+    let min = doubles(2);
+    assert!(min[0] == 0.0 && min[0].is_sign_negative());
+    assert_eq!(min[1], 3.0);
+
+    let max = doubles(3);
+    assert!(max[0] == 0.0 && max[0].is_sign_positive());
+    assert_eq!(max[1], 3.5);
+
+    // SSE has no instructions for these, they are built from other ones.
+    let mut proc = b3::Procedure::new(Default::default());
+    let entry = proc.add_block(1.0);
+
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+
+    let src = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+    let dst = builder.argument(Reg::new_gpr(ARGUMENT_GPR1), b3::Type::Int64);
+
+    let x = builder.load(b3::Type::V128, src, 0, None, None);
+    let y = builder.load(b3::Type::V128, src, 16, None, None);
+    let signed = simd_info(SIMDLane::I64x2, SIMDSignMode::Signed);
+    let unsigned = simd_info(SIMDLane::I64x2, SIMDSignMode::Unsigned);
+
+    let results = [
+        builder.vector_binary(b3::Opcode::VectorMul, info(SIMDLane::I8x16), x, y),
+        builder.vector_binary(b3::Opcode::VectorMul, info(SIMDLane::I64x2), x, y),
+        builder.vector_binary(b3::Opcode::VectorMin, signed, x, y),
+        builder.vector_binary(b3::Opcode::VectorMax, signed, x, y),
+        builder.vector_binary(b3::Opcode::VectorMin, unsigned, x, y),
+        builder.vector_binary(b3::Opcode::VectorMax, unsigned, x, y),
+    ];
+
+    for (i, result) in results.iter().enumerate() {
+        builder.store(*result, dst, i as i32 * 16, None, None);
+    }
+    builder.return_(None);
+
+    let compilation = b3::compile(proc);
+    let func: extern "C" fn(*const Aligned<4>, *mut Aligned<12>) =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    let inputs = Aligned([0x0102_8003_ff7f_1040, (-5i64) as u64, 0x0302_02fe_0281_0f05, 7]);
+    let mut out = Aligned([0u64; 12]);
+    func(&inputs, &mut out);
+    let (inputs, out) = (inputs.0, out.0);
+
+    let (x, y) = ([inputs[0], inputs[1]], [inputs[2], inputs[3]]);
+    let bytes = |pair: [u64; 2]| [pair[0].to_le_bytes(), pair[1].to_le_bytes()].concat();
+    let products = bytes(x).iter().zip(bytes(y)).map(|(a, b)| a.wrapping_mul(b));
+    assert_eq!(bytes([out[0], out[1]]), products.collect::<Vec<_>>());
+
+    assert_eq!([out[2], out[3]], [x[0].wrapping_mul(y[0]), x[1].wrapping_mul(y[1])]);
+
+    let lanes = |f: fn(u64, u64) -> u64| [f(x[0], y[0]), f(x[1], y[1])];
+    assert_eq!([out[4], out[5]], lanes(|a, b| (a as i64).min(b as i64) as u64));
+    assert_eq!([out[6], out[7]], lanes(|a, b| (a as i64).max(b as i64) as u64));
+    assert_eq!([out[8], out[9]], lanes(|a, b| a.min(b)));
+    assert_eq!([out[10], out[11]], lanes(|a, b| a.max(b)));
+    // The second lanes are -5 and 7, which order differently as signed and unsigned.
+    assert_eq!([out[5], out[9]], [(-5i64) as u64, 7]);
+}
+
+#[test]
+fn test_fences_in_memory_optimizations() {
+    let mut proc = b3::Procedure::new(Default::default());
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+    let ptr = builder.argument(Reg::new_gpr(ARGUMENT_GPR0), b3::Type::Int64);
+    let one = builder.const64(1);
+    let two = builder.const64(2);
+
+    // A full fence clobbers the loaded field, and a load-acquire is never replaced.
+    let first = builder.load(b3::Type::Int64, ptr, 0, Some(0..1), None);
+    builder.fence(None, None);
+    let after_fence = builder.load(b3::Type::Int64, ptr, 0, Some(0..1), None);
+    let acquire = builder.load(b3::Type::Int64, ptr, 0, Some(0..1), Some(0..2));
+
+    // A store-store fence keeps the first store alive, but does not stop forwarding to a load.
+    builder.store(one, ptr, 8, Some(1..2), None);
+    let released = *builder.procedure.block(entry).last().unwrap();
+    builder.fence(Some(0..2), Some(0..0));
+    builder.store(two, ptr, 8, Some(1..2), None);
+    builder.fence(Some(0..2), Some(0..0));
+    let forwarded = builder.load(b3::Type::Int64, ptr, 8, Some(1..2), None);
+
+    let sum = builder.binary(b3::Opcode::Add, first, after_fence);
+    let sum = builder.binary(b3::Opcode::Add, sum, acquire);
+    let sum = builder.binary(b3::Opcode::Add, sum, forwarded);
+    builder.return_(Some(sum));
+
+    b3::reduce_strength::reduce_strength(&mut proc);
+    assert!(b3::eliminate_common_subexpressions::eliminate_common_subexpressions(&mut proc));
+
+    let fences = proc
+        .block(entry)
+        .iter()
+        .filter(|&&value| value.opcode(&proc) == b3::Opcode::Fence)
+        .count();
+    assert_eq!(fences, 3);
+    assert_eq!(proc.value(after_fence).kind.opcode(), b3::Opcode::Load);
+    assert_eq!(proc.value(acquire).kind.opcode(), b3::Opcode::Load);
+    assert_eq!(proc.value(released).kind.opcode(), b3::Opcode::Store);
+    assert_eq!(proc.value(forwarded).kind.opcode(), b3::Opcode::Identity);
+
+    let compilation = b3::compile(proc);
+    let func: extern "C" fn(*mut i64) -> i64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+
+    let mut fields = [5, 0];
+    assert_eq!(func(fields.as_mut_ptr()), 17);
+    assert_eq!(fields, [5, 2]);
+}
+
+#[test]
+fn test_reduce_loop_strength_induction_variables() {
+    // Sums `(i << 2) + j` while `i + step < limit` or `limit > i + step`, where `i` and `j` both
+    // start at zero and go up by `step`.
+    let build = |typ: b3::Type, step: i64, limit: i64, flipped: bool| {
+        let mut opts = b3::Options::default();
+        opts.validate_b3_at_each_phase = true;
+
+        let mut proc = b3::Procedure::new(opts);
+        let entry = proc.add_block(1.0);
+        let body = proc.add_block(10.0);
+        let exit = proc.add_block(1.0);
+
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, body);
+        let i = builder.phi(typ);
+        let j = builder.phi(typ);
+        let sum = builder.phi(typ);
+
+        let constant = |builder: &mut b3::BasicBlockBuilder<'_>, value: i64| match typ {
+            b3::Type::Int32 => builder.const32(value as i32),
+            _ => builder.const64(value),
+        };
+
+        builder.block = entry;
+        let zero = constant(&mut builder, 0);
+        builder.upsilon(zero, Some(i));
+        builder.upsilon(zero, Some(j));
+        builder.upsilon(zero, Some(sum));
+        builder.jump(Some(body));
+
+        builder.block = body;
+        let two = builder.const32(2);
+        let shifted = builder.binary(b3::Opcode::Shl, i, two);
+        let term = builder.binary(b3::Opcode::Add, shifted, j);
+        let new_sum = builder.binary(b3::Opcode::Add, sum, term);
+        let step = constant(&mut builder, step);
+        let next_i = builder.binary(b3::Opcode::Add, i, step);
+        let next_j = builder.binary(b3::Opcode::Add, j, step);
+        let limit = constant(&mut builder, limit);
+        let test = if flipped {
+            builder.binary(b3::Opcode::GreaterThan, limit, next_i)
+        } else {
+            builder.binary(b3::Opcode::LessThan, next_i, limit)
+        };
+        builder.upsilon(next_i, Some(i));
+        builder.upsilon(next_j, Some(j));
+        builder.upsilon(new_sum, Some(sum));
+        builder.branch(test, body, (exit, b3::Frequency::Normal));
+        let branch = *builder.procedure.block(body).last().unwrap();
+
+        builder.block = exit;
+        builder.return_(Some(new_sum));
+
+        (proc, [j, shifted, branch])
+    };
+
+    let reduce = |typ, step, limit, flipped| {
+        let (mut proc, [j, shifted, branch]) = build(typ, step, limit, flipped);
+        proc.reset_reachability();
+        assert!(b3::reduce_loop_strength::reduce_loop_strength(&mut proc));
+        assert_eq!(b3::validate(&proc), Ok(()));
+
+        // `j` is the same as `i`, and the shift became an induction variable of its own.
+        assert_eq!(proc.value(j).kind.opcode(), b3::Opcode::Identity);
+        assert_eq!(proc.value(shifted).kind.opcode(), b3::Opcode::Identity);
+
+        let test = proc.value(branch).children[0];
+        proc.value(test).kind.opcode()
+    };
+
+    // Int64 and Int32 induction variables, with the limit on either side of the test.
+    assert_eq!(reduce(b3::Type::Int64, 1, 10, false), b3::Opcode::NotEqual);
+    assert_eq!(reduce(b3::Type::Int32, 1, 10, true), b3::Opcode::NotEqual);
+
+    // Stepping by 2 from 2, the first `i + 2` that reaches `i32::MAX` is 2^31, which wraps around.
+    // The exit test has to stay.
+    assert_eq!(reduce(b3::Type::Int32, 2, i32::MAX as i64, false), b3::Opcode::LessThan);
+
+    // 4 * (0 + 1 + ... + 9) + (0 + 1 + ... + 9)
+    let compilation = b3::compile(build(b3::Type::Int64, 1, 10, false).0);
+    let func: extern "C" fn() -> i64 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+    assert_eq!(func(), 225);
+
+    let compilation = b3::compile(build(b3::Type::Int32, 1, 10, true).0);
+    let func: extern "C" fn() -> i32 =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+    assert_eq!(func(), 225);
+}
+
+#[test]
+fn test_module_unresolved_procedures() {
+    use crate::module::{Linkage, Module};
+
+    // Returns the address of `callee`.
+    let build = |callee: b3::ProcedureId| {
+        let mut proc = b3::Procedure::new(Default::default());
+        let entry = proc.add_block(1.0);
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+        let address = builder.procedure_addr(callee);
+        builder.return_(Some(address));
+        proc
+    };
+
+    let mut module = Module::new();
+    let caller = module.declare("caller", Linkage::Export);
+    let callee = module.declare("callee", Linkage::Internal);
+    module.define(caller, build(callee));
+
+    let error = module.compile().err().unwrap();
+    assert_eq!(error.procedure, callee);
+    assert_eq!(error.message, "procedure 'callee' is declared but not defined");
+
+    let mut module = Module::new();
+    let caller = module.declare("caller", Linkage::Export);
+    module.define(caller, build(b3::ProcedureId(1)));
+
+    let error = module.compile().err().unwrap();
+    assert_eq!(error.procedure, b3::ProcedureId(1));
+    assert_eq!(
+        error.message,
+        "procedure 'caller' refers to procedure 1 which is not part of the module"
+    );
+
+    // Every use of a procedure gets its own patched move of the address.
+    let mut module = Module::new();
+    let caller = module.declare("caller", Linkage::Export);
+    let callee = module.declare("callee", Linkage::Export);
+
+    let mut proc = b3::Procedure::new(Default::default());
+    let entry = proc.add_block(1.0);
+    let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+    let first = builder.procedure_addr(callee);
+    let second = builder.procedure_addr(callee);
+    let difference = builder.binary(b3::Opcode::Sub, first, second);
+    let result = builder.binary(b3::Opcode::Add, first, difference);
+    builder.return_(Some(result));
+    module.define(caller, proc);
+    module.define(callee, build(caller));
+
+    let compilation = module.compile().unwrap();
+    assert!(compilation.byproducts().is_empty());
+
+    let func: extern "C" fn() -> *const u8 =
+        unsafe { std::mem::transmute(compilation.lookup("caller").unwrap()) };
+    assert_eq!(func(), compilation.lookup("callee").unwrap());
+
+    let func: extern "C" fn() -> *const u8 =
+        unsafe { std::mem::transmute(compilation.lookup("callee").unwrap()) };
+    assert_eq!(func(), compilation.lookup("caller").unwrap());
+}
+
+#[test]
+fn test_alloca_alignment() {
+    // Returns the address of a struct that has a 16-byte aligned member.
+    let build = || {
+        let mut proc = b3::Procedure::new(Default::default());
+        let padded = proc.add_struct_type(&[b3::Type::Int32, b3::Type::V128]);
+        let entry = proc.add_block(1.0);
+        let mut builder = b3::BasicBlockBuilder::new(&mut proc, entry);
+        let slot = builder.alloca(padded);
+        builder.return_(Some(slot));
+        proc
+    };
+
+    let mut proc = build();
+    assert!(b3::lower_aggregates::lower_aggregates(&mut proc));
+    assert_eq!(proc.stack_slots[0].min_alignment, 16);
+    assert_eq!(proc.stack_slots[0].alignment(), 16);
+
+    let text = proc.display().to_string();
+    assert!(text.contains("slot@0: 32 Locked align 16\n"));
+    let parsed = b3::parse_procedure(&text, Default::default()).unwrap();
+    assert_eq!(parsed.display().to_string(), text);
+
+    let compilation = b3::compile(build());
+    let func: extern "C" fn() -> usize =
+        unsafe { std::mem::transmute(compilation.code_ref().start()) };
+    assert_eq!(func() % 16, 0);
+}
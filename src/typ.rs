@@ -37,6 +37,17 @@ impl Type {
         Self { kind: kind as _ }
     }
 
+    /// Create a type that refers to aggregate `index` in the procedure's aggregate registry.
+    ///
+    /// Use [`Procedure::add_struct_type`](crate::Procedure::add_struct_type) or
+    /// [`Procedure::add_array_type`](crate::Procedure::add_array_type) instead of calling this directly.
+    pub const fn aggregate(index: u32) -> Self {
+        assert!(index & AGGREGATE_FLAG == 0);
+        Self {
+            kind: AGGREGATE_FLAG | index,
+        }
+    }
+
     pub const fn kind(&self) -> TypeKind {
         if (self.kind & AGGREGATE_FLAG) != 0 {
             TypeKind::Aggregate
@@ -113,6 +124,81 @@ pub const fn size_of_type(ty: Type) -> u32 {
     }
 }
 
+/// Shape of an aggregate type.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Aggregate {
+    /// Fields are laid out in order, each field is aligned to its natural alignment.
+    Struct(Vec<Type>),
+    /// `length` elements of the same type.
+    Array(Type, usize),
+}
+
+/// Aggregate type together with its memory layout.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AggregateType {
+    pub(crate) aggregate: Aggregate,
+    pub(crate) size: usize,
+    pub(crate) alignment: usize,
+    /// Offsets of struct fields. Empty for arrays.
+    pub(crate) offsets: Vec<usize>,
+}
+
+impl AggregateType {
+    pub fn aggregate(&self) -> &Aggregate {
+        &self.aggregate
+    }
+
+    /// Size of the aggregate in bytes, including trailing padding.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn alignment(&self) -> usize {
+        self.alignment
+    }
+
+    pub fn is_struct(&self) -> bool {
+        matches!(self.aggregate, Aggregate::Struct(_))
+    }
+
+    pub fn is_array(&self) -> bool {
+        matches!(self.aggregate, Aggregate::Array(..))
+    }
+
+    /// Number of struct fields or array elements.
+    pub fn num_elements(&self) -> usize {
+        match self.aggregate {
+            Aggregate::Struct(ref fields) => fields.len(),
+            Aggregate::Array(_, length) => length,
+        }
+    }
+
+    /// Type of struct field or array element at `index`.
+    pub fn element_type(&self, index: usize) -> Type {
+        match self.aggregate {
+            Aggregate::Struct(ref fields) => fields[index],
+            Aggregate::Array(element, _) => element,
+        }
+    }
+
+    /// Distance in bytes between two consecutive elements of an array.
+    pub fn stride(&self) -> usize {
+        match self.aggregate {
+            Aggregate::Array(_, length) if length != 0 => self.size / length,
+            Aggregate::Array(..) => 0,
+            Aggregate::Struct(_) => panic!("stride of struct type"),
+        }
+    }
+
+    /// Offset in bytes of struct field or array element at `index`.
+    pub fn offset_of(&self, index: usize) -> usize {
+        match self.aggregate {
+            Aggregate::Struct(_) => self.offsets[index],
+            Aggregate::Array(..) => self.stride() * index,
+        }
+    }
+}
+
 impl std::fmt::Display for Type {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.kind() {
@@ -122,7 +208,7 @@ impl std::fmt::Display for Type {
             TypeKind::Float => write!(f, "Float"),
             TypeKind::Double => write!(f, "Double"),
             TypeKind::V128 => write!(f, "V128"),
-            TypeKind::Aggregate => write!(f, "Tuple"),
+            TypeKind::Aggregate => write!(f, "agg@{}", self.aggregate_index()),
         }
     }
 }
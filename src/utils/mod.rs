@@ -11,9 +11,12 @@ pub mod phase_scope;
 
 pub trait RangeExt {
     fn overlaps(&self, other: &Self) -> bool;
+
+    /// Smallest range that covers both ranges. Empty ranges are ignored.
+    fn merge(&self, other: &Self) -> Self;
 }
 
-impl<T: PartialOrd> RangeExt for Range<T> {
+impl<T: PartialOrd + Copy> RangeExt for Range<T> {
     fn overlaps(&self, other: &Self) -> bool {
         let left_max = &self.end;
         let right_min = &other.start;
@@ -22,4 +25,19 @@ impl<T: PartialOrd> RangeExt for Range<T> {
 
         left_max > right_min && right_max > left_min
     }
+
+    fn merge(&self, other: &Self) -> Self {
+        if self.is_empty() {
+            return other.clone();
+        }
+
+        if other.is_empty() {
+            return self.clone();
+        }
+
+        let start = if self.start < other.start { self.start } else { other.start };
+        let end = if self.end > other.end { self.end } else { other.end };
+
+        start..end
+    }
 }
@@ -0,0 +1,539 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    air::ccalling_convention::check_tail_call_stack_arguments,
+    analysis::dominators::Dominators,
+    block::BlockId,
+    opcode::Opcode,
+    procedure::Procedure,
+    typ::{pointer_type, Type},
+    value::ValueId,
+};
+
+/// Error reported by [`validate`]. `value` and `block` point at the offending part of the
+/// procedure, if there is one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub value: Option<ValueId>,
+    pub block: Option<BlockId>,
+    pub message: String,
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+
+        if let Some(value) = self.value {
+            write!(f, " at v@{}", value.0)?;
+        }
+
+        if let Some(block) = self.block {
+            write!(f, " in BB{}", block.0)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Checks that `proc` is well formed. This is a port of WebKit's B3Validate.
+///
+/// Only blocks reachable from the root are checked. In them:
+/// - every block ends with a terminal and has no other terminals,
+/// - the number of successors matches the terminal, and successors agree with predecessors,
+/// - every value is in exactly one block, which is its owner,
+/// - values have the number and types of children their opcode expects,
+/// - tail calls need no more stack arguments than the procedure receives,
+/// - every Phi has Upsilons and every Upsilon feeds a Phi of its child's type,
+/// - every child is defined before its user and in a block that dominates it.
+pub fn validate(proc: &Procedure) -> Result<(), ValidationError> {
+    if proc.blocks.is_empty() {
+        return Ok(());
+    }
+
+    let mut validator = Validator {
+        proc,
+        reachable: vec![],
+        positions: HashMap::new(),
+    };
+
+    validator.compute_reachable()?;
+    validator.validate_blocks()?;
+    validator.validate_cfg()?;
+    validator.validate_values()?;
+    validator.validate_dominance()
+}
+
+struct Validator<'a> {
+    proc: &'a Procedure,
+    /// Blocks reachable from the root.
+    reachable: Vec<BlockId>,
+    /// Block and index of every value in a reachable block.
+    positions: HashMap<ValueId, (BlockId, usize)>,
+}
+
+impl<'a> Validator<'a> {
+    fn error(
+        &self,
+        value: Option<ValueId>,
+        block: Option<BlockId>,
+        message: String,
+    ) -> Result<(), ValidationError> {
+        Err(ValidationError {
+            value,
+            block,
+            message,
+        })
+    }
+
+    fn value_error(&self, value: ValueId, message: String) -> Result<(), ValidationError> {
+        let block = self.positions.get(&value).map(|&(block, _)| block);
+        self.error(Some(value), block, message)
+    }
+
+    fn compute_reachable(&mut self) -> Result<(), ValidationError> {
+        let mut seen = vec![false; self.proc.blocks.len()];
+        let mut worklist = vec![BlockId(0)];
+        seen[0] = true;
+
+        while let Some(block) = worklist.pop() {
+            self.reachable.push(block);
+
+            for &(successor, _) in self.proc.block(block).successor_list.iter() {
+                if successor.0 >= self.proc.blocks.len() {
+                    return self.error(
+                        None,
+                        Some(block),
+                        format!("successor BB{} does not exist", successor.0),
+                    );
+                }
+
+                if !seen[successor.0] {
+                    seen[successor.0] = true;
+                    worklist.push(successor);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_blocks(&mut self) -> Result<(), ValidationError> {
+        for &block in self.reachable.iter() {
+            if self.proc.block(block).is_empty() {
+                return self.error(None, Some(block), "block is empty".to_string());
+            }
+
+            for (index, &value) in self.proc.block(block).iter().enumerate() {
+                if self.proc.values.at(value).is_none() {
+                    return self.error(
+                        Some(value),
+                        Some(block),
+                        "block contains a deleted value".to_string(),
+                    );
+                }
+
+                if let Some((other, _)) = self.positions.insert(value, (block, index)) {
+                    return self.error(
+                        Some(value),
+                        Some(block),
+                        format!("value is also in BB{}", other.0),
+                    );
+                }
+
+                if self.proc.value(value).owner != Some(block) {
+                    return self.error(
+                        Some(value),
+                        Some(block),
+                        "value is not owned by its block".to_string(),
+                    );
+                }
+
+                let is_last = index + 1 == self.proc.block(block).len();
+                let is_terminal = self.proc.value(value).effects().terminal;
+
+                if is_terminal && !is_last {
+                    return self.error(
+                        Some(value),
+                        Some(block),
+                        "terminal in the middle of a block".to_string(),
+                    );
+                }
+
+                if !is_terminal && is_last {
+                    return self.error(
+                        Some(value),
+                        Some(block),
+                        "block does not end with a terminal".to_string(),
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_cfg(&self) -> Result<(), ValidationError> {
+        for &block in self.reachable.iter() {
+            let basic_block = self.proc.block(block);
+            let terminal = *basic_block.last().unwrap();
+            let num_successors = basic_block.successor_list.len();
+
+            let expected = match self.proc.value(terminal).kind.opcode() {
+                Opcode::Jump => Some(1),
+                Opcode::Branch => Some(2),
+                Opcode::Switch => Some(self.proc.value(terminal).num_case_values() + 1),
+                Opcode::Return | Opcode::Oops | Opcode::TailCCall => Some(0),
+                _ => None,
+            };
+
+            if let Some(expected) = expected {
+                if num_successors != expected {
+                    return self.error(
+                        Some(terminal),
+                        Some(block),
+                        format!(
+                            "{} expects {} successors, block has {}",
+                            self.proc.value(terminal).kind,
+                            expected,
+                            num_successors
+                        ),
+                    );
+                }
+            }
+
+            for &(successor, _) in basic_block.successor_list.iter() {
+                if !self.proc.block(successor).predecessor_list.contains(&block) {
+                    return self.error(
+                        None,
+                        Some(block),
+                        format!(
+                            "successor BB{} does not list it as a predecessor",
+                            successor.0
+                        ),
+                    );
+                }
+            }
+
+            for &predecessor in basic_block.predecessor_list.iter() {
+                let is_successor = predecessor.0 < self.proc.blocks.len()
+                    && self
+                        .proc
+                        .block(predecessor)
+                        .successor_list
+                        .iter()
+                        .any(|&(successor, _)| successor == block);
+
+                if !is_successor {
+                    return self.error(
+                        None,
+                        Some(block),
+                        format!(
+                            "predecessor BB{} does not list it as a successor",
+                            predecessor.0
+                        ),
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_values(&self) -> Result<(), ValidationError> {
+        let mut phis_with_upsilons = HashSet::new();
+
+        for &block in self.reachable.iter() {
+            for &value in self.proc.block(block).iter() {
+                for &child in self.proc.value(value).children.iter() {
+                    if !self.positions.contains_key(&child) {
+                        return self.value_error(
+                            value,
+                            format!("child v@{} is not in a reachable block", child.0),
+                        );
+                    }
+                }
+
+                self.validate_types(value)?;
+
+                if let Some(phi) = self.proc.value(value).phi() {
+                    phis_with_upsilons.insert(phi);
+                }
+            }
+        }
+
+        for &block in self.reachable.iter() {
+            for &value in self.proc.block(block).iter() {
+                if self.proc.value(value).kind.opcode() == Opcode::Phi
+                    && !phis_with_upsilons.contains(&value)
+                {
+                    return self.value_error(value, "Phi has no Upsilons".to_string());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn expect_children(&self, value: ValueId, count: usize) -> Result<(), ValidationError> {
+        let actual = self.proc.value(value).children.len();
+
+        if actual != count {
+            return self.value_error(
+                value,
+                format!(
+                    "{} expects {} children, got {}",
+                    self.proc.value(value).kind,
+                    count,
+                    actual
+                ),
+            );
+        }
+
+        Ok(())
+    }
+
+    fn expect_child(
+        &self,
+        value: ValueId,
+        index: usize,
+        expected: &str,
+        is_expected: impl FnOnce(Type) -> bool,
+    ) -> Result<(), ValidationError> {
+        let child = self.proc.value(value).children[index];
+        let typ = self.proc.value(child).typ();
+
+        if !is_expected(typ) {
+            return self.value_error(
+                value,
+                format!(
+                    "child {} of {} must be {}, got {}",
+                    index,
+                    self.proc.value(value).kind,
+                    expected,
+                    typ
+                ),
+            );
+        }
+
+        Ok(())
+    }
+
+    fn expect_type(
+        &self,
+        value: ValueId,
+        expected: &str,
+        is_expected: impl FnOnce(Type) -> bool,
+    ) -> Result<(), ValidationError> {
+        let typ = self.proc.value(value).typ();
+
+        if !is_expected(typ) {
+            return self.value_error(
+                value,
+                format!(
+                    "{} must be {}, got {}",
+                    self.proc.value(value).kind,
+                    expected,
+                    typ
+                ),
+            );
+        }
+
+        Ok(())
+    }
+
+    fn validate_types(&self, value: ValueId) -> Result<(), ValidationError> {
+        let typ = self.proc.value(value).typ();
+        let own_type = format!("{}", typ);
+        let same = |other: Type| other == typ;
+        let int = |other: Type| other.is_int();
+        let int32 = |other: Type| other == Type::Int32;
+        let pointer = |other: Type| other == pointer_type();
+
+        match self.proc.value(value).kind.opcode() {
+            Opcode::Identity
+            | Opcode::Opaque
+            | Opcode::Neg
+            | Opcode::Abs
+            | Opcode::Ceil
+            | Opcode::Floor
+            | Opcode::Sqrt
+            | Opcode::Clz => {
+                self.expect_children(value, 1)?;
+                self.expect_child(value, 0, &own_type, same)
+            }
+
+            Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Div
+            | Opcode::UDiv
+            | Opcode::Mod
+            | Opcode::UMod
+            | Opcode::BitAnd
+            | Opcode::BitOr
+            | Opcode::BitXor
+            | Opcode::FMax
+            | Opcode::FMin => {
+                self.expect_children(value, 2)?;
+                self.expect_child(value, 0, &own_type, same)?;
+                self.expect_child(value, 1, &own_type, same)
+            }
+
+            Opcode::Shl | Opcode::SShr | Opcode::ZShr | Opcode::RotR | Opcode::RotL => {
+                self.expect_children(value, 2)?;
+                self.expect_child(value, 0, &own_type, same)?;
+                self.expect_child(value, 1, "an integer", int)
+            }
+
+            Opcode::Equal
+            | Opcode::NotEqual
+            | Opcode::LessThan
+            | Opcode::GreaterThan
+            | Opcode::LessEqual
+            | Opcode::GreaterEqual
+            | Opcode::Above
+            | Opcode::Below
+            | Opcode::AboveEqual
+            | Opcode::BelowEqual
+            | Opcode::EqualOrUnordered => {
+                self.expect_children(value, 2)?;
+                self.expect_type(value, "an integer", int)?;
+                let left = self.proc.value(self.proc.value(value).children[0]).typ();
+                self.expect_child(value, 1, &format!("{}", left), |right| right == left)
+            }
+
+            Opcode::Select => {
+                self.expect_children(value, 3)?;
+                self.expect_child(value, 0, "an integer", int)?;
+                self.expect_child(value, 1, &own_type, same)?;
+                self.expect_child(value, 2, &own_type, same)
+            }
+
+            Opcode::SExt8 | Opcode::SExt16 => {
+                self.expect_children(value, 1)?;
+                self.expect_type(value, "Int32", int32)?;
+                self.expect_child(value, 0, "Int32", int32)
+            }
+
+            Opcode::SExt8To64 | Opcode::SExt16To64 | Opcode::SExt32 | Opcode::ZExt32 => {
+                self.expect_children(value, 1)?;
+                self.expect_type(value, "Int64", |typ| typ == Type::Int64)?;
+                self.expect_child(value, 0, "Int32", int32)
+            }
+
+            Opcode::Trunc => {
+                self.expect_children(value, 1)?;
+                let expected = match typ {
+                    Type::Float => Type::Double,
+                    _ => Type::Int64,
+                };
+                self.expect_type(value, "Int32 or Float", |typ| {
+                    typ == Type::Int32 || typ == Type::Float
+                })?;
+                self.expect_child(value, 0, &format!("{}", expected), |child| {
+                    child == expected
+                })
+            }
+
+            Opcode::IToD | Opcode::IToF => {
+                self.expect_children(value, 1)?;
+                self.expect_child(value, 0, "an integer", int)
+            }
+
+            Opcode::FToI | Opcode::FloatToDouble => {
+                self.expect_children(value, 1)?;
+                self.expect_child(value, 0, "Float", |typ| typ == Type::Float)
+            }
+
+            Opcode::DToI | Opcode::DoubleToFloat => {
+                self.expect_children(value, 1)?;
+                self.expect_child(value, 0, "Double", |typ| typ == Type::Double)
+            }
+
+            Opcode::Load8Z | Opcode::Load8S | Opcode::Load16Z | Opcode::Load16S => {
+                self.expect_children(value, 1)?;
+                self.expect_type(value, "Int32", int32)?;
+                self.expect_child(value, 0, "a pointer", pointer)
+            }
+
+            Opcode::Load => {
+                self.expect_children(value, 1)?;
+                self.expect_child(value, 0, "a pointer", pointer)
+            }
+
+            Opcode::Store8 | Opcode::Store16 => {
+                self.expect_children(value, 2)?;
+                self.expect_child(value, 0, "Int32", int32)?;
+                self.expect_child(value, 1, "a pointer", pointer)
+            }
+
+            Opcode::Store => {
+                self.expect_children(value, 2)?;
+                self.expect_child(value, 1, "a pointer", pointer)
+            }
+
+            Opcode::Upsilon => {
+                self.expect_children(value, 1)?;
+
+                let phi = match self.proc.value(value).phi() {
+                    Some(phi) => phi,
+                    None => return self.value_error(value, "Upsilon has no Phi".to_string()),
+                };
+
+                if self.proc.values.at(phi).is_none()
+                    || self.proc.value(phi).kind.opcode() != Opcode::Phi
+                {
+                    return self.value_error(value, format!("v@{} is not a Phi", phi.0));
+                }
+
+                let phi_type = self.proc.value(phi).typ();
+                self.expect_child(value, 0, &format!("{}", phi_type), |child| {
+                    child == phi_type
+                })
+            }
+
+            Opcode::Phi | Opcode::Jump | Opcode::Oops => self.expect_children(value, 0),
+
+            Opcode::Branch | Opcode::Switch => {
+                self.expect_children(value, 1)?;
+                self.expect_child(value, 0, "an integer", int)
+            }
+
+            Opcode::TailCCall => match check_tail_call_stack_arguments(self.proc, value) {
+                Ok(()) => Ok(()),
+                Err(message) => self.value_error(value, message),
+            },
+
+            _ => Ok(()),
+        }
+    }
+
+    fn validate_dominance(&self) -> Result<(), ValidationError> {
+        let dominators = Dominators::new(self.proc);
+
+        for &block in self.reachable.iter() {
+            for (index, &value) in self.proc.block(block).iter().enumerate() {
+                for &child in self.proc.value(value).children.iter() {
+                    let (child_block, child_index) = self.positions[&child];
+
+                    let dominated = if child_block == block {
+                        child_index < index
+                    } else {
+                        dominators.dominates(child_block, block)
+                    };
+
+                    if !dominated {
+                        return self.value_error(
+                            value,
+                            format!("child v@{} does not dominate its use", child.0),
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
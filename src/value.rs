@@ -8,17 +8,18 @@ use tinyvec::TinyVec;
 use crate::{
     air::stack_slot::StackSlotId,
     bank::{bank_for_type, Bank},
-    block::{BasicBlock, BlockId, FrequentBlock},
+    block::{fmt_frequent_block, BasicBlock, BlockId, FrequentBlock},
     effects::Effects,
     jit::reg::Reg,
     kind::Kind,
     opcode::Opcode,
     patchpoint_value::PatchpointValue,
-    procedure::Procedure,
+    procedure::{GlobalId, Procedure, ProcedureId},
+    simd::SIMDInfo,
     sparse_collection::SparseElement,
     stackmap_value::StackMapValue,
     typ::{Type, TypeKind},
-    utils::index_set::KeyIndex,
+    utils::{index_set::KeyIndex, RangeExt},
     variable::VariableId,
     width::{width_for_type, Width},
     *,
@@ -108,8 +109,37 @@ pub enum ValueData {
         range: Range<usize>,
         fence_range: Range<usize>,
     },
+    /// Memory value of an atomic operation. `width` is the width of the memory access, which may be
+    /// smaller than the width of the value type.
+    AtomicValue {
+        width: Width,
+        offset: i32,
+        range: Range<usize>,
+        fence_range: Range<usize>,
+    },
+    /// Heap ranges that a standalone `Fence` is treated as reading and writing. Stores to `read`
+    /// and all accesses to `write` cannot be reordered across the fence.
+    Fence {
+        read: Range<usize>,
+        write: Range<usize>,
+    },
+    /// Lane description of a `Vector*` operation. `immediate` is the lane index of
+    /// `VectorExtractLane` and `VectorReplaceLane` and is zero otherwise.
+    SIMD {
+        info: SIMDInfo,
+        immediate: u8,
+    },
     Argument(Reg),
-    CCallValue(Effects),
+    /// Index of the parameter read by `Parameter` in the procedure signature.
+    Parameter(usize),
+    /// Index of the tuple element read by `Extract`.
+    Extract(usize),
+    /// Effects of a `CCall`. `variadic` is set if the callee takes variable arguments like
+    /// `printf`, which needs the number of vector registers used in `AL` on x86-64.
+    CCallValue {
+        effects: Effects,
+        variadic: bool,
+    },
     Variable(VariableId),
     Upsilon(Option<ValueId>),
     StackMap(StackMapValue),
@@ -117,7 +147,26 @@ pub enum ValueData {
     SlotBase(StackSlotId),
     Switch(Vec<i64>),
     Alloca(Type),
-    Procedure,
+    GetElementPtr(Type),
+    Procedure(ProcedureId),
+    Global(GlobalId),
+}
+
+/// Print heap ranges of a memory access unless they are the defaults used by the builder.
+fn fmt_heap_ranges<W: std::fmt::Write>(
+    f: &mut W,
+    range: &Range<usize>,
+    fence_range: &Range<usize>,
+) -> std::fmt::Result {
+    if *range != (0..usize::MAX) {
+        write!(f, " range={:?}", range)?;
+    }
+
+    if *fence_range != (0..0) {
+        write!(f, " fence={:?}", fence_range)?;
+    }
+
+    Ok(())
 }
 
 impl Value {
@@ -128,6 +177,28 @@ impl Value {
         }
     }
 
+    /// Aggregate type that a `GetElementPtr` indexes into.
+    pub fn element_ptr_type(&self) -> Option<Type> {
+        match self.data {
+            ValueData::GetElementPtr(ty) => Some(ty),
+            _ => None,
+        }
+    }
+
+    pub fn procedure_addr(&self) -> Option<ProcedureId> {
+        match self.data {
+            ValueData::Procedure(id) => Some(id),
+            _ => None,
+        }
+    }
+
+    pub fn global(&self) -> Option<GlobalId> {
+        match self.data {
+            ValueData::Global(id) => Some(id),
+            _ => None,
+        }
+    }
+
     pub fn switch_cases(&self) -> Option<&[i64]> {
         match self.data {
             ValueData::Switch(ref cases) => Some(cases),
@@ -213,6 +284,24 @@ impl Value {
         }
     }
 
+    pub fn parameter_index(&self) -> Option<usize> {
+        match self.data {
+            ValueData::Parameter(index) => Some(index),
+            _ => None,
+        }
+    }
+
+    pub fn is_variadic_ccall(&self) -> bool {
+        matches!(self.data, ValueData::CCallValue { variadic: true, .. })
+    }
+
+    pub fn extract_index(&self) -> Option<usize> {
+        match self.data {
+            ValueData::Extract(index) => Some(index),
+            _ => None,
+        }
+    }
+
     pub fn is_int64_of(&self, val: i64) -> bool {
         match self.kind.opcode() {
             Opcode::Const64 => self.as_int64() == Some(val),
@@ -283,6 +372,8 @@ impl Value {
             Opcode::Store => width_for_type(proc.value(self.children[0]).typ()),
             Opcode::Load => width_for_type(self.typ()),
 
+            op if op.is_atomic() => self.atomic_width().unwrap(),
+
             _ => Width::W8,
         }
     }
@@ -313,6 +404,12 @@ impl Value {
                 offset,
                 ref range,
                 ref fence_range,
+            }
+            | ValueData::AtomicValue {
+                offset,
+                ref range,
+                ref fence_range,
+                ..
             } => Some((offset, range.clone(), fence_range.clone())),
             _ => None,
         }
@@ -324,11 +421,61 @@ impl Value {
                 ref mut offset,
                 ref mut range,
                 ref mut fence_range,
+            }
+            | ValueData::AtomicValue {
+                ref mut offset,
+                ref mut range,
+                ref mut fence_range,
+                ..
             } => Some((offset, range, fence_range)),
             _ => None,
         }
     }
 
+    /// True if this memory access is also a fence: an acquire for loads, a release for stores and
+    /// a full fence for atomics.
+    pub fn has_fence(&self) -> bool {
+        match self.memory_value() {
+            Some((_offset, _range, fence_range)) => !fence_range.is_empty(),
+            None => false,
+        }
+    }
+
+    /// Read and write ranges of a standalone fence.
+    pub fn fence_ranges(&self) -> Option<(Range<usize>, Range<usize>)> {
+        match self.data {
+            ValueData::Fence {
+                ref read,
+                ref write,
+            } => Some((read.clone(), write.clone())),
+            _ => None,
+        }
+    }
+
+    /// Lane description of a vector operation.
+    pub fn simd_info(&self) -> Option<SIMDInfo> {
+        match self.data {
+            ValueData::SIMD { info, .. } => Some(info),
+            _ => None,
+        }
+    }
+
+    /// Lane index of `VectorExtractLane` and `VectorReplaceLane`.
+    pub fn simd_lane(&self) -> Option<u8> {
+        match self.data {
+            ValueData::SIMD { immediate, .. } => Some(immediate),
+            _ => None,
+        }
+    }
+
+    /// Width of the memory access performed by an atomic operation.
+    pub fn atomic_width(&self) -> Option<Width> {
+        match self.data {
+            ValueData::AtomicValue { width, .. } => Some(width),
+            _ => None,
+        }
+    }
+
     pub fn effects(&self) -> Effects {
         let mut result = Effects::none();
 
@@ -342,7 +489,9 @@ impl Value {
 
                 result.reads = range;
 
-                if fence_range.start != 0 {
+                // A load-acquire keeps later accesses to the fence range from being hoisted above
+                // it, which we model as a write to that range.
+                if !fence_range.is_empty() {
                     result.writes = fence_range;
                     result.fence = true;
                 }
@@ -350,20 +499,71 @@ impl Value {
                 result.control_dependent = true;
             }
 
+            Opcode::Store8 | Opcode::Store16 | Opcode::Store => {
+                let (_offset, range, fence_range) = self.memory_value().unwrap();
+
+                result.writes = range;
+
+                // A store-release keeps earlier accesses to the fence range from being sunk below
+                // it, which we model as a read of that range.
+                if !fence_range.is_empty() {
+                    result.reads = fence_range;
+                    result.fence = true;
+                }
+
+                result.control_dependent = true;
+            }
+
+            Opcode::Fence => {
+                let (read, write) = self.fence_ranges().unwrap();
+
+                result.reads = read;
+                result.writes = write;
+                result.fence = true;
+            }
+
+            Opcode::AtomicWeakCAS
+            | Opcode::AtomicStrongCAS
+            | Opcode::AtomicXchg
+            | Opcode::AtomicXchgAdd
+            | Opcode::AtomicXchgAnd
+            | Opcode::AtomicXchgOr
+            | Opcode::AtomicXchgSub
+            | Opcode::AtomicXchgXor => {
+                let (_offset, range, fence_range) = self.memory_value().unwrap();
+
+                // Atomics both read and write the memory they access, and order all accesses in
+                // the fence range.
+                let range = range.merge(&fence_range);
+                result.reads = range.clone();
+                result.writes = range;
+                result.fence = !fence_range.is_empty();
+                result.control_dependent = true;
+            }
+
             Opcode::CCall => match self.data {
-                ValueData::CCallValue(ref ccall) => result = ccall.clone(),
+                ValueData::CCallValue { ref effects, .. } => result = effects.clone(),
                 _ => unreachable!(),
             },
 
+            Opcode::TailCCall => {
+                result = Effects::for_call();
+                result.terminal = true;
+            }
+
             Opcode::Patchpoint => match self.data {
                 ValueData::Patchpoint(ref patchpoint) => result = patchpoint.effects.clone(),
                 _ => todo!(),
             },
 
-            Opcode::Phi | Opcode::Upsilon => {
+            Opcode::Phi | Opcode::Upsilon | Opcode::Set => {
                 result.writes_local_state = true;
             }
 
+            Opcode::Get => {
+                result.reads_local_state = true;
+            }
+
             Opcode::Jump
             | Opcode::Branch
             | Opcode::Switch
@@ -380,6 +580,11 @@ impl Value {
             _ => (),
         }
 
+        if self.kind.traps() {
+            result.exit_sideways = true;
+            result.reads = 0..usize::MAX;
+        }
+
         result
     }
 
@@ -450,7 +655,10 @@ impl Value {
             typ,
             owner: None,
             num_children: NumChildren::VarArgs,
-            data: ValueData::CCallValue(effects.unwrap_or_else(Effects::for_call)),
+            data: ValueData::CCallValue {
+                effects: effects.unwrap_or_else(Effects::for_call),
+                variadic: false,
+            },
             children: Self::build_adjacency_list(NumChildren::VarArgs, args),
         }
     }
@@ -695,11 +903,11 @@ impl Value {
         } else if self.has_double() {
             Self::make_const_double(-self.as_double().unwrap())
         } else if self.has_int32() {
-            Self::make_const32(-self.as_int32().unwrap())
+            Self::make_const32(self.as_int32().unwrap().wrapping_neg())
         } else if self.has_int64() {
-            Self::make_const64(-self.as_int64().unwrap())
+            Self::make_const64(self.as_int64().unwrap().wrapping_neg())
         } else if self.has_int128() {
-            Self::make_const128(-self.as_int128().unwrap())
+            Self::make_const128(self.as_int128().unwrap().wrapping_neg())
         } else {
             return None;
         })
@@ -739,7 +947,7 @@ impl Value {
 
     pub fn zext32_constant(&self) -> Option<Value> {
         Some(if self.has_int32() {
-            Self::make_const64(self.as_int32().unwrap() as i64)
+            Self::make_const64(self.as_int32().unwrap() as u32 as i64)
         } else {
             return None;
         })
@@ -771,7 +979,7 @@ impl Value {
 
     pub fn sext16to64_constant(&self) -> Option<Value> {
         Some(if self.has_int32() {
-            Self::make_const64(self.as_int32().unwrap() as i64)
+            Self::make_const64(self.as_int32().unwrap() as i16 as i64)
         } else {
             return None;
         })
@@ -779,7 +987,7 @@ impl Value {
 
     pub fn sext8to64_constant(&self) -> Option<Value> {
         Some(if self.has_int32() {
-            Self::make_const64(self.as_int32().unwrap() as i64)
+            Self::make_const64(self.as_int32().unwrap() as i8 as i64)
         } else {
             return None;
         })
@@ -1207,18 +1415,15 @@ impl Value {
     pub fn rotr_constant(&self, other: &Value) -> Option<Value> {
         if self.has_int32() && other.has_int32() {
             Some(Self::make_const32(
-                (self.as_int32()? >> (other.as_int32()? & 31))
-                    | (self.as_int32()? << (32 - (other.as_int32()? & 31))),
+                self.as_int32()?.rotate_right(other.as_int32()? as u32 & 31),
             ))
         } else if self.has_int64() && other.has_int32() {
             Some(Self::make_const64(
-                (self.as_int64()? >> (other.as_int32()? & 63))
-                    | (self.as_int64()? << (64 - (other.as_int32()? & 63))),
+                self.as_int64()?.rotate_right(other.as_int32()? as u32 & 63),
             ))
         } else if self.has_int128() && other.has_int32() {
             Some(Self::make_const128(
-                (self.as_int128()? >> (other.as_int32()? & 127))
-                    | (self.as_int128()? << (128 - (other.as_int32()? & 127))),
+                self.as_int128()?.rotate_right(other.as_int32()? as u32 & 127),
             ))
         } else {
             None
@@ -1228,18 +1433,15 @@ impl Value {
     pub fn rotl_constant(&self, other: &Value) -> Option<Value> {
         if self.has_int32() && other.has_int32() {
             Some(Self::make_const32(
-                (self.as_int32()? << (other.as_int32()? & 31))
-                    | (self.as_int32()? >> (32 - (other.as_int32()? & 31))),
+                self.as_int32()?.rotate_left(other.as_int32()? as u32 & 31),
             ))
         } else if self.has_int64() && other.has_int32() {
             Some(Self::make_const64(
-                (self.as_int64()? << (other.as_int32()? & 63))
-                    | (self.as_int64()? >> (64 - (other.as_int32()? & 63))),
+                self.as_int64()?.rotate_left(other.as_int32()? as u32 & 63),
             ))
         } else if self.has_int128() && other.has_int32() {
             Some(Self::make_const128(
-                (self.as_int128()? << (other.as_int32()? & 127))
-                    | (self.as_int128()? >> (128 - (other.as_int32()? & 127))),
+                self.as_int128()?.rotate_left(other.as_int32()? as u32 & 127),
             ))
         } else {
             None
@@ -1543,15 +1745,13 @@ impl Value {
         block: &BasicBlock,
     ) -> std::fmt::Result {
         if self.kind.opcode() == Opcode::Branch && block.successor_list().len() == 2 {
-            write!(
-                f,
-                "Then: BB{}, Else: BB{}",
-                block.taken().0 .0,
-                block.not_taken().0 .0
-            )
+            write!(f, "Then: ")?;
+            fmt_frequent_block(f, block.taken())?;
+            write!(f, ", Else: ")?;
+            fmt_frequent_block(f, block.not_taken())
         } else {
             for (i, succ) in block.successor_list().iter().enumerate() {
-                write!(f, "BB{}", succ.0 .0)?;
+                fmt_frequent_block(f, *succ)?;
                 if i < block.successor_list().len() - 1 {
                     write!(f, ", ")?;
                 }
@@ -1580,12 +1780,60 @@ impl Value {
             ValueData::Float(x) => write!(f, " ${:x}", x)?,
             ValueData::Double(x) => write!(f, " ${:x}", x)?,
             ValueData::Variable(x) => write!(f, " var@{}", x.0)?,
-            ValueData::MemoryValue { offset, .. } => write!(f, " ${:x}", offset)?,
-            ValueData::Argument(x) => write!(f, "{:?}", x)?,
+            ValueData::MemoryValue {
+                offset,
+                ref range,
+                ref fence_range,
+            } => {
+                write!(f, " ${:x}", offset)?;
+                fmt_heap_ranges(f, range, fence_range)?;
+            }
+            ValueData::AtomicValue {
+                width,
+                offset,
+                ref range,
+                ref fence_range,
+            } => {
+                write!(f, " {} ${:x}", width, offset)?;
+                fmt_heap_ranges(f, range, fence_range)?;
+            }
+            ValueData::Argument(x) => write!(f, " {}", x)?,
+            ValueData::Parameter(x) => write!(f, " #{}", x)?,
+            ValueData::Extract(x) => write!(f, " #{}", x)?,
+            ValueData::CCallValue {
+                ref effects,
+                variadic,
+            } => {
+                if variadic {
+                    write!(f, " variadic")?;
+                }
+                if *effects != Effects::for_call() {
+                    write!(f, " effects={:?}", effects)?;
+                }
+            }
             ValueData::Upsilon(x) => match x {
                 Some(x) => write!(f, " phi=v@{}", x.0)?,
                 None => write!(f, " phi=none")?,
             },
+            ValueData::SlotBase(x) => write!(f, " slot@{}", x.0)?,
+            ValueData::Switch(ref cases) => write!(f, " cases={:?}", cases)?,
+            ValueData::Alloca(x) => write!(f, " {}", x)?,
+            ValueData::GetElementPtr(x) => write!(f, " {}", x)?,
+            ValueData::Procedure(x) => write!(f, " proc@{}", x.0)?,
+            ValueData::Global(x) => write!(f, " global@{}", x.0)?,
+            ValueData::Fence {
+                ref read,
+                ref write,
+            } => write!(f, " read={:?}, write={:?}", read, write)?,
+            ValueData::SIMD { info, immediate } => {
+                write!(f, " {:?} {:?}", info.lane, info.sign_mode)?;
+                if matches!(
+                    self.kind.opcode(),
+                    Opcode::VectorExtractLane | Opcode::VectorReplaceLane
+                ) {
+                    write!(f, " lane={}", immediate)?;
+                }
+            }
             _ => (),
         }
 